@@ -0,0 +1,221 @@
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom},
+    path::{Component, Path, PathBuf},
+};
+
+use flate2::read::GzDecoder;
+use glob::Pattern;
+use ruzstd::decoding::StreamingDecoder;
+use tar::EntryType;
+use zip::ZipArchive;
+
+use crate::{download::{cached, is_url}, manifest::{hash_file, hash_reader}, mode::set_mode, special::symlink, Error, FileOptions};
+
+/// Which members of an archive are extracted
+pub(crate) struct Selection<'a> {
+    /// Number of leading path components removed from each member
+    pub strip_components: usize,
+    /// Glob patterns, matched against the stripped path, of members to extract. Everything is extracted if empty
+    pub include: &'a [String],
+    /// Glob patterns, matched against the stripped path, of members never extracted
+    pub exclude: &'a [String],
+}
+
+/// A member of an archive
+enum Member<'a> {
+    /// Directory
+    Directory,
+    /// File, read from the archive, and its mode
+    File(&'a mut dyn Read, u32),
+    /// Symbolic link to a target
+    Link(PathBuf),
+    /// Hard link to another member, by its stripped path
+    HardLink(PathBuf),
+}
+
+impl Selection<'_> {
+    /// Path a member is extracted to, relative to the directory, or None if it isn't extracted.
+    /// Members outside the directory, e.g. "../file", are never extracted
+    fn path(&self, member: &Path) -> Option<PathBuf> {
+        let mut components = vec![];
+        for component in member.components() {
+            match component {
+                Component::Normal(component) => components.push(component),
+                Component::CurDir => (),
+                _ => return None,
+            }
+        }
+        let path = components.into_iter().skip(self.strip_components).collect::<PathBuf>();
+        let matches = |patterns: &[String]| patterns
+            .iter()
+            .any(|pattern| Pattern::new(pattern).map(|pattern| pattern.matches_path(&path)).unwrap_or(false));
+
+        let selected = !path.as_os_str().is_empty()
+            && (self.include.is_empty() || matches(self.include))
+            && !matches(self.exclude);
+        selected.then_some(path)
+    }
+}
+
+impl FileOptions {
+    /// Members of an Archive file that are extracted
+    pub(crate) fn selection(&self) -> Selection<'_> {
+        Selection { strip_components: self.strip_components, include: &self.include, exclude: &self.exclude }
+    }
+}
+
+/// Location of an archive given by a path or url. Urls are read from the download cache
+pub(crate) fn source(data: &str, sha256: &Option<String>) -> PathBuf {
+    if is_url(data) {
+        cached(data, sha256)
+    } else {
+        PathBuf::from(data)
+    }
+}
+
+/// Visit the selected members of a tar archive, compressed with gzip or zstd or not at all, or a zip archive.
+/// The format is detected from the archive's contents. Stops early if f returns false
+fn members<F>(archive: &Path, selection: &Selection, mut f: F) -> io::Result<bool>
+where
+    F: FnMut(PathBuf, Member) -> io::Result<bool>
+{
+    let mut file = File::open(archive)?;
+    let mut magic = [0; 4];
+    let read = file.read(&mut magic)?;
+    file.seek(SeekFrom::Start(0))?;
+
+    let reader: Box<dyn Read> = match &magic[..read] {
+        [b'P', b'K', 3 | 5, 4 | 6] => return zip_members(file, selection, f),
+        [0x1f, 0x8b, ..] => Box::new(GzDecoder::new(BufReader::new(file))),
+        [0x28, 0xb5, 0x2f, 0xfd] => Box::new(StreamingDecoder::new(BufReader::new(file)).map_err(io::Error::other)?),
+        _ => Box::new(BufReader::new(file)),
+    };
+
+    let mut tar = tar::Archive::new(reader);
+    for entry in tar.entries()? {
+        let mut entry = entry?;
+        let path = match selection.path(&entry.path()?) {
+            Some(path) => path,
+            None => continue,
+        };
+        let link = || -> io::Result<PathBuf> {
+            Ok(entry.link_name()?.map(|link| link.to_path_buf()).unwrap_or_default())
+        };
+        let member = match entry.header().entry_type() {
+            EntryType::Directory => Member::Directory,
+            EntryType::Symlink => Member::Link(link()?),
+            EntryType::Link => match selection.path(&link()?) {
+                Some(target) => Member::HardLink(target),
+                None => continue,
+            },
+            EntryType::Regular | EntryType::Continuous => {
+                let mode = entry.header().mode()?;
+                if !f(path, Member::File(&mut entry, mode))? {
+                    return Ok(false);
+                }
+                continue;
+            },
+            _ => continue,
+        };
+        if !f(path, member)? {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Visit the selected members of a zip archive
+fn zip_members<F>(file: File, selection: &Selection, mut f: F) -> io::Result<bool>
+where
+    F: FnMut(PathBuf, Member) -> io::Result<bool>
+{
+    let mut zip = ZipArchive::new(BufReader::new(file)).map_err(io::Error::other)?;
+    for index in 0..zip.len() {
+        let mut member = zip.by_index(index).map_err(io::Error::other)?;
+        let path = match member.enclosed_name().and_then(|path| selection.path(&path)) {
+            Some(path) => path,
+            None => continue,
+        };
+        let visited = if member.is_dir() {
+            f(path, Member::Directory)?
+        } else if member.is_symlink() {
+            let mut target = String::new();
+            member.read_to_string(&mut target)?;
+            f(path, Member::Link(PathBuf::from(target)))?
+        } else {
+            let mode = member.unix_mode().unwrap_or(0o644);
+            f(path, Member::File(&mut member, mode))?
+        };
+        if !visited {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Extract the selected members of an archive into a directory, creating it if needed
+pub(crate) fn extract(archive: &Path, directory: &Path, selection: &Selection) -> Result<(), Error> {
+    let context = || format!("{:?} -> {:?}", archive, directory);
+    fs::create_dir_all(directory).map_err(|e| Error::IO(e, context()))?;
+    members(archive, selection, |path, member| {
+        let path = directory.join(path);
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        if !matches!(member, Member::Directory) && fs::symlink_metadata(&path).is_ok() {
+            fs::remove_file(&path)?;
+        }
+        match member {
+            Member::Directory => fs::create_dir_all(&path)?,
+            Member::File(reader, mode) => {
+                io::copy(reader, &mut File::create(&path)?)?;
+                if mode & 0o7777 != 0 {
+                    set_mode(&path, mode & 0o7777)?;
+                }
+            },
+            Member::Link(target) => symlink(target, &path)?,
+            Member::HardLink(target) => fs::hard_link(directory.join(target), &path)?,
+        }
+        Ok(true)
+    })
+    .map(|_| ())
+    .map_err(|e| Error::IO(e, context()))
+}
+
+/// Check whether a directory already holds the selected members of an archive. Files are compared by hash
+pub(crate) fn extracted(archive: &Path, directory: &Path, selection: &Selection) -> Result<bool, Error> {
+    members(archive, selection, |path, member| {
+        let path = directory.join(path);
+        Ok(match member {
+            Member::Directory => path.is_dir(),
+            Member::File(reader, _) => {
+                let hash = hash_reader(reader)?;
+                fs::symlink_metadata(&path).map(|metadata| metadata.is_file()).unwrap_or(false)
+                    && hash_file(&path).map(|actual| actual == hash).unwrap_or(false)
+            },
+            Member::Link(target) => fs::read_link(&path).map(|actual| actual == target).unwrap_or(false),
+            Member::HardLink(_) => path.is_file(),
+        })
+    })
+    .map_err(|e| Error::IO(e, format!("{:?}", archive)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use super::Selection;
+
+    #[test]
+    fn test() {
+        let (include, exclude) = (vec!["bin/*".to_string(), "lib/**/*.so".to_string()], vec!["bin/debug*".to_string()]);
+        let selection = Selection { strip_components: 1, include: &include, exclude: &exclude };
+        assert_eq!(selection.path(Path::new("pkg/bin/tool")), Some(PathBuf::from("bin/tool")));
+        assert_eq!(selection.path(Path::new("./pkg/lib/x/libx.so")), Some(PathBuf::from("lib/x/libx.so")));
+        assert_eq!(selection.path(Path::new("pkg/bin/debug-tool")), None);
+        assert_eq!(selection.path(Path::new("pkg/doc/README")), None);
+        assert_eq!(selection.path(Path::new("pkg")), None);
+        assert_eq!(selection.path(Path::new("pkg/../../bin/tool")), None);
+    }
+}
@@ -0,0 +1,365 @@
+use std::{
+    future::Future,
+    io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+
+use tokio::{fs, io::{AsyncBufReadExt, AsyncRead, AsyncReadExt, AsyncWriteExt, BufReader}, process, task::{self, JoinSet}, time};
+
+use crate::{
+    command::{check_commands, echo, kill_group, Outcome, Stderr, STDERR_GRACE},
+    download::check_offline,
+    plan::{batches, create_file_from, partial, Partial},
+    policy::check_policy,
+    Command, CreateOptions, Error, FSchema, FileOptions, FileType, Operation, OverwritePolicy, Plan,
+};
+
+impl FSchema {
+    /// Create file system structure from schema without blocking the async runtime. Takes the location of where to place root as an argument 
+    pub async fn create_async(&self, root: PathBuf) -> Result<(), Error> {
+        self.create_async_with(root, &CreateOptions::default()).await
+    }
+
+    /// Create file system structure from schema with the given options without blocking the async runtime. 
+    /// Builds that keep a manifest, checkpoint or cache, roll back on error or are staged are run on tokio's blocking thread pool
+    pub async fn create_async_with(&self, root: PathBuf, options: &CreateOptions) -> Result<(), Error> {
+        if options.staged || options.rollback_on_error || options.manifest.is_some() || options.checkpoint.is_some() || options.cache.is_some() {
+            let (schema, options) = (self.clone(), options.clone());
+            return task::spawn_blocking(move || schema.create_with(root, &options))
+                .await
+                .map_err(|e| Error::IO(io::Error::other(e), "create".to_string()))?;
+        }
+        self.plan_with(root, options)?.execute_async(options).await
+    }
+}
+
+impl Plan {
+    /// Execute each operation in order with the given options without blocking the async runtime, stopping at the first error. 
+    /// Manifests, checkpoints, caches and rollback aren't supported
+    pub async fn execute_async(&self, options: &CreateOptions) -> Result<(), Error> {
+        if options.offline {
+            check_offline(self.operations())?;
+        }
+        if !options.allow_commands {
+            check_commands(self.operations())?;
+        }
+        if let Some(policy) = &options.policy {
+            check_policy(policy, self.operations())?;
+        }
+
+        for batch in batches(self.operations()) {
+            let operations = &self.operations()[batch];
+            if options.parallelism <= 1 || operations.len() == 1 {
+                for operation in operations {
+                    operation.execute_async(options).await?;
+                }
+                continue;
+            }
+
+            for chunk in operations.chunks(options.parallelism) {
+                let mut tasks = JoinSet::new();
+                for operation in chunk {
+                    let (operation, options) = (operation.clone(), options.clone());
+                    tasks.spawn(async move { operation.execute_async(&options).await });
+                }
+                while let Some(result) = tasks.join_next().await {
+                    result.map_err(|e| Error::IO(io::Error::other(e), "create".to_string()))??;
+                }
+            }
+        }
+        let (plan, options) = (self.clone(), options.clone());
+        task::spawn_blocking(move || plan.finish(&options))
+            .await
+            .map_err(|e| Error::IO(io::Error::other(e), "create".to_string()))?
+    }
+}
+
+impl Operation {
+    /// Perform the operation with the given options without blocking the async runtime. The onCreate commands of the file or
+    /// directory it creates are run once it's created, or its onError commands if it fails
+    pub async fn execute_async(&self, create_options: &CreateOptions) -> Result<(), Error> {
+        let result = self.create_async(create_options).await;
+        let (on_create, on_error) = self.hooks();
+        match result {
+            Ok(()) => {
+                for command in on_create {
+                    run(command, create_options.quiet).await?;
+                }
+                Ok(())
+            },
+            Err(e) => {
+                for command in on_error {
+                    if let Err(hook) = run(command, create_options.quiet).await {
+                        eprintln!("Warning: {}, whilst handling {}", hook, e);
+                    }
+                }
+                Err(e)
+            },
+        }
+    }
+
+    /// Create the file or directory, run the command or remove the entry the operation is for without blocking the async runtime. 
+    /// Commands, and the commands of Piped files, are run asynchronously. Everything else is done on tokio's blocking thread pool
+    async fn create_async(&self, create_options: &CreateOptions) -> Result<(), Error> {
+        match self {
+            Operation::Run(command) => run(command, create_options.quiet).await,
+            Operation::Parallel(commands) => {
+                let mut tasks = JoinSet::new();
+                for command in commands {
+                    let (command, quiet) = (command.clone(), create_options.quiet);
+                    tasks.spawn(async move { run(&command, quiet).await });
+                }
+                let mut result = Ok(());
+                while let Some(finished) = tasks.join_next().await {
+                    let finished = finished.map_err(|e| Error::IO(io::Error::other(e), "run".to_string())).and_then(|finished| finished);
+                    result = result.and(finished);
+                }
+                result
+            },
+            Operation::File { path, data, options: options @ FileOptions { ftype: FileType::Piped, run_as: None, .. } } => {
+                let policy = create_options.overwrite_policy(options);
+                if policy == OverwritePolicy::Skip && fs::symlink_metadata(path).await.is_ok() {
+                    return Ok(());
+                }
+                let output = Partial(partial(path));
+                output_to(&options.command(data), &output.0, options.max_size, create_options.quiet).await?;
+
+                let (path, data, options, create_options) = (path.clone(), data.clone(), options.clone(), create_options.clone());
+                task::spawn_blocking(move || create_file_from(&path, &data, &options, &create_options, Some(output)))
+                    .await
+                    .map_err(|e| Error::IO(io::Error::other(e), format!("{:?}", self)))?
+            },
+            operation => {
+                let (operation, create_options) = (operation.clone(), create_options.clone());
+                task::spawn_blocking(move || operation.create(&create_options))
+                    .await
+                    .map_err(|e| Error::IO(io::Error::other(e), format!("{:?}", self)))?
+            },
+        }
+    }
+}
+
+/// Run a command without blocking the async runtime, retrying it if it fails, passing its output on unless quiet is set
+async fn run(command: &Command, quiet: bool) -> Result<(), Error> {
+    retried(command, || attempt(command, quiet)).await
+}
+
+/// Write the output of a command to a file as it runs, without blocking the async runtime, retrying it if it fails.
+/// What it writes to stderr is passed on unless quiet is set
+async fn output_to(command: &Command, path: &Path, limit: Option<u64>, quiet: bool) -> Result<(), Error> {
+    retried(command, || stream(command, path, limit, quiet)).await
+}
+
+/// Make attempts at running a command until one succeeds or it's out of retries
+async fn retried<F, A>(command: &Command, mut attempt: F) -> Result<(), Error>
+where
+    F: FnMut() -> A,
+    A: Future<Output = io::Result<(Outcome, String)>>
+{
+    let mut attempts = 0;
+    loop {
+        let (outcome, stderr) = attempt().await.map_err(|e| Error::IO(e, command.to_string()))?;
+        match command.failure(outcome, stderr) {
+            None => return Ok(()),
+            Some(_) if attempts < command.retries => attempts += 1,
+            Some(error) => return command.give_up(error),
+        }
+    }
+}
+
+/// Run a command once, killing it if it runs for longer than its timeout. Returns how it ended, and the end of what it
+/// wrote to stderr. Its output is passed on unless quiet is set
+async fn attempt(command: &Command, quiet: bool) -> io::Result<(Outcome, String)> {
+    let label = (!quiet).then(|| command.label());
+    let stdout = match quiet {
+        true => Stdio::null(),
+        false => Stdio::piped(),
+    };
+    let mut process = command.process();
+    process.stdin(command.input()?).stdout(stdout).stderr(Stdio::piped());
+    let mut child = process::Command::from(process).spawn()?;
+    let pid = child.id();
+    let stdout = child.stdout.take().map(|stdout| read_output(stdout, label.clone(), false));
+    let (stderr, reader) = read_output(child.stderr.take().expect("stderr is piped"), label, true);
+    let status = match command.timeout {
+        Some(timeout) => time::timeout(Duration::from_secs(timeout), child.wait()).await.ok(),
+        None => Some(child.wait().await),
+    };
+    let outcome = match (status, pid) {
+        (Some(status), _) => Outcome::from(status?),
+        (None, Some(pid)) => {
+            kill_group(pid);
+            Outcome::TimedOut
+        },
+        (None, None) => Outcome::TimedOut,
+    };
+    if let Some((_, stdout)) = stdout {
+        time::timeout(STDERR_GRACE, stdout).await.ok();
+    }
+    time::timeout(STDERR_GRACE, reader).await.ok();
+    Ok((outcome, stderr.text()))
+}
+
+/// Run a command once, writing its output to a file, killing it if it runs for longer than its timeout or writes more
+/// than limit bytes. Returns how it ended, and the end of what it wrote to stderr, which is passed on unless quiet is set
+async fn stream(command: &Command, path: &Path, limit: Option<u64>, quiet: bool) -> io::Result<(Outcome, String)> {
+    let mut file = fs::File::create(path).await?;
+    let mut process = command.process();
+    #[cfg(unix)]
+    if limit.is_some() {
+        process.process_group(0);
+    }
+    process.stdin(command.input()?).stdout(Stdio::piped()).stderr(Stdio::piped());
+    let mut child = process::Command::from(process).spawn()?;
+    let pid = child.id();
+    let (stderr, reader) = read_output(child.stderr.take().expect("stderr is piped"), (!quiet).then(|| command.label()), true);
+    let mut stdout = child.stdout.take().expect("stdout is piped");
+    let written = async {
+        let written = tokio::io::copy(&mut (&mut stdout).take(limit.map_or(u64::MAX, |limit| limit.saturating_add(1))), &mut file).await?;
+        file.flush().await?;
+        match limit {
+            Some(limit) if written > limit => {
+                file.set_len(limit).await?;
+                if let Some(pid) = pid {
+                    kill_group(pid);
+                }
+                child.wait().await?;
+                Ok(Outcome::TooLarge(limit))
+            },
+            _ => child.wait().await.map(Outcome::from),
+        }
+    };
+    let outcome = match command.timeout {
+        Some(timeout) => match time::timeout(Duration::from_secs(timeout), written).await {
+            Ok(outcome) => outcome?,
+            Err(_) => {
+                if let Some(pid) = pid {
+                    kill_group(pid);
+                }
+                Outcome::TimedOut
+            },
+        },
+        None => written.await?,
+    };
+    time::timeout(STDERR_GRACE, reader).await.ok();
+    Ok((outcome, stderr.text()))
+}
+
+/// Read a command's stdout, or its stderr if stderr is set, in a task, a line at a time, passing each line on prefixed
+/// by label if there is one. The end of stderr is kept
+fn read_output<R>(output: R, label: Option<String>, stderr: bool) -> (Stderr, task::JoinHandle<()>)
+where
+    R: AsyncRead + Unpin + Send + 'static
+{
+    let kept = Stderr::default();
+    let keeping = kept.clone();
+    let reader = task::spawn(async move {
+        let mut output = BufReader::new(output);
+        let mut line = vec![];
+        while let Ok(1..) = output.read_until(b'\n', &mut line).await {
+            if let Some(label) = &label {
+                echo(&line, label, stderr);
+            }
+            if stderr {
+                keeping.keep(&line);
+            }
+            line.clear();
+        }
+    });
+    (kept, reader)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf};
+
+    use crate::{mode::mode_of, plan::partial, CreateOptions, Error, FSchema, OverwritePolicy};
+
+    #[tokio::test]
+    async fn test() {
+        let root = env::temp_dir().join(format!("fschema-async-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ 
+            "root": { "dir": { "file": ["data"] }, "a": ["echo a", {"ftype": "Piped"}], "b": ["echo b", {"ftype": "Piped"}] } 
+        }"#).unwrap();
+
+        schema.create_async_with(root.clone(), &CreateOptions { parallelism: 2, ..Default::default() }).await.unwrap();
+        assert_eq!(fs::read_to_string(root.join("dir/file")).unwrap(), "data");
+        assert_eq!(fs::read_to_string(root.join("b")).unwrap(), "b\n");
+        schema.create_async(root.clone()).await.unwrap();
+
+        let schema = FSchema::from_str(r#"{ "root": { "big": ["yes", {"ftype": "Piped", "maxSize": "1K"}] } }"#).unwrap();
+        assert!(matches!(schema.create_async(root.clone()).await, Err(Error::OutputLimit(_, 1024))));
+        assert!(!root.join("big").exists());
+
+        fs::write(root.join("hosts"), "127.0.0.1 localhost\n").unwrap();
+        let schema = FSchema::from_str(r#"{ "root": { "hosts": ["10.0.0.1 db\n", {"ftype": "Append"}] } }"#).unwrap();
+        let options = CreateOptions { overwrite: OverwritePolicy::Skip, ..Default::default() };
+        schema.create_async_with(root.clone(), &options).await.unwrap();
+        schema.create_async_with(root.clone(), &options).await.unwrap();
+        assert_eq!(fs::read_to_string(root.join("hosts")).unwrap(), "127.0.0.1 localhost\n10.0.0.1 db\n");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[tokio::test]
+    async fn same_as_blocking() {
+        let dir = env::temp_dir().join(format!("fschema-async-same-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("source"), "copied").unwrap();
+        let schema = FSchema::from_str(&format!(r#"{{ "root": {{
+            "bin": {{ "tool": ["echo tool", {{"ftype": "Piped", "mode": "755"}}] }},
+            "copy": ["{}", {{"ftype": "Copy"}}],
+            "current": ["bin/tool", {{"ftype": "Link"}}],
+            "hex": ["00ff", {{"ftype": "Hex"}}],
+            "sized": ["1K", {{"ftype": "Size"}}],
+            "hosts": ["10.0.0.1 db\n", {{"ftype": "Append"}}]
+        }} }}"#, dir.join("source").display())).unwrap();
+        let options = CreateOptions { quiet: true, ..Default::default() };
+        schema.create_with(dir.join("blocking"), &options).unwrap();
+        schema.create_async_with(dir.join("async"), &options).await.unwrap();
+
+        for name in ["bin/tool", "copy", "current", "hex", "sized", "hosts"] {
+            let (blocking, asynchronous) = (dir.join("blocking").join(name), dir.join("async").join(name));
+            assert_eq!(fs::read(&blocking).unwrap(), fs::read(&asynchronous).unwrap(), "{}", name);
+            assert_eq!(mode_of(&fs::symlink_metadata(&blocking).unwrap()), mode_of(&fs::symlink_metadata(&asynchronous).unwrap()), "{}", name);
+        }
+        assert_eq!(fs::read_link(dir.join("async/current")).unwrap(), PathBuf::from("bin/tool"));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn commands() {
+        let dir = env::temp_dir().join(format!("fschema-async-commands-{}", std::process::id()));
+        let root = dir.join("out");
+        let schema = FSchema::from_str(r#"{
+            "prebuild": ["echo prebuild >> \"$ROOT/../log\""],
+            "root": {
+                "a": ["echo run >> \"$ROOT/../log\"; echo a", {"ftype": "Piped", "onCreate": ["echo created >> \"$ROOT/../log\""]}],
+                "b": ["exit 3", {"ftype": "Piped", "defer": 1, "onError": ["echo failed >> \"$ROOT/../log\""]}]
+            }
+        }"#).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        let options = CreateOptions { overwrite: OverwritePolicy::Skip, quiet: true, ..Default::default() };
+
+        assert!(schema.create_async_with(root.clone(), &options).await.is_err());
+        assert_eq!(fs::read_to_string(dir.join("log")).unwrap(), "prebuild\nrun\ncreated\nfailed\n");
+        assert_eq!(fs::read_to_string(root.join("a")).unwrap(), "a\n");
+        assert!(!root.join("b").exists() && !partial(&root.join("b")).exists());
+
+        fs::write(dir.join("log"), "").unwrap();
+        assert!(schema.create_async_with(root.clone(), &options).await.is_err());
+        assert_eq!(fs::read_to_string(dir.join("log")).unwrap(), "prebuild\ncreated\nfailed\n");
+
+        let schema = FSchema::from_str(r#"{ "root": { "killed": ["kill -9 $$", {"ftype": "Piped"}] } }"#).unwrap();
+        assert!(matches!(schema.create_async_with(root.clone(), &options).await, Err(Error::Command(-1, ..))));
+        let schema = FSchema::from_str(r#"{ "root": {}, "postbuild": ["kill -9 $$"] }"#).unwrap();
+        assert!(matches!(schema.create_async_with(root.clone(), &options).await, Err(Error::Command(-1, ..))));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
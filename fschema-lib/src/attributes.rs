@@ -0,0 +1,470 @@
+use std::{
+    collections::BTreeMap,
+    ffi::{CStr, CString},
+    fs, io,
+    path::Path,
+};
+#[cfg(target_os = "linux")]
+use std::os::unix::prelude::{AsRawFd, OpenOptionsExt, OsStrExt};
+
+use crate::{
+    mode::mode_of,
+    owner::{group_id, user_id},
+    Error,
+};
+
+#[cfg(target_os = "linux")]
+/// Set an extended attribute, without following links
+fn set_xattr(path: &Path, name: &CStr, value: &[u8]) -> io::Result<()> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let result = unsafe { 
+        libc::lsetxattr(path.as_ptr(), name.as_ptr(), value.as_ptr() as *const libc::c_void, value.len(), 0) 
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Set an extended attribute, unsupported other than on linux
+fn set_xattr(_path: &Path, _name: &CStr, _value: &[u8]) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "extended attributes are only supported on linux"))
+}
+
+#[cfg(target_os = "linux")]
+/// Extended attributes of a file, directory or link, their names and values, without following links. Empty if the file
+/// system doesn't support them
+pub(crate) fn get_xattrs(path: &Path) -> io::Result<Vec<(CString, Vec<u8>)>> {
+    let path = CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let size = unsafe { libc::llistxattr(path.as_ptr(), std::ptr::null_mut(), 0) };
+    if size < 0 {
+        let e = io::Error::last_os_error();
+        return match e.raw_os_error() {
+            Some(libc::ENOTSUP) => Ok(vec![]),
+            _ => Err(e),
+        };
+    }
+    let mut names = vec![0u8; size as usize];
+    let size = unsafe { libc::llistxattr(path.as_ptr(), names.as_mut_ptr() as *mut libc::c_char, names.len()) };
+    if size < 0 {
+        return Err(io::Error::last_os_error());
+    }
+    names.truncate(size as usize);
+
+    let mut xattrs = vec![];
+    for name in names.split(|byte| *byte == 0).filter(|name| !name.is_empty()) {
+        let name = CString::new(name).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), std::ptr::null_mut(), 0) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        let mut value = vec![0u8; size as usize];
+        let size = unsafe { libc::lgetxattr(path.as_ptr(), name.as_ptr(), value.as_mut_ptr() as *mut libc::c_void, value.len()) };
+        if size < 0 {
+            return Err(io::Error::last_os_error());
+        }
+        value.truncate(size as usize);
+        xattrs.push((name, value));
+    }
+    Ok(xattrs)
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Extended attributes of a file, directory or link, none other than on linux
+pub(crate) fn get_xattrs(_path: &Path) -> io::Result<Vec<(CString, Vec<u8>)>> {
+    Ok(vec![])
+}
+
+/// Put back extended attributes read with get_xattrs, without following links
+pub(crate) fn put_xattrs(path: &Path, xattrs: &[(CString, Vec<u8>)]) -> io::Result<()> {
+    xattrs.iter().try_for_each(|(name, value)| set_xattr(path, name, value))
+}
+
+/// Set extended attributes on a file, directory or link, without following links
+pub(crate) fn set_xattrs(path: &Path, xattrs: &BTreeMap<String, String>) -> Result<(), Error> {
+    if xattrs.is_empty() {
+        return Ok(());
+    }
+
+    for (name, value) in xattrs {
+        let context = || format!("{:?}: xattr {}", path, name);
+        let cname = CString::new(name.as_str())
+            .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), context()))?;
+        set_xattr(path, &cname, value.as_bytes()).map_err(|e| Error::IO(e, context()))?;
+    }
+    Ok(())
+}
+
+// ACL tags and the version of the extended attribute ACLs are stored in, from linux/posix_acl_xattr.h
+const ACL_USER_OBJ: u16 = 0x01;
+const ACL_USER: u16 = 0x02;
+const ACL_GROUP_OBJ: u16 = 0x04;
+const ACL_GROUP: u16 = 0x08;
+const ACL_MASK: u16 = 0x10;
+const ACL_OTHER: u16 = 0x20;
+const ACL_UNDEFINED_ID: u32 = u32::MAX;
+const ACL_XATTR_VERSION: u32 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// An entry of a POSIX ACL, e.g. "user:alice:rwx" or "default:group::r-x"
+pub(crate) struct AclEntry {
+    /// Is the entry part of a directory's default ACL
+    default: bool,
+    /// Kind of entry
+    tag: u16,
+    /// Name or id of the user or group the entry is for
+    qualifier: Option<String>,
+    /// Read, write and execute bits
+    permissions: u16,
+}
+
+/// Parse an ACL entry written like setfacl does, "[default:]user|group|mask|other:[name]:permissions"
+pub(crate) fn parse_acl_entry(entry: &str) -> Result<AclEntry, String> {
+    let mut parts = entry.split(':').collect::<Vec<_>>();
+    let default = matches!(parts.first(), Some(&"default") | Some(&"d"));
+    if default {
+        parts.remove(0);
+    }
+
+    let (tag, qualifier, permissions) = match parts.as_slice() {
+        [tag, qualifier, permissions] => (*tag, *qualifier, *permissions),
+        [tag @ ("other" | "o" | "mask" | "m"), permissions] => (*tag, "", *permissions),
+        _ => return Err(format!("Expected ACL entry like user:name:rwx, found {}", entry)),
+    };
+    let qualifier = (!qualifier.is_empty()).then(|| qualifier.to_string());
+    let tag = match (tag, &qualifier) {
+        ("user" | "u", None) => ACL_USER_OBJ,
+        ("user" | "u", Some(_)) => ACL_USER,
+        ("group" | "g", None) => ACL_GROUP_OBJ,
+        ("group" | "g", Some(_)) => ACL_GROUP,
+        ("mask" | "m", None) => ACL_MASK,
+        ("other" | "o", None) => ACL_OTHER,
+        _ => return Err(format!("Expected ACL entry to be for a user, group, mask or other, found {}", entry)),
+    };
+
+    let mut bits = 0;
+    for c in permissions.chars() {
+        bits |= match c {
+            'r' => 4,
+            'w' => 2,
+            'x' => 1,
+            '-' => 0,
+            _ => return Err(format!("Expected ACL permissions made of r, w, x and -, found {}", permissions)),
+        };
+    }
+    Ok(AclEntry { default, tag, qualifier, permissions: bits })
+}
+
+/// Set the POSIX ACL of a file or directory. Entries for the owner, group and others that aren't given are taken from the mode, 
+/// and a mask is calculated if one is needed but not given, like setfacl
+pub(crate) fn set_acl(path: &Path, acl: &[String]) -> Result<(), Error> {
+    if acl.is_empty() {
+        return Ok(());
+    }
+
+    let context = || format!("{:?}: acl", path);
+    let entries = acl
+        .iter()
+        .map(|entry| parse_acl_entry(entry).map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), context())))
+        .collect::<Result<Vec<_>, _>>()?;
+    let mode = mode_of(&fs::symlink_metadata(path).map_err(|e| Error::IO(e, context()))?) as u16;
+
+    for default in [false, true] {
+        if !entries.iter().any(|entry| entry.default == default) {
+            continue;
+        }
+
+        // ordered by tag then id, as the kernel requires
+        let mut resolved = BTreeMap::from([
+            ((ACL_USER_OBJ, ACL_UNDEFINED_ID), (mode >> 6) & 7),
+            ((ACL_GROUP_OBJ, ACL_UNDEFINED_ID), (mode >> 3) & 7),
+            ((ACL_OTHER, ACL_UNDEFINED_ID), mode & 7),
+        ]);
+        for entry in entries.iter().filter(|entry| entry.default == default) {
+            let id = match (entry.tag, &entry.qualifier) {
+                (ACL_USER, Some(user)) => user.parse::<u32>().or_else(|_| user_id(user))?,
+                (ACL_GROUP, Some(group)) => group.parse::<u32>().or_else(|_| group_id(group))?,
+                _ => ACL_UNDEFINED_ID,
+            };
+            resolved.insert((entry.tag, id), entry.permissions);
+        }
+
+        let named = resolved.keys().any(|(tag, _)| *tag == ACL_USER || *tag == ACL_GROUP);
+        if named && !resolved.contains_key(&(ACL_MASK, ACL_UNDEFINED_ID)) {
+            let mask = resolved
+                .iter()
+                .filter(|((tag, _), _)| matches!(*tag, ACL_USER | ACL_GROUP_OBJ | ACL_GROUP))
+                .fold(0, |mask, (_, permissions)| mask | permissions);
+            resolved.insert((ACL_MASK, ACL_UNDEFINED_ID), mask);
+        }
+
+        let mut value = ACL_XATTR_VERSION.to_le_bytes().to_vec();
+        for ((tag, id), permissions) in resolved {
+            value.extend(tag.to_le_bytes());
+            value.extend(permissions.to_le_bytes());
+            value.extend(id.to_le_bytes());
+        }
+
+        let name = if default { c"system.posix_acl_default" } else { c"system.posix_acl_access" };
+        set_xattr(path, name, &value).map_err(|e| Error::IO(e, context()))?;
+    }
+    Ok(())
+}
+
+/// Names of the Linux capabilities, indexed by number, from linux/capability.h
+const CAPABILITIES: &[&str] = &[
+    "cap_chown", "cap_dac_override", "cap_dac_read_search", "cap_fowner", "cap_fsetid", "cap_kill", "cap_setgid",
+    "cap_setuid", "cap_setpcap", "cap_linux_immutable", "cap_net_bind_service", "cap_net_broadcast", "cap_net_admin",
+    "cap_net_raw", "cap_ipc_lock", "cap_ipc_owner", "cap_sys_module", "cap_sys_rawio", "cap_sys_chroot", "cap_sys_ptrace",
+    "cap_sys_pacct", "cap_sys_admin", "cap_sys_boot", "cap_sys_nice", "cap_sys_resource", "cap_sys_time",
+    "cap_sys_tty_config", "cap_mknod", "cap_lease", "cap_audit_write", "cap_audit_control", "cap_setfcap",
+    "cap_mac_override", "cap_mac_admin", "cap_syslog", "cap_wake_alarm", "cap_block_suspend", "cap_audit_read",
+    "cap_perfmon", "cap_bpf", "cap_checkpoint_restore",
+];
+
+// Layout of the security.capability extended attribute, from linux/capability.h
+const VFS_CAP_REVISION_2: u32 = 0x02000000;
+const VFS_CAP_FLAGS_EFFECTIVE: u32 = 0x000001;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+/// File capabilities, as sets of capability bits
+pub(crate) struct Capabilities {
+    permitted: u64,
+    inheritable: u64,
+    effective: bool,
+}
+
+/// Parse capabilities written like setcap does, e.g. "cap_net_bind_service=+ep" or "cap_chown,cap_kill+ep cap_net_raw+i"
+pub(crate) fn parse_capabilities(text: &str) -> Result<Capabilities, String> {
+    let mut capabilities = Capabilities::default();
+    let mut effective = 0u64;
+    for clause in text.split_whitespace() {
+        let start = clause
+            .find(['=', '+', '-'])
+            .ok_or_else(|| format!("Expected capabilities followed by =, + or -, found {}", clause))?;
+        let (names, mut actions) = clause.split_at(start);
+
+        let mut bits = 0u64;
+        for name in names.split(',') {
+            bits |= match name.to_ascii_lowercase().as_str() {
+                "" if names.is_empty() => (1 << CAPABILITIES.len()) - 1,
+                "all" => (1 << CAPABILITIES.len()) - 1,
+                name => CAPABILITIES
+                    .iter()
+                    .position(|capability| *capability == name)
+                    .map(|capability| 1 << capability)
+                    .ok_or_else(|| format!("Unknown capability {}", name))?,
+            };
+        }
+
+        while let Some(operator) = actions.chars().next() {
+            let flags_end = actions[1..].find(['=', '+', '-']).map(|end| end + 1).unwrap_or(actions.len());
+            let flags = &actions[1..flags_end];
+            actions = &actions[flags_end..];
+
+            if operator == '=' {
+                capabilities.permitted &= !bits;
+                capabilities.inheritable &= !bits;
+                effective &= !bits;
+            }
+            for flag in flags.chars() {
+                let set = match flag {
+                    'p' => &mut capabilities.permitted,
+                    'i' => &mut capabilities.inheritable,
+                    'e' => &mut effective,
+                    _ => return Err(format!("Expected capability flags made of e, i and p, found {}", flags)),
+                };
+                match operator {
+                    '-' => *set &= !bits,
+                    _ => *set |= bits,
+                }
+            }
+        }
+    }
+
+    capabilities.effective = effective != 0;
+    Ok(capabilities)
+}
+
+/// Set the capabilities of a file
+pub(crate) fn set_capabilities(path: &Path, capabilities: &Option<String>) -> Result<(), Error> {
+    let capabilities = match capabilities {
+        Some(capabilities) => capabilities,
+        None => return Ok(()),
+    };
+
+    let context = || format!("{:?}: capabilities {}", path, capabilities);
+    let capabilities = parse_capabilities(capabilities)
+        .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), context()))?;
+
+    let magic = VFS_CAP_REVISION_2 | if capabilities.effective { VFS_CAP_FLAGS_EFFECTIVE } else { 0 };
+    let mut value = magic.to_le_bytes().to_vec();
+    for shift in [0, 32] {
+        value.extend(((capabilities.permitted >> shift) as u32).to_le_bytes());
+        value.extend(((capabilities.inheritable >> shift) as u32).to_le_bytes());
+    }
+    set_xattr(path, c"security.capability", &value).map_err(|e| Error::IO(e, context()))
+}
+
+// File attribute flags, by the letter chattr and lsattr use for them, from linux/fs.h
+const FILE_ATTRIBUTES: &[(char, libc::c_int)] = &[
+    ('s', 0x00000001), ('u', 0x00000002), ('c', 0x00000004), ('S', 0x00000008), ('i', 0x00000010), ('a', 0x00000020),
+    ('d', 0x00000040), ('A', 0x00000080), ('j', 0x00004000), ('t', 0x00008000), ('D', 0x00010000), ('T', 0x00020000),
+    ('C', 0x00800000),
+];
+
+/// Parse file attribute flags written like chattr, e.g. "i" for immutable or "a" for append only
+pub(crate) fn parse_attrs(attrs: &str) -> Result<libc::c_int, String> {
+    attrs.chars().try_fold(0, |flags, attr| FILE_ATTRIBUTES
+        .iter()
+        .find(|(name, _)| *name == attr)
+        .map(|(_, flag)| flags | flag)
+        .ok_or_else(|| format!("Unknown file attribute {}", attr)))
+}
+
+/// Add or remove file attribute flags of a file or directory
+fn update_attrs(path: &Path, attrs: &Option<String>, add: bool) -> Result<(), Error> {
+    let attrs = match attrs {
+        Some(attrs) => attrs,
+        None => return Ok(()),
+    };
+
+    let context = || format!("{:?}: attrs {}", path, attrs);
+    let flags = parse_attrs(attrs)
+        .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), context()))?;
+    update_flags(path, flags, add).map_err(|e| Error::IO(e, context()))
+}
+
+#[cfg(target_os = "linux")]
+/// File attribute flags of a file or directory, read with the FS_IOC_GETFLAGS ioctl
+pub(crate) fn get_flags(path: &Path) -> io::Result<libc::c_int> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)?;
+    let mut flags: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(flags)
+}
+
+#[cfg(not(target_os = "linux"))]
+/// File attribute flags of a file or directory, unsupported other than on linux
+pub(crate) fn get_flags(_path: &Path) -> io::Result<libc::c_int> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "file attributes are only supported on linux"))
+}
+
+#[cfg(target_os = "linux")]
+/// Replace the file attribute flags of a file or directory with the FS_IOC_SETFLAGS ioctl
+pub(crate) fn put_flags(path: &Path, flags: libc::c_int) -> io::Result<()> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)?;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &flags) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Replace the file attribute flags of a file or directory, unsupported other than on linux
+pub(crate) fn put_flags(_path: &Path, _flags: libc::c_int) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "file attributes are only supported on linux"))
+}
+
+#[cfg(target_os = "linux")]
+/// Add or remove file attribute flags with the FS_IOC_GETFLAGS and FS_IOC_SETFLAGS ioctls
+fn update_flags(path: &Path, flags: libc::c_int, add: bool) -> io::Result<()> {
+    let file = fs::OpenOptions::new()
+        .read(true)
+        .custom_flags(libc::O_NOFOLLOW | libc::O_NONBLOCK)
+        .open(path)?;
+
+    let unsupported = |e: io::Error| match e.raw_os_error() {
+        Some(libc::ENOTTY | libc::EOPNOTSUPP | libc::EINVAL) => 
+            io::Error::new(io::ErrorKind::Unsupported, format!("{}, file attributes aren't supported by the file system", e)),
+        _ => e,
+    };
+    let mut current: libc::c_int = 0;
+    if unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_GETFLAGS, &mut current) } != 0 {
+        return Err(unsupported(io::Error::last_os_error()));
+    }
+    let updated = if add { current | flags } else { current & !flags };
+    if updated != current && unsafe { libc::ioctl(file.as_raw_fd(), libc::FS_IOC_SETFLAGS, &updated) } != 0 {
+        return Err(unsupported(io::Error::last_os_error()));
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Add or remove file attribute flags, unsupported other than on linux
+fn update_flags(_path: &Path, _flags: libc::c_int, _add: bool) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "file attributes are only supported on linux"))
+}
+
+/// Set file attribute flags on a file or directory
+pub(crate) fn set_attrs(path: &Path, attrs: &Option<String>) -> Result<(), Error> {
+    update_attrs(path, attrs, true)
+}
+
+/// Clear file attribute flags from a file or directory, so it can be changed
+pub(crate) fn clear_attrs(path: &Path, attrs: &Option<String>) -> Result<(), Error> {
+    update_attrs(path, attrs, false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, env, fs};
+
+    use super::{parse_acl_entry, parse_attrs, parse_capabilities, set_xattrs, ACL_GROUP_OBJ, ACL_USER};
+
+    #[test]
+    fn xattrs() {
+        let path = env::temp_dir().join(format!("fschema-xattrs-{}", std::process::id()));
+        fs::write(&path, "data").unwrap();
+
+        let xattrs = BTreeMap::from([("user.fschema".to_string(), "tagged".to_string())]);
+        match set_xattrs(&path, &xattrs) {
+            Ok(_) => (),
+            // not every file system supports user xattrs
+            Err(crate::Error::IO(e, _)) if e.raw_os_error() == Some(libc::ENOTSUP) || e.kind() == std::io::ErrorKind::Unsupported => (),
+            Err(e) => panic!("{}", e),
+        }
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn acl() {
+        let entry = parse_acl_entry("user:alice:rw-").unwrap();
+        assert_eq!((entry.tag, entry.qualifier.as_deref(), entry.permissions), (ACL_USER, Some("alice"), 6));
+
+        let entry = parse_acl_entry("d:g::rx").unwrap();
+        assert_eq!((entry.default, entry.tag, entry.permissions), (true, ACL_GROUP_OBJ, 5));
+
+        assert!(parse_acl_entry("other:bob:r").is_err());
+        assert!(parse_acl_entry("user:alice:rwz").is_err());
+    }
+
+    #[test]
+    fn capabilities() {
+        let capabilities = parse_capabilities("cap_net_bind_service=+ep").unwrap();
+        assert_eq!((capabilities.permitted, capabilities.inheritable, capabilities.effective), (1 << 10, 0, true));
+
+        let capabilities = parse_capabilities("cap_chown,cap_kill+p cap_chown-p cap_net_raw+i").unwrap();
+        assert_eq!((capabilities.permitted, capabilities.inheritable, capabilities.effective), (1 << 5, 1 << 13, false));
+
+        assert!(parse_capabilities("cap_flying+ep").is_err());
+        assert!(parse_capabilities("cap_chown+q").is_err());
+    }
+
+    #[test]
+    fn attrs() {
+        assert_eq!(parse_attrs("ia"), Ok(0x30));
+        assert_eq!(parse_attrs(""), Ok(0));
+        assert!(parse_attrs("iz").is_err());
+    }
+}
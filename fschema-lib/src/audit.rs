@@ -0,0 +1,145 @@
+use std::{collections::HashMap, fmt::Display, path::Path};
+
+use crate::{command::Step, run_as::install_commands, Command, FSchema, FileOptions, FileType, Node};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Command a schema can run, with what runs it
+pub struct Audited {
+    /// What runs the command, e.g. "prebuild", "after level 1" or "src/version (Piped)". Nodes are given by their path in the
+    /// schema, before variables are substituted, and commands of profiles are prefixed by the profile, e.g. "profile dev: postbuild"
+    pub owner: String,
+    /// Command, as it's written in the schema
+    pub command: Command,
+}
+
+impl Display for Audited {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_fmt(format_args!("{}: {}", self.owner, self.command))?;
+        match &self.command.run_as {
+            Some(user) => f.write_fmt(format_args!(" (as {})", user)),
+            None => Ok(()),
+        }
+    }
+}
+
+impl FSchema {
+    /// Every command the schema, or any of its profiles, can run, in the order they're declared, without planning the schema.
+    /// Commands of nodes whose "when" or "hosts" exclude them are listed too, so a schema can be reviewed before it's trusted
+    pub fn commands(&self) -> Vec<Audited> {
+        let mut commands = vec![];
+        self.audit("", &mut commands);
+        for (name, profile) in &self.profiles {
+            profile.audit(&format!("profile {}: ", name), &mut commands);
+        }
+        commands
+    }
+
+    /// Add the commands of the schema, excluding its profiles, to commands, with their owners prefixed by prefix
+    fn audit(&self, prefix: &str, commands: &mut Vec<Audited>) {
+        let mut add = |owner: String, command: &Command| commands.push(Audited { owner: format!("{}{}", prefix, owner), command: command.clone() });
+        for command in self.prebuild.iter().flat_map(Step::commands) {
+            add("prebuild".to_string(), command);
+        }
+        for (level, level_commands) in &self.before_level {
+            for command in level_commands {
+                add(format!("before level {}", level), command);
+            }
+        }
+        audit_nodes(&self.root, &self.root_ord, "", &mut add);
+        for (level, level_commands) in &self.after_level {
+            for command in level_commands {
+                add(format!("after level {}", level), command);
+            }
+        }
+        for command in self.postbuild.iter().flat_map(Step::commands) {
+            add("postbuild".to_string(), command);
+        }
+    }
+}
+
+/// Add the commands of the nodes of a directory, and everything inside them, in the order they're declared
+fn audit_nodes<F>(contents: &HashMap<String, Node>, ord: &[String], parent: &str, add: &mut F)
+where
+    F: FnMut(String, &Command)
+{
+    for name in ord {
+        let path = format!("{}{}", parent, name);
+        match contents.get(name) {
+            Some(Node::File { data, options }) => audit_file(&path, data, options, add),
+            Some(Node::Directory { contents, ord, options }) => {
+                for command in &options.on_create {
+                    add(format!("{} (onCreate)", path), command);
+                }
+                for command in &options.on_error {
+                    add(format!("{} (onError)", path), command);
+                }
+                if let Some(defaults) = &options.default_file_options {
+                    for command in &defaults.on_create {
+                        add(format!("{} (defaultFileOptions onCreate)", path), command);
+                    }
+                    for command in &defaults.on_error {
+                        add(format!("{} (defaultFileOptions onError)", path), command);
+                    }
+                }
+                audit_nodes(contents, ord, &format!("{}/", path), add);
+            },
+            _ => (),
+        }
+    }
+}
+
+/// Add the commands of a file, its data if it's Piped or Secret, the commands installing it if it's created as another user,
+/// then its hooks
+fn audit_file<F>(path: &str, data: &str, options: &FileOptions, add: &mut F)
+where
+    F: FnMut(String, &Command)
+{
+    match options.ftype {
+        FileType::Piped => add(format!("{} (Piped)", path), &options.command(data)),
+        FileType::Secret => add(format!("{} (Secret)", path), &options.command(data)),
+        _ => (),
+    }
+    if let Some(user) = &options.run_as {
+        for command in install_commands(user, Path::new(path), options, false) {
+            add(format!("{} (runAs)", path), &command);
+        }
+    }
+    for command in &options.on_create {
+        add(format!("{} (onCreate)", path), command);
+    }
+    for command in &options.on_error {
+        add(format!("{} (onError)", path), command);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::FSchema;
+
+    #[test]
+    fn test() {
+        let schema = FSchema::from_str(r#"{
+            "prebuild": ["echo pre"],
+            "root": {
+                "src": {
+                    ".": {"onCreate": ["echo src"]},
+                    "version": ["git describe", {"ftype": "Piped", "when": "os == 'windows'"}],
+                    "plain": ["text"]
+                },
+                "token": ["pass show token", {"ftype": "Secret", "onError": ["echo failed"]}]
+            },
+            "postbuild": [["make", "all"]],
+            "profiles": { "dev": { "postbuild": ["echo dev"] } }
+        }"#).unwrap();
+        let commands = schema.commands().iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert_eq!(commands, [
+            "prebuild: echo pre",
+            "src (onCreate): echo src",
+            "src/version (Piped): git describe",
+            "token (Secret): pass show token",
+            "token (onError): echo failed",
+            "postbuild: make all",
+            "profile dev: postbuild: echo dev",
+        ]);
+    }
+}
@@ -0,0 +1,137 @@
+use crate::{command::Step, node::{directory, names}, Command, FSchema, FileOptions, FileType, Node};
+
+#[derive(Debug, Default, Clone)]
+/// Builds a schema in code, e.g. `FSchema::builder().dir("src").file("src/main.rs", "fn main() {}").prebuild("cargo fmt").build()`.
+/// Paths are relative to the root, separated by "/", and directories they pass through are created if they aren't already
+pub struct SchemaBuilder {
+    schema: FSchema,
+}
+
+impl FSchema {
+    /// Builder of a schema, starting with an empty root
+    pub fn builder() -> SchemaBuilder {
+        SchemaBuilder::default()
+    }
+}
+
+impl SchemaBuilder {
+    /// Add an empty directory, or keep the directory at path if there is one
+    pub fn dir(mut self, path: &str) -> Self {
+        directory(&mut self.schema.root, &mut self.schema.root_ord, &names(path));
+        self
+    }
+
+    /// Add a text file
+    pub fn file(self, path: &str, data: &str) -> Self {
+        self.file_with(path, data, FileOptions::default())
+    }
+
+    /// Add a file with options, e.g. its type and mode
+    pub fn file_with(self, path: &str, data: &str, options: FileOptions) -> Self {
+        self.node(path, Node::file(data, options))
+    }
+
+    /// Add a symbolic link to target
+    pub fn link(self, path: &str, target: &str) -> Self {
+        self.file_with(path, target, FileOptions { ftype: FileType::Link, ..Default::default() })
+    }
+
+    /// Add a node, replacing the node at path if there is one
+    pub fn node(mut self, path: &str, node: Node) -> Self {
+        self.schema.insert(path, node);
+        self
+    }
+
+    /// Add a command run before anything is created
+    pub fn prebuild(mut self, command: impl Into<Command>) -> Self {
+        self.schema.prebuild.push(Step::Command(command.into()));
+        self
+    }
+
+    /// Add a command run once everything has been created
+    pub fn postbuild(mut self, command: impl Into<Command>) -> Self {
+        self.schema.postbuild.push(Step::Command(command.into()));
+        self
+    }
+
+    /// Set a variable, referenced as {{name}}
+    pub fn variable(mut self, name: &str, value: &str) -> Self {
+        self.schema.variables.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Set an environment variable given to every command, which is also a variable
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.schema.env.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Set the shell commands are run with unless they give their own, e.g. "sh"
+    pub fn shell(mut self, shell: &str) -> Self {
+        self.schema.shell = Some(shell.to_string());
+        self
+    }
+
+    /// Set the umask applied to the permissions of everything created without a mode
+    pub fn umask(mut self, umask: u32) -> Self {
+        self.schema.umask = Some(umask);
+        self
+    }
+
+    /// Set the seconds commands can run for before they're killed, unless they give their own timeout
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.schema.timeout = Some(timeout);
+        self
+    }
+
+    /// Add a profile, overlaid on the schema when it's activated
+    pub fn profile(mut self, name: &str, profile: FSchema) -> Self {
+        self.schema.profiles.insert(name.to_string(), profile);
+        self
+    }
+
+    /// The schema built
+    pub fn build(self) -> FSchema {
+        self.schema
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CreateOptions, Error, FSchema, FileOptions, FileType};
+
+    #[test]
+    fn test() {
+        let schema = FSchema::builder()
+            .variable("name", "app")
+            .dir("logs")
+            .file("src/{{name}}.conf", "port = 80\n")
+            .link("src/current", "{{name}}.conf")
+            .file("src/{{name}}.conf", "port = 8080\n")
+            .postbuild("echo done > \"$ROOT/logs/built\"")
+            .build();
+        let json = serde_json::to_value(&schema).unwrap();
+        assert_eq!(json["root"]["src"]["{{name}}.conf"][1], "port = 8080\n");
+        assert_eq!(json["root"]["src"]["current"][0]["ftype"], "Link");
+        assert_eq!(json["root"].as_object().unwrap().keys().collect::<Vec<_>>(), ["logs", "src"]);
+        assert_eq!(json["root"]["src"].as_object().unwrap().keys().collect::<Vec<_>>(), ["{{name}}.conf", "current"]);
+
+        let root = std::env::temp_dir().join(format!("fschema-builder-{}", std::process::id()));
+        schema.create_with(root.clone(), &CreateOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("src/current")).unwrap(), "port = 8080\n");
+        assert!(root.join("logs/built").is_file());
+
+        let invalid = [
+            FileOptions::new().ftype(FileType::Hex),
+            FileOptions::new().ftype(FileType::Bits),
+            FileOptions::new().ftype(FileType::GzipBase64),
+            FileOptions::new().ftype(FileType::ZstdBase64),
+        ];
+        for options in invalid {
+            let schema = FSchema::builder().file_with("bad", "zz", options).build();
+            assert!(matches!(schema.create(root.clone()), Err(Error::IO(e, _)) if e.kind() == std::io::ErrorKind::InvalidData));
+            assert!(!root.join("bad").exists());
+        }
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
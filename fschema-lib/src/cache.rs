@@ -0,0 +1,132 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::{Path, PathBuf},
+    time::UNIX_EPOCH,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{manifest::hash_bytes, Error, FileOptions, FileType, Operation};
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// Fingerprint of a node and the file it produced
+struct CacheEntry {
+    /// Hash of the node's data and options
+    fingerprint: String,
+    /// Size of the produced file
+    size: u64,
+    /// Modification time of the produced file (nanoseconds since the unix epoch)
+    modified: u128,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// Fingerprints of the files created by previous builds. 
+/// Files whose node is unchanged, and that haven't been touched since they were created, don't need to be created again
+pub struct Cache {
+    entries: HashMap<PathBuf, CacheEntry>,
+}
+
+impl Cache {
+    /// Default file name of a cache stored in the root of a file system structure
+    pub const DEFAULT_NAME: &'static str = ".fschema-cache.json";
+
+    /// Load a cache from a file, or an empty cache if the file doesn't exist
+    pub fn load(path: &Path) -> Result<Cache, Error> {
+        if !path.exists() {
+            return Ok(Cache::default());
+        }
+        let cache = fs::read_to_string(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        serde_json::from_str(&cache).map_err(|e| Error::IO(e.into(), format!("{:?}", path)))
+    }
+
+    /// Save the cache to a file
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let cache = serde_json::to_string(self).map_err(|e| Error::IO(e.into(), format!("{:?}", path)))?;
+        fs::write(path, cache).map_err(|e| Error::IO(e, format!("{:?}", path)))
+    }
+
+    /// Check whether an operation's file was created from the same node and hasn't been touched since
+    pub fn is_fresh(&self, root: &Path, operation: &Operation) -> bool {
+        let (path, fingerprint) = match (operation.path(), fingerprint(operation)) {
+            (Some(path), Some(fingerprint)) => (path, fingerprint),
+            _ => return false,
+        };
+
+        match (self.entries.get(relative(root, path)), stat(path)) {
+            (Some(entry), Some((size, modified))) => entry.fingerprint == fingerprint 
+                && entry.size == size 
+                && entry.modified == modified,
+            _ => false,
+        }
+    }
+
+    /// Record the file created by an operation
+    pub fn record(&mut self, root: &Path, operation: &Operation) {
+        let (path, fingerprint) = match (operation.path(), fingerprint(operation)) {
+            (Some(path), Some(fingerprint)) => (path, fingerprint),
+            _ => return,
+        };
+
+        match stat(path) {
+            Some((size, modified)) => self.entries.insert(relative(root, path).to_path_buf(), CacheEntry { fingerprint, size, modified }),
+            None => self.entries.remove(relative(root, path)),
+        };
+    }
+}
+
+/// Path relative to root
+fn relative<'a>(root: &Path, path: &'a Path) -> &'a Path {
+    path.strip_prefix(root).unwrap_or(path)
+}
+
+/// Size and modification time of a file, without following links
+fn stat(path: &Path) -> Option<(u64, u128)> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    let modified = metadata.modified().ok()?.duration_since(UNIX_EPOCH).ok()?.as_nanos();
+    Some((metadata.len(), modified))
+}
+
+/// Hash of a file operation's data and options. The size and modification time of copied files are included, so changes to them are noticed.
+/// Checksums files depend on other files, so they're always recreated
+fn fingerprint(operation: &Operation) -> Option<String> {
+    let (data, options) = match operation {
+        Operation::File { options: FileOptions { ftype: FileType::Checksums, .. }, .. } => return None,
+        Operation::File { data, options, .. } => (data, options),
+        _ => return None,
+    };
+
+    let mut fingerprint = serde_json::to_vec(&(data, options)).ok()?;
+    if let FileType::Copy = options.ftype {
+        let (size, modified) = stat(Path::new(data))?;
+        fingerprint.extend(format!("{}:{}", size, modified).bytes());
+    }
+    Some(hash_bytes(&fingerprint))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::{CreateOptions, FSchema};
+
+    use super::Cache;
+
+    #[test]
+    fn test() {
+        let root = env::temp_dir().join(format!("fschema-cache-{}", std::process::id()));
+        let options = CreateOptions { cache: Some(root.join(Cache::DEFAULT_NAME)), ..Default::default() };
+        let schema = FSchema::from_str(r#"{ "root": { "file": ["data"], "piped": ["date +%N", {"ftype": "Piped"}] } }"#).unwrap();
+
+        schema.create_with(root.clone(), &options).unwrap();
+        let piped = fs::read(root.join("piped")).unwrap();
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(fs::read(root.join("piped")).unwrap(), piped);
+
+        fs::write(root.join("file"), "changed").unwrap();
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(fs::read_to_string(root.join("file")).unwrap(), "data");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
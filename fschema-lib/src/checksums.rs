@@ -0,0 +1,95 @@
+use std::{collections::BTreeSet, io, path::Path};
+
+use glob::Pattern;
+
+use crate::{manifest::hash_file, Error};
+
+/// Glob patterns, relative to a Checksums file's directory, of the files it lists. Patterns are given one per line,
+/// and every file in the directory is listed if none are given
+fn patterns(data: &str) -> Vec<&str> {
+    let patterns = data.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>();
+    match patterns.is_empty() {
+        true => vec!["*"],
+        false => patterns,
+    }
+}
+
+/// Check a Checksums file's patterns are valid globs
+pub(crate) fn parse_checksums(data: &str) -> Result<(), String> {
+    for pattern in patterns(data) {
+        Pattern::new(pattern).map_err(|e| format!("Invalid checksums pattern {}, {}", pattern, e))?;
+    }
+    Ok(())
+}
+
+/// Contents of a Checksums file, a line with the sha256 hash and path of each file matching its patterns, relative to its
+/// directory and sorted, like sha256sum's output, e.g. "<hash>  bin/app". The Checksums file itself is never listed
+pub(crate) fn checksums(path: &Path, data: &str) -> Result<Vec<u8>, Error> {
+    let directory = path.parent().unwrap_or(Path::new("."));
+    let base = Pattern::escape(&directory.to_string_lossy());
+    let mut files = BTreeSet::new();
+    for pattern in patterns(data) {
+        let pattern = format!("{}/{}", base, pattern);
+        let paths = glob::glob(&pattern).map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), pattern.clone()))?;
+        for file in paths {
+            let file = file.map_err(|e| Error::IO(e.into(), pattern.clone()))?;
+            if file.is_file() && file != path {
+                files.insert(file);
+            }
+        }
+    }
+
+    let mut contents = String::new();
+    for file in files {
+        let relative = file.strip_prefix(directory).unwrap_or(&file);
+        contents += &format!("{}  {}\n", hash_file(&file)?, relative.to_string_lossy());
+    }
+    Ok(contents.into_bytes())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, process};
+
+    use crate::{manifest::hash_bytes, FSchema};
+
+    use super::{checksums, parse_checksums};
+
+    #[test]
+    fn test() {
+        let dir = std::env::temp_dir().join(format!("fschema-checksums-list-{}", std::process::id()));
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("b.tar"), "b").unwrap();
+        fs::write(dir.join("a.tar"), "a").unwrap();
+        fs::write(dir.join("notes"), "n").unwrap();
+        fs::write(dir.join("bin/app"), "app").unwrap();
+        fs::write(dir.join("SHA256SUMS"), "stale").unwrap();
+
+        let listed = String::from_utf8(checksums(&dir.join("SHA256SUMS"), "*.tar\n\nbin/*\n").unwrap()).unwrap();
+        assert_eq!(listed, format!("{}  a.tar\n{}  b.tar\n{}  bin/app\n", hash_bytes(b"a"), hash_bytes(b"b"), hash_bytes(b"app")));
+        let everything = String::from_utf8(checksums(&dir.join("SHA256SUMS"), "").unwrap()).unwrap();
+        assert_eq!(everything.lines().map(|line| &line[66..]).collect::<Vec<_>>(), ["a.tar", "b.tar", "notes"]);
+
+        assert!(parse_checksums("*.tar\nbin/[").is_err());
+        assert!(parse_checksums("").is_ok());
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn verifies() {
+        let root = std::env::temp_dir().join(format!("fschema-checksums-verifies-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": { "release": {
+            "SHA256SUMS": ["*.tar\nbin/*", {"ftype": "Checksums"}],
+            "app.tar": ["tar"],
+            "bin": { "app": ["printf app", {"ftype": "Piped", "defer": 2}] }
+        } } }"#).unwrap();
+        schema.create(root.clone()).unwrap();
+
+        let check = || process::Command::new("sha256sum").args(["--check", "--quiet", "SHA256SUMS"]).current_dir(root.join("release")).stdout(process::Stdio::null()).stderr(process::Stdio::null()).status().unwrap();
+        assert!(check().success());
+        fs::write(root.join("release/bin/app"), "tampered").unwrap();
+        assert!(!check().success());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,94 @@
+use std::{fs, io, path::PathBuf};
+
+use crate::{checksums::checksums, edit::is_edit, plan::{inline_contents, unchanged}, Error, FSchema, FileType, Operation};
+
+impl FSchema {
+    /// Remove the files and directories the schema would create at root. Directories are only removed once empty
+    /// and entries that aren't part of the schema are left untouched. The root itself is never removed. 
+    /// Files are only removed if they still hold exactly what the schema would create, so files that already existed and were
+    /// edited (e.g. Append) or touched, have been modified since, or whose contents come from a command, are left in place. 
+    /// Use a manifest to remove exactly what a build created. Returns the removed paths
+    pub fn remove(&self, root: PathBuf) -> Result<Vec<PathBuf>, Error> {
+        let plan = self.plan(root.clone())?;
+        let mut removed = vec![];
+
+        for operation in plan.operations().iter().rev() {
+            match operation {
+                Operation::Run(_) | Operation::Parallel(_) | Operation::Remove(_) => (),
+                Operation::Directory { path, .. } => {
+                    if *path == root || !path.is_dir() || path.is_symlink() {
+                        continue;
+                    }
+                    let empty = fs::read_dir(path)
+                        .map_err(|e| Error::IO(e, format!("{:?}", path)))?
+                        .next()
+                        .is_none();
+                    if empty {
+                        fs::remove_dir(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+                        removed.push(path.clone());
+                    }
+                },
+                Operation::File { options, .. } if is_edit(options.ftype) || matches!(options.ftype, FileType::Touch) => (),
+                Operation::File { path, data, options } => match fs::symlink_metadata(path) {
+                    Ok(metadata) if !metadata.is_dir() => {
+                        let contents = match options.ftype {
+                            FileType::Checksums => checksums(path, data).ok(),
+                            ftype => inline_contents(data, ftype).ok().flatten(),
+                        };
+                        if !unchanged(path, &metadata, data, options, contents.as_deref()).unwrap_or(false) {
+                            continue;
+                        }
+                        fs::remove_file(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+                        removed.push(path.clone());
+                    },
+                    Ok(_) => (),
+                    Err(e) if e.kind() == io::ErrorKind::NotFound => (),
+                    Err(e) => return Err(Error::IO(e, format!("{:?}", path))),
+                },
+            }
+        }
+
+        Ok(removed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{CreateOptions, FSchema, OverwritePolicy};
+
+    #[test]
+    fn test() {
+        let root = std::env::temp_dir().join(format!("fschema-clean-{}", std::process::id()));
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/hosts"), "127.0.0.1 localhost\n").unwrap();
+        fs::write(root.join("etc/history.log"), "mine\n").unwrap();
+        fs::write(root.join("etc/notes"), "mine\n").unwrap();
+        fs::write(root.join("keep"), "not in the schema\n").unwrap();
+        let schema = FSchema::from_str(r#"{ "root": {
+            "etc": {
+                "hosts": ["10.0.0.1 db\n", {"ftype": "Append"}],
+                "history.log": ["", {"ftype": "Touch"}],
+                "notes": ["generated\n"],
+                "config": ["port = 80\n"]
+            },
+            "app": { "main.conf": ["debug = false\n"], "current": ["main.conf", {"ftype": "Link"}], "edited": ["x"] },
+            "tool": ["echo built", {"ftype": "Piped"}]
+        } }"#).unwrap();
+        let options = CreateOptions { overwrite: OverwritePolicy::Skip, quiet: true, ..Default::default() };
+        schema.create_with(root.clone(), &options).unwrap();
+        fs::write(root.join("app/edited"), "changed").unwrap();
+
+        let removed = schema.remove(root.clone()).unwrap();
+        assert_eq!(removed, [root.join("app/current"), root.join("app/main.conf"), root.join("etc/config")]);
+        assert_eq!(fs::read_to_string(root.join("etc/hosts")).unwrap(), "127.0.0.1 localhost\n10.0.0.1 db\n");
+        assert_eq!(fs::read_to_string(root.join("etc/history.log")).unwrap(), "mine\n");
+        assert_eq!(fs::read_to_string(root.join("etc/notes")).unwrap(), "mine\n");
+        assert_eq!(fs::read_to_string(root.join("app/edited")).unwrap(), "changed");
+        assert_eq!(fs::read_to_string(root.join("tool")).unwrap(), "built\n");
+        assert!(root.join("keep").is_file());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,864 @@
+use std::{
+    collections::BTreeMap,
+    fmt::Display,
+    fs::File,
+    io::{self, BufRead, BufReader, Read, Write},
+    path::Path,
+    process::{self, Child, Stdio},
+    sync::{Arc, Mutex, PoisonError},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+
+use serde::{de::{Error as _, Visitor}, ser::SerializeMap, Deserialize, Deserializer, Serialize};
+
+use crate::{interpolate::{substitute, substitute_root}, Error, FSchema, FileOptions, Operation};
+
+/// Environment variable commands are given the root the file system structure is created at in
+pub(crate) const ROOT_VARIABLE: &str = "FSCHEMA_ROOT";
+/// Environment variable Piped files' commands are given their file's path in
+pub(crate) const PATH_VARIABLE: &str = "FSCHEMA_PATH";
+/// Bytes of the end of a command's stderr kept for the error it fails with
+const STDERR_KEPT: usize = 4096;
+/// How long reading a command's stderr can take to finish once it's exited
+pub(crate) const STDERR_GRACE: Duration = Duration::from_millis(200);
+
+#[cfg(unix)]
+/// Shell commands are run with unless they give their own
+const DEFAULT_SHELL: &str = "bash";
+#[cfg(windows)]
+/// Shell commands are run with unless they give their own
+const DEFAULT_SHELL: &str = "cmd";
+
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+/// Command a schema runs, e.g. before or after it's built. Written as the command line, as the program and its arguments,
+/// e.g. ["git", "init"], run without a shell, or as an object giving either of them with
+/// the environment, working directory and shell it's run with, and how failures are handled,
+/// e.g. {"cmd": "make", "cwd": "build", "env": {"CC": "clang"}, "retries": 2}
+pub struct Command {
+    /// Command line, run with the shell
+    pub cmd: String,
+    /// Program and arguments run directly, without a shell, instead of the command line if they're given
+    pub argv: Vec<String>,
+    /// Environment variables set for the command, on top of those it inherits
+    pub env: BTreeMap<String, String>,
+    /// Directory the command runs in, relative to the root of the file system structure. The current directory if not given
+    pub cwd: Option<String>,
+    /// File the command reads its input from, relative to the root of the file system structure. Inherits fschema's input if not given
+    pub stdin: Option<String>,
+    /// Shell the command line is run with, e.g. "sh", "zsh", "fish", "pwsh" or "cmd". The schema's shell if not given
+    pub shell: Option<String>,
+    /// Print a warning and carry on building if the command still fails once it's out of retries
+    pub allow_failure: bool,
+    /// Seconds the command can run for before it's killed and counted as failed
+    pub timeout: Option<u64>,
+    /// Times the command is run again if it fails
+    pub retries: u32,
+    /// User the command is run as with sudo, e.g. "root", so only the parts of a schema needing privileges have them.
+    /// Only the command's own environment and FSCHEMA_ROOT are passed on. The invoking user if not given
+    pub run_as: Option<String>,
+}
+
+/// How an attempt at running a command ended
+pub(crate) enum Outcome {
+    Exited(i32),
+    TimedOut,
+    /// Wrote more output, in bytes, than it was allowed to
+    TooLarge(u64),
+}
+
+impl From<process::ExitStatus> for Outcome {
+    /// Outcome of a process that exited by itself. One killed by a signal has no exit code, and exits with -1 so it fails
+    fn from(status: process::ExitStatus) -> Outcome {
+        Outcome::Exited(status.code().unwrap_or(-1))
+    }
+}
+
+impl From<&str> for Command {
+    fn from(cmd: &str) -> Self {
+        Command { cmd: cmd.to_string(), ..Default::default() }
+    }
+}
+
+impl From<Vec<String>> for Command {
+    fn from(argv: Vec<String>) -> Self {
+        Command { argv, ..Default::default() }
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.argv.is_empty() {
+            true => f.write_str(&self.cmd),
+            false => f.write_str(&self.argv.join(" ")),
+        }
+    }
+}
+
+impl Command {
+    /// Whether the command is only a command line, run like one given as a string
+    fn is_plain(&self) -> bool {
+        self.env.is_empty() && self.cwd.is_none() && self.stdin.is_none() && self.shell.is_none() && !self.allow_failure && self.timeout.is_none() && self.retries == 0 && self.run_as.is_none()
+    }
+
+    /// Copy of the command with variables and root, written "$ROOT", substituted into its command line, environment and
+    /// working directory, its working directory and input resolved against root, and run with shell unless it gives its own.
+    /// Root is also given to the command as FSCHEMA_ROOT
+    pub(crate) fn planned(&self, variables: &BTreeMap<String, String>, root: &Path, shell: Option<&String>) -> Command {
+        let planned = |text: &str| substitute_root(&substitute(text, variables), root);
+        let mut env = self.env.iter().map(|(name, value)| (name.clone(), planned(value))).collect::<BTreeMap<_, _>>();
+        env.entry(ROOT_VARIABLE.to_string()).or_insert_with(|| root.to_string_lossy().to_string());
+        Command {
+            cmd: planned(&self.cmd),
+            argv: self.argv.iter().map(|arg| planned(arg)).collect(),
+            env,
+            cwd: self.cwd.as_ref().map(|cwd| root.join(planned(cwd)).to_string_lossy().to_string()),
+            stdin: self.stdin.as_ref().map(|stdin| root.join(planned(stdin)).to_string_lossy().to_string()),
+            shell: self.shell.as_ref().or(shell).cloned(),
+            run_as: self.run_as.as_ref().map(|user| planned(user)),
+            ..self.clone()
+        }
+    }
+
+    /// Process running the command. Commands with a timeout get a process group of their own on unix, so everything they start
+    /// can be killed with them. Commands run as another user are run with sudo, given their environment with env
+    pub(crate) fn process(&self) -> process::Command {
+        let (program, args) = match self.argv.split_first() {
+            Some((program, args)) => (program.as_str(), args.to_vec()),
+            None => {
+                let shell = self.shell.as_deref().unwrap_or(DEFAULT_SHELL);
+                (shell, shell_flags(shell).iter().map(ToString::to_string).chain([self.cmd.clone()]).collect())
+            },
+        };
+        let mut process = match &self.run_as {
+            Some(user) => {
+                let mut process = process::Command::new("sudo");
+                process
+                    .args(["-u", user, "--", "env"])
+                    .args(self.env.iter().map(|(name, value)| format!("{}={}", name, value)))
+                    .arg(program)
+                    .args(args);
+                process
+            },
+            None => {
+                let mut process = process::Command::new(program);
+                process.args(args).envs(&self.env);
+                process
+            },
+        };
+        if let Some(cwd) = &self.cwd {
+            process.current_dir(cwd);
+        }
+        #[cfg(unix)]
+        if self.timeout.is_some() {
+            process.process_group(0);
+        }
+        process
+    }
+
+    /// Run the command, retrying it if it fails. Fails if it's still failing once it's out of retries, unless failure is allowed.
+    /// Its output is passed on as it's written, each line prefixed by the command's label, unless quiet is set
+    pub(crate) fn run(&self, quiet: bool) -> Result<(), Error> {
+        self.retried(|| self.attempt(quiet))
+    }
+
+    /// Write the output of the command to a file as it runs, instead of holding it in memory, retrying it if it fails. Commands
+    /// writing more than limit bytes are killed and count as failed. The output of the last attempt is kept if failure is allowed.
+    /// What it writes to stderr is passed on as it's written, each line prefixed by the command's label, unless quiet is set
+    pub(crate) fn output_to(&self, path: &Path, limit: Option<u64>, quiet: bool) -> Result<(), Error> {
+        self.retried(|| self.stream(path, limit, quiet))
+    }
+
+    /// Label the command's output is prefixed with as it's passed on, the name of the file it's run for, or of its program
+    pub(crate) fn label(&self) -> String {
+        let name = |path: &str| Path::new(path).file_name().map_or(path.to_string(), |name| name.to_string_lossy().to_string());
+        match (self.env.get(PATH_VARIABLE), self.argv.first()) {
+            (Some(path), _) => name(path),
+            (None, Some(program)) => name(program),
+            (None, None) => name(self.cmd.split_whitespace().next().unwrap_or_default()),
+        }
+    }
+
+    /// Make attempts at running the command until one succeeds or it's out of retries
+    fn retried<F>(&self, mut attempt: F) -> Result<(), Error>
+    where
+        F: FnMut() -> io::Result<(Outcome, String)>
+    {
+        let mut attempts = 0;
+        loop {
+            let (outcome, stderr) = attempt().map_err(|e| Error::IO(e, self.to_string()))?;
+            match self.failure(outcome, stderr) {
+                None => return Ok(()),
+                Some(_) if attempts < self.retries => attempts += 1,
+                Some(error) => return self.give_up(error),
+            }
+        }
+    }
+
+    /// Run the command once, killing it if it runs for longer than its timeout. Returns how it ended, and the end of what it
+    /// wrote to stderr. Its output is passed on unless quiet is set
+    fn attempt(&self, quiet: bool) -> io::Result<(Outcome, String)> {
+        let label = (!quiet).then(|| self.label());
+        let stdout = match quiet {
+            true => Stdio::null(),
+            false => Stdio::piped(),
+        };
+        let mut child = self.process().stdin(self.input()?).stdout(stdout).stderr(Stdio::piped()).spawn()?;
+        let stdout = child.stdout.take().map(|stdout| read_output(stdout, label.clone(), false));
+        let stderr = read_output(child.stderr.take().expect("stderr is piped"), label, true);
+        let outcome = wait(&mut child, self.timeout.map(Duration::from_secs))?;
+        if let Some(stdout) = stdout {
+            stdout.finish();
+        }
+        Ok((outcome, stderr.finish()))
+    }
+
+    /// Run the command once, writing its output to a file, killing it if it runs for longer than its timeout or writes more
+    /// than limit bytes. Returns how it ended, and the end of what it wrote to stderr, which is passed on unless quiet is set
+    fn stream(&self, path: &Path, limit: Option<u64>, quiet: bool) -> io::Result<(Outcome, String)> {
+        let file = File::create(path)?;
+        let mut process = self.process();
+        #[cfg(unix)]
+        if limit.is_some() {
+            process.process_group(0);
+        }
+        process.stdin(self.input()?).stdout(Stdio::piped()).stderr(Stdio::piped());
+        let mut child = process.spawn()?;
+        let pid = child.id();
+        let stderr = read_output(child.stderr.take().expect("stderr is piped"), (!quiet).then(|| self.label()), true);
+        let stdout = child.stdout.take().expect("stdout is piped");
+        let writer = thread::spawn(move || write_limited(stdout, file, limit).inspect(|too_large| if *too_large {
+            kill_group(pid);
+        }));
+        let outcome = wait(&mut child, self.timeout.map(Duration::from_secs))?;
+        let too_large = writer.join().map_err(|_| io::Error::other("writing the command's output panicked"))??;
+        let outcome = match (too_large, limit) {
+            (true, Some(limit)) => Outcome::TooLarge(limit),
+            _ => outcome,
+        };
+        Ok((outcome, stderr.finish()))
+    }
+
+    /// Input the command reads, its stdin file if it has one
+    pub(crate) fn input(&self) -> io::Result<Stdio> {
+        match &self.stdin {
+            Some(stdin) => File::open(stdin).map(Stdio::from),
+            None => Ok(Stdio::inherit()),
+        }
+    }
+
+    /// Error an attempt that didn't succeed fails with, given the end of what it wrote to stderr, or None if it succeeded
+    pub(crate) fn failure(&self, outcome: Outcome, stderr: String) -> Option<Error> {
+        match outcome {
+            Outcome::Exited(0) => None,
+            Outcome::Exited(status) => Some(Error::Command(status, self.to_string(), stderr)),
+            Outcome::TimedOut => Some(Error::Timeout(self.to_string(), self.timeout.unwrap_or_default())),
+            Outcome::TooLarge(limit) => Some(Error::OutputLimit(self.to_string(), limit)),
+        }
+    }
+
+    /// Fail with the error of the last attempt, or warn and carry on if failure is allowed
+    pub(crate) fn give_up(&self, error: Error) -> Result<(), Error> {
+        if !self.allow_failure {
+            return Err(error);
+        }
+        eprintln!("Warning: {}, continuing since failure is allowed", error);
+        Ok(())
+    }
+}
+
+impl FSchema {
+    /// Copy of the schema whose commands, and Piped files' commands, are killed once they've run for timeout seconds,
+    /// unless they give their own timeout
+    pub fn with_timeout(&self, timeout: u64) -> FSchema {
+        let mut schema = self.clone();
+        schema.timeout = Some(timeout);
+        schema
+    }
+}
+
+impl FileOptions {
+    /// Command a Piped or Secret file's data runs, with the file's environment, input, shell, failure handling and user
+    pub(crate) fn command(&self, data: &str) -> Command {
+        Command {
+            cmd: data.to_string(),
+            env: self.env.clone(),
+            stdin: self.stdin.clone(),
+            shell: self.shell.clone(),
+            allow_failure: self.allow_failure,
+            timeout: self.timeout,
+            retries: self.retries,
+            run_as: self.run_as.clone(),
+            ..Default::default()
+        }
+    }
+}
+
+/// Check a plan doesn't run any commands, so a build with commands disabled fails before anything is created
+pub(crate) fn check_commands(operations: &[Operation]) -> Result<(), Error> {
+    match operations.iter().flat_map(Operation::commands).next() {
+        Some(command) => Err(Error::CommandsDisabled(command.to_string())),
+        None => Ok(()),
+    }
+}
+
+/// Flags a shell is given before the command line it runs, by the name of its program,
+/// e.g. "-c" for sh, bash, zsh and fish, "-Command" for PowerShell and "/C" for cmd
+fn shell_flags(shell: &str) -> &'static [&'static str] {
+    let name = shell.rsplit(['/', '\\']).next().unwrap_or(shell).to_lowercase();
+    match name.trim_end_matches(".exe") {
+        "pwsh" | "powershell" => &["-NoProfile", "-NonInteractive", "-Command"],
+        "cmd" => &["/C"],
+        _ => &["-c"],
+    }
+}
+
+#[cfg(unix)]
+/// Kill a process and everything in its process group
+pub(crate) fn kill_group(pid: u32) {
+    // SAFETY: kill only sends a signal, a negative pid signals the process group
+    unsafe { libc::kill(-(pid as libc::pid_t), libc::SIGKILL) };
+}
+
+#[cfg(windows)]
+/// Kill a process and every process it started
+pub(crate) fn kill_group(pid: u32) {
+    process::Command::new("taskkill").args(["/T", "/F", "/PID", &pid.to_string()]).stdout(Stdio::null()).stderr(Stdio::null()).status().ok();
+}
+
+/// Write a command's output to a file, stopping once it's more than limit bytes, and cutting the file down to the limit.
+/// Returns whether it was more
+fn write_limited(mut output: impl Read, mut file: File, limit: Option<u64>) -> io::Result<bool> {
+    let limit = match limit {
+        Some(limit) => limit,
+        None => return io::copy(&mut output, &mut file).map(|_| false),
+    };
+    let written = io::copy(&mut output.take(limit.saturating_add(1)), &mut file)?;
+    if written > limit {
+        file.set_len(limit)?;
+    }
+    Ok(written > limit)
+}
+
+/// End of what a command wrote to stderr, as it's read. Only the end is kept, as it's usually what says why it failed
+#[derive(Clone, Default)]
+pub(crate) struct Stderr(Arc<Mutex<Vec<u8>>>);
+
+impl Stderr {
+    /// Keep what a command wrote
+    pub(crate) fn keep(&self, written: &[u8]) {
+        let mut kept = self.0.lock().unwrap_or_else(PoisonError::into_inner);
+        kept.extend_from_slice(written);
+        let excess = kept.len().saturating_sub(STDERR_KEPT);
+        kept.drain(..excess);
+    }
+
+    /// What's been kept, as text
+    pub(crate) fn text(&self) -> String {
+        String::from_utf8_lossy(&self.0.lock().unwrap_or_else(PoisonError::into_inner)).trim().to_string()
+    }
+}
+
+/// Pass a line of a command's output on to fschema's stdout, or its stderr if it's from stderr, prefixed by the command's label
+pub(crate) fn echo(line: &[u8], label: &str, stderr: bool) {
+    let mut prefixed = format!("[{}] ", label).into_bytes();
+    prefixed.extend_from_slice(line.strip_suffix(b"\n").unwrap_or(line));
+    prefixed.push(b'\n');
+    match stderr {
+        true => io::stderr().write_all(&prefixed),
+        false => io::stdout().write_all(&prefixed),
+    }.ok();
+}
+
+/// A thread reading a command's stdout or stderr
+struct OutputReader {
+    kept: Stderr,
+    thread: JoinHandle<()>,
+}
+
+impl OutputReader {
+    /// The end of what the command wrote to stderr, once the command has exited. Reading is given a moment to finish, but
+    /// isn't waited for if something the command left running, e.g. a daemon, still holds its output open
+    fn finish(self) -> String {
+        let deadline = Instant::now() + STDERR_GRACE;
+        while !self.thread.is_finished() && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+        self.kept.text()
+    }
+}
+
+/// Read a command's stdout, or its stderr if stderr is set, in a thread, a line at a time, passing each line on prefixed
+/// by label if there is one. The end of stderr is kept
+fn read_output(output: impl Read + Send + 'static, label: Option<String>, stderr: bool) -> OutputReader {
+    let kept = Stderr::default();
+    let keeping = kept.clone();
+    let thread = thread::spawn(move || {
+        let mut output = BufReader::new(output);
+        let mut line = vec![];
+        while let Ok(1..) = output.read_until(b'\n', &mut line) {
+            if let Some(label) = &label {
+                echo(&line, label, stderr);
+            }
+            if stderr {
+                keeping.keep(&line);
+            }
+            line.clear();
+        }
+    });
+    OutputReader { kept, thread }
+}
+
+/// Wait for a process to exit, killing it if it's still running once the timeout has passed
+fn wait(child: &mut Child, timeout: Option<Duration>) -> io::Result<Outcome> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(Outcome::from),
+    };
+    let deadline = Instant::now() + timeout;
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Outcome::from(status));
+        }
+        if Instant::now() >= deadline {
+            kill_group(child.id());
+            child.wait()?;
+            return Ok(Outcome::TimedOut);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+}
+
+impl Serialize for Command {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer
+    {
+        match (self.is_plain(), self.argv.is_empty()) {
+            (true, true) => return serializer.serialize_str(&self.cmd),
+            (true, false) => return self.argv.serialize(serializer),
+            _ => (),
+        }
+        let mut map = serializer.serialize_map(None)?;
+        match self.argv.is_empty() {
+            true => map.serialize_entry("cmd", &self.cmd)?,
+            false => map.serialize_entry("cmd", &self.argv)?,
+        }
+        if !self.env.is_empty() {
+            map.serialize_entry("env", &self.env)?;
+        }
+        if let Some(cwd) = &self.cwd {
+            map.serialize_entry("cwd", cwd)?;
+        }
+        if let Some(stdin) = &self.stdin {
+            map.serialize_entry("stdin", stdin)?;
+        }
+        if let Some(shell) = &self.shell {
+            map.serialize_entry("shell", shell)?;
+        }
+        if self.allow_failure {
+            map.serialize_entry("allowFailure", &self.allow_failure)?;
+        }
+        if let Some(timeout) = self.timeout {
+            map.serialize_entry("timeoutSecs", &timeout)?;
+        }
+        if self.retries != 0 {
+            map.serialize_entry("retries", &self.retries)?;
+        }
+        if let Some(user) = &self.run_as {
+            map.serialize_entry("runAs", user)?;
+        }
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for Command {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        deserializer.deserialize_any(CommandVisitor)
+    }
+}
+
+struct CommandVisitor;
+
+impl<'de> Visitor<'de> for CommandVisitor {
+    type Value = Command;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a command line, a program and its arguments, or an object with a cmd")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        Ok(Command::from(v))
+    }
+
+    fn visit_seq<A>(self, mut seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        let mut argv = vec![];
+        while let Some(arg) = seq.next_element::<String>()? {
+            argv.push(arg);
+        }
+        if argv.is_empty() {
+            return Err(A::Error::invalid_length(0, &"a program and its arguments"));
+        }
+        Ok(Command::from(argv))
+    }
+
+    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        visit_command(None, map)
+    }
+}
+
+/// Read a command written as an object. Its first key has already been read if it's given
+fn visit_command<'de, A>(first: Option<String>, mut map: A) -> Result<Command, A::Error>
+where
+    A: serde::de::MapAccess<'de>,
+{
+    let mut command = Command::default();
+    let mut cmd = None;
+    let mut next = match first {
+        Some(key) => Some(key),
+        None => map.next_key::<String>()?,
+    };
+    while let Some(key) = next {
+        match key.as_str() {
+            "cmd" => cmd = Some(map.next_value::<Command>()?),
+            "env" => command.env = map.next_value()?,
+            "cwd" => command.cwd = Some(map.next_value()?),
+            "stdin" => command.stdin = Some(map.next_value()?),
+            "shell" => command.shell = Some(map.next_value()?),
+            "allowFailure" => command.allow_failure = map.next_value()?,
+            "timeoutSecs" => command.timeout = Some(map.next_value()?),
+            "retries" => command.retries = map.next_value()?,
+            "runAs" => command.run_as = Some(map.next_value()?),
+            _ => return Err(A::Error::unknown_field(&key, &["cmd", "env", "cwd", "stdin", "shell", "allowFailure", "timeoutSecs", "retries", "runAs"])),
+        }
+        next = map.next_key::<String>()?;
+    }
+    let Command { cmd, argv, .. } = cmd.ok_or_else(|| A::Error::missing_field("cmd"))?;
+    Ok(Command { cmd, argv, ..command })
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// Step of a schema's prebuild or postbuild, a command, or commands run concurrently, written {"parallel": [...]}
+pub(crate) enum Step {
+    Command(Command),
+    Parallel(Vec<Command>),
+}
+
+impl Step {
+    /// Commands the step runs
+    pub(crate) fn commands(&self) -> &[Command] {
+        match self {
+            Step::Command(command) => std::slice::from_ref(command),
+            Step::Parallel(commands) => commands,
+        }
+    }
+}
+
+impl Serialize for Step {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer
+    {
+        match self {
+            Step::Command(command) => command.serialize(serializer),
+            Step::Parallel(commands) => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry("parallel", commands)?;
+                map.end()
+            },
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Step {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        deserializer.deserialize_any(StepVisitor)
+    }
+}
+
+struct StepVisitor;
+
+impl<'de> Visitor<'de> for StepVisitor {
+    type Value = Step;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("a command, or an object with commands to run in parallel")
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+    where
+        E: serde::de::Error,
+    {
+        CommandVisitor.visit_str(v).map(Step::Command)
+    }
+
+    fn visit_seq<A>(self, seq: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::SeqAccess<'de>,
+    {
+        CommandVisitor.visit_seq(seq).map(Step::Command)
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+    where
+        A: serde::de::MapAccess<'de>,
+    {
+        match map.next_key::<String>()? {
+            Some(key) if key == "parallel" => {
+                let commands = map.next_value::<Vec<Command>>()?;
+                match map.next_key::<String>()? {
+                    Some(key) => Err(A::Error::unknown_field(&key, &["parallel"])),
+                    None => Ok(Step::Parallel(commands)),
+                }
+            },
+            key => visit_command(key, map).map(Step::Command),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, env, fs, path::Path};
+
+    use crate::{CreateOptions, Error, FSchema, Operation};
+
+    use super::{shell_flags, Command};
+
+    /// Variables the tests' commands are planned with
+    fn variables() -> BTreeMap<String, String> {
+        BTreeMap::from([("file".to_string(), "name.txt".to_string()), ("name".to_string(), "app".to_string())])
+    }
+
+    #[test]
+    fn test() {
+        let command = serde_json::from_str::<Command>(r#"{"cmd": "echo $NAME > {{file}}", "env": {"NAME": "{{name}}"}, "cwd": "out", "shell": "sh"}"#).unwrap();
+        let planned = command.planned(&variables(), Path::new("/root"), None);
+        assert_eq!(planned.cmd, "echo $NAME > name.txt");
+        assert_eq!(serde_json::to_string(&Command::from("make")).unwrap(), "\"make\"");
+        assert!(serde_json::from_str::<Command>(r#"{"env": {}}"#).is_err());
+        assert!(matches!(Command::from("echo oops >&2; exit 3").run(true), Err(Error::Command(3, _, stderr)) if stderr == "oops"));
+        let failing = Command::from("echo partial; echo \"$(seq 5000)\" >&2; exit 1");
+        let output = env::temp_dir().join(format!("fschema-command-{}.out", std::process::id()));
+        assert!(matches!(failing.output_to(&output, None, true), Err(Error::Command(1, _, stderr)) if stderr.len() <= 4096 && stderr.ends_with("4999\n5000")));
+        let env = BTreeMap::from([("FSCHEMA_PATH".to_string(), "/out/src/version".to_string())]);
+        assert_eq!(Command { env, ..Command::from("git describe") }.label(), "version");
+        fs::remove_file(&output).unwrap();
+
+        let root = env::temp_dir().join(format!("fschema-command-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": {}, "postbuild": ["touch \"$ROOT/made\""] }"#).unwrap();
+        let options = CreateOptions { allow_commands: false, ..Default::default() };
+        assert!(matches!(schema.create_with(root.clone(), &options), Err(Error::CommandsDisabled(_))));
+        assert!(!root.exists());
+        schema.create(root.clone()).unwrap();
+        assert!(root.join("made").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn argv() {
+        let variables = variables();
+        assert_eq!(Command::from(vec!["echo".to_string(), "$ROOT/bin".to_string(), "$ROOTS".to_string()]).planned(&variables, Path::new("/srv"), None).argv, ["echo", "/srv/bin", "$ROOTS"]);
+        assert!(serde_json::from_str::<Command>("[]").is_err());
+        assert_eq!(Command::from(vec!["/usr/bin/git".to_string()]).label(), "git");
+
+        let command = serde_json::from_str::<Command>(r#"["printf", "%s", "{{name}}; rm -rf ~"]"#).unwrap();
+        let output = env::temp_dir().join(format!("fschema-command-argv-{}.out", std::process::id()));
+        command.planned(&variables, Path::new("/"), None).output_to(&output, None, false).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "app; rm -rf ~");
+        assert_eq!(serde_json::to_string(&command).unwrap(), r#"["printf","%s","{{name}}; rm -rf ~"]"#);
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn shell() {
+        assert_eq!(shell_flags("C:\\Windows\\System32\\cmd.exe"), ["/C"]);
+        assert_eq!(shell_flags("/usr/bin/pwsh").last(), Some(&"-Command"));
+
+        let root = env::temp_dir().join(format!("fschema-command-shell-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{
+            "shell": "sh",
+            "root": {
+                "schema": ["printf %s \"$0\"", {"ftype": "Piped"}],
+                "own": ["printf %s \"$0\"", {"ftype": "Piped", "shell": "bash"}]
+            },
+            "postbuild": [{ "cmd": "printf %s \"$0\" > command", "cwd": ".", "shell": "bash" }]
+        }"#).unwrap();
+        schema.create(root.clone()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("schema")).unwrap(), "sh");
+        assert_eq!(fs::read_to_string(root.join("own")).unwrap(), "bash");
+        assert_eq!(fs::read_to_string(root.join("command")).unwrap(), "bash");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn env() {
+        let command = serde_json::from_str::<Command>(r#"{"cmd": "true", "env": {"NAME": "{{name}}"}}"#).unwrap();
+        let planned = command.planned(&variables(), Path::new("/root"), None);
+        assert_eq!(planned.env["NAME"], "app");
+        assert_eq!(planned.env["FSCHEMA_ROOT"], "/root");
+
+        let root = env::temp_dir().join(format!("fschema-command-env-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{
+            "variables": { "name": "app" },
+            "root": {
+                "where": ["printf '%s %s' \"${ROOT}\" \"$FSCHEMA_PATH\"", {"ftype": "Piped"}],
+                "name": ["printf %s \"$NAME\"", {"ftype": "Piped", "env": {"NAME": "{{name}}"}}]
+            },
+            "postbuild": [{ "cmd": "printf %s \"$NAME\" > postbuild", "env": { "NAME": "{{name}}" }, "cwd": "." }]
+        }"#).unwrap();
+        schema.create(root.clone()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("where")).unwrap(), format!("{0} {0}/where", root.display()));
+        assert_eq!(fs::read_to_string(root.join("name")).unwrap(), "app");
+        assert_eq!(fs::read_to_string(root.join("postbuild")).unwrap(), "app");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn cwd() {
+        let command = serde_json::from_str::<Command>(r#"{"cmd": "true", "cwd": "{{name}}"}"#).unwrap();
+        assert_eq!(command.planned(&variables(), Path::new("/root"), None).cwd.as_deref(), Some("/root/app"));
+        assert_eq!(Command::from("true").planned(&variables(), Path::new("/root"), None).cwd, None);
+
+        let root = env::temp_dir().join(format!("fschema-command-cwd-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": { "out": {} }, "postbuild": [{ "cmd": "touch made", "cwd": "out" }] }"#).unwrap();
+        schema.create(root.clone()).unwrap();
+        assert!(root.join("out/made").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn stdin() {
+        let schema = FSchema::from_str(r#"{ "root": { "min": ["tr -d ' '", {"ftype": "Piped", "stdin": "missing"}] } }"#).unwrap();
+        assert!(matches!(schema.plan(env::temp_dir()), Err(Error::UnknownDependency(_, dependency)) if dependency == "missing"));
+
+        let root = env::temp_dir().join(format!("fschema-command-stdin-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{
+            "root": { "out": {
+                "upper": ["tr a-z A-Z", {"ftype": "Piped", "stdin": "out/lower"}],
+                "lower": ["app"]
+            } },
+            "postbuild": [{ "cmd": "tr a-z A-Z > copied", "cwd": "out", "stdin": "out/lower" }]
+        }"#).unwrap();
+        schema.create(root.clone()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("out/upper")).unwrap(), "APP");
+        assert_eq!(fs::read_to_string(root.join("out/copied")).unwrap(), "APP");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn allow_failure() {
+        assert!(serde_json::from_str::<Command>(r#"{"cmd": ["false"], "allowFailure": true}"#).unwrap().run(false).is_ok());
+        assert!(serde_json::from_str::<Command>(r#"{"cmd": ["false"]}"#).unwrap().run(false).is_err());
+
+        let output = env::temp_dir().join(format!("fschema-command-allow-failure-{}.out", std::process::id()));
+        Command { allow_failure: true, ..Command::from("echo partial; exit 1") }.output_to(&output, None, false).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "partial\n");
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn timeout() {
+        let output = env::temp_dir().join(format!("fschema-command-timeout-{}.out", std::process::id()));
+        let slow = Command { cmd: "echo partial; sleep 5".to_string(), timeout: Some(1), ..Default::default() };
+        assert!(matches!(slow.run(false), Err(Error::Timeout(_, 1))));
+        assert!(matches!(slow.output_to(&output, None, false), Err(Error::Timeout(_, 1))));
+        Command { allow_failure: true, ..slow }.output_to(&output, None, false).unwrap();
+        assert_eq!(fs::read_to_string(&output).unwrap(), "partial\n");
+        fs::remove_file(&output).unwrap();
+    }
+
+    #[test]
+    fn retries() {
+        let root = env::temp_dir().join(format!("fschema-command-retries-{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let flaky = Command { cmd: "n=$(cat count 2>/dev/null || echo 0); echo $((n + 1)) > count; [ $n -ge 2 ]".to_string(), cwd: Some(root.to_string_lossy().to_string()), ..Default::default() };
+        assert!(flaky.run(false).is_err());
+        assert_eq!(fs::read_to_string(root.join("count")).unwrap(), "1\n");
+        fs::remove_file(root.join("count")).unwrap();
+        assert!(Command { retries: 2, ..flaky.clone() }.run(false).is_ok());
+        assert_eq!(fs::read_to_string(root.join("count")).unwrap(), "3\n");
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn run_as() {
+        let command = serde_json::from_str::<Command>(r#"{"cmd": ["id", "-un"], "runAs": "{{name}}"}"#).unwrap();
+        assert_eq!(serde_json::to_string(&command).unwrap(), r#"{"cmd":["id","-un"],"runAs":"{{name}}"}"#);
+        let planned = command.planned(&variables(), Path::new("/root"), None);
+        assert_eq!(planned.run_as.as_deref(), Some("app"));
+        let process = planned.process();
+        assert_eq!(process.get_program(), "sudo");
+        assert_eq!(process.get_args().take(3).collect::<Vec<_>>(), ["-u", "app", "--"]);
+    }
+
+    #[test]
+    fn schema_timeout() {
+        let root = env::temp_dir().join(format!("fschema-command-schema-timeout-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "timeoutSecs": 1, "root": { "hung": ["sleep 5", {"ftype": "Piped"}] }, "postbuild": ["make"] }"#).unwrap();
+        assert!(matches!(schema.create(root.clone()), Err(Error::Timeout(_, 1))));
+        let plan = schema.with_timeout(30).plan(root.clone()).unwrap();
+        assert!(matches!(plan.operations().last(), Some(Operation::Run(command)) if command.timeout == Some(30)));
+        let schema = FSchema::from_str(r#"{ "timeoutSecs": 1, "root": {}, "postbuild": [{"cmd": "make", "timeoutSecs": 600}] }"#).unwrap();
+        let plan = schema.plan(root.clone()).unwrap();
+        assert!(matches!(plan.operations().last(), Some(Operation::Run(command)) if command.timeout == Some(600)));
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn max_size() {
+        let output = env::temp_dir().join(format!("fschema-command-max-size-{}.out", std::process::id()));
+        assert!(matches!(Command::from("yes").output_to(&output, Some(4096), false), Err(Error::OutputLimit(_, 4096))));
+        assert_eq!(fs::metadata(&output).unwrap().len(), 4096);
+        fs::remove_file(&output).unwrap();
+
+        let root = env::temp_dir().join(format!("fschema-command-max-size-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": { "big": ["yes", {"ftype": "Piped", "maxSize": "1K"}] } }"#).unwrap();
+        assert!(matches!(schema.create(root.clone()), Err(Error::OutputLimit(_, 1024))));
+        assert!(!root.join("big").exists() && !root.join(".big.fschema-part").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn parallel() {
+        let root = env::temp_dir().join(format!("fschema-command-parallel-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": {}, "postbuild": [{"parallel": [
+            {"cmd": "until [ -e b ]; do sleep 0.01; done; touch a", "cwd": ".", "timeoutSecs": 5},
+            {"cmd": "touch b", "cwd": "."}
+        ]}] }"#).unwrap();
+        schema.create(root.clone()).unwrap();
+        assert!(root.join("a").exists() && root.join("b").exists());
+        assert!(serde_json::to_string(&schema).unwrap().contains(r#""postbuild":[{"parallel":[{"cmd":"#));
+        assert!(FSchema::from_str(r#"{ "prebuild": [{"parallel": ["true"], "cwd": "."}] }"#).is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn killed() {
+        let killed = Command::from("kill -9 $$");
+        assert!(matches!(killed.run(false), Err(Error::Command(-1, ..))));
+        assert!(matches!(Command { timeout: Some(5), ..killed.clone() }.run(false), Err(Error::Command(-1, ..))));
+
+        let root = env::temp_dir().join(format!("fschema-command-killed-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": { "killed": ["echo partial; kill -9 $$", {"ftype": "Piped"}] } }"#).unwrap();
+        assert!(matches!(schema.create_with(root.clone(), &CreateOptions { quiet: true, ..Default::default() }), Err(Error::Command(-1, ..))));
+        assert!(!root.join("killed").exists() && !root.join(".killed.fschema-part").exists());
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
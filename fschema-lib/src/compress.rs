@@ -0,0 +1,47 @@
+use std::io::Read;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use flate2::read::GzDecoder;
+use ruzstd::decoding::StreamingDecoder;
+
+/// Decode base64, ignoring whitespace so long data can be wrapped over several lines
+fn decode_base64(data: &str) -> Result<Vec<u8>, String> {
+    let data = data.chars().filter(|c| !c.is_whitespace()).collect::<String>();
+    STANDARD.decode(data).map_err(|e| format!("Expected base64 data, {}", e))
+}
+
+/// Decompress gzip compressed, base64 encoded data
+pub(crate) fn decode_gzip_base64(data: &str) -> Result<Vec<u8>, String> {
+    let compressed = decode_base64(data)?;
+    let mut contents = vec![];
+    GzDecoder::new(compressed.as_slice())
+        .read_to_end(&mut contents)
+        .map_err(|e| format!("Expected gzip compressed data, {}", e))?;
+    Ok(contents)
+}
+
+/// Decompress zstd compressed, base64 encoded data
+pub(crate) fn decode_zstd_base64(data: &str) -> Result<Vec<u8>, String> {
+    let compressed = decode_base64(data)?;
+    let mut contents = vec![];
+    StreamingDecoder::new(compressed.as_slice())
+        .map_err(|e| format!("Expected zstd compressed data, {}", e))?
+        .read_to_end(&mut contents)
+        .map_err(|e| format!("Expected zstd compressed data, {}", e))?;
+    Ok(contents)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{decode_gzip_base64, decode_zstd_base64};
+
+    #[test]
+    fn test() {
+        // printf 'hello\n' | gzip -n | base64
+        assert_eq!(decode_gzip_base64("H4sIAAAAAAAAA8tIzcnJ5wIAIDA6NgYAAAA=").unwrap(), b"hello\n");
+        // A zstd frame holding 'hello\n' in a single raw block
+        assert_eq!(decode_zstd_base64("KLUv/SAG\nMQAAaGVsbG8K").unwrap(), b"hello\n");
+        assert!(decode_gzip_base64("aGVsbG8K").is_err());
+        assert!(decode_zstd_base64("not base64!").is_err());
+    }
+}
@@ -0,0 +1,312 @@
+use std::{collections::BTreeMap, env, iter::Peekable, str::Chars};
+
+use glob::Pattern;
+
+use crate::Error;
+
+/// Token of a condition
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Token {
+    /// Name of a variable, e.g. os
+    Name(String),
+    /// Quoted string or number, e.g. 'linux'
+    Literal(String),
+    Equals,
+    NotEquals,
+    And,
+    Or,
+    Not,
+    Open,
+    Close,
+}
+
+/// Operand of a comparison
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Operand {
+    Name(String),
+    Literal(String),
+}
+
+/// Parsed condition
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Condition {
+    /// Whether a variable is set to something other than "", "false" or "0"
+    Truthy(Operand),
+    /// Whether two operands are equal
+    Equals(Operand, Operand),
+    Not(Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+}
+
+/// Split a condition into tokens
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = vec![];
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        let token = match c {
+            c if c.is_whitespace() => continue,
+            '(' => Token::Open,
+            ')' => Token::Close,
+            '=' if chars.next_if_eq(&'=').is_some() => Token::Equals,
+            '!' if chars.next_if_eq(&'=').is_some() => Token::NotEquals,
+            '!' => Token::Not,
+            '&' if chars.next_if_eq(&'&').is_some() => Token::And,
+            '|' if chars.next_if_eq(&'|').is_some() => Token::Or,
+            '\'' | '"' => Token::Literal(quoted(c, &mut chars)?),
+            c if is_name_char(c) => {
+                let mut name = c.to_string();
+                while let Some(c) = chars.next_if(|c| is_name_char(*c)) {
+                    name.push(c);
+                }
+                match name.starts_with(|c: char| c.is_ascii_digit()) {
+                    true => Token::Literal(name),
+                    false => Token::Name(name),
+                }
+            },
+            c => return Err(format!("unexpected '{}'", c)),
+        };
+        tokens.push(token);
+    }
+    Ok(tokens)
+}
+
+/// Characters names and unquoted numbers are made of
+fn is_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '_' | '.' | '-')
+}
+
+/// Read a string up to its closing quote
+fn quoted(quote: char, chars: &mut Peekable<Chars>) -> Result<String, String> {
+    let mut string = String::new();
+    for c in chars.by_ref() {
+        if c == quote {
+            return Ok(string);
+        }
+        string.push(c);
+    }
+    Err(format!("missing closing {}", quote))
+}
+
+/// Recursive descent parser of conditions. || binds loosest, then &&, then !
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn or(&mut self) -> Result<Condition, String> {
+        let mut condition = self.and()?;
+        while self.peek() == Some(&Token::Or) {
+            self.next();
+            condition = Condition::Or(Box::new(condition), Box::new(self.and()?));
+        }
+        Ok(condition)
+    }
+
+    fn and(&mut self) -> Result<Condition, String> {
+        let mut condition = self.not()?;
+        while self.peek() == Some(&Token::And) {
+            self.next();
+            condition = Condition::And(Box::new(condition), Box::new(self.not()?));
+        }
+        Ok(condition)
+    }
+
+    fn not(&mut self) -> Result<Condition, String> {
+        match self.peek() {
+            Some(Token::Not) => {
+                self.next();
+                Ok(Condition::Not(Box::new(self.not()?)))
+            },
+            Some(Token::Open) => {
+                self.next();
+                let condition = self.or()?;
+                match self.next() {
+                    Some(Token::Close) => Ok(condition),
+                    _ => Err("missing closing )".to_string()),
+                }
+            },
+            _ => self.comparison(),
+        }
+    }
+
+    fn comparison(&mut self) -> Result<Condition, String> {
+        let left = self.operand()?;
+        match self.peek() {
+            Some(Token::Equals) => {
+                self.next();
+                Ok(Condition::Equals(left, self.operand()?))
+            },
+            Some(Token::NotEquals) => {
+                self.next();
+                Ok(Condition::Not(Box::new(Condition::Equals(left, self.operand()?))))
+            },
+            _ => Ok(Condition::Truthy(left)),
+        }
+    }
+
+    fn operand(&mut self) -> Result<Operand, String> {
+        match self.next() {
+            Some(Token::Name(name)) => Ok(Operand::Name(name)),
+            Some(Token::Literal(literal)) => Ok(Operand::Literal(literal)),
+            Some(token) => Err(format!("expected a name or string, found {:?}", token)),
+            None => Err("expected a name or string, found the end of the condition".to_string()),
+        }
+    }
+}
+
+/// Parse a condition
+fn parse(text: &str) -> Result<Condition, String> {
+    let mut parser = Parser { tokens: tokenize(text)?, position: 0 };
+    let condition = parser.or()?;
+    match parser.peek() {
+        Some(token) => Err(format!("unexpected {:?}", token)),
+        None => Ok(condition),
+    }
+}
+
+/// Check a node's "when" condition is valid, e.g. "os == 'linux' && feature_x"
+pub(crate) fn parse_condition(text: &str) -> Result<(), String> {
+    parse(text).map(|_| ()).map_err(|e| format!("Invalid condition '{}', {}", text, e))
+}
+
+impl Condition {
+    fn evaluate<F>(&self, lookup: &F) -> bool
+    where
+        F: Fn(&str) -> Option<String>
+    {
+        let value = |operand: &Operand| match operand {
+            Operand::Name(name) => lookup(name),
+            Operand::Literal(literal) => Some(literal.clone()),
+        };
+        match self {
+            Condition::Truthy(operand) => value(operand).is_some_and(|value| !matches!(value.as_str(), "" | "false" | "0")),
+            Condition::Equals(left, right) => value(left).unwrap_or_default() == value(right).unwrap_or_default(),
+            Condition::Not(condition) => !condition.evaluate(lookup),
+            Condition::And(left, right) => left.evaluate(lookup) && right.evaluate(lookup),
+            Condition::Or(left, right) => left.evaluate(lookup) || right.evaluate(lookup),
+        }
+    }
+}
+
+/// Names conditions can use besides variables and the environment: os, e.g. "linux" or "macos", arch, e.g. "x86_64", family, "unix" or "windows",
+/// hostname, and target, the hostname unless the "target" variable identifies the machine
+fn builtin(name: &str) -> Option<String> {
+    match name {
+        "os" => Some(env::consts::OS.to_string()),
+        "arch" => Some(env::consts::ARCH.to_string()),
+        "family" => Some(env::consts::FAMILY.to_string()),
+        "hostname" | "target" => hostname(),
+        _ => None,
+    }
+}
+
+#[cfg(unix)]
+/// Name of the machine
+fn hostname() -> Option<String> {
+    let mut name = [0u8; 256];
+    // SAFETY: the buffer's length is passed, and the last byte is never written so the name is always nul terminated
+    if unsafe { libc::gethostname(name.as_mut_ptr().cast(), name.len() - 1) } != 0 {
+        return None;
+    }
+    std::ffi::CStr::from_bytes_until_nul(&name).ok().map(|name| name.to_string_lossy().to_string())
+}
+
+#[cfg(windows)]
+/// Name of the machine
+fn hostname() -> Option<String> {
+    env::var("COMPUTERNAME").ok()
+}
+
+/// Check a node's "hosts" are valid glob patterns, e.g. ["laptop", "build-*"]
+pub(crate) fn parse_hosts(hosts: &[String]) -> Result<(), String> {
+    for host in hosts {
+        Pattern::new(host).map_err(|e| format!("Invalid host pattern {}, {}", host, e))?;
+    }
+    Ok(())
+}
+
+/// Whether the machine is one of a node's hosts, matching its target, given by the "target" variable, or its hostname if there's no target.
+/// Nodes without hosts are created on every machine
+pub(crate) fn on_host(hosts: &[String], variables: &BTreeMap<String, String>) -> bool {
+    if hosts.is_empty() {
+        return true;
+    }
+    match variables.get("target").cloned().or_else(hostname) {
+        Some(name) => hosts.iter()
+            .filter_map(|host| Pattern::new(host).ok())
+            .any(|host| host.matches(&name)),
+        None => false,
+    }
+}
+
+/// Evaluate a node's "when" condition. Names are looked up in the schema's variables, then the builtin names, then the environment.
+/// Names that aren't set are false, and compare equal to ''
+pub(crate) fn holds(text: &str, variables: &BTreeMap<String, String>) -> Result<bool, Error> {
+    let condition = parse(text).map_err(|e| Error::InvalidCondition(text.to_string(), e))?;
+    Ok(condition.evaluate(&|name: &str| variables.get(name).cloned().or_else(|| builtin(name)).or_else(|| env::var(name).ok())))
+}
+
+/// Platforms nodes can be limited to, the operating systems Rust targets and the families "unix" and "windows"
+const PLATFORMS: &[&str] = &["linux", "macos", "ios", "android", "freebsd", "dragonfly", "netbsd", "openbsd", "solaris", "illumos", "windows", "unix"];
+
+/// Check a node's "platform" list only names known platforms, e.g. ["linux", "macos"]
+pub(crate) fn parse_platforms(platforms: &[String]) -> Result<(), String> {
+    match platforms.iter().find(|platform| !PLATFORMS.contains(&platform.as_str())) {
+        Some(platform) => Err(format!("Unknown platform {}, expected one of {}", platform, PLATFORMS.join(", "))),
+        None => Ok(()),
+    }
+}
+
+/// Whether the current platform is one of a node's platforms. Nodes without platforms are created on every platform
+pub(crate) fn on_platform(platforms: &[String]) -> bool {
+    platforms.is_empty() || platforms.iter().any(|platform| platform == env::consts::OS || platform == env::consts::FAMILY)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use super::{on_host, parse, parse_hosts, parse_platforms};
+
+    #[test]
+    fn test() {
+        let lookup = |name: &str| match name {
+            "os" => Some("linux".to_string()),
+            "feature_x" => Some("true".to_string()),
+            "debug" => Some("0".to_string()),
+            _ => None,
+        };
+        let holds = |text: &str| parse(text).unwrap().evaluate(&lookup);
+        assert!(holds("os == 'linux' && feature_x"));
+        assert!(holds("os != \"macos\" && !debug"));
+        assert!(!holds("missing || os == 'windows'"));
+        assert!(holds("!(debug || missing) && missing == ''"));
+        assert!(holds("feature_x == 'true' || os"));
+        assert!(parse("os ==").is_err());
+        assert!(parse("(os == 'linux'").is_err());
+        assert!(parse("os = 'linux'").is_err());
+        assert!(parse("os 'linux'").is_err());
+        assert!(parse("'linux").is_err());
+
+        assert!(parse_platforms(&["linux".to_string(), "unix".to_string()]).is_ok());
+        assert!(parse_platforms(&["osx".to_string()]).is_err());
+
+        let variables = BTreeMap::from([("target".to_string(), "build-01".to_string())]);
+        assert!(on_host(&["laptop".to_string(), "build-*".to_string()], &variables));
+        assert!(!on_host(&["laptop".to_string()], &variables));
+        assert!(on_host(&[], &variables));
+        assert!(parse_hosts(&["[".to_string()]).is_err());
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    fs, io,
+    path::{Component, Path, PathBuf},
+};
+#[cfg(unix)]
+use std::os::unix::prelude::MetadataExt;
+#[cfg(target_os = "linux")]
+use std::{fs::{File, OpenOptions}, os::fd::AsRawFd};
+
+use crate::{manifest::hash_file, mode::mode_of, owner::Ownership, Error, FileOptions, Preserve};
+
+/// Options of a Copy file with the metadata it preserves taken from its source
+pub(crate) fn preserve(source: &Path, options: &FileOptions) -> Result<FileOptions, Error> {
+    let context = |e| Error::IO(e, format!("{:?}", source));
+    let metadata = fs::metadata(source).map_err(context)?;
+    let mut options = options.clone();
+    for preserve in options.preserve.clone() {
+        match preserve {
+            Preserve::Mode => options.mode = Some(mode_of(&metadata)),
+            Preserve::Times => {
+                options.accessed = Some(metadata.accessed().map_err(context)?);
+                options.modified = Some(metadata.modified().map_err(context)?);
+            },
+            #[cfg(unix)]
+            Preserve::Owner => options.ownership = Ownership { uid: Some(metadata.uid()), gid: Some(metadata.gid()), ..Default::default() },
+            #[cfg(windows)]
+            Preserve::Owner => options.ownership = Ownership::default(),
+        }
+    }
+    Ok(options)
+}
+
+/// Copy a file and its permissions, like fs::copy. On file systems supporting copy on write, e.g. btrfs or xfs, the copy is a clone
+/// sharing the source's data, so even large files are copied instantly. fs::copy uses copy_file_range otherwise, letting the
+/// file system copy the data without reading it into memory
+pub(crate) fn copy_file(source: &Path, destination: &Path) -> io::Result<()> {
+    if clone_file(source, destination).is_ok() {
+        return Ok(());
+    }
+    fs::copy(source, destination).map(|_| ())
+}
+
+#[cfg(target_os = "linux")]
+/// Clone a file with the FICLONE ioctl, failing if the file system doesn't support it or the files are on different file systems
+fn clone_file(source: &Path, destination: &Path) -> io::Result<()> {
+    let input = File::open(source)?;
+    let metadata = input.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::ErrorKind::Unsupported.into());
+    }
+    let output = OpenOptions::new().write(true).create(true).truncate(true).open(destination)?;
+    // SAFETY: both file descriptors are open for the duration of the call
+    if unsafe { libc::ioctl(output.as_raw_fd(), libc::FICLONE, input.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    output.set_permissions(metadata.permissions())
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Clone a file, unsupported other than on linux
+fn clone_file(_source: &Path, _destination: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+/// Path without "." components, so paths glob returns can be compared with the pattern they matched
+fn normal(path: &Path) -> PathBuf {
+    path.components().filter(|component| !matches!(component, Component::CurDir)).collect()
+}
+
+/// Directory the matches of a glob are copied relative to, its leading components without wildcards,
+/// e.g. "assets" for "assets/**/*.png"
+fn glob_base(pattern: &str) -> PathBuf {
+    normal(Path::new(pattern))
+        .components()
+        .take_while(|component| !component.as_os_str().to_string_lossy().contains(['*', '?', '[']))
+        .collect()
+}
+
+/// Files matching a glob, and their paths relative to the glob's base directory. Directories that match are skipped,
+/// their files are only copied if the glob matches them too
+pub(crate) fn glob_files(pattern: &str) -> Result<Vec<(PathBuf, PathBuf)>, Error> {
+    let base = glob_base(pattern);
+    let paths = glob::glob(pattern).map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), pattern.to_string()))?;
+    let mut files = vec![];
+    for path in paths {
+        let path = path.map_err(|e| Error::IO(e.into(), pattern.to_string()))?;
+        if !path.is_file() {
+            continue;
+        }
+        let relative = match normal(&path).strip_prefix(&base) {
+            Ok(relative) if !relative.as_os_str().is_empty() => relative.to_path_buf(),
+            _ => PathBuf::from(path.file_name().unwrap_or_default()),
+        };
+        files.push((path, relative));
+    }
+    Ok(files)
+}
+
+/// Copy the files matching a glob into a directory, creating it if needed, keeping their paths relative to the glob's base directory
+pub(crate) fn copy_glob(pattern: &str, directory: &Path) -> Result<(), Error> {
+    let context = |path: &Path| format!("{:?} -> {:?}", path, directory);
+    fs::create_dir_all(directory).map_err(|e| Error::IO(e, context(Path::new(pattern))))?;
+    for (source, relative) in glob_files(pattern)? {
+        let destination = directory.join(relative);
+        if let Some(parent) = destination.parent() {
+            fs::create_dir_all(parent).map_err(|e| Error::IO(e, context(&source)))?;
+        }
+        copy_file(&source, &destination).map_err(|e| Error::IO(e, context(&source)))?;
+    }
+    Ok(())
+}
+
+/// Check whether a directory already holds copies of the files matching a glob. Files are compared by hash
+pub(crate) fn copied_glob(pattern: &str, directory: &Path) -> Result<bool, Error> {
+    for (source, relative) in glob_files(pattern)? {
+        let destination = directory.join(relative);
+        let copied = fs::symlink_metadata(&destination).map(|metadata| metadata.is_file()).unwrap_or(false)
+            && hash_file(&source)? == hash_file(&destination)?;
+        if !copied {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, path::PathBuf, time::{Duration, UNIX_EPOCH}};
+
+    use crate::{mode::{effective_mode, mode_of, set_mode}, CreateOptions, FSchema, OverwritePolicy};
+
+    use super::{copy_file, glob_base};
+
+    #[test]
+    fn test() {
+        assert_eq!(glob_base("assets/**/*.png"), PathBuf::from("assets"));
+        assert_eq!(glob_base("./static/img/*.jpg"), PathBuf::from("static/img"));
+        assert_eq!(glob_base("/srv/[ab]/*"), PathBuf::from("/srv"));
+        assert_eq!(glob_base("*.txt"), PathBuf::new());
+
+        let dir = env::temp_dir().join(format!("fschema-copy-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tool"), "#!/bin/sh\n").unwrap();
+        set_mode(&dir.join("tool"), 0o750).unwrap();
+        fs::write(dir.join("copy"), "longer existing contents").unwrap();
+        copy_file(&dir.join("tool"), &dir.join("copy")).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("copy")).unwrap(), "#!/bin/sh\n");
+        assert_eq!(mode_of(&fs::metadata(dir.join("copy")).unwrap()), effective_mode(0o750));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn preserve() {
+        let dir = env::temp_dir().join(format!("fschema-preserve-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("tool"), "#!/bin/sh\n").unwrap();
+        set_mode(&dir.join("tool"), 0o750).unwrap();
+        let modified = |path: &PathBuf| fs::metadata(path).unwrap().modified().unwrap();
+        let set_modified = |path: &PathBuf, seconds| fs::File::options()
+            .write(true)
+            .open(path)
+            .unwrap()
+            .set_modified(UNIX_EPOCH + Duration::from_secs(seconds))
+            .unwrap();
+        set_modified(&dir.join("tool"), 1_000_000_000);
+        let schema = FSchema::from_str(&format!(r#"{{ "root": {{ "tool": ["{}", {{"ftype": "Copy", "mode": "600", "preserve": ["mode", "times"]}}] }} }}"#,
+            dir.join("tool").display())).unwrap();
+        let root = dir.join("out");
+        let strict = CreateOptions { overwrite: OverwritePolicy::Error, ..Default::default() };
+
+        schema.create(root.clone()).unwrap();
+        assert_eq!(mode_of(&fs::metadata(root.join("tool")).unwrap()), effective_mode(0o750));
+        assert_eq!(modified(&root.join("tool")), modified(&dir.join("tool")));
+
+        set_mode(&dir.join("tool"), 0o700).unwrap();
+        set_modified(&dir.join("tool"), 1_100_000_000);
+        set_mode(&root.join("tool"), 0o644).unwrap();
+        schema.create_with(root.clone(), &strict).unwrap();
+        assert_eq!(mode_of(&fs::metadata(root.join("tool")).unwrap()), effective_mode(0o700));
+        assert_eq!(modified(&root.join("tool")), modified(&dir.join("tool")));
+        assert_eq!(fs::read_to_string(root.join("tool")).unwrap(), "#!/bin/sh\n");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+
+/// Parse CSV into a record for each row, keyed by the header row's fields. Fields can be quoted, holding commas, newlines and
+/// quotes written twice. Blank lines are skipped
+pub(crate) fn parse_csv(text: &str) -> Result<Vec<BTreeMap<String, String>>, String> {
+    let mut rows = vec![];
+    let mut row = vec![];
+    let mut field = String::new();
+    let mut quoted = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        match (c, quoted) {
+            ('"', true) if chars.next_if_eq(&'"').is_some() => field.push('"'),
+            ('"', true) => quoted = false,
+            ('"', false) if field.is_empty() => quoted = true,
+            (',', false) => row.push(std::mem::take(&mut field)),
+            ('\r', false) if chars.peek() == Some(&'\n') => (),
+            ('\n', false) => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            },
+            (c, _) => field.push(c),
+        }
+    }
+    if quoted {
+        return Err("Unterminated quoted field".to_string());
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+
+    let mut rows = rows.into_iter().filter(|row| !(row.len() == 1 && row[0].is_empty()));
+    let header = rows.next().ok_or("Expected a header row")?;
+    rows.enumerate()
+        .map(|(index, row)| match row.len() == header.len() {
+            true => Ok(header.iter().cloned().zip(row).collect()),
+            false => Err(format!("Row {} has {} fields, expected {} like the header", index + 2, row.len(), header.len())),
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_csv;
+
+    #[test]
+    fn test() {
+        let records = parse_csv("name,bio\r\nalice,\"likes \"\"rust\"\", go\"\n\nbob,\"multi\nline\"").unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[0]["bio"], "likes \"rust\", go");
+        assert_eq!(records[1]["name"], "bob");
+        assert_eq!(records[1]["bio"], "multi\nline");
+        assert!(parse_csv("a,b\n1\n").is_err());
+        assert!(parse_csv("a\n\"open").is_err());
+    }
+}
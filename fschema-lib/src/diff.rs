@@ -0,0 +1,299 @@
+use std::{
+    collections::HashSet,
+    fmt::Display,
+    fs::{self, Metadata},
+    io,
+    path::PathBuf,
+};
+
+use crate::{
+    checksums::checksums,
+    download::{download, is_cached, is_url},
+    edit::{edited, is_edit},
+    mode::{effective_mode, mode_of},
+    plan::{inline_contents, unchanged},
+    special::{special, Special},
+    Error, FSchema, FileType, Manifest, Operation,
+};
+
+#[derive(Debug, Clone)]
+/// A difference between a schema and an existing file system structure
+pub enum Drift {
+    /// A file or directory in the schema doesn't exist
+    Missing(PathBuf),
+    /// An entry exists but is the wrong kind, e.g. a file where a directory is expected
+    Kind{path: PathBuf, expected: &'static str, actual: &'static str},
+    /// A file's contents differ from the schema
+    Content{path: PathBuf, expected: Vec<u8>, actual: Vec<u8>},
+    /// A symbolic link points somewhere other than the schema specifies
+    Link{path: PathBuf, expected: PathBuf, actual: PathBuf},
+    /// A file or directory's permissions differ from the schema
+    Mode{path: PathBuf, expected: u32, actual: u32},
+    /// An entry exists inside a directory of the schema but isn't part of the schema
+    Extraneous(PathBuf),
+    /// A file has been modified since it was recorded in a manifest
+    Modified(PathBuf),
+}
+
+impl Drift {
+    /// Path of the entry that has drifted
+    pub fn path(&self) -> &PathBuf {
+        match self {
+            Drift::Missing(path) 
+            | Drift::Kind { path, .. } 
+            | Drift::Content { path, .. } 
+            | Drift::Link { path, .. } 
+            | Drift::Mode { path, .. } 
+            | Drift::Extraneous(path)
+            | Drift::Modified(path) => path,
+        }
+    }
+}
+
+impl Display for Drift {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Drift::Missing(path) => f.write_fmt(format_args!("missing {:?}", path)),
+            Drift::Kind { path, expected, actual } => f.write_fmt(format_args!("{:?} is a {}, expected a {}", path, actual, expected)),
+            Drift::Content { path, .. } => f.write_fmt(format_args!("contents of {:?} differ", path)),
+            Drift::Link { path, expected, actual } => f.write_fmt(format_args!("{:?} links to {:?}, expected {:?}", path, actual, expected)),
+            Drift::Mode { path, expected, actual } => f.write_fmt(format_args!("{:?} has mode {:o}, expected {:o}", path, actual, expected)),
+            Drift::Extraneous(path) => f.write_fmt(format_args!("extraneous {:?}", path)),
+            Drift::Modified(path) => f.write_fmt(format_args!("{:?} has been modified since it was created", path)),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Options used when comparing a schema against an existing file system structure
+pub struct DiffOptions {
+    /// Download the sources of Download files, and Archive files given by URL, that aren't in the download cache so they
+    /// can be compared. When unset, only their existence is checked unless they're cached
+    pub download: bool,
+}
+
+/// Name of the kind of file found at a path
+fn kind(metadata: &Metadata) -> &'static str {
+    if metadata.is_dir() {
+        "directory"
+    } else if metadata.file_type().is_symlink() {
+        "link"
+    } else {
+        match special(metadata) {
+            Some(Special::Fifo) => "fifo",
+            Some(Special::Character(..) | Special::Block(..)) => "device",
+            None => "file",
+        }
+    }
+}
+
+impl FSchema {
+    /// Compare the schema against the file system structure at root. No commands are run, so the contents of piped files aren't compared, 
+    /// and nothing is downloaded
+    pub fn diff(&self, root: PathBuf) -> Result<Vec<Drift>, Error> {
+        self.diff_with(root, None, &DiffOptions::default())
+    }
+
+    /// Compare the schema against a file system structure created with a manifest. 
+    /// Only entries recorded in the manifest that are no longer part of the schema are reported as extraneous
+    pub fn diff_managed(&self, root: PathBuf, manifest: &Manifest) -> Result<Vec<Drift>, Error> {
+        self.diff_with(root, Some(manifest), &DiffOptions::default())
+    }
+
+    /// Compare the schema against the file system structure at root with the given options, and the manifest it was created
+    /// with if there is one
+    pub fn diff_with(&self, root: PathBuf, manifest: Option<&Manifest>, diff_options: &DiffOptions) -> Result<Vec<Drift>, Error> {
+        let plan = self.plan(root.clone())?;
+        let mut drift = vec![];
+        let mut expected = HashSet::new();
+
+        for operation in &plan {
+            match operation {
+                Operation::Run(_) | Operation::Parallel(_) | Operation::Remove(_) => (),
+                Operation::Directory { path, options } => {
+                    expected.insert(path.clone());
+                    match fs::symlink_metadata(path) {
+                        Ok(metadata) if metadata.is_dir() => if let Some(mode) = options.mode {
+                            let actual = mode_of(&metadata);
+                            if actual != effective_mode(mode) {
+                                drift.push(Drift::Mode { path: path.clone(), expected: mode, actual });
+                            }
+                        },
+                        Ok(metadata) => drift.push(Drift::Kind { path: path.clone(), expected: "directory", actual: kind(&metadata) }),
+                        Err(_) => drift.push(Drift::Missing(path.clone())),
+                    }
+                },
+                Operation::File { path, data, options } => {
+                    expected.insert(path.clone());
+                    let metadata = match fs::symlink_metadata(path) {
+                        Ok(metadata) => metadata,
+                        Err(_) => {
+                            drift.push(Drift::Missing(path.clone()));
+                            continue;
+                        },
+                    };
+
+                    if let FileType::Link = options.ftype {
+                        if !metadata.file_type().is_symlink() {
+                            drift.push(Drift::Kind { path: path.clone(), expected: "link", actual: kind(&metadata) });
+                            continue;
+                        }
+                        let actual = fs::read_link(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+                        if actual.as_os_str() != data.as_str() {
+                            drift.push(Drift::Link { path: path.clone(), expected: PathBuf::from(data), actual });
+                        }
+                        continue;
+                    }
+
+                    if let FileType::Device = options.ftype {
+                        if !unchanged(path, &metadata, data, options, None)? {
+                            drift.push(Drift::Kind { path: path.clone(), expected: "device", actual: kind(&metadata) });
+                            continue;
+                        }
+                    } else if let FileType::Archive = options.ftype {
+                        if is_url(data) && !diff_options.download && !is_cached(data, &options.sha256) {
+                            continue;
+                        }
+                        if is_url(data) {
+                            download(data, &options.sha256, false)?;
+                        }
+                        if !unchanged(path, &metadata, data, options, None)? {
+                            drift.push(Drift::Kind { path: path.clone(), expected: "directory with the archive extracted", actual: kind(&metadata) });
+                        }
+                        continue;
+                    } else if let FileType::CopyGlob = options.ftype {
+                        if !unchanged(path, &metadata, data, options, None)? {
+                            drift.push(Drift::Kind { path: path.clone(), expected: "directory with the glob's files copied", actual: kind(&metadata) });
+                        }
+                        continue;
+                    } else if let FileType::Git = options.ftype {
+                        if !unchanged(path, &metadata, data, options, None)? {
+                            drift.push(Drift::Kind { path: path.clone(), expected: "git clone", actual: kind(&metadata) });
+                        }
+                        continue;
+                    } else if let FileType::Fifo = options.ftype {
+                        if special(&metadata) != Some(Special::Fifo) {
+                            drift.push(Drift::Kind { path: path.clone(), expected: "fifo", actual: kind(&metadata) });
+                            continue;
+                        }
+                    } else if !metadata.is_file() {
+                        drift.push(Drift::Kind { path: path.clone(), expected: "file", actual: kind(&metadata) });
+                        continue;
+                    }
+
+                    let contents = match options.ftype {
+                        ftype if is_edit(ftype) => Some(edited(path, data, options)?),
+                        FileType::Checksums => Some(checksums(path, data)?),
+                        FileType::Copy => Some(fs::read(data).map_err(|e| Error::IO(e, data.to_string()))?),
+                        FileType::Download if !diff_options.download && !is_cached(data, &options.sha256) => None,
+                        FileType::Download => {
+                            let source = download(data, &options.sha256, false)?;
+                            Some(fs::read(&source).map_err(|e| Error::IO(e, format!("{:?}", source)))?)
+                        },
+                        _ => inline_contents(data, options.ftype)
+                            .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e), format!("{:?}", path)))?,
+                    };
+                    if let Some(contents) = contents {
+                        let actual = fs::read(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+                        if actual != contents {
+                            drift.push(Drift::Content { path: path.clone(), expected: contents, actual });
+                        }
+                    }
+
+                    if let Some(mode) = options.mode {
+                        let actual = mode_of(&metadata);
+                        if actual != effective_mode(mode) {
+                            drift.push(Drift::Mode { path: path.clone(), expected: mode, actual });
+                        }
+                    }
+                },
+            }
+        }
+
+        if let Some(manifest) = manifest {
+            drift.extend(manifest.entries
+                .iter()
+                .map(|entry| root.join(&entry.path))
+                .filter(|path| !expected.contains(path) && fs::symlink_metadata(path).is_ok())
+                .map(Drift::Extraneous));
+            return Ok(drift);
+        }
+
+        drift.extend(extraneous(plan.operations())?.into_iter().map(Drift::Extraneous));
+        Ok(drift)
+    }
+}
+
+/// Find the entries on disk, inside the directories created by the operations, that the operations don't create
+pub(crate) fn extraneous(operations: &[Operation]) -> Result<Vec<PathBuf>, Error> {
+    let expected = operations
+        .iter()
+        .filter_map(|operation| operation.path())
+        .collect::<HashSet<&PathBuf>>();
+
+    let mut extraneous = vec![];
+    for operation in operations {
+        let directory = match operation {
+            Operation::Directory { path, .. } if path.is_dir() && !path.is_symlink() => path,
+            _ => continue,
+        };
+
+        let mut entries = fs::read_dir(directory)
+            .and_then(|entries| entries.map(|entry| entry.map(|entry| entry.path())).collect::<Result<Vec<_>, _>>())
+            .map_err(|e| Error::IO(e, format!("{:?}", directory)))?;
+        entries.sort();
+        extraneous.extend(entries
+            .into_iter()
+            .filter(|path| !expected.contains(path)));
+    }
+
+    Ok(extraneous)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{mode::set_mode, special::symlink, FSchema, Manifest};
+
+    use super::{DiffOptions, Drift};
+
+    #[test]
+    fn test() {
+        let root = std::env::temp_dir().join(format!("fschema-diff-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": {
+            "missing": ["x"],
+            "kind": {},
+            "content": ["expected\n"],
+            "link": ["target", {"ftype": "Link"}],
+            "mode": ["m", {"mode": "600"}],
+            "same": ["same"],
+            "fetched": ["http://127.0.0.1:9/fetched.txt", {"ftype": "Download"}]
+        } }"#).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        fs::write(root.join("kind"), "").unwrap();
+        fs::write(root.join("content"), "actual\n").unwrap();
+        symlink("other", root.join("link")).unwrap();
+        fs::write(root.join("mode"), "m").unwrap();
+        set_mode(&root.join("mode"), 0o644).unwrap();
+        fs::write(root.join("same"), "same").unwrap();
+        fs::write(root.join("fetched"), "anything").unwrap();
+        fs::write(root.join("extra"), "").unwrap();
+
+        let drift = schema.diff(root.clone()).unwrap();
+        assert_eq!(drift.len(), 6, "{:?}", drift);
+        assert!(matches!(&drift[0], Drift::Missing(path) if path.ends_with("missing")));
+        assert!(matches!(&drift[1], Drift::Kind { path, expected: "directory", actual: "file" } if path.ends_with("kind")));
+        assert!(matches!(&drift[2], Drift::Content { path, expected, actual } if path.ends_with("content") && expected == b"expected\n" && actual == b"actual\n"));
+        assert!(matches!(&drift[3], Drift::Link { path, expected, actual } if path.ends_with("link") && expected.ends_with("target") && actual.ends_with("other")));
+        assert!(matches!(&drift[4], Drift::Mode { path, expected: 0o600, .. } if path.ends_with("mode")));
+        assert!(matches!(&drift[5], Drift::Extraneous(path) if path.ends_with("extra")));
+        assert_eq!(drift[0].path(), &root.join("missing"));
+
+        let managed = schema.diff_managed(root.clone(), &Manifest::new()).unwrap();
+        assert!(!managed.iter().any(|drift| matches!(drift, Drift::Extraneous(_))));
+        assert!(schema.diff_with(root.clone(), None, &DiffOptions { download: true }).is_err());
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
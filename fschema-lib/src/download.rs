@@ -0,0 +1,115 @@
+use std::{
+    env, fs,
+    path::PathBuf,
+    process::Command,
+};
+
+use crate::{
+    manifest::{hash_bytes, verify_sha256},
+    Error, FileType, Operation,
+};
+
+/// Check whether file data is a url, e.g. "https://example.com/file", rather than a path
+pub(crate) fn is_url(data: &str) -> bool {
+    data.contains("://")
+}
+
+/// Directory downloads are cached in, $XDG_CACHE_HOME/fschema or ~/.cache/fschema
+pub(crate) fn cache_dir() -> PathBuf {
+    match env::var_os("XDG_CACHE_HOME").filter(|dir| !dir.is_empty()) {
+        Some(dir) => PathBuf::from(dir).join("fschema"),
+        None => PathBuf::from(env::var_os("HOME").unwrap_or_default()).join(".cache").join("fschema"),
+    }
+}
+
+/// Location a download is cached at, keyed by its url and expected sha256 hash
+pub(crate) fn cached(url: &str, sha256: &Option<String>) -> PathBuf {
+    let key = format!("{}\n{}", url, sha256.as_deref().unwrap_or_default());
+    cache_dir().join(hash_bytes(key.as_bytes()))
+}
+
+/// Fetch a url with curl into the download cache, unless it's already cached. Returns the cached location.
+/// Downloads with an expected sha256 hash are only cached if they match it. When offline, nothing is fetched
+pub(crate) fn download(url: &str, sha256: &Option<String>, offline: bool) -> Result<PathBuf, Error> {
+    let path = cached(url, sha256);
+    if path.is_file() {
+        return Ok(path);
+    }
+    if offline {
+        return Err(Error::Offline(url.to_string()));
+    }
+
+    let dir = cache_dir();
+    fs::create_dir_all(&dir).map_err(|e| Error::IO(e, format!("{:?}", dir)))?;
+    let mut partial = path.clone().into_os_string();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let command = format!("curl --fail --silent --show-error --location {}", url);
+    let status = Command::new("curl")
+        .args(["--fail", "--silent", "--show-error", "--location", "--output"])
+        .arg(&partial)
+        .arg(url)
+        .status()
+        .map_err(|e| Error::IO(e, command.clone()))?;
+    if !status.success() {
+        fs::remove_file(&partial).ok();
+        return Err(Error::Command(status.code().unwrap_or(1), command, String::new()));
+    }
+
+    if let Err(e) = verify_sha256(&partial, sha256) {
+        fs::remove_file(&partial).ok();
+        return Err(e);
+    }
+    fs::rename(&partial, &path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+    Ok(path)
+}
+
+/// Check every download of a plan is cached, so an offline build fails before anything is created
+pub(crate) fn check_offline(operations: &[Operation]) -> Result<(), Error> {
+    for operation in operations {
+        if let Operation::File { data, options, .. } = operation {
+            let downloaded = matches!(options.ftype, FileType::Download) || matches!(options.ftype, FileType::Archive) && is_url(data);
+            if downloaded && !is_cached(data, &options.sha256) {
+                return Err(Error::Offline(data.to_string()));
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Check whether a url is in the download cache
+pub(crate) fn is_cached(url: &str, sha256: &Option<String>) -> bool {
+    cached(url, sha256).is_file()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use crate::Error;
+
+    use super::{download, is_cached};
+
+    #[test]
+    fn test() {
+        let dir = env::temp_dir().join(format!("fschema-download-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        env::set_var("XDG_CACHE_HOME", dir.join("cache"));
+        fs::write(dir.join("source"), "downloaded").unwrap();
+        let url = format!("file://{}", dir.join("source").display());
+
+        assert!(matches!(download(&url, &None, true), Err(Error::Offline(_))));
+        let cached = download(&url, &None, false).unwrap();
+        assert_eq!(fs::read_to_string(&cached).unwrap(), "downloaded");
+
+        let wrong = Some("0".repeat(64));
+        assert!(matches!(download(&url, &wrong, false), Err(Error::Checksum(..))));
+        assert!(!is_cached(&url, &wrong));
+
+        fs::remove_file(dir.join("source")).unwrap();
+        assert_eq!(download(&url, &None, true).unwrap(), cached);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,111 @@
+use std::{fs, io, path::Path};
+
+use regex::Regex;
+
+use crate::{merge::merge, patch::apply_patch, Error, FileOptions, FileType};
+
+/// Marker lines around the blocks of BlockInFile files, unless the file gives its own. {mark} is replaced with BEGIN or END
+const DEFAULT_MARKER: &str = "# {mark} FSCHEMA MANAGED BLOCK";
+
+/// Check whether a file type edits a file in place, rather than replacing it
+pub(crate) fn is_edit(ftype: FileType) -> bool {
+    matches!(ftype, FileType::Append | FileType::LineInFile | FileType::BlockInFile | FileType::Patch | FileType::Merge)
+}
+
+/// Contents of the file at path once an edit file type has edited it. Files that don't exist are edited as if they were empty
+pub(crate) fn edited(path: &Path, data: &str, options: &FileOptions) -> Result<Vec<u8>, Error> {
+    let current = match fs::metadata(path) {
+        Ok(metadata) if metadata.is_file() => fs::read(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?,
+        _ => vec![],
+    };
+    edit(path, &current, data, options).map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e), format!("{:?}", path)))
+}
+
+/// Contents of the file at path once an edit file type has edited its current contents
+fn edit(path: &Path, current: &[u8], data: &str, options: &FileOptions) -> Result<Vec<u8>, String> {
+    match options.ftype {
+        FileType::Append => Ok(append(current, data.as_bytes())),
+        FileType::LineInFile => line_in_file(text(current)?, data, options.regexp.as_deref()).map(String::into_bytes),
+        FileType::Patch => apply_patch(text(current)?, data).map(String::into_bytes),
+        FileType::Merge => merge(path, text(current)?, data, options).map(String::into_bytes),
+        FileType::BlockInFile => Ok(block_in_file(text(current)?, data, options.marker.as_deref().unwrap_or(DEFAULT_MARKER)).into_bytes()),
+        _ => Ok(data.as_bytes().to_vec()),
+    }
+}
+
+/// Contents of a file as text
+fn text(contents: &[u8]) -> Result<&str, String> {
+    std::str::from_utf8(contents).map_err(|e| format!("Expected a text file, {}", e))
+}
+
+/// Append data to contents, unless they already contain it
+fn append(contents: &[u8], data: &[u8]) -> Vec<u8> {
+    if data.is_empty() || contents.windows(data.len()).any(|window| window == data) {
+        return contents.to_vec();
+    }
+    [contents, data].concat()
+}
+
+/// Ensure text has a line, like Ansible's lineinfile. The last line matching the pattern is replaced with it,
+/// otherwise it's appended unless the text already has it
+fn line_in_file(text: &str, line: &str, pattern: Option<&str>) -> Result<String, String> {
+    let pattern = pattern
+        .map(Regex::new)
+        .transpose()
+        .map_err(|e| format!("Invalid regexp, {}", e))?;
+
+    let mut lines = text.lines().collect::<Vec<_>>();
+    match pattern.and_then(|pattern| lines.iter().rposition(|existing| pattern.is_match(existing))) {
+        Some(index) if lines[index] == line => return Ok(text.to_string()),
+        Some(index) => lines[index] = line,
+        None if lines.contains(&line) => return Ok(text.to_string()),
+        None => lines.push(line),
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Ensure text has a block between marker lines, like Ansible's blockinfile. An existing block between the markers is replaced,
+/// otherwise the block is added to the end
+fn block_in_file(text: &str, block: &str, marker: &str) -> String {
+    let (begin, end) = (marker.replace("{mark}", "BEGIN"), marker.replace("{mark}", "END"));
+    let mut replacement = vec![begin.as_str()];
+    replacement.extend(block.lines());
+    replacement.push(&end);
+
+    let mut lines = text.lines().collect::<Vec<_>>();
+    let existing = lines.iter().position(|line| *line == begin).and_then(|start| {
+        lines[start..].iter().position(|line| *line == end).map(|length| start..start + length + 1)
+    });
+    match existing {
+        Some(range) if lines[range.clone()] == replacement[..] => return text.to_string(),
+        Some(range) => {
+            lines.splice(range, replacement);
+        },
+        None => lines.extend(replacement),
+    }
+    lines.join("\n") + "\n"
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{append, block_in_file, line_in_file, DEFAULT_MARKER};
+
+    #[test]
+    fn test() {
+        assert_eq!(append(b"a\n", b"b\n"), b"a\nb\n");
+        assert_eq!(append(b"a\nb\nc\n", b"b\n"), b"a\nb\nc\n");
+
+        let config = "# Port 22\nPermitRootLogin yes\n";
+        assert_eq!(line_in_file(config, "PermitRootLogin no", Some("^PermitRootLogin")).unwrap(), "# Port 22\nPermitRootLogin no\n");
+        assert_eq!(line_in_file(config, "Port 2222", Some("^Port ")).unwrap(), "# Port 22\nPermitRootLogin yes\nPort 2222\n");
+        assert_eq!(line_in_file(config, "PermitRootLogin yes", None).unwrap(), config);
+        assert_eq!(line_in_file("a", "b", None).unwrap(), "a\nb\n");
+        assert!(line_in_file(config, "x", Some("(")).is_err());
+
+        let profile = block_in_file("export A=1\n", "export B=2\nexport C=3\n", DEFAULT_MARKER);
+        assert_eq!(profile, "export A=1\n# BEGIN FSCHEMA MANAGED BLOCK\nexport B=2\nexport C=3\n# END FSCHEMA MANAGED BLOCK\n");
+        assert_eq!(block_in_file(&profile, "export B=2\nexport C=3\n", DEFAULT_MARKER), profile);
+        assert_eq!(block_in_file(&(profile + "tail\n"), "export B=4", DEFAULT_MARKER), 
+            "export A=1\n# BEGIN FSCHEMA MANAGED BLOCK\nexport B=4\n# END FSCHEMA MANAGED BLOCK\ntail\n");
+    }
+}
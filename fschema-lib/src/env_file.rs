@@ -0,0 +1,121 @@
+use std::{collections::BTreeMap, fs, io, path::Path};
+
+use crate::{Error, FSchema};
+
+impl FSchema {
+    /// Copy of the schema with the variables of a .env file added to its environment, given to every command. The schema's own
+    /// "variables" and "env" take precedence over them
+    pub fn with_env_file(&self, path: &Path) -> Result<FSchema, Error> {
+        let text = fs::read_to_string(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        let env = parse_env_file(&text).map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e), format!("{:?}", path)))?;
+        let mut schema = self.clone();
+        schema.loaded_env.extend(env);
+        schema.loaded.push(path.to_path_buf());
+        Ok(schema)
+    }
+}
+
+/// Parse the variables of a .env file, lines of NAME=VALUE, optionally starting with "export". Blank lines and lines starting
+/// with # are skipped. Values may be single quoted, taken as they are, or double quoted, where \n, \t, \" and \\ are escapes.
+/// Unquoted values end at a " #" comment and are trimmed
+pub(crate) fn parse_env_file(text: &str) -> Result<BTreeMap<String, String>, String> {
+    let mut env = BTreeMap::new();
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let line = line.strip_prefix("export ").map_or(line, str::trim_start);
+        let (name, value) = match line.split_once('=') {
+            Some((name, value)) => (name.trim(), value.trim()),
+            None => return Err(format!("Expected NAME=VALUE on line {}", number + 1)),
+        };
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(format!("Invalid variable name '{}' on line {}", name, number + 1));
+        }
+        let value = match value.chars().next() {
+            Some(quote @ ('\'' | '"')) => match value[1..].find(quote) {
+                Some(end) if quote == '\'' => value[1..=end].to_string(),
+                Some(_) => unescape(&value[1..]).ok_or_else(|| format!("Unterminated quote on line {}", number + 1))?,
+                None => return Err(format!("Unterminated quote on line {}", number + 1)),
+            },
+            _ => value.split(" #").next().unwrap_or_default().trim_end().to_string(),
+        };
+        env.insert(name.to_string(), value);
+    }
+    Ok(env)
+}
+
+/// Unescape a double quoted value up to its closing quote, or None if it isn't closed
+fn unescape(quoted: &str) -> Option<String> {
+    let mut value = String::new();
+    let mut chars = quoted.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            '"' => return Some(value),
+            '\\' => match chars.next()? {
+                'n' => value.push('\n'),
+                't' => value.push('\t'),
+                c @ ('"' | '\\' | '$') => value.push(c),
+                c => value.extend(['\\', c]),
+            },
+            c => value.push(c),
+        }
+    }
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{collections::BTreeMap, fs};
+
+    use crate::{CreateOptions, FSchema, Operation};
+
+    use super::parse_env_file;
+
+    #[test]
+    fn test() {
+        let env = parse_env_file("# local settings\nexport PORT=8080\nNAME = app # the name\nGREETING=\"hi \\\"there\\\"\\n\"\nRAW='a \\n b'\n\nEMPTY=\n").unwrap();
+        assert_eq!(env, BTreeMap::from([
+            ("PORT".to_string(), "8080".to_string()),
+            ("NAME".to_string(), "app".to_string()),
+            ("GREETING".to_string(), "hi \"there\"\n".to_string()),
+            ("RAW".to_string(), "a \\n b".to_string()),
+            ("EMPTY".to_string(), String::new()),
+        ]));
+        assert!(parse_env_file("PORT").is_err());
+        assert!(parse_env_file("A-B=1").is_err());
+        assert!(parse_env_file("A=\"open").is_err());
+
+        let dir = std::env::temp_dir().join(format!("fschema-env-file-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join(".env"), "PORT=9090\nHOST=db\nTOKEN=secret\nMODE=prod\n").unwrap();
+        fs::write(dir.join("schema.json"), r#"{
+            "envFile": ".env",
+            "env": { "MODE": "dev" },
+            "variables": { "PORT": "80" },
+            "root": {
+                "port": ["{{HOST}}:{{PORT}}"],
+                "token": ["echo \"$TOKEN-$MODE\"", {"ftype": "Piped"}]
+            }
+        }"#).unwrap();
+        let schema = FSchema::from_path(&dir.join("schema.json"), None).unwrap();
+        let json = serde_json::to_value(&schema).unwrap();
+        assert_eq!(json["variables"].as_object().unwrap().keys().collect::<Vec<_>>(), ["PORT"]);
+        assert_eq!(json["variables"]["PORT"], "80");
+        assert_eq!(json["env"].as_object().unwrap().keys().collect::<Vec<_>>(), ["MODE"]);
+        assert_eq!(json["envFile"], ".env");
+        let root = dir.join("out");
+        schema.create_with(root.clone(), &CreateOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(fs::read_to_string(root.join("port")).unwrap(), "db:80");
+        assert_eq!(fs::read_to_string(root.join("token")).unwrap(), "secret-dev\n");
+
+        fs::write(dir.join("local.env"), "HOST=cache\nPORT=7070\n").unwrap();
+        let schema = schema.with_env_file(&dir.join("local.env")).unwrap();
+        let plan = schema.plan(root.clone()).unwrap();
+        assert!(plan.operations().iter().any(|operation| matches!(operation, Operation::File { path, data, .. } if path.ends_with("port") && data == "cache:80")));
+        let plan = schema.with_vars([("PORT", "6060")]).plan(root.clone()).unwrap();
+        assert!(plan.operations().iter().any(|operation| matches!(operation, Operation::File { path, data, .. } if path.ends_with("port") && data == "cache:6060")));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
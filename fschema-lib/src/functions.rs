@@ -0,0 +1,207 @@
+use std::{ffi::CString, time::{SystemTime, UNIX_EPOCH}};
+
+use regex::{Captures, Regex};
+
+use crate::{special::{random_seed, SplitMix}, times::format_time, Error, FSchema};
+
+impl FSchema {
+    /// Copy of the schema whose template functions generating random values, e.g. "{{uuid()}}", are seeded, so they generate
+    /// the same values every time
+    pub fn with_seed(&self, seed: u64) -> FSchema {
+        let mut schema = self.clone();
+        schema.seed = Some(seed);
+        schema
+    }
+}
+
+/// Template functions called in file data, e.g. "{{uuid()}}", "{{now("%Y-%m-%d")}}" or "{{rand_hex(16)}}"
+pub(crate) struct Functions {
+    random: SplitMix,
+    now: SystemTime,
+}
+
+impl Functions {
+    /// Functions generating random values from a seed, or from a random seed if none is given
+    pub(crate) fn new(seed: Option<u64>) -> Result<Functions, Error> {
+        let seed = match seed {
+            Some(seed) => seed,
+            None => random_seed().map_err(|e| Error::IO(e, "random seed".to_string()))?,
+        };
+        Ok(Functions { random: SplitMix(seed), now: SystemTime::now() })
+    }
+
+    /// Replace calls to functions in text with their results. Calls to names that aren't functions are left as they are
+    pub(crate) fn call_all(&mut self, text: &str) -> Result<String, Error> {
+        thread_local! {
+            static CALL: Regex = Regex::new(r#"\{\{\s*([a-z_]+)\(((?:[^)"']|"[^"]*"|'[^']*')*)\)\s*\}\}"#).expect("call pattern is valid");
+        }
+        if !text.contains("{{") {
+            return Ok(text.to_string());
+        }
+        CALL.with(|call| {
+            let mut error = None;
+            let called = call.replace_all(text, |captures: &Captures| {
+                let result = parse_arguments(&captures[2]).and_then(|arguments| self.call(&captures[1], &arguments));
+                match result {
+                    Ok(Some(result)) => result,
+                    Ok(None) => captures[0].to_string(),
+                    Err(e) => {
+                        error.get_or_insert(Error::InvalidFunction(captures[0].to_string(), e));
+                        String::new()
+                    },
+                }
+            });
+            match error {
+                Some(error) => Err(error),
+                None => Ok(called.to_string()),
+            }
+        })
+    }
+
+    /// Call a function, or None if there isn't a function with the name
+    fn call(&mut self, name: &str, arguments: &[String]) -> Result<Option<String>, String> {
+        let result = match (name, arguments) {
+            ("uuid", []) => self.uuid(),
+            ("now", []) => format_time(self.now),
+            ("now", [format]) => strftime(format, self.now)?,
+            ("rand_hex", [length]) => (0..count(length)?).map(|_| char::from(b"0123456789abcdef"[self.below(16)])).collect(),
+            ("rand_string", [length]) => {
+                const ALPHANUMERIC: &[u8] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789";
+                (0..count(length)?).map(|_| char::from(ALPHANUMERIC[self.below(ALPHANUMERIC.len())])).collect()
+            },
+            ("rand_int", [min, max]) => {
+                let parse = |bound: &String| bound.parse::<i64>().map_err(|_| format!("expected {} to be a whole number", bound));
+                let (min, max) = (parse(min)?, parse(max)?);
+                if min > max {
+                    return Err(format!("expected {} to be at most {}", min, max));
+                }
+                let span = max.abs_diff(min).wrapping_add(1);
+                let offset = match span {
+                    0 => self.random.next(),
+                    span => self.random.next() % span,
+                };
+                min.wrapping_add_unsigned(offset).to_string()
+            },
+            ("uuid" | "now" | "rand_hex" | "rand_string" | "rand_int", _) => return Err(format!("wrong number of arguments to {}", name)),
+            _ => return Ok(None),
+        };
+        Ok(Some(result))
+    }
+
+    /// Random number below bound
+    fn below(&mut self, bound: usize) -> usize {
+        (self.random.next() % bound as u64) as usize
+    }
+
+    /// Random version 4 uuid, e.g. "3f0c9a4e-8b1d-4c2a-9e6f-0d5b7a1c2e3f"
+    fn uuid(&mut self) -> String {
+        let mut bytes = [self.random.next().to_le_bytes(), self.random.next().to_le_bytes()].concat();
+        bytes[6] = (bytes[6] & 0x0f) | 0x40;
+        bytes[8] = (bytes[8] & 0x3f) | 0x80;
+        let hex = bytes.iter().map(|byte| format!("{:02x}", byte)).collect::<String>();
+        format!("{}-{}-{}-{}-{}", &hex[..8], &hex[8..12], &hex[12..16], &hex[16..20], &hex[20..])
+    }
+}
+
+/// Parse a length argument
+fn count(length: &str) -> Result<usize, String> {
+    length.parse::<usize>().map_err(|_| format!("expected {} to be a length", length))
+}
+
+/// Split the arguments of a call, quoted strings or bare numbers separated by commas, e.g. "'%Y, %m', 2"
+fn parse_arguments(text: &str) -> Result<Vec<String>, String> {
+    let mut arguments = vec![];
+    let mut chars = text.trim().chars().peekable();
+    while chars.peek().is_some() {
+        let argument = match chars.next_if(|c| matches!(c, '"' | '\'')) {
+            Some(quote) => {
+                let argument = chars.by_ref().take_while(|c| *c != quote).collect::<String>();
+                while chars.next_if(|c| c.is_whitespace()).is_some() {}
+                argument
+            },
+            None => {
+                let mut argument = String::new();
+                while let Some(c) = chars.next_if(|c| *c != ',') {
+                    argument.push(c);
+                }
+                argument.trim().to_string()
+            },
+        };
+        arguments.push(argument);
+        match chars.next() {
+            Some(',') => while chars.next_if(|c| c.is_whitespace()).is_some() {},
+            Some(c) => return Err(format!("unexpected '{}' after argument", c)),
+            None => (),
+        }
+    }
+    Ok(arguments)
+}
+
+/// Format a time in local time, like strftime, e.g. "%Y-%m-%d"
+fn strftime(format: &str, time: SystemTime) -> Result<String, String> {
+    let seconds = time.duration_since(UNIX_EPOCH).map(|since| since.as_secs()).unwrap_or_default() as libc::time_t;
+    let format = CString::new(format).map_err(|_| "format can't contain nul".to_string())?;
+    let tm = localtime(seconds).ok_or_else(|| "couldn't get the local time".to_string())?;
+    let mut buffer = [0u8; 256];
+    // SAFETY: strftime is given the buffer's length, and a tm filled in by localtime
+    let length = unsafe { crt::strftime(buffer.as_mut_ptr().cast(), buffer.len(), format.as_ptr(), &tm) };
+    Ok(String::from_utf8_lossy(&buffer[..length]).to_string())
+}
+
+#[cfg(unix)]
+use libc as crt;
+
+#[cfg(windows)]
+/// Functions of the C runtime the libc crate doesn't declare on windows
+mod crt {
+    extern "C" {
+        pub(super) fn strftime(s: *mut libc::c_char, max: libc::size_t, format: *const libc::c_char, tm: *const libc::tm) -> libc::size_t;
+    }
+}
+
+#[cfg(unix)]
+/// Local time of seconds since the unix epoch
+fn localtime(seconds: libc::time_t) -> Option<libc::tm> {
+    // SAFETY: tm is only read once localtime_r has filled it in
+    unsafe {
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        (!libc::localtime_r(&seconds, &mut tm).is_null()).then_some(tm)
+    }
+}
+
+#[cfg(windows)]
+/// Local time of seconds since the unix epoch
+fn localtime(seconds: libc::time_t) -> Option<libc::tm> {
+    // SAFETY: tm is only read once localtime_s has filled it in
+    unsafe {
+        let mut tm = std::mem::zeroed::<libc::tm>();
+        (libc::localtime_s(&mut tm, &seconds) == 0).then_some(tm)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{parse_arguments, Functions};
+
+    #[test]
+    fn test() {
+        assert_eq!(parse_arguments(" '%Y, %m' , 2").unwrap(), ["%Y, %m", "2"]);
+        assert!(parse_arguments("").unwrap().is_empty());
+        assert!(parse_arguments("'a' b").is_err());
+
+        let mut functions = Functions::new(Some(7)).unwrap();
+        functions.now = UNIX_EPOCH + Duration::from_secs(86400 * 500);
+        let text = functions.call_all("id={{uuid()}} key={{ rand_hex(16) }} n={{rand_int(1, 6)}} {{name}} {{other()}}").unwrap();
+        assert_eq!(text, Functions::new(Some(7)).unwrap().call_all("id={{uuid()}} key={{ rand_hex(16) }} n={{rand_int(1, 6)}} {{name}} {{other()}}").unwrap());
+        let id = &text[3..39];
+        assert_eq!(id.len(), 36);
+        assert_eq!(&id[14..15], "4");
+        assert!(text.contains("{{name}} {{other()}}"));
+        assert_eq!(functions.call_all("{{now()}} {{now('%Y')}}").unwrap(), "1971-05-16T00:00:00Z 1971");
+        assert_eq!(functions.call_all("{{rand_string(12)}}").unwrap().len(), 12);
+        assert!(functions.call_all("{{rand_hex()}}").is_err());
+        assert!(functions.call_all("{{rand_int(5, 1)}}").is_err());
+    }
+}
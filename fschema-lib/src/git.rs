@@ -0,0 +1,125 @@
+use std::{path::Path, process::Command};
+
+use crate::Error;
+
+/// Run git with arguments, failing if it exits unsuccessfully
+fn git(args: &[&str]) -> Result<String, Error> {
+    let command = format!("git {}", args.join(" "));
+    let output = Command::new("git")
+        .args(args)
+        .output()
+        .map_err(|e| Error::IO(e, command.clone()))?;
+    if !output.status.success() {
+        return Err(Error::Command(output.status.code().unwrap_or(1), command, String::from_utf8_lossy(&output.stderr).trim().to_string()));
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+/// Clone a repository into path, optionally checking out a ref.
+/// Shallow clones only fetch the ref's commit, so the ref must be a branch or tag
+pub(crate) fn clone(path: &Path, url: &str, git_ref: &Option<String>, shallow: bool) -> Result<(), Error> {
+    let path = path.to_string_lossy();
+    let mut args = vec!["clone", "--quiet"];
+    if shallow {
+        args.extend(["--depth", "1"]);
+        if let Some(git_ref) = git_ref {
+            args.extend(["--branch", git_ref]);
+        }
+    }
+    args.extend(["--", url, &path]);
+    git(&args)?;
+
+    match git_ref {
+        Some(git_ref) if !shallow => git(&["-C", &path, "checkout", "--quiet", git_ref]).map(|_| ()),
+        _ => Ok(()),
+    }
+}
+
+/// Check whether path holds a clone of a repository
+pub(crate) fn is_clone(path: &Path, url: &str) -> bool {
+    path.join(".git").exists()
+        && git(&["-C", &path.to_string_lossy(), "remote", "get-url", "origin"]).map(|origin| origin == url).unwrap_or(false)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{CreateOptions, FSchema, OverwritePolicy};
+
+    use super::{clone, git, is_clone};
+
+    /// Create a repository at path whose "version" file is "1" at tag v1 and "2" at its head
+    fn repository(path: &Path) -> String {
+        fs::create_dir_all(path).unwrap();
+        let path = path.to_string_lossy().to_string();
+        let commit = |message: &str| {
+            fs::write(Path::new(&path).join("version"), message).unwrap();
+            git(&["-C", &path, "add", "version"]).unwrap();
+            git(&["-C", &path, "-c", "user.name=fschema", "-c", "user.email=fschema@localhost", "commit", "--quiet", "-m", message]).unwrap();
+        };
+        git(&["init", "--quiet", &path]).unwrap();
+        commit("1");
+        git(&["-C", &path, "tag", "v1"]).unwrap();
+        commit("2");
+        path
+    }
+
+    #[test]
+    fn test() {
+        let dir = std::env::temp_dir().join(format!("fschema-git-{}", std::process::id()));
+        let origin = dir.join("origin");
+        let origin_path = repository(&origin);
+
+        clone(&dir.join("latest"), &origin_path, &None, false).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("latest/version")).unwrap(), "2");
+        assert!(is_clone(&dir.join("latest"), &origin_path));
+        assert!(!is_clone(&dir.join("latest"), "https://example.com/other.git"));
+        assert!(!is_clone(&origin, &origin_path));
+
+        clone(&dir.join("tagged"), &origin_path, &Some("v1".to_string()), false).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("tagged/version")).unwrap(), "1");
+        let url = format!("file://{}", origin_path);
+        clone(&dir.join("shallow"), &url, &Some("v1".to_string()), true).unwrap();
+        assert_eq!(git(&["-C", &dir.join("shallow").to_string_lossy(), "rev-list", "--count", "HEAD"]).unwrap(), "1");
+        assert!(clone(&dir.join("missing"), &origin_path, &Some("v9".to_string()), false).is_err());
+
+        let schema = FSchema::from_str(&format!(r#"{{ "root": {{ "repo": ["{}", {{"ftype": "Git", "ref": "v1"}}] }} }}"#, origin_path)).unwrap();
+        let root = dir.join("out");
+        schema.create(root.clone()).unwrap();
+        fs::write(root.join("repo/local"), "mine").unwrap();
+        schema.create_with(root.clone(), &CreateOptions { overwrite: OverwritePolicy::Error, ..Default::default() }).unwrap();
+        assert_eq!(fs::read_to_string(root.join("repo/local")).unwrap(), "mine");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn bare() {
+        let dir = std::env::temp_dir().join(format!("fschema-git-bare-{}", std::process::id()));
+        let work = repository(&dir.join("work"));
+        let bare = dir.join("origin.git").to_string_lossy().to_string();
+        git(&["clone", "--quiet", "--bare", &work, &bare]).unwrap();
+        let first = git(&["-C", &bare, "rev-parse", "v1^{commit}"]).unwrap();
+        git(&["-C", &bare, "branch", "release", "v1"]).unwrap();
+
+        for (name, git_ref, shallow) in [("tag", "v1", false), ("branch", "release", false), ("commit", first.as_str(), false), ("shallow", "release", true)] {
+            let path = dir.join(name);
+            clone(&path, &bare, &Some(git_ref.to_string()), shallow).unwrap();
+            assert_eq!(fs::read_to_string(path.join("version")).unwrap(), "1", "{}", name);
+            assert_eq!(git(&["-C", &path.to_string_lossy(), "rev-parse", "HEAD"]).unwrap(), first, "{}", name);
+            assert!(is_clone(&path, &bare), "{}", name);
+        }
+
+        let schema = FSchema::from_str(&format!(r#"{{ "root": {{
+            "head": ["{0}", {{"ftype": "Git"}}],
+            "pinned": ["{0}", {{"ftype": "Git", "ref": "{1}"}}]
+        }} }}"#, bare, first)).unwrap();
+        let root = dir.join("out");
+        schema.create(root.clone()).unwrap();
+        assert_eq!(fs::read_to_string(root.join("head/version")).unwrap(), "2");
+        assert_eq!(fs::read_to_string(root.join("pinned/version")).unwrap(), "1");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,185 @@
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+};
+
+use crate::{mode::mode_of, special::{special, Special}, DirectoryOptions, Error, FSchema, FileOptions, FileType, Node};
+
+#[derive(Debug, Clone)]
+/// Options used when importing an existing directory into a schema
+pub struct ImportOptions {
+    /// Largest text file (in bytes) that will be inlined into the schema. Larger or binary files are referenced as copies
+    pub inline_limit: u64,
+    /// Record the permissions of imported files
+    pub modes: bool,
+}
+
+impl Default for ImportOptions {
+    fn default() -> Self {
+        ImportOptions { inline_limit: 64 * 1024, modes: true }
+    }
+}
+
+impl FSchema {
+    /// Create a schema from an existing directory. The contents of the directory become the schema's root
+    pub fn from_dir(path: &Path, options: &ImportOptions) -> Result<FSchema, Error> {
+        let path = fs::canonicalize(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        let (root, root_ord) = import_dir(&path, options)?;
+
+        Ok(FSchema { root, root_ord, ..Default::default() })
+    }
+}
+
+/// Import the contents of a directory
+fn import_dir(path: &Path, options: &ImportOptions) -> Result<(HashMap<String, Node>, Vec<String>), Error> {
+    let mut entries = fs::read_dir(path)
+        .and_then(|entries| entries.collect::<Result<Vec<_>, _>>())
+        .map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+    entries.sort_by_key(|entry| entry.file_name());
+
+    let mut contents = HashMap::new();
+    let mut ord = vec![];
+    for entry in entries {
+        // names are always expanded, so references to variables are escaped
+        let name = entry.file_name().to_string_lossy().replace("${", "$${");
+        if let Some(node) = import_entry(&entry.path(), options)? {
+            contents.insert(name.clone(), node);
+            ord.push(name);
+        }
+    }
+
+    Ok((contents, ord))
+}
+
+/// Import a single directory entry. Entries that can't be represented in a schema (sockets) are skipped
+fn import_entry(path: &Path, options: &ImportOptions) -> Result<Option<Node>, Error> {
+    let metadata = fs::symlink_metadata(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+    let mode = if options.modes {
+        Some(mode_of(&metadata))
+    } else {
+        None
+    };
+
+    if metadata.is_dir() {
+        let (contents, ord) = import_dir(path, options)?;
+        Ok(Some(Node::Directory { contents, ord, options: DirectoryOptions { mode, ..Default::default() } }))
+    } else if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?.to_string_lossy().to_string();
+        Ok(Some(Node::File {
+            options: FileOptions { ftype: FileType::Link, raw: target.contains("${"), ..Default::default() },
+            data: target,
+        }))
+    } else if metadata.is_file() {
+        let inlined = if metadata.len() <= options.inline_limit {
+            fs::read(path)
+                .map_err(|e| Error::IO(e, format!("{:?}", path)))
+                .map(|data| String::from_utf8(data).ok())?
+        } else {
+            None
+        };
+
+        let (data, ftype) = match inlined {
+            Some(data) => (data, FileType::Text),
+            None => (path.to_string_lossy().to_string(), FileType::Copy),
+        };
+        Ok(Some(Node::File { options: FileOptions { ftype, mode, raw: data.contains("${"), ..Default::default() }, data }))
+    } else {
+        let device = |kind, major, minor| Some(Node::File {
+            data: format!("{} {} {}", kind, major, minor),
+            options: FileOptions { ftype: FileType::Device, mode, ..Default::default() },
+        });
+        match special(&metadata) {
+            Some(Special::Fifo) => Ok(Some(Node::File { data: String::new(), options: FileOptions { ftype: FileType::Fifo, mode, ..Default::default() } })),
+            Some(Special::Character(major, minor)) => Ok(device("c", major, minor)),
+            Some(Special::Block(major, minor)) => Ok(device("b", major, minor)),
+            None => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::{mode::{effective_mode, mode_of, set_mode}, special::symlink, FSchema, FileType, Format, Node};
+
+    use super::ImportOptions;
+
+    /// Create a directory holding inlined, copied, executable and linked files
+    fn source(dir: &Path) {
+        fs::create_dir_all(dir.join("bin")).unwrap();
+        fs::write(dir.join("readme"), "hello ${name}\n").unwrap();
+        fs::write(dir.join("logo"), [0, 159, 146, 150]).unwrap();
+        fs::write(dir.join("big"), "x".repeat(100)).unwrap();
+        fs::write(dir.join("bin/run"), "#!/bin/sh\n").unwrap();
+        set_mode(&dir.join("bin/run"), 0o755).unwrap();
+        symlink("bin/run", dir.join("current")).unwrap();
+    }
+
+    /// Paths inside a directory, relative to it and sorted
+    fn entries(dir: &Path) -> Vec<String> {
+        let mut entries = vec![];
+        let mut stack = vec![dir.to_path_buf()];
+        while let Some(path) = stack.pop() {
+            for entry in fs::read_dir(&path).unwrap() {
+                let entry = entry.unwrap().path();
+                if entry.is_dir() && !entry.is_symlink() {
+                    stack.push(entry.clone());
+                }
+                entries.push(entry.strip_prefix(dir).unwrap().to_string_lossy().replace('\\', "/"));
+            }
+        }
+        entries.sort();
+        entries
+    }
+
+    #[test]
+    fn test() {
+        let dir = std::env::temp_dir().join(format!("fschema-import-{}", std::process::id()));
+        let source_dir = dir.join("source");
+        source(&source_dir);
+
+        let schema = FSchema::from_dir(&source_dir, &ImportOptions { inline_limit: 64, ..Default::default() }).unwrap();
+        let ftype = |path: &str| schema.get(path).and_then(Node::file_options).map(|options| options.get_ftype());
+        assert_eq!(schema.walk().map(|(path, _)| path.to_string_lossy().replace('\\', "/")).collect::<Vec<_>>(), [
+            "big", "bin", "bin/run", "current", "logo", "readme",
+        ]);
+        assert!(matches!(ftype("readme"), Some(FileType::Text)));
+        assert!(matches!((ftype("logo"), ftype("big")), (Some(FileType::Copy), Some(FileType::Copy))));
+        assert!(matches!(ftype("current"), Some(FileType::Link)));
+        assert_eq!(schema.get("bin/run").and_then(Node::file_options).and_then(|options| options.get_mode()), Some(effective_mode(0o755)));
+
+        let schema = FSchema::from_dir(&source_dir, &ImportOptions { modes: false, ..Default::default() }).unwrap();
+        assert!(schema.walk().all(|(_, node)| node.file_options().is_none_or(|options| options.get_mode().is_none())));
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn round_trip() {
+        let dir = std::env::temp_dir().join(format!("fschema-import-round-trip-{}", std::process::id()));
+        let source_dir = dir.join("source");
+        source(&source_dir);
+
+        let imported = FSchema::from_dir(&source_dir, &ImportOptions { inline_limit: 64, ..Default::default() }).unwrap();
+        let schema = FSchema::from_str_format(&imported.to_string_format(Format::Json).unwrap(), Format::Json).unwrap();
+        let copy = dir.join("copy");
+        schema.create(copy.clone()).unwrap();
+
+        assert_eq!(entries(&copy), entries(&source_dir));
+        for name in entries(&source_dir) {
+            let (original, created) = (source_dir.join(&name), copy.join(&name));
+            let (original_metadata, created_metadata) = (fs::symlink_metadata(&original).unwrap(), fs::symlink_metadata(&created).unwrap());
+            assert_eq!(created_metadata.file_type(), original_metadata.file_type(), "{}", name);
+            if original_metadata.is_symlink() {
+                assert_eq!(fs::read_link(&created).unwrap(), fs::read_link(&original).unwrap(), "{}", name);
+            } else if original_metadata.is_file() {
+                assert_eq!(fs::read(&created).unwrap(), fs::read(&original).unwrap(), "{}", name);
+                assert_eq!(mode_of(&created_metadata), mode_of(&original_metadata), "{}", name);
+            }
+        }
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,266 @@
+use std::{collections::BTreeMap, env, path::Path};
+
+use regex::{Captures, NoExpand, Regex};
+
+use crate::{Error, FSchema};
+
+impl FSchema {
+    /// Copy of the schema with its variables overridden, or added to, by vars
+    pub fn with_vars<K, V>(&self, vars: impl IntoIterator<Item = (K, V)>) -> FSchema
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        let mut schema = self.clone();
+        schema.overrides.extend(vars.into_iter().map(|(name, value)| (name.into(), value.into())));
+        schema
+    }
+
+    /// Values of the schema's variables. Those loaded from .env files are overridden by its "variables", which are overridden
+    /// by its "env", and the variables set with with_vars or by answering its prompts take precedence over all of them
+    pub(crate) fn variables(&self) -> BTreeMap<String, String> {
+        self.loaded_env
+            .iter()
+            .chain(&self.variables)
+            .chain(&self.env)
+            .chain(&self.overrides)
+            .map(|(name, value)| (name.clone(), value.clone()))
+            .collect()
+    }
+
+    /// Create the file system structure at root with the schema's variables overridden, or added to, by vars
+    pub fn create_with_vars<K, V>(&self, root: std::path::PathBuf, options: &crate::CreateOptions, vars: impl IntoIterator<Item = (K, V)>) -> Result<(), Error>
+    where
+        K: Into<String>,
+        V: Into<String>,
+    {
+        self.with_vars(vars).create_with(root, options)
+    }
+}
+
+/// Substitute references to schema variables in text, e.g. "{{name}}" or "{{ name }}". References to names that aren't
+/// variables are left as they are, so text like templates using the same syntax is unchanged
+pub(crate) fn substitute(text: &str, variables: &BTreeMap<String, String>) -> String {
+    if variables.is_empty() || !text.contains("{{") {
+        return text.to_string();
+    }
+
+    let mut substituted = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("{{") {
+        substituted.push_str(&rest[..start]);
+        let reference = &rest[start + 2..];
+        match reference.find("}}").and_then(|end| variables.get(reference[..end].trim()).map(|value| (end, value))) {
+            Some((end, value)) => {
+                substituted.push_str(value);
+                rest = &reference[end + 2..];
+            },
+            None => {
+                substituted.push_str("{{");
+                rest = reference;
+            },
+        }
+    }
+    substituted.push_str(rest);
+    substituted
+}
+
+/// Substitute the root the file system structure is created at into a command, written "$ROOT" or "${ROOT}"
+pub(crate) fn substitute_root(text: &str, root: &Path) -> String {
+    thread_local! {
+        static ROOT: Regex = Regex::new(r"\$(?:ROOT\b|\{ROOT\})").expect("root pattern is valid");
+    }
+    if !text.contains("ROOT") {
+        return text.to_string();
+    }
+    ROOT.with(|pattern| pattern.replace_all(text, NoExpand(&root.to_string_lossy())).to_string())
+}
+
+/// Expand references to environment variables in text, e.g. "${HOME}/.config" or "${PORT:-8080}", which falls back to a default
+/// when the variable isn't set. "$${" is written as a literal "${". XDG base directories that aren't set have their default values, 
+/// e.g. "${XDG_CONFIG_HOME}" is "~/.config". References to other variables that aren't set are an error
+pub(crate) fn expand(text: &str) -> Result<String, Error> {
+    expand_with(text, lookup)
+}
+
+/// Value of an environment variable, or the default of an XDG base directory if it isn't set or is empty
+fn lookup(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.is_empty() || !name.starts_with("XDG_")).or_else(|| {
+        let home = env::var("HOME").ok()?;
+        match name {
+            "XDG_CONFIG_HOME" => Some(home + "/.config"),
+            "XDG_DATA_HOME" => Some(home + "/.local/share"),
+            "XDG_STATE_HOME" => Some(home + "/.local/state"),
+            "XDG_CACHE_HOME" => Some(home + "/.cache"),
+            "XDG_BIN_HOME" => Some(home + "/.local/bin"),
+            _ => None,
+        }
+    })
+}
+
+/// Expand the home directory and XDG base directories in a path, written "~", "$HOME" or e.g. "$XDG_CONFIG_HOME", as well as 
+/// the references "${...}" expands. "~" is only expanded at the start of the path
+pub fn expand_home(path: &str) -> Result<String, Error> {
+    thread_local! {
+        static UNBRACED: Regex = Regex::new(r"\$(HOME|XDG_[A-Z_]+)\b").expect("variable pattern is valid");
+    }
+    let path = match path.strip_prefix('~') {
+        Some(rest) if rest.is_empty() || rest.starts_with('/') => lookup("HOME").ok_or_else(|| Error::UnsetVariable("HOME".to_string()))? + rest,
+        _ => path.to_string(),
+    };
+    let path = UNBRACED.with(|unbraced| {
+        let mut unset = None;
+        let path = unbraced.replace_all(&path, |captures: &Captures| lookup(&captures[1]).unwrap_or_else(|| {
+            unset = Some(captures[1].to_string());
+            String::new()
+        }));
+        match unset {
+            Some(name) => Err(Error::UnsetVariable(name)),
+            None => Ok(path.to_string()),
+        }
+    })?;
+    expand(&path)
+}
+
+/// Whether a name is a plain identifier, e.g. "HOME" or "_port", rather than a shell expansion like "1%.txt" or "#x"
+fn is_identifier(name: &str) -> bool {
+    name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+/// Expand references to variables in text, looking up their values with lookup. References that aren't to plain identifiers,
+/// e.g. a shell's "${1%.txt}" or "${#x}", are left as they are
+fn expand_with<F>(text: &str, lookup: F) -> Result<String, Error>
+where
+    F: Fn(&str) -> Option<String>
+{
+    if !text.contains("${") {
+        return Ok(text.to_string());
+    }
+
+    let mut expanded = String::with_capacity(text.len());
+    let mut rest = text;
+    while let Some(start) = rest.find("${") {
+        if rest[..start].ends_with('$') {
+            expanded.push_str(&rest[..start - 1]);
+            expanded.push_str("${");
+            rest = &rest[start + 2..];
+            continue;
+        }
+        expanded.push_str(&rest[..start]);
+
+        let reference = &rest[start + 2..];
+        let end = reference.find('}').ok_or_else(|| Error::InvalidVariable(format!("${{{}", reference)))?;
+        let (name, default) = match reference[..end].split_once(":-") {
+            Some((name, default)) => (name, Some(default)),
+            None => (&reference[..end], None),
+        };
+        if !is_identifier(name) {
+            expanded.push_str("${");
+            rest = reference;
+            continue;
+        }
+        match (lookup(name), default) {
+            (Some(value), _) => expanded.push_str(&value),
+            (None, Some(default)) => expanded.push_str(default),
+            (None, None) => return Err(Error::UnsetVariable(name.to_string())),
+        }
+        rest = &reference[end + 1..];
+    }
+    expanded.push_str(rest);
+    Ok(expanded)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::FSchema;
+
+    use super::{expand_home, expand_with, substitute};
+
+    #[test]
+    fn test() {
+        let lookup = |name: &str| (name == "HOME").then(|| "/home/alice".to_string());
+        assert_eq!(expand_with("${HOME}/.config", lookup).unwrap(), "/home/alice/.config");
+        assert_eq!(expand_with("port ${PORT:-8080}, home ${HOME:-/}", lookup).unwrap(), "port 8080, home /home/alice");
+        assert_eq!(expand_with("echo $${HOME} $HOME $", lookup).unwrap(), "echo ${HOME} $HOME $");
+        assert!(expand_with("${USER}", lookup).is_err());
+        assert!(expand_with("${HOME", lookup).is_err());
+        assert_eq!(expand_with("${a b} ${1%.txt} ${#HOME} ${HOME}", lookup).unwrap(), "${a b} ${1%.txt} ${#HOME} /home/alice");
+
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(expand_home("~/.bashrc").unwrap(), format!("{}/.bashrc", home));
+        assert_eq!(expand_home("$HOME/a~").unwrap(), format!("{}/a~", home));
+        assert_eq!(expand_home("~user").unwrap(), "~user");
+        if std::env::var("XDG_CACHE_HOME").is_err() {
+            assert_eq!(expand_home("$XDG_CACHE_HOME/app").unwrap(), format!("{}/.cache/app", home));
+            assert_eq!(expand_home("${XDG_CACHE_HOME}").unwrap(), format!("{}/.cache", home));
+        }
+        assert!(expand_home("$XDG_UNKNOWN_DIR").is_err());
+
+        let variables = BTreeMap::from([("name".to_string(), "app".to_string())]);
+        assert_eq!(substitute("{{name}}/{{ name }}.conf", &variables), "app/app.conf");
+        assert_eq!(substitute("${{ github.ref }} {{other}} {{name", &variables), "${{ github.ref }} {{other}} {{name");
+    }
+
+    #[test]
+    fn shell() {
+        let root = std::env::temp_dir().join(format!("fschema-interpolate-shell-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": {
+            "piped": ["for f in a b; do echo ${f}; done", {"ftype": "Piped"}],
+            "script": ["name=${1%.txt} length=${#name}", {"ftype": "Text"}]
+        } }"#).unwrap();
+
+        schema.create(root.clone()).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("piped")).unwrap(), "a\nb\n");
+        assert_eq!(std::fs::read_to_string(root.join("script")).unwrap(), "name=${1%.txt} length=${#name}");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn data() {
+        let root = std::env::temp_dir().join(format!("fschema-interpolate-data-{}", std::process::id()));
+        let home = std::env::var("HOME").unwrap();
+        let schema = FSchema::from_str(r#"{
+            "variables": { "name": "${HOME}" },
+            "root": {
+                "plain": ["home = ${HOME}, name = {{name}}", {"ftype": "Text"}],
+                "expanded": ["home = ${HOME}, name = {{name}}, port = ${PORT:-8080}, $${HOME}", {"ftype": "Text", "expand": true}],
+                "conf": {
+                    ".": { "defaultFileOptions": { "expand": true } },
+                    "inherited": ["${HOME}", {"ftype": "Text"}]
+                }
+            }
+        }"#).unwrap();
+
+        schema.create(root.clone()).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("plain")).unwrap(), "home = ${HOME}, name = ${HOME}");
+        assert_eq!(std::fs::read_to_string(root.join("expanded")).unwrap(), format!("home = {}, name = ${{HOME}}, port = 8080, ${{HOME}}", home));
+        assert_eq!(std::fs::read_to_string(root.join("conf/inherited")).unwrap(), home);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn precedence() {
+        let dir = std::env::temp_dir().join(format!("fschema-interpolate-precedence-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join(".env"), "A=loaded\nB=loaded\nC=loaded\nD=loaded\nE=loaded\n").unwrap();
+        let schema = FSchema::from_str(r#"{
+            "variables": { "A": "variables", "B": "variables", "C": "variables" },
+            "env": { "B": "env", "C": "env" },
+            "root": {
+                "vars": ["{{A}} {{B}} {{C}} {{D}}", {"ftype": "Text"}],
+                "env": ["echo \"$B $D $E\"", {"ftype": "Piped"}]
+            }
+        }"#).unwrap();
+        let schema = schema.with_env_file(&dir.join(".env")).unwrap().with_vars([("C", "override")]);
+
+        schema.create_with(dir.join("out"), &crate::CreateOptions { quiet: true, ..Default::default() }).unwrap();
+        assert_eq!(std::fs::read_to_string(dir.join("out/vars")).unwrap(), "variables env override loaded");
+        assert_eq!(std::fs::read_to_string(dir.join("out/env")).unwrap(), "env loaded loaded\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
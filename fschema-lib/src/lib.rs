@@ -1,63 +1,215 @@
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{BTreeMap, HashMap},
     fmt::Display,
-    fs::{self, File},
-    io,
-    os::unix::{self, prelude::PermissionsExt},
-    path::PathBuf,
-    process::Command, str::FromStr,
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::SystemTime,
 };
 
-use itertools::Itertools;
 use serde::{Deserialize, Serialize};
 
+use command::Step;
+use owner::Ownership;
+
 pub mod parse;
+mod archive;
+mod attributes;
+mod builder;
+mod audit;
+#[cfg(feature = "tokio")]
+mod asynchronous;
+mod cache;
+mod checksums;
+mod clean;
+mod command;
+mod compress;
+mod condition;
+mod dataset;
+mod copy;
+mod diff;
+mod download;
+mod edit;
+mod env_file;
+mod functions;
+mod git;
+mod import;
+mod interpolate;
+mod manifest;
+mod merge;
+mod mode;
+mod node;
+mod options;
+mod overlay;
+mod owner;
+mod patch;
+mod plan;
+mod policy;
+mod prompt;
+mod rollback;
+mod run_as;
+mod secret;
+mod special;
+mod stage;
+mod times;
+mod visit;
+mod walk;
+
+pub use audit::Audited;
+pub use builder::SchemaBuilder;
+pub use cache::Cache;
+pub use command::Command;
+pub use diff::{DiffOptions, Drift};
+pub use import::ImportOptions;
+pub use interpolate::expand_home;
+pub use manifest::{EntryKind, Manifest, ManifestEntry};
+pub use plan::{Operation, Plan};
+pub use policy::Policy;
+pub use prompt::Prompt;
+pub use visit::{NodeTransformer, NodeVisitor, Transform};
+pub use walk::Walk;
 
 #[derive(Debug)]
 /// FSchema Errors
 pub enum Error {
     /// An IO error occurred
     IO(io::Error, String),
-    /// An Error occurred whilst running a command
-    Command(i32, String),
+    /// An Error occurred whilst running a command, with its exit code, the command, and the end of what it wrote to stderr
+    Command(i32, String, String),
     /// An Error occurred converting a string to a path
     Path(std::convert::Infallible, String),
+    /// An Error occurred, then rolling back what had been created also failed
+    Rollback(Box<Error>, Box<Error>),
+    /// A file already exists
+    Exists(PathBuf),
+    /// A file depends on a path that isn't part of the schema
+    UnknownDependency(String, String),
+    /// Files depend on each other in a cycle
+    DependencyCycle(Vec<String>),
+    /// A user isn't in the users database
+    UnknownUser(String),
+    /// A group isn't in the groups database
+    UnknownGroup(String),
+    /// A file's contents don't have the expected sha256 hash. Holds the path, expected hash and actual hash
+    Checksum(PathBuf, String, String),
+    /// A download isn't cached and downloading is disabled
+    Offline(String),
+    /// A command ran for longer than its timeout, in seconds, and was killed
+    Timeout(String, u64),
+    /// A Piped file's command wrote more than its file's size limit, in bytes, and was killed
+    OutputLimit(String, u64),
+    /// A variable referenced by the schema isn't set
+    UnsetVariable(String),
+    /// A reference to a variable is malformed, e.g. "${HOME"
+    InvalidVariable(String),
+    /// A node's "when" condition is malformed. Holds the condition and what's wrong with it
+    InvalidCondition(String, String),
+    /// A profile that isn't part of the schema was activated
+    UnknownProfile(String),
+    /// A call to a template function is malformed, e.g. "{{rand_hex()}}". Holds the call and what's wrong with it
+    InvalidFunction(String, String),
+    /// A prompt's answer isn't one of its choices. Holds the prompt's name, the answer and the choices
+    InvalidAnswer(String, String, Vec<String>),
+    /// A prompt without a default wasn't answered
+    Unanswered(String),
+    /// The schema runs a command and running commands is disabled
+    CommandsDisabled(String),
+    /// A command isn't allowed by the policy the schema is created with. Holds the command and why it isn't allowed
+    Disallowed(String, String),
 }
 
 impl Display for Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
             Error::IO(e, data) => f.write_fmt(format_args!("An IO error occurred with '{}': {}", data, e)),
-            Error::Command(exit, data) => f.write_fmt(format_args!("Command, '{}', exited with code {}", data, exit)),
+            Error::Command(exit, data, stderr) if stderr.is_empty() => f.write_fmt(format_args!("Command, '{}', exited with code {}", data, exit)),
+            Error::Command(exit, data, stderr) => f.write_fmt(format_args!("Command, '{}', exited with code {}:\n{}", data, exit, stderr)),
             Error::Path(e, data) => f.write_fmt(format_args!("Could not create path from '{}': {}", data, e)),
+            Error::Rollback(e, rollback) => f.write_fmt(format_args!("{}, then rolling back failed: {}", e, rollback)),
+            Error::Exists(path) => f.write_fmt(format_args!("{:?} already exists", path)),
+            Error::UnknownDependency(path, dependency) => f.write_fmt(format_args!("{:?} depends on {:?}, which isn't part of the schema", path, dependency)),
+            Error::DependencyCycle(cycle) => f.write_fmt(format_args!("Dependency cycle, {}", cycle.join(" -> "))),
+            Error::UnknownUser(user) => f.write_fmt(format_args!("No user named '{}' exists", user)),
+            Error::UnknownGroup(group) => f.write_fmt(format_args!("No group named '{}' exists", group)),
+            Error::Checksum(path, expected, actual) => f.write_fmt(format_args!("{:?} has sha256 {}, expected {}", path, actual, expected)),
+            Error::Offline(url) => f.write_fmt(format_args!("'{}' isn't in the download cache and downloading is disabled", url)),
+            Error::Timeout(command, timeout) => f.write_fmt(format_args!("Command, '{}', timed out after {} seconds", command, timeout)),
+            Error::OutputLimit(command, limit) => f.write_fmt(format_args!("Command, '{}', wrote more than {} bytes", command, limit)),
+            Error::UnsetVariable(name) => f.write_fmt(format_args!("Variable '{}' isn't set. Write $${{ for a literal ${{", name)),
+            Error::InvalidVariable(reference) => f.write_fmt(format_args!("Invalid variable reference '{}'. Write $${{ for a literal ${{", reference)),
+            Error::InvalidCondition(condition, e) => f.write_fmt(format_args!("Invalid condition '{}', {}", condition, e)),
+            Error::UnknownProfile(name) => f.write_fmt(format_args!("No profile named '{}' is part of the schema", name)),
+            Error::InvalidFunction(call, e) => f.write_fmt(format_args!("Invalid call '{}', {}", call, e)),
+            Error::InvalidAnswer(name, answer, choices) => f.write_fmt(format_args!("'{}' isn't an answer to prompt '{}', expected one of {}", answer, name, choices.join(", "))),
+            Error::Unanswered(name) => f.write_fmt(format_args!("Prompt '{}' wasn't answered and has no default", name)),
+            Error::CommandsDisabled(command) => f.write_fmt(format_args!("Command, '{}', wasn't run as running commands is disabled", command)),
+            Error::Disallowed(command, e) => f.write_fmt(format_args!("Command, '{}', isn't allowed by the policy as it {}", command, e)),
         }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 /// FSchema
 /// A file system structure schema. Used to create nested directories and files.
 pub struct FSchema {
     root: HashMap<String, Node>,
     root_ord: Vec<String>,
-    prebuild: Vec<String>,
-    postbuild: Vec<String>,
+    prebuild: Vec<Step>,
+    postbuild: Vec<Step>,
+    /// Commands run before each defer level
+    before_level: BTreeMap<i64, Vec<Command>>,
+    /// Commands run after each defer level
+    after_level: BTreeMap<i64, Vec<Command>>,
+    /// Umask applied to the permissions of everything created without a mode, instead of the invoking user's
+    umask: Option<u32>,
+    /// Variables referenced as {{name}} in file data, names and commands
+    variables: BTreeMap<String, String>,
+    /// Named schemas overlaid on the schema when they're activated, e.g. "dev" or "prod"
+    profiles: BTreeMap<String, FSchema>,
+    /// Questions whose answers set variables
+    prompts: Vec<Prompt>,
+    /// Seed of the template functions generating random values, so they generate the same values every time
+    seed: Option<u64>,
+    /// Shell commands are run with unless they give their own, e.g. "sh" or "pwsh". Bash if not given
+    shell: Option<String>,
+    /// Seconds commands, and Piped files' commands, can run for before they're killed, unless they give their own timeout
+    timeout: Option<u64>,
+    /// Environment variables given to every command, and Piped and Secret files' commands, which are also variables
+    env: BTreeMap<String, String>,
+    /// .env file the environment is loaded from, relative to the schema's directory
+    env_file: Option<String>,
+    /// Environment variables loaded from .env files, which variables and env take precedence over. Kept out of the serialized schema
+    loaded_env: BTreeMap<String, String>,
+    /// Variables set when the schema is used, e.g. with --var or by answering its prompts, taking precedence over its environment
+    overrides: BTreeMap<String, String>,
+    /// Files the schema was read from, its own file and those it includes, its data files and .env files. Kept out of the serialized schema
+    loaded: Vec<PathBuf>,
 }
 
 
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 /// Node in file system structure tree
+#[allow(clippy::large_enum_variant)]
 pub enum Node {
     File{data: String, options: FileOptions},
-    Directory{contents: HashMap<String, Node>, ord: Vec<String>},
+    Directory{contents: HashMap<String, Node>, ord: Vec<String>, options: DirectoryOptions},
     Comment(String),
+    /// Marker removing the node with its name when the schema is overlaid on another, written {"$delete": true}
+    Delete,
+    /// Reference to one of the schema's definitions, e.g. {"$ref": "#/definitions/logdir"}, and the arguments given to its parameters.
+    /// Replaced by a copy of the definition once the schema is parsed
+    Ref{reference: String, with: BTreeMap<String, String>},
+    /// Entry repeated for each item of a list variable, with the item replacing "{{each}}", or "{{each.field}}" for records, in its name 
+    /// and the node. Replaced by the entries once the schema is parsed
+    Foreach{list: String, each: String, node: Box<Node>},
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
 /// File Data Type
 pub enum FileType {
     /// Text
+    #[default]
     Text,
     /// Copy of existing file
     Copy,
@@ -69,175 +221,480 @@ pub enum FileType {
     Hex,
     /// Create from bits
     Bits,
+    /// Named pipe (FIFO). The data is ignored
+    Fifo,
+    /// Character or block device, from its type and major and minor numbers, e.g. "c 1 3". Only root can create devices
+    Device,
+    /// File of a size, e.g. "10G", filled with zeros. Created sparse unless preallocated
+    Size,
+    /// File of a size, e.g. "1M", filled with random data. Reproducible if seeded
+    Random,
+    /// Create from gzip compressed bytes, encoded as base64
+    GzipBase64,
+    /// Create from zstd compressed bytes, encoded as base64
+    ZstdBase64,
+    /// Download from a url with curl. Downloads are cached in ~/.cache/fschema, keyed by the url and sha256 option
+    Download,
+    /// Clone a git repository from a url into a directory, optionally at a ref given by the "ref" option
+    Git,
+    /// Extract a tar archive, compressed with gzip or zstd or not at all, or a zip archive, from a path or url into a directory
+    Archive,
+    /// Append text to a file, creating it if it doesn't exist. Text already in the file isn't appended again
+    Append,
+    /// Ensure a file has a line, replacing the last line matching the "regexp" option if one is given, like Ansible's lineinfile
+    LineInFile,
+    /// Ensure a file has a block of lines between marker comments, replacing the block if the markers already exist
+    BlockInFile,
+    /// Apply a unified diff to a file. A diff that has already been applied is skipped, and one that conflicts fails
+    Patch,
+    /// Deep merge a json, yaml, toml or ini fragment into a config file, keeping the keys it doesn't give
+    Merge,
+    /// Create an empty file, ignoring the file data, unless the file already exists. Existing contents are never overwritten
+    Touch,
+    /// Copy the files matching a glob, e.g. "assets/**/*.png", into a directory, keeping their paths relative to the glob's
+    /// leading directories without wildcards
+    CopyGlob,
+    /// Write the output of a command reading a secret, e.g. "pass show db/password". Created with mode 600 unless given one,
+    /// and the output is never printed and is zeroed in memory once written
+    Secret,
+    /// Decrypt age or GPG ciphertext in ASCII armor with the decryption key the schema is built with. Created like Secret files
+    Encrypted,
+    /// List the sha256 hashes of the files in the same directory matching glob patterns, one per line, like sha256sum's output,
+    /// e.g. a release's SHA256SUMS. Every file in the directory is listed if no patterns are given. Created once everything it
+    /// could list has been
+    Checksums,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Schema Formats
+pub enum Format {
+    /// JSON with comments (JSONC)
+    #[default]
+    Json,
+    #[cfg(feature = "ron")]
+    /// Rusty Object Notation
+    Ron,
+    #[cfg(feature = "yaml")]
+    /// YAML
+    Yaml,
+    #[cfg(feature = "toml")]
+    /// TOML
+    Toml,
+}
+
+impl Format {
+    /// Get the format associated with a file extension, if it is supported
+    pub fn from_extension(extension: &str) -> Option<Format> {
+        match extension.to_ascii_lowercase().as_str() {
+            "json" | "jsonc" => Some(Format::Json),
+            #[cfg(feature = "ron")]
+            "ron" => Some(Format::Ron),
+            #[cfg(feature = "yaml")]
+            "yaml" | "yml" => Some(Format::Yaml),
+            #[cfg(feature = "toml")]
+            "toml" => Some(Format::Toml),
+            _ => None,
+        }
+    }
+
+    /// Get the format of a file from its path's extension, if it is supported
+    pub fn from_path(path: &Path) -> Option<Format> {
+        path.extension()
+            .and_then(|ext| ext.to_str())
+            .and_then(Format::from_extension)
+    }
+
+    /// Name of the format, as it's given to from_extension
+    pub fn name(&self) -> &'static str {
+        match self {
+            Format::Json => "json",
+            #[cfg(feature = "ron")]
+            Format::Ron => "ron",
+            #[cfg(feature = "yaml")]
+            Format::Yaml => "yaml",
+            #[cfg(feature = "toml")]
+            Format::Toml => "toml",
+        }
+    }
+
+    /// Serialize a value into a pretty printed string in the format
+    pub(crate) fn to_string_pretty<T: Serialize>(self, value: &T) -> io::Result<String> {
+        match self {
+            Format::Json => Ok(serde_json::to_string_pretty(value)?),
+            #[cfg(feature = "ron")]
+            Format::Ron => ron::ser::to_string_pretty(value, ron::ser::PrettyConfig::default())
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            #[cfg(feature = "yaml")]
+            Format::Yaml => serde_yaml::to_string(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            #[cfg(feature = "toml")]
+            Format::Toml => toml::to_string_pretty(value).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Format::from_extension(s).ok_or_else(|| format!("unsupported schema format '{}'", s))
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// Order the entries of a directory are created in, within a defer level
+pub enum CreationOrder {
+    /// The order they are written in the schema
+    #[default]
+    Declared,
+    /// Alphabetical order of their names
+    Alphabetical,
+}
+
+impl FromStr for CreationOrder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "declared" => Ok(CreationOrder::Declared),
+            "alphabetical" => Ok(CreationOrder::Alphabetical),
+            _ => Err(format!("unsupported creation order '{}'", s)),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+/// What to do when a file being created already exists
+pub enum OverwritePolicy {
+    /// Fail with an error
+    Error,
+    /// Leave the existing file untouched. Edits, e.g. Append, are still applied
+    Skip,
+    /// Replace the existing file
+    #[default]
+    Overwrite,
+    /// Rename the existing file, appending '~' to its name, then create the file
+    Backup,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+/// Metadata of a Copy file's source kept by the copy, like cp -p
+pub enum Preserve {
+    /// Permissions
+    Mode,
+    /// Access and modification times
+    Times,
+    /// Owning user and group
+    Owner,
+}
+
+#[derive(Debug, Clone)]
+/// Options used when creating a file system structure
+pub struct CreateOptions {
+    /// Write a manifest of the created files and directories to this location
+    pub manifest: Option<PathBuf>,
+    /// Remove everything created so far if an error occurs. Entries that existed beforehand are restored as they were
+    pub rollback_on_error: bool,
+    /// Build in a temporary sibling of root and move it into place once complete, so a partially built root is never visible. 
+    /// Root must be empty or not exist yet
+    pub staged: bool,
+    /// Append progress to this location after each step, so a failed build can be resumed. Removed once the build succeeds
+    pub checkpoint: Option<PathBuf>,
+    /// Resume from the checkpoint, if it exists and was made for the same plan. 
+    /// Completed steps whose files are unchanged (by size, modification time and content hash) are skipped
+    pub resume: bool,
+    /// What to do when a file already exists, unless the file's options say otherwise
+    pub overwrite: OverwritePolicy,
+    /// Overwrite files that already exist, even those whose options give another overwrite policy
+    pub force: bool,
+    /// Remove entries inside the schema's directories that aren't part of the schema, so the directories match it exactly. 
+    /// Entries directly inside root are left, unless sync_root is set
+    pub sync: bool,
+    /// Also remove entries directly inside root that aren't part of the schema when syncing. Root is often a directory
+    /// holding other things too, e.g. the schema itself
+    pub sync_root: bool,
+    /// Glob patterns, relative to root, of entries that are never removed when syncing
+    pub exclude: Vec<String>,
+    /// Cache of node fingerprints, stored at this location. Files whose node hasn't changed since the last build, 
+    /// and that haven't been touched since, are skipped without running commands or reading sources
+    pub cache: Option<PathBuf>,
+    /// Maximum number of files, within the same defer level, created at once. Files are created one at a time when 0 or 1
+    pub parallelism: usize,
+    /// Order the entries of a directory are created in, within a defer level
+    pub ordering: CreationOrder,
+    /// Access and modification time given to every file and directory that doesn't set its own, e.g. from SOURCE_DATE_EPOCH
+    pub timestamp: Option<SystemTime>,
+    /// Never download, failing before anything is created if a download isn't already cached
+    pub offline: bool,
+    /// Key Encrypted files are decrypted with, an age identity file or a file holding a GPG passphrase.
+    /// GPG data is decrypted with the GPG keyring if it isn't given
+    pub decryption_key: Option<PathBuf>,
+    /// Run the schema's commands, its prebuild, postbuild and hook commands and the commands of Piped and Secret files.
+    /// When unset, a schema that runs any fails before anything is created
+    pub allow_commands: bool,
+    /// Programs the schema's commands may run. A schema running any others fails before any command runs
+    pub policy: Option<Policy>,
+    /// Don't pass the output of commands on as they run. What failing commands wrote to stderr is still in their errors
+    pub quiet: bool,
 }
 
-impl Default for FileType {
+impl Default for CreateOptions {
     fn default() -> Self {
-        FileType::Text
+        CreateOptions {
+            manifest: None,
+            rollback_on_error: false,
+            staged: false,
+            checkpoint: None,
+            resume: false,
+            overwrite: OverwritePolicy::default(),
+            force: false,
+            sync: false,
+            sync_root: false,
+            exclude: vec![],
+            cache: None,
+            parallelism: 0,
+            ordering: CreationOrder::default(),
+            timestamp: None,
+            offline: false,
+            decryption_key: None,
+            allow_commands: true,
+            policy: None,
+            quiet: false,
+        }
+    }
+}
+
+impl CreateOptions {
+    /// What to do when a file being created already exists. The file's own policy is used unless the build is forced
+    pub(crate) fn overwrite_policy(&self, options: &FileOptions) -> OverwritePolicy {
+        match self.force {
+            true => OverwritePolicy::Overwrite,
+            false => options.overwrite.unwrap_or(self.overwrite),
+        }
     }
 }
 
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 /// File options
 pub struct FileOptions {
     /// Type of file data
     ftype: FileType,
     /// Permissions (octal)
     mode: Option<u32>,
-    /// At what stage should this file be created
-    defer: u64,
+    /// At what stage should this file be created. Negative stages are created before ordinary files
+    defer: i64,
     /// Is the path stored in the file data relative to the root of the file system structure
     internal: bool,
+    /// What to do if the file already exists. Overrides the policy the schema is created with
+    overwrite: Option<OverwritePolicy>,
+    /// Paths, relative to root, of files or directories that must be created before this file
+    depends_on: Vec<String>,
+    /// User and group that own the file
+    ownership: Ownership,
+    /// Access time
+    accessed: Option<SystemTime>,
+    /// Modification time
+    modified: Option<SystemTime>,
+    /// Extended attributes, by name
+    xattrs: BTreeMap<String, String>,
+    /// POSIX ACL entries, e.g. "user:alice:rwx"
+    acl: Vec<String>,
+    /// Linux file capabilities, e.g. "cap_net_bind_service=+ep"
+    capabilities: Option<String>,
+    /// File attribute flags, written like chattr, e.g. "i" for immutable. Set once everything has been created
+    attrs: Option<String>,
+    /// Allocate the space of Size files on disk, instead of creating them sparse
+    preallocate: bool,
+    /// Seed of the data of Random files, so it's the same every time
+    seed: Option<u64>,
+    /// Expected sha256 hash of the file's contents, checked once it's created
+    sha256: Option<String>,
+    /// Branch, tag or commit Git files check out
+    git_ref: Option<String>,
+    /// Only fetch the latest commit of Git files
+    shallow: bool,
+    /// Number of leading path components removed from the members of Archive files
+    strip_components: usize,
+    /// Glob patterns of the members of Archive files to extract. Everything is extracted if empty
+    include: Vec<String>,
+    /// Glob patterns of the members of Archive files never extracted
+    exclude: Vec<String>,
+    /// Regular expression matching the line LineInFile files replace
+    regexp: Option<String>,
+    /// Marker lines around the block of BlockInFile files, with {mark} replaced by BEGIN or END
+    marker: Option<String>,
+    /// Format structured data given in place of a file's text is written in
+    serialize_as: Option<Format>,
+    /// Metadata Copy files keep from their source, taking precedence over the file's own
+    preserve: Vec<Preserve>,
+    /// Leave the file's data as it is, without substituting variables or expanding references to environment variables
+    raw: bool,
+    /// Expand references to environment variables in the file's data, e.g. "${HOME}"
+    expand: bool,
+    /// Condition the file is only created if it holds, e.g. "os == 'linux' && feature_x"
+    when: Option<String>,
+    /// Platforms the file is only created on, e.g. "linux" or "macos". Created on every platform if empty
+    platform: Vec<String>,
+    /// Glob patterns of the hostnames or targets of the machines the file is only created on. Created on every machine if empty
+    hosts: Vec<String>,
+    /// Write the output Piped files' commands have when they fail, printing a warning, instead of failing the build
+    allow_failure: bool,
+    /// Seconds Piped files' commands can run for before they're killed and counted as failed
+    timeout: Option<u64>,
+    /// Times Piped files' commands are run again if they fail
+    retries: u32,
+    /// Shell Piped and Secret files' commands are run with, e.g. "sh" or "pwsh". The schema's shell if not given
+    shell: Option<String>,
+    /// Environment variables set for Piped files' commands, on top of FSCHEMA_ROOT and FSCHEMA_PATH
+    env: BTreeMap<String, String>,
+    /// File Piped files' commands read their input from, e.g. a file of the schema to minify. Relative paths are files of the
+    /// schema, relative to the root, created before the file, and absolute paths are read as they are
+    stdin: Option<String>,
+    /// Largest output, in bytes, Piped files' commands can write before they're killed and counted as failed
+    max_size: Option<u64>,
+    /// Commands run once the file is created, e.g. "systemctl reload nginx"
+    on_create: Vec<Command>,
+    /// Commands run if creating the file fails, before the build fails
+    on_error: Vec<Command>,
+    /// User the file is created as, e.g. "root" for files in /etc. The file is built by the invoking user,
+    /// then installed into place as the user with sudo. Its Piped or Secret command runs as the user too
+    run_as: Option<String>,
+}
+
+#[derive(Debug, Default, Clone)]
+/// Directory options, given by a directory's "." entry
+pub struct DirectoryOptions {
+    /// At what stage should this directory, and everything inside it, be created. 
+    /// By default directories are created at stage 0, or earlier if something inside them has a negative stage
+    defer: Option<i64>,
+    /// Paths, relative to root, of files or directories that must be created before this directory
+    depends_on: Vec<String>,
+    /// Permissions (octal)
+    mode: Option<u32>,
+    /// User and group that own the directory
+    ownership: Ownership,
+    /// Access time, set once everything inside the directory has been created
+    accessed: Option<SystemTime>,
+    /// Modification time, set once everything inside the directory has been created
+    modified: Option<SystemTime>,
+    /// Extended attributes, by name
+    xattrs: BTreeMap<String, String>,
+    /// POSIX ACL entries, e.g. "user:alice:rwx"
+    acl: Vec<String>,
+    /// File attribute flags, written like chattr, e.g. "i" for immutable. Set once everything has been created
+    attrs: Option<String>,
+    /// Mode given to everything inside the directory that doesn't set its own
+    default_mode: Option<String>,
+    /// Options files inside the directory inherit, unless they override them
+    default_file_options: Option<Box<FileOptions>>,
+    /// Umask applied to the permissions of everything inside the directory created without a mode
+    umask: Option<u32>,
+    /// Condition the directory, and everything inside it, is only created if it holds
+    when: Option<String>,
+    /// Platforms the directory, and everything inside it, is only created on. Created on every platform if empty
+    platform: Vec<String>,
+    /// Glob patterns of the hostnames or targets of the machines the directory, and everything inside it, is only created on
+    hosts: Vec<String>,
+    /// Commands run once the directory is created, before anything inside it
+    on_create: Vec<Command>,
+    /// Commands run if creating the directory fails, before the build fails
+    on_error: Vec<Command>,
 }
 
 impl FSchema {
-    /// Create from reader, Must implement io::Read.
+    /// Create from reader, Must implement io::Read. Comments and trailing commas are allowed (JSONC).
     pub fn from_reader<R>(reader: &mut R) -> io::Result<FSchema> 
     where
         R: io::Read
     {
-        Ok(serde_json::from_reader(reader)?)
+        let mut json = String::new();
+        reader.read_to_string(&mut json)?;
+        FSchema::from_str(&json)
     }
 
-    /// Create from string containing json
+    /// Create from string containing json. Comments and trailing commas are allowed (JSONC).
+    #[allow(clippy::should_implement_trait)]
     pub fn from_str(json: &str) -> io::Result<FSchema> {
-        Ok(serde_json::from_str(json)?)
+        Ok(serde_json::from_str(&parse::strip_jsonc(json))?)
     }
 
-    /// Create file system structure from schema. Takes the location of where to place root as an argument 
-    pub fn create(&self, root: PathBuf) -> Result<(), Error> {
+    /// Read a schema from a file, detecting its format from the file's extension if one isn't given. 
+    /// Files included with "$include" are read relative to the schema's directory
+    pub fn from_path(path: &Path, format: Option<Format>) -> io::Result<FSchema> {
+        let format = format.or_else(|| Format::from_path(path)).unwrap_or_default();
+        let mut reader = fs::File::open(path)?;
+        let (schema, loaded) = parse::with_schema_path(path, || FSchema::from_reader_format(&mut reader, format));
+        Ok(FSchema { loaded, ..schema? })
+    }
 
-        for command in &self.prebuild {
-            run(command)?;
-        }
+    /// Files the schema was read from: its own file, the files it includes, its data files and its .env files, including those
+    /// of the schemas overlaid on it. Empty for schemas that weren't read from a file
+    pub fn loaded_files(&self) -> &[PathBuf] {
+        &self.loaded
+    }
 
-        let mut stack = self
-            .root_ord
-            .iter()
-            .map(|name| (name.to_string(), &self.root[name]))
-            .collect::<VecDeque<(String, &Node)>>();
-        let mut backstack = VecDeque::new();
-        let mut defered = VecDeque::new();
-        let mut deferal_level = 0;
-
-        if !root.exists() {
-            fs::create_dir_all(&root).map_err(|e| Error::IO(e, format!("{:?}", root)))?;
-        }
+    /// Create from reader containing a schema in the given format, Must implement io::Read.
+    pub fn from_reader_format<R>(reader: &mut R, format: Format) -> io::Result<FSchema> 
+    where
+        R: io::Read
+    {
+        let mut schema = String::new();
+        reader.read_to_string(&mut schema)?;
+        FSchema::from_str_format(&schema, format)
+    }
 
-        while stack.len() != 0 {
-            while let Some((inner_path, node)) = stack.pop_front() {
-                let path = root.join(&inner_path);
-
-                match node {
-                    Node::File { data, options } => {
-                        if options.defer > deferal_level{
-                            defered.push_back((inner_path, node));
-                            continue;
-                        }
-                        
-                        match options.ftype {
-                            FileType::Text => if data.len() == 0 {
-                                File::create(&path).map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?;
-                            } else {
-                                fs::write(&path, data).map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?
-                            },
-                            FileType::Copy => fs::copy(resolve_data_path(data, options.internal, &root)?, &path)
-                                .map(|_| ())
-                                .map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?,
-                            FileType::Link => unix::fs::symlink(resolve_data_path(data, options.internal, &root)?, &path)
-                                    .map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?,
-                            FileType::Piped => fs::write(&path, &pipe(data)?)
-                                .map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?,
-                            FileType::Hex => fs::write(&path, data.chars()
-                                    .chunks(2)
-                                    .into_iter()
-                                    .map(|byte| u8::from_str_radix(&byte.collect::<String>(), 16).unwrap())
-                                    .collect::<Vec<u8>>()
-                                ).map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?,
-                            FileType::Bits => fs::write(&path, data.chars()
-                                    .chunks(8)
-                                    .into_iter()
-                                    .map(|byte| u8::from_str_radix(&byte.collect::<String>(), 2).unwrap())
-                                    .collect::<Vec<u8>>()
-                                ).map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?,
-                        }
-
-                        if let Some(mode) = options.mode {
-                            let f = File::options()
-                                .read(true)
-                                .write(true)
-                                .open(&path)
-                                .map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?;
-                            let metadata = f.metadata().map_err(|e| Error::IO(e, format!("{}: [{}, {:?}]", inner_path, data, options.ftype)))?;
-                            metadata.permissions().set_mode(mode);
-                        }
-                    }
-                    Node::Directory{contents, ord} => {
-                        fs::create_dir_all(&path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
-
-                        backstack.extend(
-                            ord
-                                .iter()
-                                .map(|name| (inner_path.to_string() + "/" + name, &contents[name])),
-                        );
-                    }
-                    Node::Comment(_) => (),
-                }
-            }
-
-            (stack, backstack) = (backstack, stack);
-            if stack.len() == 0 {
-                (stack, defered) = (defered, stack);
-                deferal_level += 1;
-            }
+    /// Create from string containing a schema in the given format
+    pub fn from_str_format(schema: &str, format: Format) -> io::Result<FSchema> {
+        match format {
+            Format::Json => FSchema::from_str(schema),
+            #[cfg(feature = "ron")]
+            Format::Ron => FSchema::from_ron_str(schema),
+            #[cfg(feature = "yaml")]
+            Format::Yaml => serde_yaml::from_str(schema).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
+            #[cfg(feature = "toml")]
+            Format::Toml => toml::from_str(schema).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e)),
         }
+    }
+
+    /// Serialize the schema into a string in the given format
+    pub fn to_string_format(&self, format: Format) -> io::Result<String> {
+        format.to_string_pretty(self)
+    }
+
+    #[cfg(feature = "ron")]
+    /// Create from ron reader, Must implement io::Read.
+    pub fn from_ron_reader<R>(reader: &mut R) -> io::Result<FSchema> 
+    where
+        R: io::Read
+    {
+        ron::de::from_reader(reader).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
 
-        for command in &self.postbuild {
-            run(command)?;
+    #[cfg(feature = "ron")]
+    /// Create from string containing ron
+    pub fn from_ron_str(ron: &str) -> io::Result<FSchema> {
+        ron::de::from_str(ron).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Create file system structure from schema. Takes the location of where to place root as an argument 
+    pub fn create(&self, root: PathBuf) -> Result<(), Error> {
+        self.plan(root)?.execute()
+    }
+
+    /// Create file system structure from schema with the given options. Takes the location of where to place root as an argument 
+    pub fn create_with(&self, root: PathBuf, options: &CreateOptions) -> Result<(), Error> {
+        if options.staged {
+            return self.create_staged(root, options);
         }
-        Ok(())
+        self.plan_with(root, options)?.execute_with(options)
     }
 }
 
 /// Resolve path stored in data string
-fn resolve_data_path(data: &str, internal: bool, root: &PathBuf) -> Result<PathBuf, Error> {
+fn resolve_data_path(data: &str, internal: bool, root: &Path) -> Result<PathBuf, Error> {
     if internal {
         Ok(root.join(data))
     } else {
         PathBuf::from_str(data).map_err(|e| Error::Path(e, data.to_string()))
     }
 }
-
-/// Run a command in bash
-fn run(command: &str) -> Result<(), Error> {
-    Command::new("bash")
-        .args(["-c", &command])
-        .spawn()
-        .map_err(|e| Error::IO(e, command.to_string()))
-        .and_then(|mut child| child.wait().map_err(|e| Error::IO(e, command.to_string())))
-        .and_then(|status| {
-            let status = status.code().unwrap_or(0);
-            if status == 0 {
-                Ok(())
-            } else {
-                Err(Error::Command(status, command.to_string()))
-            }
-        })
-}
-
-
-/// Capture the output of a command run in bash
-fn pipe(command: &str) -> Result<String, Error> {
-    Command::new("bash")
-        .args(["-c", &command])
-        .output()
-        .map_err(|e| Error::IO(e, command.to_string()))
-        .and_then(|output| {
-            let status = output.status.code().unwrap_or(0);
-            if status == 0 {
-                Ok(String::from_utf8_lossy(&output.stdout).to_string())
-            } else {
-                Err(Error::Command(status, command.to_string()))
-            }
-        })
-}
@@ -0,0 +1,509 @@
+use std::{
+    collections::HashMap,
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::{mode::mode_of, special::{special, Special}, Drift, Error, FileOptions, FileType, Operation};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+/// Kind of entry recorded in a manifest
+pub enum EntryKind {
+    /// A file
+    File,
+    /// A directory
+    Directory,
+    /// A symbolic link
+    Link,
+    /// A named pipe (FIFO)
+    Fifo,
+    /// A character or block device
+    Device,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A file, directory or link created from a schema
+pub struct ManifestEntry {
+    /// Path relative to the root of the file system structure
+    pub path: PathBuf,
+    /// Kind of entry
+    pub kind: EntryKind,
+    /// Sha256 hash of a file's contents. Size files, which only hold zeros, aren't hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sha256: Option<String>,
+    /// Target of a link
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub target: Option<PathBuf>,
+    /// Permissions
+    pub mode: u32,
+    /// Modification time (seconds since the unix epoch)
+    pub modified: u64,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// Record of what was created from a schema. Lets later operations manage only what fschema created
+pub struct Manifest {
+    /// When the file system structure was created (seconds since the unix epoch)
+    pub created: u64,
+    /// Created entries, in the order they were created
+    pub entries: Vec<ManifestEntry>,
+}
+
+impl Manifest {
+    /// Default file name of a manifest stored in the root of a file system structure
+    pub const DEFAULT_NAME: &'static str = ".fschema-manifest.json";
+    /// Default file name of a build checkpoint stored in the root of a file system structure
+    pub const CHECKPOINT_NAME: &'static str = ".fschema-checkpoint.json";
+
+    /// Create an empty manifest
+    pub fn new() -> Manifest {
+        Manifest { created: seconds(SystemTime::now()), entries: vec![] }
+    }
+
+    /// Load a manifest from a file
+    pub fn load(path: &Path) -> Result<Manifest, Error> {
+        let manifest = fs::read_to_string(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        serde_json::from_str(&manifest).map_err(|e| Error::IO(e.into(), format!("{:?}", path)))
+    }
+
+    /// Save the manifest to a file
+    pub fn save(&self, path: &Path) -> Result<(), Error> {
+        let manifest = serde_json::to_string_pretty(self).map_err(|e| Error::IO(e.into(), format!("{:?}", path)))?;
+        fs::write(path, manifest).map_err(|e| Error::IO(e, format!("{:?}", path)))
+    }
+
+    /// Find the entry for a path relative to the root
+    pub fn get(&self, path: &Path) -> Option<&ManifestEntry> {
+        self.entries.iter().find(|entry| entry.path == path)
+    }
+
+    /// Record the result of an operation that has been executed. Commands and the root itself aren't recorded. 
+    /// Only record operations whose entry didn't exist before they ran, as removing the manifest removes everything it records
+    pub fn record(&mut self, root: &Path, operation: &Operation) -> Result<(), Error> {
+        let path = match operation.path() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let relative = match path.strip_prefix(root) {
+            Ok(relative) if relative.as_os_str().is_empty() => return Ok(()),
+            Ok(relative) => relative.to_path_buf(),
+            Err(_) => path.clone(),
+        };
+
+        let entry = entry(path, relative, hashed(operation))?;
+        self.entries.retain(|existing| existing.path != entry.path);
+        self.entries.push(entry);
+        Ok(())
+    }
+
+    /// Check the entries of the manifest still match the file system structure at root
+    pub fn verify(&self, root: &Path) -> Result<Vec<Drift>, Error> {
+        let mut drift = vec![];
+        for recorded in &self.entries {
+            let path = root.join(&recorded.path);
+            let actual = match entry(&path, recorded.path.clone(), recorded.sha256.is_some()) {
+                Ok(actual) => actual,
+                Err(Error::IO(e, _)) if e.kind() == io::ErrorKind::NotFound => {
+                    drift.push(Drift::Missing(path));
+                    continue;
+                },
+                Err(e) => return Err(e),
+            };
+
+            if actual.kind != recorded.kind {
+                drift.push(Drift::Kind { path, expected: recorded.kind.name(), actual: actual.kind.name() });
+            } else if actual.sha256 != recorded.sha256 {
+                drift.push(Drift::Modified(path));
+            } else if let (Some(expected), Some(actual)) = (&recorded.target, actual.target) {
+                if *expected != actual {
+                    drift.push(Drift::Link { path, expected: expected.clone(), actual });
+                }
+            } else if actual.mode != recorded.mode {
+                drift.push(Drift::Mode { path, expected: recorded.mode, actual: actual.mode });
+            }
+        }
+        Ok(drift)
+    }
+
+    /// Remove the entries of the manifest from the file system structure at root, newest first. 
+    /// Files modified since they were recorded and directories that aren't empty are left in place. Returns the removed paths
+    pub fn remove(&self, root: &Path) -> Result<Vec<PathBuf>, Error> {
+        let mut removed = vec![];
+        for recorded in self.entries.iter().rev() {
+            let path = root.join(&recorded.path);
+            let actual = match entry(&path, recorded.path.clone(), recorded.sha256.is_some()) {
+                Ok(actual) => actual,
+                Err(Error::IO(e, _)) if e.kind() == io::ErrorKind::NotFound => continue,
+                Err(e) => return Err(e),
+            };
+            if actual.kind != recorded.kind || actual.sha256 != recorded.sha256 || actual.target != recorded.target {
+                continue;
+            }
+
+            match recorded.kind {
+                EntryKind::Directory => {
+                    let empty = fs::read_dir(&path)
+                        .map_err(|e| Error::IO(e, format!("{:?}", path)))?
+                        .next()
+                        .is_none();
+                    if !empty {
+                        continue;
+                    }
+                    fs::remove_dir(&path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+                },
+                EntryKind::File | EntryKind::Link | EntryKind::Fifo | EntryKind::Device => fs::remove_file(&path).map_err(|e| Error::IO(e, format!("{:?}", path)))?,
+            }
+            removed.push(path);
+        }
+        Ok(removed)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+/// Progress of a build, used to resume a build that failed part way through. Saved as lines of JSON: the fingerprint of the
+/// plan, then a line for each operation as it completes
+pub(crate) struct Checkpoint {
+    /// Hash of the plan being executed. A checkpoint is only used to resume the same plan
+    pub fingerprint: String,
+    /// Number of operations of the plan that completed
+    pub completed: usize,
+    /// What the completed operations created, by their index in the plan
+    pub stamps: HashMap<usize, Stamp>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// First line of a checkpoint
+struct Header {
+    fingerprint: String,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+/// Line of a checkpoint recording an operation that completed, and what it created
+struct Completed {
+    index: usize,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    stamp: Option<Stamp>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+/// What an entry was like when the operation creating it completed
+pub(crate) struct Stamp {
+    kind: EntryKind,
+    /// Size of a file
+    len: u64,
+    /// Modification time of a file (nanoseconds since the unix epoch)
+    modified: u64,
+    /// Sha256 hash of a file's contents. Size files, which only hold zeros, aren't hashed
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    sha256: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    target: Option<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Load a checkpoint from a file. A last line cut short by the build being interrupted is ignored
+    pub fn load(path: &Path) -> Result<Checkpoint, Error> {
+        let checkpoint = fs::read_to_string(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        let mut lines = checkpoint.lines();
+        let header: Header = serde_json::from_str(lines.next().unwrap_or_default()).map_err(|e| Error::IO(e.into(), format!("{:?}", path)))?;
+        let mut loaded = Checkpoint { fingerprint: header.fingerprint, ..Default::default() };
+        for completed in lines.map_while(|line| serde_json::from_str::<Completed>(line).ok()) {
+            loaded.completed = completed.index + 1;
+            if let Some(stamp) = completed.stamp {
+                loaded.stamps.insert(completed.index, stamp);
+            }
+        }
+        Ok(loaded)
+    }
+
+    /// Check whether an operation was completed and what it created is unchanged. 
+    /// Commands only need to have completed, files are compared by their size, modification time and hash
+    pub fn is_complete(&self, root: &Path, index: usize, operation: &Operation) -> bool {
+        if index >= self.completed {
+            return false;
+        }
+
+        let path = match operation.path() {
+            Some(path) => path,
+            None => return true,
+        };
+        if path == root {
+            return path.is_dir();
+        }
+
+        match (self.stamps.get(&index), stamp(path, hashed(operation))) {
+            (Some(recorded), Ok(actual)) => *recorded == actual,
+            _ => false,
+        }
+    }
+}
+
+/// Checkpoint being written as a build progresses. Each completed operation is appended, rather than rewriting the checkpoint
+pub(crate) struct Progress {
+    path: PathBuf,
+    file: fs::File,
+}
+
+impl Progress {
+    /// Start the checkpoint of a plan, replacing the checkpoint at path
+    pub fn start(path: &Path, fingerprint: &str) -> Result<Progress, Error> {
+        let mut file = fs::File::create(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        let header = serde_json::to_string(&Header { fingerprint: fingerprint.to_string() }).map_err(|e| Error::IO(e.into(), format!("{:?}", path)))?;
+        writeln!(file, "{}", header).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        Ok(Progress { path: path.to_path_buf(), file })
+    }
+
+    /// Record that an operation completed, with what it created
+    pub fn record(&mut self, index: usize, operation: &Operation) -> Result<(), Error> {
+        let stamp = match operation.path() {
+            Some(path) => Some(stamp(path, hashed(operation))?),
+            None => None,
+        };
+        let line = serde_json::to_string(&Completed { index, stamp }).map_err(|e| Error::IO(e.into(), format!("{:?}", self.path)))?;
+        writeln!(self.file, "{}", line).map_err(|e| Error::IO(e, format!("{:?}", self.path)))
+    }
+
+    /// Remove the checkpoint once the build has succeeded
+    pub fn finish(self) -> Result<(), Error> {
+        drop(self.file);
+        fs::remove_file(&self.path).map_err(|e| Error::IO(e, format!("{:?}", self.path)))
+    }
+}
+
+impl EntryKind {
+    /// Name of the kind of entry
+    pub fn name(&self) -> &'static str {
+        match self {
+            EntryKind::File => "file",
+            EntryKind::Directory => "directory",
+            EntryKind::Link => "link",
+            EntryKind::Fifo => "fifo",
+            EntryKind::Device => "device",
+        }
+    }
+}
+
+/// Are the contents of the file an operation creates hashed. Size files only hold zeros, and may be too large to read
+fn hashed(operation: &Operation) -> bool {
+    !matches!(operation, Operation::File { options: FileOptions { ftype: FileType::Size, .. }, .. })
+}
+
+/// Stamp what currently exists at path. Only the kind of directories is kept, as creating entries inside them changes their
+/// modification time. Files are only hashed if asked
+fn stamp(path: &Path, hash: bool) -> Result<Stamp, Error> {
+    let metadata = fs::symlink_metadata(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+    let (kind, target) = if metadata.file_type().is_symlink() {
+        (EntryKind::Link, Some(fs::read_link(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?))
+    } else if metadata.is_dir() {
+        return Ok(Stamp { kind: EntryKind::Directory, len: 0, modified: 0, sha256: None, target: None });
+    } else {
+        match special(&metadata) {
+            Some(Special::Fifo) => (EntryKind::Fifo, None),
+            Some(Special::Character(..) | Special::Block(..)) => (EntryKind::Device, None),
+            None => (EntryKind::File, None),
+        }
+    };
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+        .map(|duration| duration.as_nanos() as u64)
+        .unwrap_or_default();
+    let sha256 = match kind {
+        EntryKind::File if hash => Some(hash_file(path)?),
+        _ => None,
+    };
+    Ok(Stamp { kind, len: metadata.len(), modified, sha256, target })
+}
+
+/// Describe what currently exists at path. Files are only hashed if asked
+fn entry(path: &Path, relative: PathBuf, hash: bool) -> Result<ManifestEntry, Error> {
+    let metadata = fs::symlink_metadata(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+    let (kind, sha256, target) = if metadata.file_type().is_symlink() {
+        let target = fs::read_link(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        (EntryKind::Link, None, Some(target))
+    } else if metadata.is_dir() {
+        (EntryKind::Directory, None, None)
+    } else {
+        match special(&metadata) {
+            Some(Special::Fifo) => (EntryKind::Fifo, None, None),
+            Some(Special::Character(..) | Special::Block(..)) => (EntryKind::Device, None, None),
+            None => (EntryKind::File, hash.then(|| hash_file(path)).transpose()?, None),
+        }
+    };
+
+    Ok(ManifestEntry {
+        path: relative,
+        kind,
+        sha256,
+        target,
+        mode: mode_of(&metadata),
+        modified: metadata.modified().map(seconds).unwrap_or_default(),
+    })
+}
+
+/// Sha256 hash of a file's contents as a hex string
+pub(crate) fn hash_file(path: &Path) -> Result<String, Error> {
+    let mut file = fs::File::open(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+    hash_reader(&mut file).map_err(|e| Error::IO(e, format!("{:?}", path)))
+}
+
+/// Sha256 hash of everything read from a reader as a hex string
+pub(crate) fn hash_reader(reader: &mut dyn Read) -> io::Result<String> {
+    let mut hasher = Sha256::new();
+    let mut buffer = [0; 8192];
+    loop {
+        match reader.read(&mut buffer)? {
+            0 => break,
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+    Ok(hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// Check the contents of a file have the expected sha256 hash. Only regular files, or links to them, are checked
+pub(crate) fn verify_sha256(path: &Path, expected: &Option<String>) -> Result<(), Error> {
+    let expected = match expected {
+        Some(expected) if fs::metadata(path).map(|metadata| metadata.is_file()).unwrap_or(false) => expected,
+        _ => return Ok(()),
+    };
+
+    let actual = hash_file(path)?;
+    if actual != *expected {
+        return Err(Error::Checksum(path.to_path_buf(), expected.clone(), actual));
+    }
+    Ok(())
+}
+
+/// Sha256 hash of bytes as a hex string
+pub(crate) fn hash_bytes(bytes: &[u8]) -> String {
+    Sha256::digest(bytes).iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Seconds since the unix epoch
+fn seconds(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map(|duration| duration.as_secs()).unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use crate::{CreateOptions, Drift, FSchema, OverwritePolicy};
+
+    use super::{Checkpoint, Manifest};
+
+    #[test]
+    fn test() {
+        let root = std::env::temp_dir().join(format!("fschema-manifest-{}", std::process::id()));
+        fs::create_dir_all(root.join("etc")).unwrap();
+        fs::write(root.join("etc/config"), "port = 80\n").unwrap();
+        fs::write(root.join("etc/notes"), "mine\n").unwrap();
+        fs::write(root.join("etc/hosts"), "127.0.0.1 localhost\n").unwrap();
+        let schema = FSchema::from_str(r#"{ "root": { "etc": {
+            "config": ["port = 80\n"],
+            "notes": ["generated\n"],
+            "hosts": ["10.0.0.1 db\n", {"ftype": "Append"}],
+            "app": { "main.conf": ["debug = false\n"] }
+        } } }"#).unwrap();
+        let path = root.join(Manifest::DEFAULT_NAME);
+        let options = CreateOptions { manifest: Some(path.clone()), overwrite: OverwritePolicy::Skip, quiet: true, ..Default::default() };
+
+        schema.create_with(root.clone(), &options).unwrap();
+        let manifest = Manifest::load(&path).unwrap();
+        let recorded = manifest.entries.iter().map(|entry| entry.path.to_string_lossy().replace('\\', "/")).collect::<Vec<_>>();
+        assert_eq!(recorded, ["etc/app", "etc/app/main.conf"]);
+
+        schema.create_with(root.clone(), &options).unwrap();
+        let manifest = Manifest::load(&path).unwrap();
+        assert_eq!(manifest.entries.len(), 2);
+        assert!(manifest.verify(&root).unwrap().is_empty());
+        fs::write(root.join("etc/app/main.conf"), "debug = true\n").unwrap();
+        assert!(matches!(manifest.verify(&root).unwrap().as_slice(), [Drift::Modified(path)] if path.ends_with("main.conf")));
+        assert!(manifest.remove(&root).unwrap().is_empty());
+
+        fs::write(root.join("etc/app/main.conf"), "debug = false\n").unwrap();
+        assert_eq!(manifest.remove(&root).unwrap(), [root.join("etc/app/main.conf"), root.join("etc/app")]);
+        assert_eq!(fs::read_to_string(root.join("etc/config")).unwrap(), "port = 80\n");
+        assert_eq!(fs::read_to_string(root.join("etc/notes")).unwrap(), "mine\n");
+        assert_eq!(fs::read_to_string(root.join("etc/hosts")).unwrap(), "127.0.0.1 localhost\n10.0.0.1 db\n");
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn checkpoint() {
+        let dir = std::env::temp_dir().join(format!("fschema-checkpoint-{}", std::process::id()));
+        let root = dir.join("out");
+        let checkpoint = root.join(Manifest::CHECKPOINT_NAME);
+        let schema = FSchema::from_str(r#"{ "root": {
+            "config": ["port = 80\n"],
+            "first": ["echo run >> \"$ROOT/../runs\"; echo first", {"ftype": "Piped"}],
+            "second": ["test -f \"$ROOT/../ready\" && echo second", {"ftype": "Piped"}]
+        } }"#).unwrap();
+        fs::create_dir_all(&root).unwrap();
+        let options = CreateOptions { checkpoint: Some(checkpoint.clone()), quiet: true, ..Default::default() };
+
+        assert!(schema.create_with(root.clone(), &options).is_err());
+        assert!(Checkpoint::load(&checkpoint).unwrap().completed > 0);
+        assert!(!root.join("second").exists());
+
+        let modified = fs::metadata(root.join("config")).unwrap().modified().unwrap();
+        fs::write(root.join("config"), "port = 81\n").unwrap();
+        fs::File::options().write(true).open(root.join("config")).unwrap().set_modified(modified).unwrap();
+        fs::write(dir.join("ready"), "").unwrap();
+        schema.create_with(root.clone(), &CreateOptions { resume: true, ..options }).unwrap();
+        assert_eq!(fs::read_to_string(dir.join("runs")).unwrap(), "run\n");
+        assert_eq!(fs::read_to_string(root.join("second")).unwrap(), "second\n");
+        assert_eq!(fs::read_to_string(root.join("config")).unwrap(), "port = 80\n");
+        assert!(!checkpoint.exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn resume() {
+        let dir = std::env::temp_dir().join(format!("fschema-resume-{}", std::process::id()));
+        let root = dir.join("out");
+        let checkpoint = root.join(Manifest::CHECKPOINT_NAME);
+        let schema = |last: &str| FSchema::from_str(&r#"{ "root": {
+            "kept": ["echo kept >> \"$ROOT/../runs\"; echo kept", {"ftype": "Piped"}],
+            "edited": ["echo edited >> \"$ROOT/../runs\"; echo edited", {"ftype": "Piped"}],
+            "removed": ["echo removed >> \"$ROOT/../runs\"; echo removed", {"ftype": "Piped"}],
+            "last": ["LAST", {"ftype": "Piped", "defer": 1}]
+        } }"#.replace("LAST", last)).unwrap();
+        let runs = || fs::read_to_string(dir.join("runs")).unwrap().lines().map(str::to_string).collect::<Vec<_>>();
+        fs::create_dir_all(&root).unwrap();
+        let options = CreateOptions { checkpoint: Some(checkpoint.clone()), quiet: true, ..Default::default() };
+
+        let failing = schema("test -f \\\"$ROOT/../ready\\\" && echo last");
+        assert!(failing.create_with(root.clone(), &options).is_err());
+        assert_eq!(runs(), ["kept", "edited", "removed"]);
+        assert!(checkpoint.exists());
+
+        fs::write(dir.join("runs"), "").unwrap();
+        let modified = fs::metadata(root.join("edited")).unwrap().modified().unwrap();
+        fs::write(root.join("edited"), "EDITED\n").unwrap();
+        fs::File::options().write(true).open(root.join("edited")).unwrap().set_modified(modified).unwrap();
+        fs::remove_file(root.join("removed")).unwrap();
+        fs::write(dir.join("ready"), "").unwrap();
+        failing.create_with(root.clone(), &CreateOptions { resume: true, ..options.clone() }).unwrap();
+        assert_eq!(runs(), ["edited", "removed"]);
+        assert_eq!(fs::read_to_string(root.join("edited")).unwrap(), "edited\n");
+        assert_eq!(fs::read_to_string(root.join("last")).unwrap(), "last\n");
+        assert!(!checkpoint.exists());
+
+        fs::write(dir.join("runs"), "").unwrap();
+        assert!(schema("exit 1").create_with(root.clone(), &options).is_err());
+        fs::write(dir.join("runs"), "").unwrap();
+        schema("echo changed").create_with(root.clone(), &CreateOptions { resume: true, ..options }).unwrap();
+        assert_eq!(runs(), ["kept", "edited", "removed"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,221 @@
+use std::path::Path;
+
+use crate::FileOptions;
+
+/// Formats of config files a fragment can be merged into
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum ConfigFormat {
+    Json,
+    #[cfg(feature = "yaml")]
+    Yaml,
+    #[cfg(feature = "toml")]
+    Toml,
+    Ini,
+}
+
+/// Format of a config file, given by the "serializeAs" option or the file's extension
+fn config_format(path: &Path, options: &FileOptions) -> Result<ConfigFormat, String> {
+    let extension = match options.serialize_as {
+        Some(format) => format.name().to_string(),
+        None => path.extension().map(|extension| extension.to_string_lossy().to_ascii_lowercase()).unwrap_or_default(),
+    };
+    match extension.as_str() {
+        "json" | "jsonc" => Ok(ConfigFormat::Json),
+        #[cfg(feature = "yaml")]
+        "yaml" | "yml" => Ok(ConfigFormat::Yaml),
+        #[cfg(feature = "toml")]
+        "toml" => Ok(ConfigFormat::Toml),
+        "ini" | "cfg" | "conf" => Ok(ConfigFormat::Ini),
+        _ => Err(format!("Couldn't tell the config format of {:?}, expected a json, yaml, toml or ini file", path)),
+    }
+}
+
+/// Deep merge a fragment of config into the text of the config file at path. Objects are merged key by key, and other values
+/// are replaced by the fragment's. Files that already hold the fragment's values are left as they are
+pub(crate) fn merge(path: &Path, text: &str, fragment: &str, options: &FileOptions) -> Result<String, String> {
+    match config_format(path, options)? {
+        ConfigFormat::Json => {
+            let mut config = match text.trim().is_empty() {
+                true => serde_json::Value::Object(Default::default()),
+                false => serde_json::from_str(text).map_err(|e| format!("Invalid json config, {}", e))?,
+            };
+            let original = config.clone();
+            merge_json(&mut config, serde_json::from_str(fragment).map_err(|e| format!("Invalid json fragment, {}", e))?);
+            if config == original {
+                return Ok(text.to_string());
+            }
+            serde_json::to_string_pretty(&config).map(|config| config + "\n").map_err(|e| e.to_string())
+        },
+        #[cfg(feature = "yaml")]
+        ConfigFormat::Yaml => {
+            let mut config = match text.trim().is_empty() {
+                true => serde_yaml::Value::Mapping(Default::default()),
+                false => serde_yaml::from_str(text).map_err(|e| format!("Invalid yaml config, {}", e))?,
+            };
+            let original = config.clone();
+            merge_yaml(&mut config, serde_yaml::from_str(fragment).map_err(|e| format!("Invalid yaml fragment, {}", e))?);
+            if config == original {
+                return Ok(text.to_string());
+            }
+            serde_yaml::to_string(&config).map_err(|e| e.to_string())
+        },
+        #[cfg(feature = "toml")]
+        ConfigFormat::Toml => {
+            let mut config = toml::from_str::<toml::Table>(text).map_err(|e| format!("Invalid toml config, {}", e))?;
+            let original = config.clone();
+            merge_toml(&mut config, toml::from_str(fragment).map_err(|e| format!("Invalid toml fragment, {}", e))?);
+            if config == original {
+                return Ok(text.to_string());
+            }
+            toml::to_string_pretty(&config).map_err(|e| e.to_string())
+        },
+        ConfigFormat::Ini => merge_ini(text, fragment),
+    }
+}
+
+/// Deep merge a json value into another
+fn merge_json(config: &mut serde_json::Value, fragment: serde_json::Value) {
+    match (config, fragment) {
+        (serde_json::Value::Object(config), serde_json::Value::Object(fragment)) => for (key, value) in fragment {
+            match config.get_mut(&key) {
+                Some(existing) => merge_json(existing, value),
+                None => {
+                    config.insert(key, value);
+                },
+            }
+        },
+        (config, fragment) => *config = fragment,
+    }
+}
+
+#[cfg(feature = "yaml")]
+/// Deep merge a yaml value into another
+fn merge_yaml(config: &mut serde_yaml::Value, fragment: serde_yaml::Value) {
+    match (config, fragment) {
+        (serde_yaml::Value::Mapping(config), serde_yaml::Value::Mapping(fragment)) => for (key, value) in fragment {
+            match config.get_mut(&key) {
+                Some(existing) => merge_yaml(existing, value),
+                None => {
+                    config.insert(key, value);
+                },
+            }
+        },
+        (config, fragment) => *config = fragment,
+    }
+}
+
+#[cfg(feature = "toml")]
+/// Deep merge a toml table into another
+fn merge_toml(config: &mut toml::Table, fragment: toml::Table) {
+    for (key, value) in fragment {
+        match (config.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(value)) => merge_toml(existing, value),
+            (_, value) => {
+                config.insert(key, value);
+            },
+        }
+    }
+}
+
+/// A line of an ini file
+enum IniLine<'a> {
+    /// Section header, e.g. "[server]"
+    Section(&'a str),
+    /// Key and value, e.g. "port = 8080"
+    Entry(&'a str, &'a str),
+    /// Blank line or comment
+    Other,
+}
+
+/// Parse a line of an ini file, or None if it isn't a section, entry, comment or blank
+fn parse_ini_line(line: &str) -> Option<IniLine<'_>> {
+    let line = line.trim();
+    if line.is_empty() || line.starts_with(';') || line.starts_with('#') {
+        return Some(IniLine::Other);
+    }
+    if let Some(section) = line.strip_prefix('[').and_then(|line| line.strip_suffix(']')) {
+        return Some(IniLine::Section(section.trim()));
+    }
+    line.split_once('=').map(|(key, value)| IniLine::Entry(key.trim(), value.trim()))
+}
+
+/// Merge the entries of an ini fragment into ini text. Entries of the text's sections are replaced in place and new entries are added
+/// to the end of their section, so comments and the layout of the text are kept
+fn merge_ini(text: &str, fragment: &str) -> Result<String, String> {
+    let mut lines = text.lines().map(str::to_string).collect::<Vec<_>>();
+    let mut section = "";
+    let mut changed = false;
+    for line in fragment.lines() {
+        match parse_ini_line(line).ok_or_else(|| format!("Invalid line in ini fragment, {}", line))? {
+            IniLine::Section(name) => section = name,
+            IniLine::Entry(key, value) => changed |= set_ini_entry(&mut lines, section, key, value, line.trim()),
+            IniLine::Other => (),
+        }
+    }
+
+    if !changed {
+        return Ok(text.to_string());
+    }
+    Ok(lines.join("\n") + "\n")
+}
+
+/// Set an entry of a section of ini lines, adding the section if it doesn't exist. The section "" holds the entries before
+/// the first section. Returns whether the lines changed
+fn set_ini_entry(lines: &mut Vec<String>, section: &str, key: &str, value: &str, line: &str) -> bool {
+    let mut current = "";
+    let mut last_in_section = None;
+    let mut found = section.is_empty();
+    for (index, existing) in lines.iter().enumerate() {
+        match parse_ini_line(existing) {
+            Some(IniLine::Section(name)) => {
+                current = name;
+                if current == section {
+                    found = true;
+                    last_in_section = Some(index);
+                }
+                continue;
+            },
+            Some(IniLine::Entry(existing_key, existing_value)) if current == section && existing_key == key => {
+                if existing_value == value {
+                    return false;
+                }
+                lines[index] = line.to_string();
+                return true;
+            },
+            Some(IniLine::Entry(..)) if current == section => last_in_section = Some(index),
+            _ => (),
+        }
+    }
+
+    match (found, last_in_section) {
+        (true, Some(index)) => lines.insert(index + 1, line.to_string()),
+        (true, None) => lines.insert(0, line.to_string()),
+        (false, _) => {
+            if lines.last().is_some_and(|last| !last.trim().is_empty()) {
+                lines.push(String::new());
+            }
+            lines.push(format!("[{}]", section));
+            lines.push(line.to_string());
+        },
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{merge_ini, merge_json};
+
+    #[test]
+    fn test() {
+        let mut config = serde_json::json!({"name": "app", "server": {"port": 80, "host": "localhost"}});
+        merge_json(&mut config, serde_json::json!({"server": {"port": 8080, "tls": true}, "debug": false}));
+        assert_eq!(config, serde_json::json!({"name": "app", "server": {"port": 8080, "host": "localhost", "tls": true}, "debug": false}));
+
+        let ini = "; settings\nname = app\n\n[server]\nport = 80\nhost = localhost\n";
+        let merged = merge_ini(ini, "[server]\nport = 8080\ntls = true\n[log]\nlevel = info\n").unwrap();
+        assert_eq!(merged, "; settings\nname = app\n\n[server]\nport = 8080\nhost = localhost\ntls = true\n\n[log]\nlevel = info\n");
+        assert_eq!(merge_ini(&merged, "[server]\nport=8080\n").unwrap(), merged);
+        assert_eq!(merge_ini("[a]\nb = c\n", "name = app").unwrap(), "name = app\n[a]\nb = c\n");
+        assert!(merge_ini("", "not an entry").is_err());
+    }
+}
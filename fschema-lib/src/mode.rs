@@ -0,0 +1,175 @@
+use std::{fs::{self, Metadata}, io, path::Path};
+#[cfg(unix)]
+use std::{fs::Permissions, os::unix::prelude::PermissionsExt};
+
+/// Permissions a new file gets with the usual umask of 022. Symbolic modes are applied to them
+const FILE_BASE: u32 = 0o644;
+/// Permissions a new directory gets with the usual umask of 022. Symbolic modes are applied to them
+const DIRECTORY_BASE: u32 = 0o755;
+
+/// Parse a mode given as an octal, e.g. "750" or "0o750", as permissions like ls shows them, e.g. "rwxr-x---",
+/// or as chmod's symbolic modes, e.g. "u+x,go-r"
+pub(crate) fn parse_mode(mode: &str, directory: bool) -> Result<u32, String> {
+    let mode = mode.strip_prefix("0o").unwrap_or(mode);
+    if !mode.is_empty() && mode.chars().all(|c| c.is_digit(8)) {
+        return u32::from_str_radix(mode, 8)
+            .ok()
+            .filter(|mode| *mode <= 0o7777)
+            .ok_or_else(|| format!("Expected a mode of at most 7777, found {}", mode));
+    }
+    if mode.len() == 9 && mode.chars().all(|c| "rwxsStT-".contains(c)) {
+        return parse_permissions(mode);
+    }
+    parse_symbolic(mode, if directory { DIRECTORY_BASE } else { FILE_BASE }, directory)
+}
+
+/// Parse a umask given as an octal, e.g. "022" or "0o027"
+pub(crate) fn parse_umask(umask: &str) -> Result<u32, String> {
+    let octal = umask.strip_prefix("0o").unwrap_or(umask);
+    u32::from_str_radix(octal, 8)
+        .ok()
+        .filter(|umask| *umask <= 0o777)
+        .ok_or_else(|| format!("Expected an octal umask of at most 777, found {}", umask))
+}
+
+/// Parse permissions like ls shows them, e.g. "rwsr-x--T"
+fn parse_permissions(permissions: &str) -> Result<u32, String> {
+    let invalid = || format!("Expected permissions like rwxr-x---, found {}", permissions);
+    let mut mode = 0;
+    for (class, triple) in permissions.as_bytes().chunks(3).enumerate() {
+        let shift = 6 - class as u32 * 3;
+        let special = 0o4000 >> class;
+        mode |= match triple[0] {
+            b'r' => 0o4 << shift,
+            b'-' => 0,
+            _ => return Err(invalid()),
+        };
+        mode |= match triple[1] {
+            b'w' => 0o2 << shift,
+            b'-' => 0,
+            _ => return Err(invalid()),
+        };
+        mode |= match (triple[2], class) {
+            (b'x', _) => 0o1 << shift,
+            (b'-', _) => 0,
+            (b's', 0 | 1) | (b't', 2) => special | 0o1 << shift,
+            (b'S', 0 | 1) | (b'T', 2) => special,
+            _ => return Err(invalid()),
+        };
+    }
+    Ok(mode)
+}
+
+/// Apply chmod's symbolic modes, e.g. "u+x,go-r" or "a=rX,u+w", to a mode
+fn parse_symbolic(symbolic: &str, mut mode: u32, directory: bool) -> Result<u32, String> {
+    for clause in symbolic.split(',') {
+        let start = clause
+            .find(['+', '-', '='])
+            .ok_or_else(|| format!("Expected an octal or symbolic mode, found {}", symbolic))?;
+        let (who, mut actions) = clause.split_at(start);
+
+        let mut classes = if who.is_empty() { 0o7777 } else { 0 };
+        for class in who.chars() {
+            classes |= match class {
+                'u' => 0o4700,
+                'g' => 0o2070,
+                'o' => 0o1007,
+                'a' => 0o7777,
+                _ => return Err(format!("Expected u, g, o or a in symbolic mode, found {}", class)),
+            };
+        }
+
+        while let Some(operator) = actions.chars().next() {
+            let end = actions[1..].find(['+', '-', '=']).map(|end| end + 1).unwrap_or(actions.len());
+            let mut bits = 0;
+            for permission in actions[1..end].chars() {
+                bits |= match permission {
+                    'r' => 0o444,
+                    'w' => 0o222,
+                    'x' => 0o111,
+                    'X' if directory || mode & 0o111 != 0 => 0o111,
+                    'X' => 0,
+                    's' => 0o6000,
+                    't' => 0o1000,
+                    'u' => (mode >> 6 & 0o7) * 0o111,
+                    'g' => (mode >> 3 & 0o7) * 0o111,
+                    'o' => (mode & 0o7) * 0o111,
+                    _ => return Err(format!("Expected r, w, x, X, s, t, u, g or o in symbolic mode, found {}", permission)),
+                };
+            }
+            actions = &actions[end..];
+
+            bits &= classes;
+            match operator {
+                '+' => mode |= bits,
+                '-' => mode &= !bits,
+                _ => mode = mode & !classes | bits,
+            }
+        }
+    }
+    Ok(mode)
+}
+
+#[cfg(unix)]
+/// Permissions of a file or directory, e.g. 0o644
+pub(crate) fn mode_of(metadata: &Metadata) -> u32 {
+    metadata.permissions().mode() & 0o7777
+}
+
+#[cfg(windows)]
+/// Permissions of a file or directory. Windows only has a read only flag, so they're either 0o444 or 0o666
+pub(crate) fn mode_of(metadata: &Metadata) -> u32 {
+    effective_mode(if metadata.permissions().readonly() { 0 } else { 0o222 })
+}
+
+/// Permissions a file or directory given a mode has once it's set, which on windows is only whether it can be written to
+pub(crate) fn effective_mode(mode: u32) -> u32 {
+    match (cfg!(windows), mode & 0o222) {
+        (false, _) => mode,
+        (true, 0) => 0o444,
+        (true, _) => 0o666,
+    }
+}
+
+#[cfg(unix)]
+/// Set the permissions of a file or directory
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    fs::set_permissions(path, Permissions::from_mode(mode))
+}
+
+#[cfg(windows)]
+/// Set the permissions of a file or directory. Windows only has a read only flag, set if no one can write to it
+pub(crate) fn set_mode(path: &Path, mode: u32) -> io::Result<()> {
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_readonly(effective_mode(mode) & 0o222 == 0);
+    fs::set_permissions(path, permissions)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_mode, parse_umask};
+
+    #[test]
+    fn test() {
+        assert_eq!(parse_mode("750", false), Ok(0o750));
+        assert_eq!(parse_mode("4755", false), Ok(0o4755));
+        assert!(parse_mode("17777", false).is_err());
+        assert_eq!(parse_mode("0o640", false), Ok(0o640));
+
+        assert_eq!(parse_mode("rwxr-x---", false), Ok(0o750));
+        assert_eq!(parse_mode("rwsr-sr-T", false), Ok(0o7754));
+        assert!(parse_mode("rwxrwxrws", false).is_err());
+
+        assert_eq!(parse_mode("u+x,go-r", false), Ok(0o700));
+        assert_eq!(parse_mode("go-rx", true), Ok(0o700));
+        assert_eq!(parse_mode("a=rX,u+w", false), Ok(0o644));
+        assert_eq!(parse_mode("a=rX,u+w", true), Ok(0o755));
+        assert_eq!(parse_mode("u+s,+t", false), Ok(0o5644));
+        assert_eq!(parse_mode("g=u", false), Ok(0o664));
+        assert!(parse_mode("u+q", false).is_err());
+        assert!(parse_mode("rwx", false).is_err());
+
+        assert_eq!(parse_umask("027"), Ok(0o027));
+        assert!(parse_umask("1777").is_err());
+    }
+}
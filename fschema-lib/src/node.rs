@@ -0,0 +1,211 @@
+use std::collections::HashMap;
+
+use crate::{DirectoryOptions, FSchema, FileOptions, Node};
+
+impl Node {
+    /// File with data, read according to its options' type
+    pub fn file(data: &str, options: FileOptions) -> Node {
+        Node::File { data: data.to_string(), options }
+    }
+
+    /// Empty directory
+    pub fn dir() -> Node {
+        Node::Directory { contents: HashMap::new(), ord: vec![], options: DirectoryOptions::default() }
+    }
+
+    /// Comment, written as a string in place of a node. Nothing is created for it
+    pub fn comment(text: &str) -> Node {
+        Node::Comment(text.to_string())
+    }
+
+    /// Copy of a directory with a node added to it, replacing the node with its name if there is one. Other nodes are returned as they are
+    pub fn with_child(mut self, name: &str, node: Node) -> Node {
+        if let Node::Directory { contents, ord, .. } = &mut self {
+            if contents.insert(name.to_string(), node).is_none() {
+                ord.push(name.to_string());
+            }
+        }
+        self
+    }
+
+    /// Is the node a file
+    pub fn is_file(&self) -> bool {
+        matches!(self, Node::File { .. })
+    }
+
+    /// Is the node a directory
+    pub fn is_dir(&self) -> bool {
+        matches!(self, Node::Directory { .. })
+    }
+
+    /// Is the node a comment
+    pub fn is_comment(&self) -> bool {
+        matches!(self, Node::Comment(_))
+    }
+
+    /// Data of a file, or the text of a comment
+    pub fn data(&self) -> Option<&str> {
+        match self {
+            Node::File { data, .. } | Node::Comment(data) => Some(data),
+            _ => None,
+        }
+    }
+
+    /// Options of a file
+    pub fn file_options(&self) -> Option<&FileOptions> {
+        match self {
+            Node::File { options, .. } => Some(options),
+            _ => None,
+        }
+    }
+
+    /// Options of a directory
+    pub fn dir_options(&self) -> Option<&DirectoryOptions> {
+        match self {
+            Node::Directory { options, .. } => Some(options),
+            _ => None,
+        }
+    }
+
+    /// Names and nodes of a directory's entries, in the order they're declared. Empty for anything but a directory
+    pub fn children(&self) -> impl Iterator<Item = (&str, &Node)> {
+        let (contents, ord) = match self {
+            Node::Directory { contents, ord, .. } => (Some(contents), ord.as_slice()),
+            _ => (None, [].as_slice()),
+        };
+        ord.iter().filter_map(move |name| Some((name.as_str(), contents?.get(name)?)))
+    }
+
+    /// Entry of a directory with a name
+    pub fn child(&self, name: &str) -> Option<&Node> {
+        match self {
+            Node::Directory { contents, .. } => contents.get(name),
+            _ => None,
+        }
+    }
+
+    /// Mutable entry of a directory with a name
+    pub fn child_mut(&mut self, name: &str) -> Option<&mut Node> {
+        match self {
+            Node::Directory { contents, .. } => contents.get_mut(name),
+            _ => None,
+        }
+    }
+}
+
+impl FSchema {
+    /// Node at a path, relative to the root and separated by "/", e.g. "src/main.rs"
+    pub fn get(&self, path: &str) -> Option<&Node> {
+        let names = names(path);
+        let (name, parents) = names.split_last()?;
+        let mut contents = &self.root;
+        for parent in parents {
+            contents = match contents.get(*parent)? {
+                Node::Directory { contents, .. } => contents,
+                _ => return None,
+            };
+        }
+        contents.get(*name)
+    }
+
+    /// Mutable node at a path, relative to the root and separated by "/"
+    pub fn get_mut(&mut self, path: &str) -> Option<&mut Node> {
+        let names = names(path);
+        let (name, parents) = names.split_last()?;
+        let mut contents = &mut self.root;
+        for parent in parents {
+            contents = match contents.get_mut(*parent)? {
+                Node::Directory { contents, .. } => contents,
+                _ => return None,
+            };
+        }
+        contents.get_mut(*name)
+    }
+
+    /// Insert a node at a path, relative to the root and separated by "/", creating the directories it's in if they don't
+    /// exist. Nodes in the way that aren't directories are replaced. Returns the node replaced at the path, if there was one
+    pub fn insert(&mut self, path: &str, node: Node) -> Option<Node> {
+        let names = names(path);
+        let (name, parents) = names.split_last()?;
+        let (contents, ord) = directory(&mut self.root, &mut self.root_ord, parents);
+        let replaced = contents.insert(name.to_string(), node);
+        if replaced.is_none() {
+            ord.push(name.to_string());
+        }
+        replaced
+    }
+
+    /// Remove the node at a path, relative to the root and separated by "/", returning it
+    pub fn remove_node(&mut self, path: &str) -> Option<Node> {
+        let names = names(path);
+        let (name, parents) = names.split_last()?;
+        let (mut contents, mut ord) = (&mut self.root, &mut self.root_ord);
+        for parent in parents {
+            (contents, ord) = match contents.get_mut(*parent)? {
+                Node::Directory { contents, ord, .. } => (contents, ord),
+                _ => return None,
+            };
+        }
+        let removed = contents.remove(*name)?;
+        ord.retain(|entry| entry != name);
+        Some(removed)
+    }
+}
+
+/// Names of the entries a path passes through, ignoring empty and "." components
+pub(crate) fn names(path: &str) -> Vec<&str> {
+    path.split('/').filter(|name| !name.is_empty() && *name != ".").collect()
+}
+
+/// Contents of the directory at the path given by names, creating it, and the directories it's in, if they don't exist.
+/// Nodes in the way that aren't directories are replaced
+pub(crate) fn directory<'a>(mut contents: &'a mut HashMap<String, Node>, mut ord: &'a mut Vec<String>, names: &[&str]) -> (&'a mut HashMap<String, Node>, &'a mut Vec<String>) {
+    for name in names {
+        if !contents.contains_key(*name) {
+            ord.push(name.to_string());
+        }
+        let node = contents.entry(name.to_string()).or_insert_with(Node::dir);
+        if !node.is_dir() {
+            *node = Node::dir();
+        }
+        (contents, ord) = match node {
+            Node::Directory { contents, ord, .. } => (contents, ord),
+            _ => unreachable!("the node was just made a directory"),
+        };
+    }
+    (contents, ord)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FSchema, FileOptions, FileType, Node};
+
+    #[test]
+    fn test() {
+        let node = Node::dir()
+            .with_child("readme", Node::file("hello", FileOptions::new()))
+            .with_child("note", Node::comment("generated"))
+            .with_child("bin", Node::dir().with_child("run", Node::file("echo hi", FileOptions::new().ftype(FileType::Piped))));
+        assert!(node.is_dir() && !node.is_file());
+        assert_eq!(node.children().map(|(name, _)| name).collect::<Vec<_>>(), ["readme", "note", "bin"]);
+        assert_eq!(node.child("readme").and_then(Node::data), Some("hello"));
+        assert!(node.child("note").is_some_and(Node::is_comment));
+        let run = node.child("bin").and_then(|bin| bin.child("run")).unwrap();
+        assert!(matches!(run.file_options().map(FileOptions::get_ftype), Some(FileType::Piped)));
+        assert_eq!(run.children().count(), 0);
+        assert_eq!(serde_json::to_string(&node.child("note")).unwrap(), r#""generated""#);
+
+        let mut schema = FSchema::from_str(r#"{ "root": { "src": { "main.rs": ["fn main() {}"] }, "readme": ["hi"] } }"#).unwrap();
+        assert_eq!(schema.get("src/main.rs").and_then(Node::data), Some("fn main() {}"));
+        assert!(schema.get("readme/x").is_none() && schema.get("").is_none());
+        assert!(schema.insert("src/bin/tool.rs", Node::file("fn main() {}", FileOptions::new())).is_none());
+        assert_eq!(schema.insert("./readme", Node::file("hello", FileOptions::new())).as_ref().and_then(Node::data), Some("hi"));
+        if let Some(Node::File { data, .. }) = schema.get_mut("src/bin/tool.rs") {
+            data.push('\n');
+        }
+        assert!(schema.remove_node("src/main.rs").is_some_and(|node| node.is_file()));
+        assert!(schema.remove_node("src/main.rs").is_none());
+        assert_eq!(schema.get("src").unwrap().children().map(|(name, _)| name).collect::<Vec<_>>(), ["bin"]);
+        assert_eq!(schema.get("src/bin/tool.rs").and_then(Node::data), Some("fn main() {}\n"));
+    }
+}
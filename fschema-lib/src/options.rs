@@ -0,0 +1,518 @@
+use std::{collections::BTreeMap, time::SystemTime};
+
+use crate::{Command, FileOptions, FileType, Format, OverwritePolicy, Preserve};
+
+impl FileOptions {
+    /// Default options, of a Text file, to build on, e.g. `FileOptions::new().mode(0o644).defer(1)`. Options that are lists
+    /// or maps are added to one at a time, and every option can be read back with its getter, e.g. `get_mode()`
+    pub fn new() -> FileOptions {
+        FileOptions::default()
+    }
+
+    /// Set the type of the file's data
+    pub fn ftype(mut self, ftype: FileType) -> Self {
+        self.ftype = ftype;
+        self
+    }
+
+    /// Set the permissions, e.g. 0o644
+    pub fn mode(mut self, mode: u32) -> Self {
+        self.mode = Some(mode);
+        self
+    }
+
+    /// Set the stage the file is created at. Negative stages are created before ordinary files
+    pub fn defer(mut self, defer: i64) -> Self {
+        self.defer = defer;
+        self
+    }
+
+    /// Set whether the path in the file's data is relative to the root
+    pub fn internal(mut self, internal: bool) -> Self {
+        self.internal = internal;
+        self
+    }
+
+    /// Set what to do if the file already exists, overriding the policy the schema is created with
+    pub fn overwrite(mut self, overwrite: OverwritePolicy) -> Self {
+        self.overwrite = Some(overwrite);
+        self
+    }
+
+    /// Add a path, relative to root, that must be created before the file
+    pub fn depends_on(mut self, path: &str) -> Self {
+        self.depends_on.push(path.to_string());
+        self
+    }
+
+    /// Set the id of the user owning the file
+    pub fn uid(mut self, uid: u32) -> Self {
+        self.ownership.uid = Some(uid);
+        self
+    }
+
+    /// Set the id of the group owning the file
+    pub fn gid(mut self, gid: u32) -> Self {
+        self.ownership.gid = Some(gid);
+        self
+    }
+
+    /// Set the name of the user owning the file
+    pub fn owner(mut self, owner: &str) -> Self {
+        self.ownership.owner = Some(owner.to_string());
+        self
+    }
+
+    /// Set the name of the group owning the file
+    pub fn group(mut self, group: &str) -> Self {
+        self.ownership.group = Some(group.to_string());
+        self
+    }
+
+    /// Set the access time
+    pub fn accessed(mut self, accessed: SystemTime) -> Self {
+        self.accessed = Some(accessed);
+        self
+    }
+
+    /// Set the modification time
+    pub fn modified(mut self, modified: SystemTime) -> Self {
+        self.modified = Some(modified);
+        self
+    }
+
+    /// Add an extended attribute
+    pub fn xattrs(mut self, name: &str, value: &str) -> Self {
+        self.xattrs.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Add a POSIX ACL entry, e.g. "user:alice:rwx"
+    pub fn acl(mut self, entry: &str) -> Self {
+        self.acl.push(entry.to_string());
+        self
+    }
+
+    /// Set the Linux file capabilities, e.g. "cap_net_bind_service=+ep"
+    pub fn capabilities(mut self, capabilities: &str) -> Self {
+        self.capabilities = Some(capabilities.to_string());
+        self
+    }
+
+    /// Set the attribute flags, written like chattr, e.g. "i" for immutable
+    pub fn attrs(mut self, attrs: &str) -> Self {
+        self.attrs = Some(attrs.to_string());
+        self
+    }
+
+    /// Set whether the space of a Size file is allocated on disk, instead of it being created sparse
+    pub fn preallocate(mut self, preallocate: bool) -> Self {
+        self.preallocate = preallocate;
+        self
+    }
+
+    /// Set the seed of a Random file's data
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+
+    /// Set the sha256 hash the file's contents are checked against once it's created
+    pub fn sha256(mut self, sha256: &str) -> Self {
+        self.sha256 = Some(sha256.to_ascii_lowercase());
+        self
+    }
+
+    /// Set the branch, tag or commit a Git file checks out
+    pub fn git_ref(mut self, git_ref: &str) -> Self {
+        self.git_ref = Some(git_ref.to_string());
+        self
+    }
+
+    /// Set whether a Git file only fetches the latest commit
+    pub fn shallow(mut self, shallow: bool) -> Self {
+        self.shallow = shallow;
+        self
+    }
+
+    /// Set the number of leading path components removed from an Archive file's members
+    pub fn strip_components(mut self, strip_components: usize) -> Self {
+        self.strip_components = strip_components;
+        self
+    }
+
+    /// Add a glob pattern of the members of an Archive file to extract
+    pub fn include(mut self, pattern: &str) -> Self {
+        self.include.push(pattern.to_string());
+        self
+    }
+
+    /// Add a glob pattern of the members of an Archive file never extracted
+    pub fn exclude(mut self, pattern: &str) -> Self {
+        self.exclude.push(pattern.to_string());
+        self
+    }
+
+    /// Set the regular expression matching the line a LineInFile file replaces
+    pub fn regexp(mut self, regexp: &str) -> Self {
+        self.regexp = Some(regexp.to_string());
+        self
+    }
+
+    /// Set the marker lines around a BlockInFile file's block, with {mark} replaced by BEGIN or END
+    pub fn marker(mut self, marker: &str) -> Self {
+        self.marker = Some(marker.to_string());
+        self
+    }
+
+    /// Set the format structured data given in place of the file's text is written in
+    pub fn serialize_as(mut self, serialize_as: Format) -> Self {
+        self.serialize_as = Some(serialize_as);
+        self
+    }
+
+    /// Add metadata a Copy file keeps from its source
+    pub fn preserve(mut self, preserve: Preserve) -> Self {
+        self.preserve.push(preserve);
+        self
+    }
+
+    /// Set whether the file's data is left as it is, without substituting variables or expanding environment variables
+    pub fn raw(mut self, raw: bool) -> Self {
+        self.raw = raw;
+        self
+    }
+
+    /// Set whether references to environment variables in the file's data are expanded
+    pub fn expand(mut self, expand: bool) -> Self {
+        self.expand = expand;
+        self
+    }
+
+    /// Set the condition the file is only created if it holds, e.g. "os == 'linux'"
+    pub fn when(mut self, when: &str) -> Self {
+        self.when = Some(when.to_string());
+        self
+    }
+
+    /// Add a platform the file is created on, e.g. "linux"
+    pub fn platform(mut self, platform: &str) -> Self {
+        self.platform.push(platform.to_string());
+        self
+    }
+
+    /// Add a glob pattern of the hostnames or targets of the machines the file is created on
+    pub fn hosts(mut self, host: &str) -> Self {
+        self.hosts.push(host.to_string());
+        self
+    }
+
+    /// Set whether a Piped file's failing command prints a warning instead of failing the build
+    pub fn allow_failure(mut self, allow_failure: bool) -> Self {
+        self.allow_failure = allow_failure;
+        self
+    }
+
+    /// Set the seconds a Piped file's command can run for
+    pub fn timeout(mut self, timeout: u64) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Set the times a Piped file's command is run again if it fails
+    pub fn retries(mut self, retries: u32) -> Self {
+        self.retries = retries;
+        self
+    }
+
+    /// Set the shell a Piped or Secret file's command is run with, e.g. "sh"
+    pub fn shell(mut self, shell: &str) -> Self {
+        self.shell = Some(shell.to_string());
+        self
+    }
+
+    /// Add an environment variable set for a Piped file's command
+    pub fn env(mut self, name: &str, value: &str) -> Self {
+        self.env.insert(name.to_string(), value.to_string());
+        self
+    }
+
+    /// Set the file a Piped file's command reads its input from
+    pub fn stdin(mut self, stdin: &str) -> Self {
+        self.stdin = Some(stdin.to_string());
+        self
+    }
+
+    /// Set the largest output, in bytes, a Piped file's command can write
+    pub fn max_size(mut self, max_size: u64) -> Self {
+        self.max_size = Some(max_size);
+        self
+    }
+
+    /// Add a command run once the file is created
+    pub fn on_create(mut self, command: impl Into<Command>) -> Self {
+        self.on_create.push(command.into());
+        self
+    }
+
+    /// Add a command run if creating the file fails
+    pub fn on_error(mut self, command: impl Into<Command>) -> Self {
+        self.on_error.push(command.into());
+        self
+    }
+
+    /// Set the user the file is created as, e.g. "root"
+    pub fn run_as(mut self, run_as: &str) -> Self {
+        self.run_as = Some(run_as.to_string());
+        self
+    }
+
+    /// Type of the file's data
+    pub fn get_ftype(&self) -> FileType {
+        self.ftype
+    }
+
+    /// Permissions
+    pub fn get_mode(&self) -> Option<u32> {
+        self.mode
+    }
+
+    /// Stage the file is created at
+    pub fn get_defer(&self) -> i64 {
+        self.defer
+    }
+
+    /// Whether the path in the file's data is relative to the root
+    pub fn get_internal(&self) -> bool {
+        self.internal
+    }
+
+    /// What to do if the file already exists
+    pub fn get_overwrite(&self) -> Option<OverwritePolicy> {
+        self.overwrite
+    }
+
+    /// Paths, relative to root, that must be created before the file
+    pub fn get_depends_on(&self) -> &[String] {
+        &self.depends_on
+    }
+
+    /// Id of the user owning the file
+    pub fn get_uid(&self) -> Option<u32> {
+        self.ownership.uid
+    }
+
+    /// Id of the group owning the file
+    pub fn get_gid(&self) -> Option<u32> {
+        self.ownership.gid
+    }
+
+    /// Name of the user owning the file
+    pub fn get_owner(&self) -> Option<&str> {
+        self.ownership.owner.as_deref()
+    }
+
+    /// Name of the group owning the file
+    pub fn get_group(&self) -> Option<&str> {
+        self.ownership.group.as_deref()
+    }
+
+    /// Access time
+    pub fn get_accessed(&self) -> Option<SystemTime> {
+        self.accessed
+    }
+
+    /// Modification time
+    pub fn get_modified(&self) -> Option<SystemTime> {
+        self.modified
+    }
+
+    /// Extended attributes, by name
+    pub fn get_xattrs(&self) -> &BTreeMap<String, String> {
+        &self.xattrs
+    }
+
+    /// POSIX ACL entries
+    pub fn get_acl(&self) -> &[String] {
+        &self.acl
+    }
+
+    /// Linux file capabilities
+    pub fn get_capabilities(&self) -> Option<&str> {
+        self.capabilities.as_deref()
+    }
+
+    /// Attribute flags, written like chattr
+    pub fn get_attrs(&self) -> Option<&str> {
+        self.attrs.as_deref()
+    }
+
+    /// Whether the space of a Size file is allocated on disk
+    pub fn get_preallocate(&self) -> bool {
+        self.preallocate
+    }
+
+    /// Seed of a Random file's data
+    pub fn get_seed(&self) -> Option<u64> {
+        self.seed
+    }
+
+    /// Sha256 hash the file's contents are checked against
+    pub fn get_sha256(&self) -> Option<&str> {
+        self.sha256.as_deref()
+    }
+
+    /// Branch, tag or commit a Git file checks out
+    pub fn get_git_ref(&self) -> Option<&str> {
+        self.git_ref.as_deref()
+    }
+
+    /// Whether a Git file only fetches the latest commit
+    pub fn get_shallow(&self) -> bool {
+        self.shallow
+    }
+
+    /// Number of leading path components removed from an Archive file's members
+    pub fn get_strip_components(&self) -> usize {
+        self.strip_components
+    }
+
+    /// Glob patterns of the members of an Archive file to extract
+    pub fn get_include(&self) -> &[String] {
+        &self.include
+    }
+
+    /// Glob patterns of the members of an Archive file never extracted
+    pub fn get_exclude(&self) -> &[String] {
+        &self.exclude
+    }
+
+    /// Regular expression matching the line a LineInFile file replaces
+    pub fn get_regexp(&self) -> Option<&str> {
+        self.regexp.as_deref()
+    }
+
+    /// Marker lines around a BlockInFile file's block
+    pub fn get_marker(&self) -> Option<&str> {
+        self.marker.as_deref()
+    }
+
+    /// Format structured data given in place of the file's text is written in
+    pub fn get_serialize_as(&self) -> Option<Format> {
+        self.serialize_as
+    }
+
+    /// Metadata a Copy file keeps from its source
+    pub fn get_preserve(&self) -> &[Preserve] {
+        &self.preserve
+    }
+
+    /// Whether the file's data is left as it is, without substituting variables or expanding environment variables
+    pub fn get_raw(&self) -> bool {
+        self.raw
+    }
+
+    /// Whether references to environment variables in the file's data are expanded
+    pub fn get_expand(&self) -> bool {
+        self.expand
+    }
+
+    /// Condition the file is only created if it holds
+    pub fn get_when(&self) -> Option<&str> {
+        self.when.as_deref()
+    }
+
+    /// Platforms the file is only created on. Every platform if empty
+    pub fn get_platform(&self) -> &[String] {
+        &self.platform
+    }
+
+    /// Glob patterns of the hostnames or targets of the machines the file is only created on
+    pub fn get_hosts(&self) -> &[String] {
+        &self.hosts
+    }
+
+    /// Whether a Piped file's failing command prints a warning instead of failing the build
+    pub fn get_allow_failure(&self) -> bool {
+        self.allow_failure
+    }
+
+    /// Seconds a Piped file's command can run for
+    pub fn get_timeout(&self) -> Option<u64> {
+        self.timeout
+    }
+
+    /// Times a Piped file's command is run again if it fails
+    pub fn get_retries(&self) -> u32 {
+        self.retries
+    }
+
+    /// Shell a Piped or Secret file's command is run with
+    pub fn get_shell(&self) -> Option<&str> {
+        self.shell.as_deref()
+    }
+
+    /// Environment variables set for a Piped file's command
+    pub fn get_env(&self) -> &BTreeMap<String, String> {
+        &self.env
+    }
+
+    /// File a Piped file's command reads its input from
+    pub fn get_stdin(&self) -> Option<&str> {
+        self.stdin.as_deref()
+    }
+
+    /// Largest output, in bytes, a Piped file's command can write
+    pub fn get_max_size(&self) -> Option<u64> {
+        self.max_size
+    }
+
+    /// Commands run once the file is created
+    pub fn get_on_create(&self) -> &[Command] {
+        &self.on_create
+    }
+
+    /// Commands run if creating the file fails
+    pub fn get_on_error(&self) -> &[Command] {
+        &self.on_error
+    }
+
+    /// User the file is created as
+    pub fn get_run_as(&self) -> Option<&str> {
+        self.run_as.as_deref()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{FSchema, FileOptions, FileType, OverwritePolicy};
+
+    #[test]
+    fn test() {
+        let options = FileOptions::new()
+            .ftype(FileType::Piped)
+            .mode(0o640)
+            .defer(1)
+            .owner("www-data")
+            .gid(33)
+            .overwrite(OverwritePolicy::Backup)
+            .depends_on("src")
+            .depends_on("assets")
+            .env("RUST_LOG", "info")
+            .on_create("systemctl reload nginx");
+        assert!(matches!(options.get_ftype(), FileType::Piped));
+        assert_eq!(options.get_mode(), Some(0o640));
+        assert_eq!(options.get_defer(), 1);
+        assert_eq!((options.get_owner(), options.get_uid(), options.get_gid()), (Some("www-data"), None, Some(33)));
+        assert_eq!(options.get_overwrite(), Some(OverwritePolicy::Backup));
+        assert_eq!(options.get_depends_on(), ["src", "assets"]);
+        assert_eq!(options.get_env()["RUST_LOG"], "info");
+        assert_eq!(options.get_on_create()[0].to_string(), "systemctl reload nginx");
+        assert_eq!(options.get_shell(), None);
+
+        let schema = FSchema::builder().file_with("version", "git describe", options).build();
+        let json = serde_json::to_value(&schema).unwrap();
+        assert_eq!(json["root"]["version"][0]["mode"], "640");
+        assert_eq!(json["root"]["version"][0]["dependsOn"], serde_json::json!(["src", "assets"]));
+    }
+}
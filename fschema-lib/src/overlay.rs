@@ -0,0 +1,113 @@
+use std::collections::HashMap;
+
+use crate::{Error, FSchema, Node};
+
+impl FSchema {
+    /// Copy of the schema with another schema deep merged over it. Directories are merged entry by entry, taking the other
+    /// directory's options if it gives any, other nodes are replaced and nodes marked with {"$delete": true} are removed.
+    /// The other schema's commands run after the schema's own, and its variables, environment, umask, seed, shell, profiles and prompts take precedence
+    pub fn overlay(&self, other: &FSchema) -> FSchema {
+        let mut schema = self.clone();
+        merge_contents(&mut schema.root, &mut schema.root_ord, &other.root, &other.root_ord);
+        schema.prebuild.extend(other.prebuild.iter().cloned());
+        schema.postbuild.extend(other.postbuild.iter().cloned());
+        for (level, commands) in &other.before_level {
+            schema.before_level.entry(*level).or_default().extend(commands.iter().cloned());
+        }
+        for (level, commands) in &other.after_level {
+            schema.after_level.entry(*level).or_default().extend(commands.iter().cloned());
+        }
+        schema.umask = other.umask.or(schema.umask);
+        schema.seed = other.seed.or(schema.seed);
+        schema.shell = other.shell.clone().or(schema.shell);
+        schema.timeout = other.timeout.or(schema.timeout);
+        schema.variables.extend(other.variables.clone());
+        schema.env.extend(other.env.clone());
+        schema.env_file = other.env_file.clone().or(schema.env_file);
+        schema.loaded_env.extend(other.loaded_env.clone());
+        schema.overrides.extend(other.overrides.clone());
+        schema.loaded.extend(other.loaded.iter().filter(|path| !self.loaded.contains(path)).cloned());
+        schema.profiles.extend(other.profiles.clone());
+        for prompt in &other.prompts {
+            match schema.prompts.iter_mut().find(|existing| existing.name == prompt.name) {
+                Some(existing) => *existing = prompt.clone(),
+                None => schema.prompts.push(prompt.clone()),
+            }
+        }
+        schema
+    }
+
+    /// Copy of the schema with a profile's nodes, variables and commands overlaid on it
+    pub fn with_profile(&self, name: &str) -> Result<FSchema, Error> {
+        match self.profiles.get(name) {
+            Some(profile) => Ok(self.overlay(profile)),
+            None => Err(Error::UnknownProfile(name.to_string())),
+        }
+    }
+}
+
+/// Deep merge the entries of a directory over another's
+fn merge_contents(contents: &mut HashMap<String, Node>, ord: &mut Vec<String>, other: &HashMap<String, Node>, other_ord: &[String]) {
+    for name in other_ord {
+        let node = &other[name];
+        match (contents.get_mut(name), node) {
+            (_, Node::Delete) => {
+                contents.remove(name);
+                ord.retain(|entry| entry != name);
+            },
+            (
+                Some(Node::Directory { contents, ord, options }),
+                Node::Directory { contents: other_contents, ord: other_ord, options: other_options },
+            ) => {
+                if !other_options.is_default() {
+                    *options = other_options.clone();
+                }
+                merge_contents(contents, ord, other_contents, other_ord);
+            },
+            (Some(existing), node) => *existing = node.clone(),
+            (None, node) => {
+                contents.insert(name.clone(), node.clone());
+                ord.push(name.clone());
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::FSchema;
+
+    #[test]
+    fn test() {
+        let schema = FSchema::from_str(r#"{
+            "variables": { "env": "base", "port": "80" },
+            "root": {
+                "app": { ".": { "mode": "755" }, "config": ["base"], "log": {} },
+                "README": ["readme"]
+            },
+            "profiles": {
+                "dev": {
+                    "variables": { "env": "dev" },
+                    "root": { "app": { "config": ["dev"], "debug": ["true"] } }
+                }
+            }
+        }"#).unwrap();
+        let dev = schema.with_profile("dev").unwrap();
+        assert_eq!(dev.variables["env"], "dev");
+        assert_eq!(dev.variables["port"], "80");
+        let plan = dev.plan(PathBuf::from("/out")).unwrap().to_string();
+        assert!(plan.contains("\"/out/app/config\" (3 bytes)"));
+        assert!(plan.contains("/out/app/debug"));
+        assert!(plan.contains("/out/app/log"));
+        assert!(plan.contains("/out/README"));
+        assert!(schema.with_profile("prod").is_err());
+
+        let overlay = FSchema::from_str(r#"{ "root": { "README": {"$delete": true}, "app": { "log": {"$delete": true} } } }"#).unwrap();
+        let plan = schema.overlay(&overlay).plan(PathBuf::from("/out")).unwrap().to_string();
+        assert!(plan.contains("/out/app/config"));
+        assert!(!plan.contains("/out/README") && !plan.contains("/out/app/log"));
+        assert!(FSchema::from_str(r#"{ "root": { "a": {"$delete": true, "b": [""]} } }"#).is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use std::{fmt::Display, io, path::Path};
+#[cfg(unix)]
+use std::{ffi::CString, mem, os::unix, ptr};
+
+use crate::Error;
+
+#[derive(Debug, Default, Clone)]
+/// The user and group that own a file or directory, by id or by name. Ids take precedence over names
+pub(crate) struct Ownership {
+    /// Id of the owning user
+    pub(crate) uid: Option<u32>,
+    /// Id of the owning group
+    pub(crate) gid: Option<u32>,
+    /// Name of the owning user, looked up when the file is created
+    pub(crate) owner: Option<String>,
+    /// Name of the owning group, looked up when the file is created
+    pub(crate) group: Option<String>,
+}
+
+impl Ownership {
+    /// Is no owner or group given
+    pub(crate) fn is_empty(&self) -> bool {
+        self.uid.is_none() && self.gid.is_none() && self.owner.is_none() && self.group.is_none()
+    }
+
+    /// Fill in the user and group that aren't given from defaults. A user or group given by id or name is kept whole
+    pub(crate) fn inherit(&self, defaults: &Ownership) -> Ownership {
+        let (uid, owner) = match self.uid.is_some() || self.owner.is_some() {
+            true => (self.uid, self.owner.clone()),
+            false => (defaults.uid, defaults.owner.clone()),
+        };
+        let (gid, group) = match self.gid.is_some() || self.group.is_some() {
+            true => (self.gid, self.group.clone()),
+            false => (defaults.gid, defaults.group.clone()),
+        };
+        Ownership { uid, gid, owner, group }
+    }
+
+    #[cfg(unix)]
+    /// Resolve the ids of the owning user and group, looking up names in the users database
+    pub(crate) fn ids(&self) -> Result<(Option<u32>, Option<u32>), Error> {
+        let uid = match (self.uid, &self.owner) {
+            (Some(uid), _) => Some(uid),
+            (None, Some(owner)) => Some(user_id(owner)?),
+            (None, None) => None,
+        };
+        let gid = match (self.gid, &self.group) {
+            (Some(gid), _) => Some(gid),
+            (None, Some(group)) => Some(group_id(group)?),
+            (None, None) => None,
+        };
+        Ok((uid, gid))
+    }
+
+    #[cfg(unix)]
+    /// Change the owner and group of a file, directory or link. Only root can give files away, 
+    /// so when permission is denied to anyone else a warning is printed instead of failing
+    pub(crate) fn apply(&self, path: &Path) -> Result<(), Error> {
+        if self.is_empty() {
+            return Ok(());
+        }
+
+        let (uid, gid) = self.ids()?;
+        match unix::fs::lchown(path, uid, gid) {
+            Err(e) if e.kind() == io::ErrorKind::PermissionDenied && unsafe { libc::geteuid() } != 0 => {
+                eprintln!("Warning: couldn't change the owner of {:?}, {}", path, e);
+                Ok(())
+            },
+            result => result.map_err(|e| Error::IO(e, format!("{:?}", path))),
+        }
+    }
+
+    #[cfg(windows)]
+    /// Change the owner and group of a file, directory or link. Windows files don't have unix owners, so a warning is printed instead
+    pub(crate) fn apply(&self, path: &Path) -> Result<(), Error> {
+        if !self.is_empty() {
+            eprintln!("Warning: couldn't change the owner of {:?}, owners aren't supported on windows", path);
+        }
+        Ok(())
+    }
+}
+
+impl Display for Ownership {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match (self.uid, &self.owner) {
+            (Some(uid), _) => f.write_fmt(format_args!(" uid {}", uid))?,
+            (None, Some(owner)) => f.write_fmt(format_args!(" owner {}", owner))?,
+            (None, None) => (),
+        }
+        match (self.gid, &self.group) {
+            (Some(gid), _) => f.write_fmt(format_args!(" gid {}", gid)),
+            (None, Some(group)) => f.write_fmt(format_args!(" group {}", group)),
+            (None, None) => Ok(()),
+        }
+    }
+}
+
+#[cfg(unix)]
+/// Size of the buffer first used for users database lookups. Doubled until the entry fits
+const BUFFER_SIZE: usize = 1024;
+
+#[cfg(unix)]
+/// Look up the id of a user by name
+pub(crate) fn user_id(name: &str) -> Result<u32, Error> {
+    let cname = CString::new(name).map_err(|_| Error::UnknownUser(name.to_string()))?;
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    loop {
+        let mut passwd: libc::passwd = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let code = unsafe { libc::getpwnam_r(cname.as_ptr(), &mut passwd, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+        match code {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if result.is_null() => return Err(Error::UnknownUser(name.to_string())),
+            0 => return Ok(passwd.pw_uid),
+            code => return Err(Error::IO(io::Error::from_raw_os_error(code), name.to_string())),
+        }
+    }
+}
+
+#[cfg(unix)]
+/// Look up the id of a group by name
+pub(crate) fn group_id(name: &str) -> Result<u32, Error> {
+    let cname = CString::new(name).map_err(|_| Error::UnknownGroup(name.to_string()))?;
+    let mut buffer = vec![0 as libc::c_char; BUFFER_SIZE];
+    loop {
+        let mut group: libc::group = unsafe { mem::zeroed() };
+        let mut result = ptr::null_mut();
+        let code = unsafe { libc::getgrnam_r(cname.as_ptr(), &mut group, buffer.as_mut_ptr(), buffer.len(), &mut result) };
+        match code {
+            libc::ERANGE => buffer.resize(buffer.len() * 2, 0),
+            0 if result.is_null() => return Err(Error::UnknownGroup(name.to_string())),
+            0 => return Ok(group.gr_gid),
+            code => return Err(Error::IO(io::Error::from_raw_os_error(code), name.to_string())),
+        }
+    }
+}
+
+#[cfg(windows)]
+/// Look up the id of a user by name, windows has no users database
+pub(crate) fn user_id(name: &str) -> Result<u32, Error> {
+    Err(Error::IO(io::Error::new(io::ErrorKind::Unsupported, "users can't be looked up on windows"), name.to_string()))
+}
+
+#[cfg(windows)]
+/// Look up the id of a group by name, windows has no groups database
+pub(crate) fn group_id(name: &str) -> Result<u32, Error> {
+    Err(Error::IO(io::Error::new(io::ErrorKind::Unsupported, "groups can't be looked up on windows"), name.to_string()))
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use crate::Error;
+
+    use super::Ownership;
+
+    #[test]
+    fn test() {
+        let ownership = Ownership { owner: Some("root".to_string()), group: Some("root".to_string()), ..Default::default() };
+        assert_eq!(ownership.ids().unwrap(), (Some(0), Some(0)));
+
+        let ownership = Ownership { owner: Some("no-such-user".to_string()), ..Default::default() };
+        assert!(matches!(ownership.ids(), Err(Error::UnknownUser(_))));
+    }
+}
@@ -1,8 +1,94 @@
-use std::{collections::HashMap};
+use std::{cell::RefCell, collections::{BTreeMap, HashMap}, fs, path::{Path, PathBuf}, str::FromStr, time::SystemTime};
 
-use serde::{ser::{SerializeSeq, SerializeMap}, Deserialize, Serialize, de::{Visitor, Error}, Deserializer};
+use glob::Pattern;
+use regex::Regex;
+use serde::{ser::{SerializeSeq, SerializeMap}, Deserialize, Serialize, de::{DeserializeSeed, Visitor, Error}, Deserializer};
 
-use crate::{FSchema, FileOptions, FileType, Node};
+use crate::{attributes::{parse_acl_entry, parse_attrs, parse_capabilities}, checksums::parse_checksums, command::Step, Command, compress::{decode_gzip_base64, decode_zstd_base64}, interpolate::substitute, condition::{parse_condition, parse_hosts, parse_platforms}, dataset::parse_csv, env_file::parse_env_file, mode::{parse_mode, parse_umask}, owner::Ownership, patch::parse_patch, secret::parse_encrypted, special::{parse_device, parse_size}, times::{format_time, from_seconds, parse_time}, DirectoryOptions, FSchema, FileOptions, FileType, Format, Node, OverwritePolicy, Preserve, Prompt};
+
+/// Strip JSONC comments and trailing commas so the result can be parsed as plain json. 
+/// Stripped characters are replaced with whitespace so error positions still line up with the source.
+pub(crate) fn strip_jsonc(json: &str) -> String {
+    let mut stripped = String::with_capacity(json.len());
+    let mut chars = json.chars().peekable();
+    let mut in_string = false;
+
+    while let Some(c) = chars.next() {
+        if in_string {
+            stripped.push(c);
+            match c {
+                '\\' => if let Some(escaped) = chars.next() {
+                    stripped.push(escaped);
+                },
+                '"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match (c, chars.peek()) {
+            ('"', _) => {
+                in_string = true;
+                stripped.push(c);
+            },
+            ('/', Some('/')) => {
+                stripped.push(' ');
+                for c in chars.by_ref() {
+                    if c == '\n' {
+                        stripped.push(c);
+                        break;
+                    }
+                    stripped.push(' ');
+                }
+            },
+            ('/', Some('*')) => {
+                chars.next();
+                stripped.push_str("  ");
+                let mut prev = ' ';
+                for c in chars.by_ref() {
+                    stripped.push(if c == '\n' { c } else { ' ' });
+                    if prev == '*' && c == '/' {
+                        break;
+                    }
+                    prev = c;
+                }
+            },
+            _ => stripped.push(c),
+        }
+    }
+
+    let mut in_string = false;
+    let mut escaped = false;
+    let mut trailing_comma = None;
+    let mut bytes = stripped.into_bytes();
+    for i in 0..bytes.len() {
+        let c = bytes[i];
+        if in_string {
+            match c {
+                _ if escaped => escaped = false,
+                b'\\' => escaped = true,
+                b'"' => in_string = false,
+                _ => (),
+            }
+            continue;
+        }
+
+        match c {
+            b'"' => {
+                in_string = true;
+                trailing_comma = None;
+            },
+            b',' => trailing_comma = Some(i),
+            b'}' | b']' => if let Some(comma) = trailing_comma.take() {
+                bytes[comma] = b' ';
+            },
+            c if c.is_ascii_whitespace() => (),
+            _ => trailing_comma = None,
+        }
+    }
+
+    String::from_utf8(bytes).unwrap_or_default()
+}
 
 impl Serialize for FSchema {
     fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
@@ -10,16 +96,93 @@ impl Serialize for FSchema {
         S: serde::Serializer 
     {
         let mut map = serializer.serialize_map(None)?;
-        map.serialize_entry("root", &self.root)?;
+        map.serialize_entry("root", &Ordered(&self.root, &self.root_ord, None))?;
         
         map.serialize_entry("prebuild",  &self.prebuild)?;
         map.serialize_entry("postbuild",  &self.postbuild)?;
+        if !self.before_level.is_empty() || !self.after_level.is_empty() {
+            map.serialize_entry("hooks", &Hooks(&self.before_level, &self.after_level))?;
+        }
+        if let Some(umask) = self.umask {
+            map.serialize_entry("umask", &format!("{:03o}", umask))?;
+        }
+        if !self.variables.is_empty() || !self.overrides.is_empty() {
+            map.serialize_entry("variables", &self.variables.iter().chain(&self.overrides).collect::<BTreeMap<_, _>>())?;
+        }
+        if !self.profiles.is_empty() {
+            map.serialize_entry("profiles", &self.profiles)?;
+        }
+        if !self.prompts.is_empty() {
+            map.serialize_entry("prompts", &self.prompts)?;
+        }
+        if let Some(seed) = self.seed {
+            map.serialize_entry("seed", &seed)?;
+        }
+        if let Some(shell) = &self.shell {
+            map.serialize_entry("shell", shell)?;
+        }
+        if let Some(timeout) = self.timeout {
+            map.serialize_entry("timeoutSecs", &timeout)?;
+        }
+        if !self.env.is_empty() {
+            map.serialize_entry("env", &self.env)?;
+        }
+        if let Some(env_file) = &self.env_file {
+            map.serialize_entry("envFile", env_file)?;
+        }
 
         map.end()
     }
 }
 
 impl<'de> Deserialize<'de> for FSchema {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de> 
+    {  
+        let Unresolved { mut schema, scope, profiles } = deserializer.deserialize_map(FSchemaVisitor)?;
+        resolve_contents(&mut schema.root, &mut schema.root_ord, &scope, &mut vec![]).map_err(Error::custom)?;
+        for (name, Unresolved { schema: mut profile, scope: profile_scope, .. }) in profiles {
+            let scope = scope.within(profile_scope);
+            resolve_contents(&mut profile.root, &mut profile.root_ord, &scope, &mut vec![]).map_err(|e| Error::custom(format!("Profile {}, {}", name, e)))?;
+            schema.profiles.insert(name, profile);
+        }
+        Ok(schema)
+    }
+}
+
+/// Schema whose references to definitions and foreach entries haven't been resolved, with its definitions, lists and profiles
+struct Unresolved {
+    schema: FSchema,
+    scope: Scope,
+    profiles: BTreeMap<String, Unresolved>,
+}
+
+/// Node declared once in a schema's definitions, and copied wherever it's referenced
+#[derive(Clone)]
+struct Definition {
+    /// Parameters given by references, replacing "{{param}}" in the node
+    params: Vec<String>,
+    node: Node,
+}
+
+/// Split the name of a definition into its name and parameters, e.g. "service(name, port)"
+fn parse_signature(signature: &str) -> Result<(String, Vec<String>), String> {
+    let (name, params) = match signature.split_once('(') {
+        Some((name, params)) => match params.strip_suffix(')') {
+            Some(params) => (name.trim(), params.split(',').map(str::trim).filter(|param| !param.is_empty()).collect::<Vec<_>>()),
+            None => return Err(format!("Expected definition {} to end with )", signature)),
+        },
+        None => (signature.trim(), vec![]),
+    };
+    let valid = |name: &str| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || matches!(c, '_' | '-'));
+    if !valid(name) || !params.iter().all(|param| valid(param)) {
+        return Err(format!("Invalid definition name {}, expected a name optionally followed by parameters, e.g. service(name, port)", signature));
+    }
+    Ok((name.to_string(), params.into_iter().map(str::to_string).collect()))
+}
+
+impl<'de> Deserialize<'de> for Unresolved {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: Deserializer<'de> 
@@ -28,10 +191,291 @@ impl<'de> Deserialize<'de> for FSchema {
     }
 }
 
+/// Prefix of references to definitions, e.g. "#/definitions/logdir"
+const DEFINITIONS_PREFIX: &str = "#/definitions/";
+
+/// Definitions and lists references and foreach entries are resolved with
+#[derive(Clone, Default)]
+struct Scope {
+    definitions: BTreeMap<String, Definition>,
+    lists: BTreeMap<String, Vec<Item>>,
+}
+
+impl Scope {
+    /// Scope with another's definitions and lists taking precedence
+    fn within(&self, other: Scope) -> Scope {
+        let mut scope = self.clone();
+        scope.definitions.extend(other.definitions);
+        scope.lists.extend(other.lists);
+        scope
+    }
+}
+
+/// Element of a list variable, a string, number or boolean, or a record of them
+#[derive(Clone)]
+enum Item {
+    Scalar(String),
+    Record(BTreeMap<String, String>),
+}
+
+impl Item {
+    /// Arguments substituted into a foreach entry for the item, "{{each}}" for scalars and "{{each.field}}" for records
+    fn arguments(&self, each: &str) -> BTreeMap<String, String> {
+        match self {
+            Item::Scalar(value) => BTreeMap::from([(each.to_string(), value.clone())]),
+            Item::Record(fields) => fields.iter().map(|(field, value)| (format!("{}.{}", each, field), value.clone())).collect(),
+        }
+    }
+}
+
+/// Most entries the ranges in a name can expand to, counting the entries inside expanded directories, so a schema can't make
+/// the parser run out of memory
+const MAX_EXPANDED_ENTRIES: u64 = 10_000;
+
+/// First numeric range in the name of an entry, its bounds, the width numbers are padded to, and where it is in the name
+fn first_range(name: &str) -> Option<(i64, i64, usize, usize, usize)> {
+    thread_local! {
+        static RANGE: Regex = Regex::new(r"\{(-?\d+)\.\.(-?\d+)\}").expect("range pattern is valid");
+    }
+    RANGE.with(|range| range.captures(name).and_then(|captures| {
+        let (start, end) = (&captures[1], &captures[2]);
+        let padded = [start, end].iter().any(|bound| bound.trim_start_matches('-').len() > 1 && bound.trim_start_matches('-').starts_with('0'));
+        let width = if padded { start.len().max(end.len()) } else { 0 };
+        let whole = captures.get(0).expect("the whole match is captured");
+        Some((start.parse::<i64>().ok()?, end.parse::<i64>().ok()?, width, whole.start(), whole.end()))
+    }))
+}
+
+/// Number of names the ranges in the name of an entry expand to
+fn expanded_count(name: &str) -> u64 {
+    match first_range(name) {
+        Some((start, end, _, _, to)) => start.abs_diff(end).saturating_add(1).saturating_mul(expanded_count(&name[to..])),
+        None => 1,
+    }
+}
+
+/// Number of entries in a node, counting itself and, for directories, everything inside them
+fn entry_count(node: &Node) -> u64 {
+    match node {
+        Node::Directory { contents, .. } => contents.values().fold(1, |count, node| count.saturating_add(entry_count(node))),
+        _ => 1,
+    }
+}
+
+/// Expand the numeric ranges in the name of an entry into a name for each number, e.g. "shard-{0..31}" or "{01..12}.log", which pads
+/// the numbers with zeros. Ranges can count down, and names with several ranges are expanded into every combination. Names
+/// expanding to more than MAX_EXPANDED_ENTRIES names are an error
+fn expand_ranges(name: &str) -> Result<Vec<String>, String> {
+    if expanded_count(name) > MAX_EXPANDED_ENTRIES {
+        return Err(format!("Ranges in {} expand to more than {} entries", name, MAX_EXPANDED_ENTRIES));
+    }
+    Ok(expand_bounded(name))
+}
+
+/// Expand the numeric ranges in the name of an entry, once they're known to expand to a bounded number of names
+fn expand_bounded(name: &str) -> Vec<String> {
+    match first_range(name) {
+        Some((start, end, width, from, to)) => {
+            let numbers = match start <= end {
+                true => (start..=end).collect::<Vec<_>>(),
+                false => (end..=start).rev().collect(),
+            };
+            numbers
+                .into_iter()
+                .flat_map(|number| expand_bounded(&name[to..])
+                    .into_iter()
+                    .map(move |rest| format!("{}{:0width$}{}", &name[..from], number, rest, width = width)))
+                .collect()
+        },
+        None => vec![name.to_string()],
+    }
+}
+
+/// Insert an entry into a directory by its path inside it, e.g. "nginx/conf.d/site.conf", creating the directories it's inside.
+/// Directories given more than once are merged, the later's options taking precedence if it gives any
+fn insert_entry(contents: &mut HashMap<String, Node>, ord: &mut Vec<String>, path: &str, node: Node) -> Result<(), String> {
+    let (name, rest) = match path.split_once('/') {
+        Some((name, rest)) => (name, Some(rest)),
+        None => (path, None),
+    };
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(format!("Invalid entry path {}, expected names separated by /", path));
+    }
+
+    let node = match rest {
+        Some(rest) => {
+            let mut inner = HashMap::new();
+            let mut inner_ord = vec![];
+            insert_entry(&mut inner, &mut inner_ord, rest, node)?;
+            Node::Directory { contents: inner, ord: inner_ord, options: DirectoryOptions::default() }
+        },
+        None => node,
+    };
+    match (contents.get_mut(name), node) {
+        (Some(Node::Directory { contents, ord, options }), Node::Directory { contents: mut inner, ord: inner_ord, options: inner_options }) => {
+            if !inner_options.is_default() {
+                *options = inner_options;
+            }
+            for name in inner_ord {
+                let node = inner.remove(&name).expect("ordered entries exist");
+                insert_entry(contents, ord, &name, node)?;
+            }
+        },
+        (Some(_), _) if rest.is_some() => return Err(format!("{} is a directory in {} but not a directory elsewhere", name, path)),
+        (_, node) => if contents.insert(name.to_string(), node).is_none() {
+            ord.push(name.to_string());
+        },
+    }
+    Ok(())
+}
+
+/// Replace the references to definitions inside a directory with copies of the definitions, its foreach entries with an
+/// entry for each item of their lists, and entries with ranges in their names with an entry for each number. Entries
+/// whose names are paths are moved into the directories they name
+fn resolve_contents(contents: &mut HashMap<String, Node>, ord: &mut Vec<String>, scope: &Scope, resolving: &mut Vec<String>) -> Result<(), String> {
+    let mut resolved = HashMap::new();
+    let mut resolved_ord = vec![];
+    for name in ord.drain(..) {
+        let mut entries = vec![];
+        match contents.remove(&name) {
+            Some(Node::Foreach { list, each, node }) => {
+                let items = scope.lists.get(&list).ok_or_else(|| format!("No list variable named {}", list))?;
+                for item in items {
+                    let arguments = item.arguments(&each);
+                    entries.push((substitute(&name, &arguments), instantiate(&node, &arguments)));
+                }
+            },
+            Some(node) => entries.push((name, node)),
+            None => (),
+        }
+        for (name, mut node) in entries {
+            resolve(&mut node, scope, resolving)?;
+            let names = expand_ranges(&name)?;
+            if names.len() > 1 && (names.len() as u64).saturating_mul(entry_count(&node)) > MAX_EXPANDED_ENTRIES {
+                return Err(format!("Ranges in {} expand to more than {} entries", name, MAX_EXPANDED_ENTRIES));
+            }
+            for name in names {
+                insert_entry(&mut resolved, &mut resolved_ord, &name, node.clone())?;
+            }
+        }
+    }
+    *contents = resolved;
+    *ord = resolved_ord;
+    Ok(())
+}
+
+/// Replace a reference to a definition, and the references inside it, with a copy of the definition with the reference's
+/// arguments substituted for its parameters. Definitions referencing themselves, directly or not, are an error
+fn resolve(node: &mut Node, scope: &Scope, resolving: &mut Vec<String>) -> Result<(), String> {
+    match node {
+        Node::Ref { reference, with } => {
+            let name = reference
+                .strip_prefix(DEFINITIONS_PREFIX)
+                .ok_or_else(|| format!("Expected reference to start with {}, found {}", DEFINITIONS_PREFIX, reference))?
+                .to_string();
+            if resolving.contains(&name) {
+                return Err(format!("Definition cycle, {} -> {}", resolving.join(" -> "), name));
+            }
+            let Definition { params, node: definition } = scope.definitions.get(&name).ok_or_else(|| format!("No definition named {}", name))?;
+            if let Some(param) = params.iter().find(|param| !with.contains_key(*param)) {
+                return Err(format!("Reference to {} is missing parameter {}", name, param));
+            }
+            if let Some(argument) = with.keys().find(|argument| !params.contains(argument)) {
+                return Err(format!("Definition {} has no parameter {}", name, argument));
+            }
+
+            let mut definition = instantiate(definition, with);
+            resolving.push(name);
+            resolve(&mut definition, scope, resolving)?;
+            resolving.pop();
+            *node = definition;
+        },
+        Node::Directory { contents, ord, .. } => resolve_contents(contents, ord, scope, resolving)?,
+        Node::Foreach { list, .. } => return Err(format!("Expected foreach over {} to be an entry of a directory", list)),
+        Node::File { .. } | Node::Comment(_) | Node::Delete => (),
+    }
+    Ok(())
+}
+
+/// Copy of a definition's node with arguments substituted for "{{param}}" in the names of entries, file data, comments and the
+/// arguments of references
+fn instantiate(node: &Node, arguments: &BTreeMap<String, String>) -> Node {
+    if arguments.is_empty() {
+        return node.clone();
+    }
+    match node {
+        Node::File { data, options } => Node::File { data: substitute(data, arguments), options: options.clone() },
+        Node::Directory { contents, ord, options } => Node::Directory {
+            contents: ord.iter().map(|name| (substitute(name, arguments), instantiate(&contents[name], arguments))).collect(),
+            ord: ord.iter().map(|name| substitute(name, arguments)).collect(),
+            options: options.clone(),
+        },
+        Node::Comment(comment) => Node::Comment(substitute(comment, arguments)),
+        Node::Delete => Node::Delete,
+        Node::Ref { reference, with } => Node::Ref {
+            reference: reference.clone(),
+            with: with.iter().map(|(param, value)| (param.clone(), substitute(value, arguments))).collect(),
+        },
+        Node::Foreach { list, each, node } => Node::Foreach { list: list.clone(), each: each.clone(), node: Box::new(instantiate(node, arguments)) },
+    }
+}
+
+/// Value of a variable, a string, number or boolean, or a list of them or of records of them
+enum Variable {
+    Scalar(String),
+    List(Vec<Item>),
+}
+
+impl<'de> Deserialize<'de> for Variable {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        let scalar = |value: serde_json::Value| match value {
+            serde_json::Value::String(value) => Some(value),
+            serde_json::Value::Number(_) | serde_json::Value::Bool(_) => Some(value.to_string()),
+            _ => None,
+        };
+        let expected = || Error::custom("Expected a string, number or boolean, or a list of them or of objects of them");
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::Array(items) => items
+                .into_iter()
+                .map(|item| match item {
+                    serde_json::Value::Object(fields) => fields
+                        .into_iter()
+                        .map(|(field, value)| scalar(value).map(|value| (field, value)))
+                        .collect::<Option<BTreeMap<_, _>>>()
+                        .map(Item::Record),
+                    item => scalar(item).map(Item::Scalar),
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(Variable::List)
+                .ok_or_else(expected),
+            value => scalar(value).map(Variable::Scalar).ok_or_else(expected),
+        }
+    }
+}
+
+/// String, number or boolean, e.g. the value of a variable, read as a string
+struct Scalar(String);
+
+impl<'de> Deserialize<'de> for Scalar {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>
+    {
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(value) => Ok(Scalar(value)),
+            value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_)) => Ok(Scalar(value.to_string())),
+            _ => Err(Error::custom("Expected a string, number or boolean")),
+        }
+    }
+}
+
 struct FSchemaVisitor;
 
 impl<'de> Visitor<'de> for FSchemaVisitor {
-    type Value = FSchema;
+    type Value = Unresolved;
 
     fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
         formatter.write_str("a fschema")
@@ -42,6 +486,8 @@ impl<'de> Visitor<'de> for FSchemaVisitor {
             A: serde::de::MapAccess<'de>, 
     {
         let mut schema = FSchema::default();
+        let mut scope = Scope::default();
+        let mut profiles = BTreeMap::new();
         while let Some(key) = map.next_key::<String>()? {
             match key.as_str() {
                 "root" => {
@@ -49,12 +495,75 @@ impl<'de> Visitor<'de> for FSchemaVisitor {
                     schema.root = contents;
                     schema.root_ord = ord;
                 },
-                "prebuild" => schema.prebuild = map.next_value::<Vec<String>>()?,
-                "postbuild" => schema.postbuild = map.next_value::<Vec<String>>()?,
-                _ => return Err(Error::unknown_field(&key, &["root", "prebuild", "postbuild"]))
+                "prebuild" => schema.prebuild = map.next_value::<Vec<Step>>()?,
+                "postbuild" => schema.postbuild = map.next_value::<Vec<Step>>()?,
+                "hooks" => for (hook, commands) in map.next_value::<HashMap<String, Vec<Command>>>()? {
+                    let (hooks, level) = match (hook.strip_prefix("before_level_"), hook.strip_prefix("after_level_")) {
+                        (Some(level), _) => (&mut schema.before_level, level),
+                        (_, Some(level)) => (&mut schema.after_level, level),
+                        _ => return Err(Error::custom(format!("Expected hook to be before_level_N or after_level_N, found {}", hook))),
+                    };
+                    let level = level.parse::<i64>().map_err(|_| Error::custom(format!("Expected level of hook {} to be a number", hook)))?;
+                    hooks.entry(level).or_default().extend(commands);
+                },
+                "umask" => schema.umask = Some(parse_umask(&map.next_value::<ModeText>()?.0).map_err(Error::custom)?),
+                "variables" => for (name, variable) in map.next_value::<BTreeMap<String, Variable>>()? {
+                    match variable {
+                        Variable::Scalar(value) => {
+                            schema.variables.insert(name, value);
+                        },
+                        Variable::List(items) => {
+                            scope.lists.insert(name, items);
+                        },
+                    }
+                },
+                "profiles" => for (name, profile) in map.next_value::<BTreeMap<String, Unresolved>>()? {
+                    if !profile.profiles.is_empty() {
+                        return Err(Error::custom(format!("Profile {} can't have profiles of its own", name)));
+                    }
+                    profiles.insert(name, profile);
+                },
+                "seed" => schema.seed = Some(map.next_value::<u64>()?),
+                "shell" => schema.shell = Some(map.next_value::<String>()?),
+                "timeoutSecs" => schema.timeout = Some(map.next_value::<u64>()?),
+                "prompts" => schema.prompts = map.next_value::<Vec<Prompt>>()?,
+                "env" => schema.env.extend(map.next_value::<BTreeMap<String, String>>()?),
+                "envFile" => {
+                    let path = map.next_value::<String>()?;
+                    schema.loaded_env = read_env_file(&path).map_err(Error::custom)?;
+                    schema.env_file = Some(path);
+                },
+                "data" => for (name, path) in map.next_value::<BTreeMap<String, String>>()? {
+                    scope.lists.insert(name, read_data(&path).map_err(Error::custom)?);
+                },
+                "definitions" => for (signature, node) in map.next_value::<BTreeMap<String, Node>>()? {
+                    let (name, params) = parse_signature(&signature).map_err(Error::custom)?;
+                    scope.definitions.insert(name, Definition { params, node });
+                },
+                _ => return Err(Error::unknown_field(&key, &["root", "prebuild", "postbuild", "hooks", "umask", "variables", "profiles", "prompts", "seed", "shell", "timeoutSecs", "env", "envFile", "definitions", "data"]))
             }
         }
-        Ok(schema)
+        Ok(Unresolved { schema, scope, profiles })
+    }
+}
+
+/// Commands run before and after defer levels, serialized as a map of before_level_N and after_level_N to commands
+struct Hooks<'a>(&'a BTreeMap<i64, Vec<Command>>, &'a BTreeMap<i64, Vec<Command>>);
+
+impl<'a> Serialize for Hooks<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer 
+    {
+        let Hooks(before, after) = self;
+        let mut map = serializer.serialize_map(None)?;
+        for (level, commands) in before.iter() {
+            map.serialize_entry(&format!("before_level_{}", level), commands)?;
+        }
+        for (level, commands) in after.iter() {
+            map.serialize_entry(&format!("after_level_{}", level), commands)?;
+        }
+        map.end()
     }
 }
 
@@ -65,10 +574,10 @@ impl<'de> Deserialize<'de> for Root {
     where
         D: Deserializer<'de> 
     {  
-        if let Node::Directory { contents, ord } = deserializer.deserialize_map(NodeVisitor)? {
-            Ok(Root(contents, ord))
-        } else {
-            return Err(Error::custom("Expected root object"))
+        match deserializer.deserialize_map(NodeVisitor { root: true })? {
+            Node::Directory { options, .. } if !options.is_default() => Err(Error::custom("Options aren't supported on the root directory")),
+            Node::Directory { contents, ord, .. } => Ok(Root(contents, ord)),
+            _ => Err(Error::custom("Expected root object")),
         }
     }
 }
@@ -84,12 +593,296 @@ impl Serialize for FileOptions {
         map.serialize_entry("defer", &self.defer)?;
         map.serialize_entry("internal", &self.internal)?;
         if let Some(mode) = &self.mode {
-            map.serialize_entry("mode", mode)?;
+            map.serialize_entry("mode", &format!("{:o}", mode))?;
+        }
+        if let Some(overwrite) = &self.overwrite {
+            map.serialize_entry("overwrite", overwrite)?;
+        }
+        if !self.depends_on.is_empty() {
+            map.serialize_entry("dependsOn", &self.depends_on)?;
+        }
+        serialize_ownership(&mut map, &self.ownership)?;
+        serialize_times(&mut map, self.accessed, self.modified)?;
+        if !self.xattrs.is_empty() {
+            map.serialize_entry("xattrs", &self.xattrs)?;
+        }
+        if !self.acl.is_empty() {
+            map.serialize_entry("acl", &self.acl)?;
+        }
+        if let Some(capabilities) = &self.capabilities {
+            map.serialize_entry("capabilities", capabilities)?;
+        }
+        if let Some(attrs) = &self.attrs {
+            map.serialize_entry("attrs", attrs)?;
+        }
+        if self.preallocate {
+            map.serialize_entry("preallocate", &self.preallocate)?;
+        }
+        if let Some(seed) = &self.seed {
+            map.serialize_entry("seed", seed)?;
+        }
+        if let Some(sha256) = &self.sha256 {
+            map.serialize_entry("sha256", sha256)?;
+        }
+        if let Some(git_ref) = &self.git_ref {
+            map.serialize_entry("ref", git_ref)?;
+        }
+        if self.shallow {
+            map.serialize_entry("shallow", &self.shallow)?;
+        }
+        if self.strip_components != 0 {
+            map.serialize_entry("stripComponents", &self.strip_components)?;
+        }
+        if !self.include.is_empty() {
+            map.serialize_entry("include", &self.include)?;
+        }
+        if !self.exclude.is_empty() {
+            map.serialize_entry("exclude", &self.exclude)?;
+        }
+        if let Some(regexp) = &self.regexp {
+            map.serialize_entry("regexp", regexp)?;
+        }
+        if let Some(marker) = &self.marker {
+            map.serialize_entry("marker", marker)?;
+        }
+        if let Some(format) = &self.serialize_as {
+            map.serialize_entry("serializeAs", format.name())?;
+        }
+        if !self.preserve.is_empty() {
+            map.serialize_entry("preserve", &self.preserve)?;
+        }
+        if self.raw {
+            map.serialize_entry("raw", &self.raw)?;
+        }
+        if self.expand {
+            map.serialize_entry("expand", &self.expand)?;
+        }
+        if let Some(when) = &self.when {
+            map.serialize_entry("when", when)?;
+        }
+        if !self.platform.is_empty() {
+            map.serialize_entry("platform", &self.platform)?;
+        }
+        if !self.hosts.is_empty() {
+            map.serialize_entry("hosts", &self.hosts)?;
+        }
+        if self.allow_failure {
+            map.serialize_entry("allowFailure", &self.allow_failure)?;
+        }
+        if let Some(timeout) = self.timeout {
+            map.serialize_entry("timeoutSecs", &timeout)?;
+        }
+        if self.retries != 0 {
+            map.serialize_entry("retries", &self.retries)?;
+        }
+        if let Some(shell) = &self.shell {
+            map.serialize_entry("shell", shell)?;
+        }
+        if !self.env.is_empty() {
+            map.serialize_entry("env", &self.env)?;
+        }
+        if let Some(stdin) = &self.stdin {
+            map.serialize_entry("stdin", stdin)?;
+        }
+        if let Some(max_size) = self.max_size {
+            map.serialize_entry("maxSize", &max_size.to_string())?;
+        }
+        serialize_hooks(&mut map, &self.on_create, &self.on_error)?;
+        if let Some(user) = &self.run_as {
+            map.serialize_entry("runAs", user)?;
         }
         map.end()
     }
 }
 
+/// Serialize the commands run once a file or directory is created, or if creating it fails, if there are any
+fn serialize_hooks<M>(map: &mut M, on_create: &[Command], on_error: &[Command]) -> Result<(), M::Error>
+where
+    M: SerializeMap
+{
+    if !on_create.is_empty() {
+        map.serialize_entry("onCreate", on_create)?;
+    }
+    if !on_error.is_empty() {
+        map.serialize_entry("onError", on_error)?;
+    }
+    Ok(())
+}
+
+/// Serialize the access and modification times of a file or directory, if they are set
+fn serialize_times<M>(map: &mut M, accessed: Option<SystemTime>, modified: Option<SystemTime>) -> Result<(), M::Error>
+where
+    M: SerializeMap
+{
+    if let Some(accessed) = accessed {
+        map.serialize_entry("atime", &format_time(accessed))?;
+    }
+    if let Some(modified) = modified {
+        map.serialize_entry("mtime", &format_time(modified))?;
+    }
+    Ok(())
+}
+
+/// A time given as an RFC3339 date or seconds since the unix epoch
+struct Timestamp(SystemTime);
+
+impl<'de> Deserialize<'de> for Timestamp {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+            deserializer.deserialize_any(TimestampVisitor)
+    }
+}
+
+struct TimestampVisitor;
+
+impl<'de> Visitor<'de> for TimestampVisitor {
+    type Value = Timestamp;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an RFC3339 date or seconds since the unix epoch")
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: Error, 
+    {
+        self.visit_f64(v as f64)
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: Error, 
+    {
+        self.visit_f64(v as f64)
+    }
+
+    fn visit_f64<E>(self, v: f64) -> Result<Self::Value, E>
+        where
+            E: Error, 
+    {
+        from_seconds(v).map(Timestamp).ok_or_else(|| Error::custom("Expected a time after the unix epoch"))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: Error,
+    {
+        parse_time(v).map(Timestamp).ok_or_else(|| Error::custom(format!("Expected an RFC3339 date, found {}", v)))
+    }
+}
+
+/// Text of a mode, given as a string or as a number written with octal digits, e.g. 755
+struct ModeText(String);
+
+impl<'de> Deserialize<'de> for ModeText {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+            deserializer.deserialize_any(ModeTextVisitor)
+    }
+}
+
+struct ModeTextVisitor;
+
+impl<'de> Visitor<'de> for ModeTextVisitor {
+    type Value = ModeText;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("an octal, symbolic mode or number written with octal digits")
+    }
+
+    fn visit_u64<E>(self, v: u64) -> Result<Self::Value, E>
+        where
+            E: Error, 
+    {
+        Ok(ModeText(v.to_string()))
+    }
+
+    fn visit_i64<E>(self, v: i64) -> Result<Self::Value, E>
+        where
+            E: Error, 
+    {
+        u64::try_from(v).map_err(|_| Error::custom(format!("Expected a positive mode, found {}", v))).and_then(|v| self.visit_u64(v))
+    }
+
+    fn visit_str<E>(self, v: &str) -> Result<Self::Value, E>
+        where
+            E: Error,
+    {
+        Ok(ModeText(v.to_string()))
+    }
+}
+
+/// Serialize the owner and group of a file or directory, if they are set
+fn serialize_ownership<M>(map: &mut M, ownership: &Ownership) -> Result<(), M::Error>
+where
+    M: SerializeMap
+{
+    if let Some(uid) = &ownership.uid {
+        map.serialize_entry("uid", uid)?;
+    }
+    if let Some(gid) = &ownership.gid {
+        map.serialize_entry("gid", gid)?;
+    }
+    if let Some(owner) = &ownership.owner {
+        map.serialize_entry("owner", owner)?;
+    }
+    if let Some(group) = &ownership.group {
+        map.serialize_entry("group", group)?;
+    }
+    Ok(())
+}
+
+/// Deserialize a list of ACL entries, checking each is valid
+fn deserialize_acl<'de, A>(map: &mut A) -> Result<Vec<String>, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    let acl = map.next_value::<Vec<String>>()?;
+    for entry in &acl {
+        parse_acl_entry(entry).map_err(Error::custom)?;
+    }
+    Ok(acl)
+}
+
+/// Deserialize file attribute flags, checking they're valid
+fn deserialize_attrs<'de, A>(map: &mut A) -> Result<String, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    let attrs = map.next_value::<String>()?;
+    parse_attrs(&attrs).map_err(Error::custom)?;
+    Ok(attrs)
+}
+
+/// Deserialize glob patterns, checking they're valid
+fn deserialize_patterns<'de, A>(map: &mut A) -> Result<Vec<String>, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    let patterns = map.next_value::<Vec<String>>()?;
+    for pattern in &patterns {
+        Pattern::new(pattern).map_err(|e| Error::custom(format!("Invalid glob pattern {}, {}", pattern, e)))?;
+    }
+    Ok(patterns)
+}
+
+/// Deserialize an owner or group field of a file or directory's options. Returns false if the key isn't an ownership field
+fn deserialize_ownership<'de, A>(key: &str, map: &mut A, ownership: &mut Ownership) -> Result<bool, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    match key {
+        "uid" => ownership.uid = Some(map.next_value::<u32>()?),
+        "gid" => ownership.gid = Some(map.next_value::<u32>()?),
+        "owner" => ownership.owner = Some(map.next_value::<String>()?),
+        "group" => ownership.group = Some(map.next_value::<String>()?),
+        _ => return Ok(false),
+    }
+    Ok(true)
+}
+
 impl<'de> Deserialize<'de> for FileOptions {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
@@ -98,6 +891,9 @@ impl<'de> Deserialize<'de> for FileOptions {
     }
 }
 
+/// Fields of file options
+const FILE_OPTIONS: &[&str] = &["ftype", "mode", "defer", "internal", "overwrite", "dependsOn", "uid", "gid", "owner", "group", "atime", "mtime", "xattrs", "acl", "capabilities", "attrs", "preallocate", "seed", "sha256", "ref", "shallow", "stripComponents", "include", "exclude", "regexp", "marker", "serializeAs", "preserve", "raw", "expand", "when", "platform", "hosts", "allowFailure", "timeoutSecs", "retries", "shell", "env", "stdin", "maxSize", "onCreate", "onError", "runAs"];
+
 struct FileOptionsVisitor;
 
 impl<'de> Visitor<'de> for FileOptionsVisitor {
@@ -112,13 +908,352 @@ impl<'de> Visitor<'de> for FileOptionsVisitor {
             A: serde::de::MapAccess<'de>, 
     {
         let mut options = FileOptions::default();
+        while let Some(key) = map.next_key::<String>()? {
+            deserialize_file_option(&key, &mut map, &mut options)?;
+        }
+        Ok(options)
+    }
+}
+
+/// Deserialize a field of file options
+fn deserialize_file_option<'de, A>(key: &str, map: &mut A, options: &mut FileOptions) -> Result<(), A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    match key {
+        "ftype" => options.ftype = map.next_value::<FileType>()?,
+        "mode" => options.mode = Some(parse_mode(&map.next_value::<ModeText>()?.0, false).map_err(Error::custom)?),
+        "defer" => options.defer = map.next_value::<i64>()?,
+        "internal" => options.internal = map.next_value::<bool>()?,
+        "overwrite" => options.overwrite = Some(map.next_value::<OverwritePolicy>()?),
+        "dependsOn" => options.depends_on = map.next_value::<Vec<String>>()?,
+        key if deserialize_ownership(key, map, &mut options.ownership)? => (),
+        "atime" => options.accessed = Some(map.next_value::<Timestamp>()?.0),
+        "mtime" => options.modified = Some(map.next_value::<Timestamp>()?.0),
+        "xattrs" => options.xattrs = map.next_value::<BTreeMap<String, String>>()?,
+        "acl" => options.acl = deserialize_acl(map)?,
+        "capabilities" => {
+            let capabilities = map.next_value::<String>()?;
+            parse_capabilities(&capabilities).map_err(Error::custom)?;
+            options.capabilities = Some(capabilities);
+        },
+        "attrs" => options.attrs = Some(deserialize_attrs(map)?),
+        "preallocate" => options.preallocate = map.next_value::<bool>()?,
+        "seed" => options.seed = Some(map.next_value::<u64>()?),
+        "sha256" => {
+            let sha256 = map.next_value::<String>()?.to_ascii_lowercase();
+            if sha256.len() != 64 || !sha256.chars().all(|c| c.is_ascii_hexdigit()) {
+                return Err(Error::custom(format!("Expected sha256 to be 64 hexadecimal digits, found {}", sha256)));
+            }
+            options.sha256 = Some(sha256);
+        },
+        "ref" => options.git_ref = Some(map.next_value::<String>()?),
+        "shallow" => options.shallow = map.next_value::<bool>()?,
+        "stripComponents" => options.strip_components = map.next_value::<usize>()?,
+        "include" => options.include = deserialize_patterns(map)?,
+        "exclude" => options.exclude = deserialize_patterns(map)?,
+        "regexp" => {
+            let regexp = map.next_value::<String>()?;
+            Regex::new(&regexp).map_err(|e| Error::custom(format!("Invalid regexp {}, {}", regexp, e)))?;
+            options.regexp = Some(regexp);
+        },
+        "marker" => {
+            let marker = map.next_value::<String>()?;
+            if !marker.contains("{mark}") {
+                return Err(Error::custom(format!("Expected marker to contain {{mark}}, found {}", marker)));
+            }
+            options.marker = Some(marker);
+        },
+        "raw" => options.raw = map.next_value::<bool>()?,
+        "expand" => options.expand = map.next_value::<bool>()?,
+        "when" => options.when = Some(deserialize_condition(map)?),
+        "platform" => options.platform = deserialize_platforms(map)?,
+        "hosts" => options.hosts = deserialize_hosts(map)?,
+        "allowFailure" => options.allow_failure = map.next_value::<bool>()?,
+        "timeoutSecs" => options.timeout = Some(map.next_value::<u64>()?),
+        "retries" => options.retries = map.next_value::<u32>()?,
+        "shell" => options.shell = Some(map.next_value::<String>()?),
+        "env" => options.env = map.next_value::<BTreeMap<String, String>>()?,
+        "stdin" => options.stdin = Some(map.next_value::<String>()?),
+        "maxSize" => options.max_size = Some(parse_size(&map.next_value::<String>()?).map_err(Error::custom)?),
+        "onCreate" => options.on_create = map.next_value::<Vec<Command>>()?,
+        "onError" => options.on_error = map.next_value::<Vec<Command>>()?,
+        "runAs" => options.run_as = Some(map.next_value::<String>()?),
+        "preserve" => options.preserve = map.next_value::<Vec<Preserve>>()?,
+        "serializeAs" => options.serialize_as = Some(Format::from_str(&map.next_value::<String>()?).map_err(Error::custom)?),
+        _ => return Err(Error::unknown_field(key, FILE_OPTIONS))
+    }
+    Ok(())
+}
+
+/// Deserialize a "when" condition, checking it's valid
+fn deserialize_condition<'de, A>(map: &mut A) -> Result<String, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    let condition = map.next_value::<String>()?;
+    parse_condition(&condition).map_err(Error::custom)?;
+    Ok(condition)
+}
+
+/// Deserialize a "platform" list, checking the platforms are known
+fn deserialize_platforms<'de, A>(map: &mut A) -> Result<Vec<String>, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    let platforms = map.next_value::<Vec<String>>()?;
+    parse_platforms(&platforms).map_err(Error::custom)?;
+    Ok(platforms)
+}
+
+/// Deserialize a "hosts" list, checking the patterns are valid
+fn deserialize_hosts<'de, A>(map: &mut A) -> Result<Vec<String>, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    let hosts = map.next_value::<Vec<String>>()?;
+    parse_hosts(&hosts).map_err(Error::custom)?;
+    Ok(hosts)
+}
+
+/// Key of the entry holding a directory's options
+const DIRECTORY_OPTIONS_KEY: &str = ".";
+
+/// Key of an object standing in for a file, or its data, whose contents are read from another file while parsing, 
+/// e.g. {"$include": "snippets/nginx.conf"}
+const INCLUDE_KEY: &str = "$include";
+
+/// Key of an object marking a node as deleted when a schema is overlaid on another, e.g. {"$delete": true}
+const DELETE_KEY: &str = "$delete";
+
+/// Key of an object standing in for a copy of one of the schema's definitions, e.g. {"$ref": "#/definitions/logdir"}
+const REF_KEY: &str = "$ref";
+
+/// Key of the arguments given to the parameters of a referenced definition, following its "$ref"
+const WITH_KEY: &str = "with";
+
+/// Key of an object standing in for an entry repeated for each item of a list variable, 
+/// e.g. {"$foreach": "users", "as": "user", "node": {...}}
+const FOREACH_KEY: &str = "$foreach";
+
+thread_local! {
+    /// Directory relative include paths are read from while a schema file is parsed
+    static INCLUDE_DIR: RefCell<Option<PathBuf>> = const { RefCell::new(None) };
+    /// Schema files being parsed, the outermost first, so a schema including itself is caught
+    static INCLUDING: RefCell<Vec<PathBuf>> = const { RefCell::new(vec![]) };
+    /// Files read while the outermost schema file is parsed, the schema files themselves and everything they include
+    static LOADED: RefCell<Vec<PathBuf>> = const { RefCell::new(vec![]) };
+}
+
+/// Parse the schema file at path, reading relative include paths from its directory. Returns the files read while parsing
+/// it, starting with the schema file, which are also recorded for the schema including it if there is one
+pub(crate) fn with_schema_path<T, F>(path: &Path, f: F) -> (T, Vec<PathBuf>)
+where
+    F: FnOnce() -> T
+{
+    let dir = path.parent().unwrap_or(Path::new(""));
+    let previous = INCLUDE_DIR.with(|include_dir| include_dir.replace(Some(dir.to_path_buf())));
+    let previous_loaded = LOADED.with(|loaded| loaded.replace(vec![path.to_path_buf()]));
+    INCLUDING.with(|including| including.borrow_mut().push(fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf())));
+    let result = f();
+    INCLUDING.with(|including| including.borrow_mut().pop());
+    let loaded = LOADED.with(|loaded| loaded.replace(previous_loaded));
+    record_loaded(&loaded);
+    INCLUDE_DIR.with(|include_dir| include_dir.replace(previous));
+    (result, loaded)
+}
+
+/// Record files as loaded by the schema file being parsed, once each
+fn record_loaded(paths: &[PathBuf]) {
+    LOADED.with(|loaded| {
+        let mut loaded = loaded.borrow_mut();
+        for path in paths {
+            if !loaded.contains(path) {
+                loaded.push(path.clone());
+            }
+        }
+    });
+}
+
+/// Deserialize an include, reading the included file. The include must be the only entry of its object. 
+/// Relative paths are read from the directory of the schema being parsed, or the current directory
+fn deserialize_include<'de, A>(map: &mut A) -> Result<String, A::Error>
+where
+    A: serde::de::MapAccess<'de>
+{
+    let path = map.next_value::<String>()?;
+    if map.next_key::<String>()?.is_some() {
+        return Err(Error::custom(format!("Expected {} to be the only key of its object", INCLUDE_KEY)));
+    }
+    read_include(&path).map_err(Error::custom)
+}
+
+/// Path of an included file, relative to the directory of the schema being parsed
+fn include_path(path: &str) -> PathBuf {
+    INCLUDE_DIR.with(|include_dir| match &*include_dir.borrow() {
+        Some(dir) => dir.join(path),
+        None => PathBuf::from(path),
+    })
+}
+
+/// Read an included file, recording it as loaded by the schema file being parsed if there is one
+fn read_include(path: &str) -> Result<String, String> {
+    let path = include_path(path);
+    if INCLUDING.with(|including| !including.borrow().is_empty()) {
+        record_loaded(std::slice::from_ref(&path));
+    }
+    fs::read_to_string(&path).map_err(|e| format!("Couldn't include {:?}, {}", path, e))
+}
+
+/// Read a data file into a list, a CSV file's rows or a JSON file's array. Relative paths are read from the schema's directory
+fn read_data(path: &str) -> Result<Vec<Item>, String> {
+    let text = read_include(path)?;
+    let items = match Path::new(path).extension().and_then(|extension| extension.to_str()) {
+        Some("csv") => parse_csv(&text).map(|records| records.into_iter().map(Item::Record).collect()),
+        Some("json") => match serde_json::from_str::<Variable>(&text) {
+            Ok(Variable::List(items)) => Ok(items),
+            Ok(Variable::Scalar(_)) => Err("Expected an array".to_string()),
+            Err(e) => Err(e.to_string()),
+        },
+        _ => Err("Expected a csv or json file".to_string()),
+    };
+    items.map_err(|e| format!("Couldn't read data from {}, {}", path, e))
+}
+
+/// Read the variables of a .env file, relative to the schema's directory
+fn read_env_file(path: &str) -> Result<BTreeMap<String, String>, String> {
+    parse_env_file(&read_include(path)?).map_err(|e| format!("Couldn't read env file {}, {}", path, e))
+}
+
+/// Read the root directory of an included schema file. Schemas including themselves, directly or not, are an error
+fn read_schema_include(path: &str) -> Result<Root, String> {
+    let path = include_path(path);
+    let canonical = fs::canonicalize(&path).map_err(|e| format!("Couldn't include {:?}, {}", path, e))?;
+    let cycle = INCLUDING.with(|including| {
+        let including = including.borrow();
+        including.contains(&canonical).then(|| including
+            .iter()
+            .skip_while(|schema| **schema != canonical)
+            .chain([&canonical])
+            .map(|schema| format!("{:?}", schema))
+            .collect::<Vec<_>>()
+            .join(" -> "))
+    });
+    if let Some(cycle) = cycle {
+        return Err(format!("Include cycle, {}", cycle));
+    }
+
+    let schema = FSchema::from_path(&canonical, None).map_err(|e| format!("Couldn't include {:?}, {}", path, e))?;
+    Ok(Root(schema.root, schema.root_ord))
+}
+
+impl DirectoryOptions {
+    /// Are the options all default, so they don't need to be written
+    pub(crate) fn is_default(&self) -> bool {
+        self.defer.is_none() && self.depends_on.is_empty() && self.mode.is_none() && self.ownership.is_empty() && self.accessed.is_none() && self.modified.is_none() && self.xattrs.is_empty() && self.acl.is_empty() && self.attrs.is_none()
+            && self.default_mode.is_none() && self.default_file_options.is_none() && self.umask.is_none() && self.when.is_none() && self.platform.is_empty() && self.hosts.is_empty()
+            && self.on_create.is_empty() && self.on_error.is_empty()
+    }
+}
+
+impl Serialize for DirectoryOptions {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer 
+    {   
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(defer) = &self.defer {
+            map.serialize_entry("defer", defer)?;
+        }
+        if let Some(mode) = &self.mode {
+            map.serialize_entry("mode", &format!("{:o}", mode))?;
+        }
+        if !self.depends_on.is_empty() {
+            map.serialize_entry("dependsOn", &self.depends_on)?;
+        }
+        serialize_ownership(&mut map, &self.ownership)?;
+        serialize_times(&mut map, self.accessed, self.modified)?;
+        if !self.xattrs.is_empty() {
+            map.serialize_entry("xattrs", &self.xattrs)?;
+        }
+        if !self.acl.is_empty() {
+            map.serialize_entry("acl", &self.acl)?;
+        }
+        if let Some(attrs) = &self.attrs {
+            map.serialize_entry("attrs", attrs)?;
+        }
+        if let Some(mode) = &self.default_mode {
+            map.serialize_entry("defaultMode", mode)?;
+        }
+        if let Some(options) = &self.default_file_options {
+            map.serialize_entry("defaultFileOptions", options)?;
+        }
+        if let Some(umask) = self.umask {
+            map.serialize_entry("umask", &format!("{:03o}", umask))?;
+        }
+        if let Some(when) = &self.when {
+            map.serialize_entry("when", when)?;
+        }
+        if !self.platform.is_empty() {
+            map.serialize_entry("platform", &self.platform)?;
+        }
+        if !self.hosts.is_empty() {
+            map.serialize_entry("hosts", &self.hosts)?;
+        }
+        serialize_hooks(&mut map, &self.on_create, &self.on_error)?;
+        map.end()
+    }
+}
+
+impl<'de> Deserialize<'de> for DirectoryOptions {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de> {
+            deserializer.deserialize_map(DirectoryOptionsVisitor)
+    }
+}
+
+/// Fields of directory options
+const DIRECTORY_OPTIONS: &[&str] = &["defer", "dependsOn", "mode", "uid", "gid", "owner", "group", "atime", "mtime", "xattrs", "acl", "attrs", "defaultMode", "defaultFileOptions", "umask", "when", "platform", "hosts", "onCreate", "onError"];
+
+struct DirectoryOptionsVisitor;
+
+impl<'de> Visitor<'de> for DirectoryOptionsVisitor {
+    type Value = DirectoryOptions;
+
+    fn expecting(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter.write_str("Directory Options")
+    }
+
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
+        where
+            A: serde::de::MapAccess<'de>, 
+    {
+        let mut options = DirectoryOptions::default();
         while let Some(key) = map.next_key::<String>()? {
             match key.as_str() {
-                "ftype" => options.ftype = map.next_value::<FileType>()?,
-                "mode" => options.mode = Some(u32::from_str_radix(&map.next_value::<String>()?, 8).map_err(|_| Error::custom("expected octal number"))?),
-                "defer" => options.defer = map.next_value::<u64>()?,
-                "internal" => options.internal = map.next_value::<bool>()?,
-                _ => return Err(Error::unknown_field(&key, &["ftype", "mode"]))
+                "defer" => options.defer = Some(map.next_value::<i64>()?),
+                "dependsOn" => options.depends_on = map.next_value::<Vec<String>>()?,
+                "mode" => options.mode = Some(parse_mode(&map.next_value::<ModeText>()?.0, true).map_err(Error::custom)?),
+                key if deserialize_ownership(key, &mut map, &mut options.ownership)? => (),
+                "atime" => options.accessed = Some(map.next_value::<Timestamp>()?.0),
+                "mtime" => options.modified = Some(map.next_value::<Timestamp>()?.0),
+                "xattrs" => options.xattrs = map.next_value::<BTreeMap<String, String>>()?,
+                "acl" => options.acl = deserialize_acl(&mut map)?,
+                "attrs" => options.attrs = Some(deserialize_attrs(&mut map)?),
+                "defaultMode" => {
+                    let mode = map.next_value::<ModeText>()?.0;
+                    parse_mode(&mode, false).and(parse_mode(&mode, true)).map_err(Error::custom)?;
+                    options.default_mode = Some(mode);
+                },
+                "defaultFileOptions" => options.default_file_options = Some(Box::new(map.next_value::<FileOptions>()?)),
+                "umask" => options.umask = Some(parse_umask(&map.next_value::<ModeText>()?.0).map_err(Error::custom)?),
+                "when" => options.when = Some(deserialize_condition(&mut map)?),
+                "platform" => options.platform = deserialize_platforms(&mut map)?,
+                "hosts" => options.hosts = deserialize_hosts(&mut map)?,
+                "onCreate" => options.on_create = map.next_value::<Vec<Command>>()?,
+                "onError" => options.on_error = map.next_value::<Vec<Command>>()?,
+                _ => return Err(Error::unknown_field(&key, DIRECTORY_OPTIONS))
             }
         }
         Ok(options)
@@ -137,30 +1272,92 @@ impl Serialize for Node {
                 seq.serialize_element(data)?;
                 seq.end()
             },
-            Node::Directory{contents, ord:_} => {
-                let mut map = serializer.serialize_map(Some(contents.len()))?;
-                for (key, value) in contents {
-                    map.serialize_entry(key, value)?;
+            Node::Directory{contents, ord, options} => Ordered(contents, ord, Some(options)).serialize(serializer),
+            Node::Comment(comment) => serializer.serialize_str(comment),
+            Node::Delete => {
+                let mut map = serializer.serialize_map(Some(1))?;
+                map.serialize_entry(DELETE_KEY, &true)?;
+                map.end()
+            },
+            Node::Foreach { list, each, node } => {
+                let mut map = serializer.serialize_map(Some(3))?;
+                map.serialize_entry(FOREACH_KEY, list)?;
+                map.serialize_entry("as", each)?;
+                map.serialize_entry("node", node)?;
+                map.end()
+            },
+            Node::Ref { reference, with } => {
+                let mut map = serializer.serialize_map(None)?;
+                map.serialize_entry(REF_KEY, reference)?;
+                if !with.is_empty() {
+                    map.serialize_entry(WITH_KEY, with)?;
                 }
                 map.end()
             },
-            Node::Comment(comment) => serializer.serialize_str(comment),
         }
     }
 }
 
+/// Directory contents serialized in their declared order, preceded by the directory's options
+struct Ordered<'a>(&'a HashMap<String, Node>, &'a Vec<String>, Option<&'a DirectoryOptions>);
+
+impl<'a> Serialize for Ordered<'a> {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer 
+    {
+        let Ordered(contents, ord, options) = self;
+        let mut map = serializer.serialize_map(None)?;
+        if let Some(options) = options.filter(|options| !options.is_default()) {
+            map.serialize_entry(DIRECTORY_OPTIONS_KEY, options)?;
+        }
+        for key in ord.iter() {
+            map.serialize_entry(key, &contents[key])?;
+        }
+        map.end()
+    }
+}
+
 impl<'de> Deserialize<'de> for Node {
     fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
     where
         D: serde::Deserializer<'de> 
     {   
-        deserializer.deserialize_any(NodeVisitor)
+        deserializer.deserialize_any(NodeVisitor { root: false })
     }
 }
 
 pub enum InnerFileNode {
-    FileOptions(FileOptions),
-    Data(String)
+    FileOptions(Box<FileOptions>),
+    Data(String),
+    /// Structured data, written to the file in the format given by its serializeAs option
+    Structured(serde_json::Value),
+}
+
+/// Deserializes an element of a file node. Objects and arrays following the file's options are structured data
+struct InnerFileNodeSeed {
+    found_options: bool,
+}
+
+impl<'de> DeserializeSeed<'de> for InnerFileNodeSeed {
+    type Value = InnerFileNode;
+
+    fn deserialize<D>(self, deserializer: D) -> Result<Self::Value, D::Error>
+    where
+        D: serde::Deserializer<'de>
+    {
+        if !self.found_options {
+            return InnerFileNode::deserialize(deserializer);
+        }
+        match serde_json::Value::deserialize(deserializer)? {
+            serde_json::Value::String(data) => Ok(InnerFileNode::Data(data)),
+            serde_json::Value::Object(object) if object.len() == 1 && object.contains_key(INCLUDE_KEY) => match &object[INCLUDE_KEY] {
+                serde_json::Value::String(path) => read_include(path).map(InnerFileNode::Data).map_err(Error::custom),
+                _ => Err(Error::custom(format!("Expected {} to be a path", INCLUDE_KEY))),
+            },
+            value => Ok(InnerFileNode::Structured(value)),
+        }
+    }
 }
 
 impl<'de> Deserialize<'de> for InnerFileNode {
@@ -180,11 +1377,20 @@ impl<'de> Visitor<'de> for InnerFileNodeVisitor {
         formatter.write_str("either file options or file data")
     }
 
-    fn visit_map<A>(self, map: A) -> Result<Self::Value, A::Error>
+    fn visit_map<A>(self, mut map: A) -> Result<Self::Value, A::Error>
         where
             A: serde::de::MapAccess<'de>, 
     {
-        FileOptionsVisitor.visit_map(map).map(|o| InnerFileNode::FileOptions(o))  
+        let mut options = FileOptions::default();
+        let mut first = true;
+        while let Some(key) = map.next_key::<String>()? {
+            if first && key == INCLUDE_KEY {
+                return deserialize_include(&mut map).map(InnerFileNode::Data);
+            }
+            deserialize_file_option(&key, &mut map, &mut options)?;
+            first = false;
+        }
+        Ok(InnerFileNode::FileOptions(Box::new(options)))
     }
 
     fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
@@ -209,7 +1415,10 @@ impl<'de> Visitor<'de> for InnerFileNodeVisitor {
     }
 }
 
-struct NodeVisitor;
+struct NodeVisitor {
+    /// Is the node the schema's root, which is always a directory
+    root: bool,
+}
 
 impl<'de> Visitor<'de> for NodeVisitor {
     type Value = Node;
@@ -225,37 +1434,40 @@ impl<'de> Visitor<'de> for NodeVisitor {
         
         let mut options = None; 
         let mut data = None;
+        let mut structured = None;
         
-        loop {
-            match seq.next_element::<InnerFileNode>()? {
-                Some(inner_node) => match inner_node {
-                    InnerFileNode::FileOptions(found_options) => if options.is_none() {
-                        options = Some(found_options)
-                    },
-                    InnerFileNode::Data(found_data) => if data.is_none() {
-                        data = Some(found_data)
-                    },
+        while let Some(inner_node) = seq.next_element_seed(InnerFileNodeSeed { found_options: options.is_some() })? {
+            match inner_node {
+                InnerFileNode::FileOptions(found_options) => if options.is_none() {
+                    options = Some(*found_options)
+                },
+                InnerFileNode::Data(found_data) => if data.is_none() {
+                    data = Some(found_data)
+                },
+                InnerFileNode::Structured(found_structured) => if structured.is_none() {
+                    structured = Some(found_structured)
                 },
-                None => break,
             }
         }
 
         let options = options.unwrap_or(FileOptions::default());
+        if let (None, Some(structured)) = (&data, structured) {
+            let format = options.serialize_as.ok_or_else(|| Error::custom("Expected serializeAs option for structured file data"))?;
+            let mut serialized = format
+                .to_string_pretty(&structured)
+                .map_err(|e| Error::custom(format!("Couldn't serialize file data as {}, {}", format.name(), e)))?;
+            if !serialized.ends_with('\n') {
+                serialized.push('\n');
+            }
+            data = Some(serialized);
+        }
 
         if let Some(data) = data {
             if let FileType::Hex = options.ftype {
                 if data.len() % 2 != 0 {
                     return Err(Error::custom("Expected len of hex file to be a multiple of 2"))
                 }
-                if !data.chars().all(|c| {
-                    c.is_ascii_digit() || 
-                    c.to_ascii_lowercase() == 'a'|| 
-                    c.to_ascii_lowercase() == 'b'|| 
-                    c.to_ascii_lowercase() == 'c'|| 
-                    c.to_ascii_lowercase() == 'd'|| 
-                    c.to_ascii_lowercase() == 'e'|| 
-                    c.to_ascii_lowercase() == 'f'
-                }) {
+                if !data.chars().all(|c| c.is_ascii_hexdigit()) {
                     return Err(Error::custom("Expected data of hex file to be a hexadecimal number"))
                 }
             } else if let FileType::Bits = options.ftype {
@@ -265,6 +1477,22 @@ impl<'de> Visitor<'de> for NodeVisitor {
                 if !data.chars().all(|c| c == '0' || c == '1') {
                     return Err(Error::custom("Expected data of bit file to be a string of bits"))
                 }
+            } else if let FileType::Device = options.ftype {
+                parse_device(&data).map_err(Error::custom)?;
+            } else if let FileType::Size | FileType::Random = options.ftype {
+                parse_size(&data).map_err(Error::custom)?;
+            } else if let FileType::GzipBase64 = options.ftype {
+                decode_gzip_base64(&data).map_err(Error::custom)?;
+            } else if let FileType::ZstdBase64 = options.ftype {
+                decode_zstd_base64(&data).map_err(Error::custom)?;
+            } else if let FileType::Patch = options.ftype {
+                parse_patch(&data).map_err(Error::custom)?;
+            } else if let FileType::Encrypted = options.ftype {
+                parse_encrypted(&data).map_err(Error::custom)?;
+            } else if let FileType::CopyGlob = options.ftype {
+                Pattern::new(&data).map_err(|e| Error::custom(format!("Invalid glob {}, {}", data, e)))?;
+            } else if let FileType::Checksums = options.ftype {
+                parse_checksums(&data).map_err(Error::custom)?;
             }
 
             Ok(Node::File { options, data })
@@ -279,12 +1507,68 @@ impl<'de> Visitor<'de> for NodeVisitor {
     {
         let mut contents = HashMap::new();
         let mut ord = vec![];
-        while let Some((key, node)) = map.next_entry::<String, Node>()? {
-            contents.insert(key.to_string(), node);
-            ord.push(key);
+        let mut options = DirectoryOptions::default();
+        let mut first = true;
+        let mut next = map.next_key::<String>()?;
+        while let Some(key) = next.take() {
+            let only = std::mem::replace(&mut first, false);
+            if key == INCLUDE_KEY {
+                let path = map.next_value::<String>()?;
+                next = map.next_key::<String>()?;
+                // An include on its own stands in for a file, otherwise it splices another schema's root into the directory
+                if only && next.is_none() && !self.root {
+                    let data = read_include(&path).map_err(Error::custom)?;
+                    return Ok(Node::File { options: FileOptions::default(), data });
+                }
+                let Root(included, included_ord) = read_schema_include(&path).map_err(Error::custom)?;
+                for name in included_ord {
+                    if contents.insert(name.clone(), included[&name].clone()).is_none() {
+                        ord.push(name);
+                    }
+                }
+                continue;
+            }
+            if key == DELETE_KEY && only && !self.root {
+                if !map.next_value::<bool>()? || map.next_key::<String>()?.is_some() {
+                    return Err(Error::custom(format!("Expected {} to be true and the only key of its object", DELETE_KEY)));
+                }
+                return Ok(Node::Delete);
+            }
+            if key == REF_KEY && only && !self.root {
+                let reference = map.next_value::<String>()?;
+                let with = match map.next_key::<String>()?.as_deref() {
+                    Some(WITH_KEY) => map.next_value::<BTreeMap<String, Scalar>>()?.into_iter().map(|(param, Scalar(value))| (param, value)).collect(),
+                    Some(_) => return Err(Error::custom(format!("Expected {} to be followed by nothing but {}", REF_KEY, WITH_KEY))),
+                    None => BTreeMap::new(),
+                };
+                if map.next_key::<String>()?.is_some() {
+                    return Err(Error::custom(format!("Expected {} to be followed by nothing but {}", REF_KEY, WITH_KEY)));
+                }
+                return Ok(Node::Ref { reference, with });
+            }
+            if key == FOREACH_KEY && only && !self.root {
+                let list = map.next_value::<String>()?;
+                let mut each = "item".to_string();
+                let mut node = None;
+                while let Some(key) = map.next_key::<String>()? {
+                    match key.as_str() {
+                        "as" => each = map.next_value::<String>()?,
+                        "node" => node = Some(Box::new(map.next_value::<Node>()?)),
+                        _ => return Err(Error::unknown_field(&key, &["as", "node"])),
+                    }
+                }
+                let node = node.ok_or_else(|| Error::missing_field("node"))?;
+                return Ok(Node::Foreach { list, each, node });
+            }
+            if key == DIRECTORY_OPTIONS_KEY {
+                options = map.next_value::<DirectoryOptions>()?;
+            } else if contents.insert(key.to_string(), map.next_value::<Node>()?).is_none() {
+                ord.push(key);
+            }
+            next = map.next_key::<String>()?;
         }
 
-        Ok(Node::Directory{contents, ord})
+        Ok(Node::Directory{contents, ord, options})
     }
 
     fn visit_string<E>(self, v: String) -> Result<Self::Value, E>
@@ -316,23 +1600,240 @@ mod tests {
 
     use crate::parse::FSchema;
 
-    use super::{Node, FileType, FileOptions};
+    use super::{expand_ranges, Node, FileType, FileOptions};
 
     #[test]
     fn test() {
         let mut root = HashMap::new();
-        root.insert("hello".to_string(), Node::File { options: FileOptions{ftype: FileType::Text, mode: None, defer: 0, internal: false}, data: "Hello, World!".to_string() });
-        root.insert("hex".to_string(), Node::File { options: FileOptions{ftype: FileType::Hex, mode: None, defer: 0, internal: false}, data: "00aF".to_string() });
+        root.insert("hello".to_string(), Node::File { options: FileOptions{ftype: FileType::Text, ..Default::default()}, data: "Hello, World!".to_string() });
+        root.insert("hex".to_string(), Node::File { options: FileOptions{ftype: FileType::Hex, ..Default::default()}, data: "00aF".to_string() });
         root.insert("comment".to_string(), Node::Comment("a comment".to_string()));
 
         let mut dir = HashMap::new();
         dir.insert("file".to_string(), Node::File { options: FileOptions::default(), data: "a file".to_string() });
 
-        root.insert("dir".to_string(), Node::Directory{contents: dir, ord: vec!["file".to_string()]});
+        root.insert("dir".to_string(), Node::Directory{contents: dir, ord: vec!["file".to_string()], options: Default::default()});
 
-        let schema = FSchema{root, root_ord: vec!["hello".to_string(), "hex".to_string(), "comment".to_string(), "dir".to_string()],  postbuild: vec![], prebuild: vec![]};
+        let schema = FSchema{root, root_ord: vec!["hello".to_string(), "hex".to_string(), "comment".to_string(), "dir".to_string()], ..Default::default()};
         let json = serde_json::to_string_pretty(&schema).unwrap();
         println!("{}", json);   
         println!("{:?}", serde_json::from_str::<FSchema>(&json).unwrap())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn jsonc() {
+        let schema = FSchema::from_str(r#"{
+            // line comment
+            "root": {
+                /* block
+                   comment */
+                "file": ["// not a comment", { "ftype": "Text", }],
+                "url": ["http://a/*b*/", ],
+            },
+        }"#).unwrap();
+        assert!(matches!(&schema.root["file"], Node::File { data, .. } if data == "// not a comment"));
+        assert!(matches!(&schema.root["url"], Node::File { data, .. } if data == "http://a/*b*/"));
+    }
+
+    #[test]
+    fn mode() {
+        let schema = FSchema::from_str(r#"{"root": {"file": ["", {"mode": 640}], "dir": {".": {"mode": "u=rwx,g=rx,o="}}}}"#).unwrap();
+        assert!(matches!(&schema.root["file"], Node::File { options: FileOptions { mode: Some(0o640), .. }, .. }));
+        assert!(matches!(&schema.root["dir"], Node::Directory { options, .. } if options.mode == Some(0o750)));
+
+        let json = serde_json::to_string(&schema).unwrap();
+        let schema = FSchema::from_str(&json).unwrap();
+        assert!(matches!(&schema.root["file"], Node::File { options: FileOptions { mode: Some(0o640), .. }, .. }));
+        assert!(FSchema::from_str(r#"{"root": {"file": ["", {"mode": 649}]}}"#).is_err());
+    }
+
+    #[test]
+    fn include() {
+        let dir = std::env::temp_dir().join(format!("fschema-include-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("snippets")).unwrap();
+        std::fs::write(dir.join("snippets/nginx.conf"), "server {}\n").unwrap();
+        std::fs::write(dir.join("schema.json"), r#"{"root": {
+            "nginx.conf": {"$include": "snippets/nginx.conf"},
+            "private.conf": [{"$include": "snippets/nginx.conf"}, {"mode": "600"}]
+        }}"#).unwrap();
+
+        let schema = FSchema::from_path(&dir.join("schema.json"), None).unwrap();
+        assert!(matches!(&schema.root["nginx.conf"], Node::File { data, .. } if data == "server {}\n"));
+        assert!(matches!(&schema.root["private.conf"], Node::File { data, options: FileOptions { mode: Some(0o600), .. } } if data == "server {}\n"));
+        assert_eq!(schema.loaded_files(), [dir.join("schema.json"), dir.join("snippets/nginx.conf")]);
+        assert!(serde_json::to_string(&schema).unwrap().contains(r#""server {}\n""#));
+        assert!(FSchema::from_str(r#"{"root": {"missing": {"$include": "/nonexistent"}}}"#).is_err());
+
+        std::fs::create_dir_all(dir.join("common")).unwrap();
+        std::fs::write(dir.join("common/service.json"), r#"{"root": {"unit": ["[Unit]\n"], "logs": {}}}"#).unwrap();
+        std::fs::write(dir.join("services.json"), r#"{"root": {
+            "$include": "common/service.json",
+            "web": {".": {"mode": "750"}, "$include": "common/service.json", "unit": ["[Unit]\nDescription=web\n"]}
+        }}"#).unwrap();
+        let schema = FSchema::from_path(&dir.join("services.json"), None).unwrap();
+        assert_eq!(schema.root_ord, ["unit", "logs", "web"]);
+        let common = std::fs::canonicalize(dir.join("common/service.json")).unwrap();
+        assert_eq!(schema.loaded_files(), [dir.join("services.json"), common]);
+        assert!(matches!(&schema.root["web"], Node::Directory { contents, ord, .. } 
+            if ord == &["unit", "logs"] && matches!(&contents["unit"], Node::File { data, .. } if data.contains("web"))));
+
+        std::fs::write(dir.join("a.json"), r#"{"root": {"b": {"x": [""], "$include": "b.json"}}}"#).unwrap();
+        std::fs::write(dir.join("b.json"), r#"{"root": {"$include": "a.json"}}"#).unwrap();
+        let cycle = FSchema::from_path(&dir.join("a.json"), None).unwrap_err().to_string();
+        assert!(cycle.contains("Include cycle"), "{}", cycle);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn definitions() {
+        let schema = FSchema::from_str(r##"{
+            "root": {
+                "web": { "logs": {"$ref": "#/definitions/logdir"} },
+                "worker": { "logs": {"$ref": "#/definitions/logdir"} }
+            },
+            "definitions": {
+                "logdir": { ".": {"mode": "750"}, "current": {"$ref": "#/definitions/log"} },
+                "log": ["", {"ftype": "Touch"}]
+            },
+            "profiles": { "dev": { "root": { "debug": {"$ref": "#/definitions/logdir"} } } }
+        }"##).unwrap();
+        for service in ["web", "worker"] {
+            assert!(matches!(&schema.root[service], Node::Directory { contents, .. } if matches!(&contents["logs"], Node::Directory { contents, options, .. } 
+                if options.mode == Some(0o750) && matches!(contents["current"], Node::File { options: FileOptions { ftype: FileType::Touch, .. }, .. }))));
+        }
+        assert!(matches!(schema.profiles["dev"].root["debug"], Node::Directory { .. }));
+
+        assert!(FSchema::from_str(r##"{"root": {"a": {"$ref": "#/definitions/missing"}}}"##).is_err());
+
+        let schema = FSchema::from_str(r##"{
+            "root": {
+                "web": {"$ref": "#/definitions/service", "with": {"name": "web", "port": 8080}},
+                "api": {"$ref": "#/definitions/service", "with": {"name": "api", "port": 9000}}
+            },
+            "definitions": {
+                "service(name, port)": {
+                    "{{name}}.service": ["ExecStart=/usr/bin/{{name}} --port {{port}}\n"],
+                    "logs": {"$ref": "#/definitions/logdir"},
+                    "env": {"$ref": "#/definitions/env", "with": {"file": "{{name}}.env"}}
+                },
+                "env(file)": { "{{file}}": ["PORT={{port}}\n"] },
+                "logdir": {}
+            }
+        }"##).unwrap();
+        assert!(matches!(&schema.root["api"], Node::Directory { contents, ord, .. } if ord == &["api.service", "logs", "env"]
+            && matches!(&contents["api.service"], Node::File { data, .. } if data == "ExecStart=/usr/bin/api --port 9000\n")
+            && matches!(&contents["env"], Node::Directory { contents, .. } if matches!(&contents["api.env"], Node::File { data, .. } if data == "PORT={{port}}\n"))));
+        assert!(FSchema::from_str(r##"{"root": {"a": {"$ref": "#/definitions/s"}}, "definitions": {"s(name)": {}}}"##).is_err());
+        assert!(FSchema::from_str(r##"{"root": {"a": {"$ref": "#/definitions/s", "with": {"port": 1}}}, "definitions": {"s": {}}}"##).is_err());
+        assert!(FSchema::from_str(r##"{"root": {"a": {"$ref": "#/definitions/a"}}, "definitions": {"a": {"b": {"$ref": "#/definitions/a"}}}}"##).is_err());
+    }
+
+    #[test]
+    fn foreach() {
+        let schema = FSchema::from_str(r##"{
+            "variables": { "users": ["alice", "bob"], "services": [{"name": "web", "port": 80}, {"name": "api", "port": 9000}] },
+            "root": {
+                "home": { "user-{{user}}": {"$foreach": "users", "as": "user", "node": { ".profile": ["export USER={{user}}\n"] }} },
+                "{{service.name}}.conf": {"$foreach": "services", "as": "service", "node": ["port = {{service.port}}\n"]}
+            }
+        }"##).unwrap();
+        assert_eq!(schema.root_ord, ["home", "web.conf", "api.conf"]);
+        assert!(matches!(&schema.root["api.conf"], Node::File { data, .. } if data == "port = 9000\n"));
+        assert!(matches!(&schema.root["home"], Node::Directory { ord, contents, .. } if ord == &["user-alice", "user-bob"]
+            && matches!(&contents["user-bob"], Node::Directory { contents, .. } if matches!(&contents[".profile"], Node::File { data, .. } if data == "export USER=bob\n"))));
+        assert!(FSchema::from_str(r##"{"root": {"a": {"$foreach": "missing", "node": [""]}}}"##).is_err());
+    }
+
+    #[test]
+    fn data() {
+        let dir = std::env::temp_dir().join(format!("fschema-data-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("data")).unwrap();
+        std::fs::write(dir.join("data/users.csv"), "login,shell\nalice,/bin/zsh\nbob,/bin/bash\n").unwrap();
+        std::fs::write(dir.join("data/teams.json"), r#"["ops", "dev"]"#).unwrap();
+        std::fs::write(dir.join("schema.json"), r#"{
+            "data": { "users": "data/users.csv", "teams": "data/teams.json" },
+            "root": {
+                "{{user.login}}": {"$foreach": "users", "as": "user", "node": { "shell": ["{{user.shell}}"] }},
+                "teams": { "{{item}}": {"$foreach": "teams", "node": {}} }
+            }
+        }"#).unwrap();
+
+        let schema = FSchema::from_path(&dir.join("schema.json"), None).unwrap();
+        assert_eq!(schema.root_ord, ["alice", "bob", "teams"]);
+        assert!(matches!(&schema.root["bob"], Node::Directory { contents, .. } if matches!(&contents["shell"], Node::File { data, .. } if data == "/bin/bash")));
+        assert!(matches!(&schema.root["teams"], Node::Directory { ord, .. } if ord == &["ops", "dev"]));
+        assert!(FSchema::from_str(r#"{"data": {"users": "/nonexistent.csv"}, "root": {}}"#).is_err());
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn paths() {
+        let schema = FSchema::from_str(r#"{"root": {
+            "etc/nginx/conf.d/site.conf": ["server {}"],
+            "etc/nginx/nginx.conf": ["http {}"],
+            "etc": { ".": {"mode": "755"}, "hosts": [""] },
+            "var/log/{1..2}.log": [""]
+        }}"#).unwrap();
+        assert_eq!(schema.root_ord, ["etc", "var"]);
+        assert!(matches!(&schema.root["etc"], Node::Directory { ord, options, contents } if ord == &["nginx", "hosts"] && options.mode == Some(0o755)
+            && matches!(&contents["nginx"], Node::Directory { ord, .. } if ord == &["conf.d", "nginx.conf"])));
+        assert!(matches!(&schema.root["var"], Node::Directory { contents, .. } if matches!(&contents["log"], Node::Directory { ord, .. } if ord == &["1.log", "2.log"])));
+        assert!(FSchema::from_str(r#"{"root": {"a": [""], "a/b": [""]}}"#).is_err());
+        assert!(FSchema::from_str(r#"{"root": {"a//b": [""]}}"#).is_err());
+        assert!(FSchema::from_str(r#"{"root": {"../b": [""]}}"#).is_err());
+    }
+
+    #[test]
+    fn ranges() {
+        assert_eq!(expand_ranges("shard-{0..3}").unwrap(), ["shard-0", "shard-1", "shard-2", "shard-3"]);
+        assert_eq!(expand_ranges("{08..10}.log").unwrap(), ["08.log", "09.log", "10.log"]);
+        assert_eq!(expand_ranges("{2..1}-{a..b}-{0..1}").unwrap(), ["2-{a..b}-0", "2-{a..b}-1", "1-{a..b}-0", "1-{a..b}-1"]);
+        assert_eq!(expand_ranges("{{name}}").unwrap(), ["{{name}}"]);
+        assert!(expand_ranges("f{0..99999999999}").is_err());
+        assert!(expand_ranges("f{-9223372036854775808..9223372036854775807}").is_err());
+        assert!(expand_ranges("{0..999}-{0..999}").is_err());
+
+        let schema = FSchema::from_str(r#"{"root": {"data": {"shard-{0..31}": {}}, "{1..3}.txt": [""]}}"#).unwrap();
+        assert_eq!(schema.root_ord, ["data", "1.txt", "2.txt", "3.txt"]);
+        assert!(matches!(&schema.root["data"], Node::Directory { ord, .. } if ord.len() == 32 && ord[31] == "shard-31"));
+        assert!(FSchema::from_str(r#"{"root": {"f{0..99999999999}": [""]}}"#).is_err());
+        assert!(FSchema::from_str(r#"{"root": {"{0..999}": {"{0..999}": [""]}}}"#).is_err());
+    }
+
+    #[test]
+    fn serialize_as() {
+        let schema = FSchema::from_str(r#"{"root": {
+            "package.json": [{"serializeAs": "json"}, {"name": "app", "private": true}],
+            "list.json": [{"serializeAs": "json"}, [1, 2]],
+            "missing": [{"mode": "644"}, {"name": "app"}]
+        }}"#);
+        assert!(schema.is_err());
+
+        let schema = FSchema::from_str(r#"{"root": {
+            "package.json": [{"serializeAs": "json"}, {"name": "app", "private": true}],
+            "list.json": [{"serializeAs": "json"}, [1, 2]]
+        }}"#).unwrap();
+        assert!(matches!(&schema.root["package.json"], Node::File { data, .. } if data == "{\n  \"name\": \"app\",\n  \"private\": true\n}\n"));
+        assert!(matches!(&schema.root["list.json"], Node::File { data, .. } if data == "[\n  1,\n  2\n]\n"));
+        let reparsed = FSchema::from_str(&serde_json::to_string(&schema).unwrap()).unwrap();
+        assert!(matches!(&reparsed.root["list.json"], Node::File { data, options } if data == "[\n  1,\n  2\n]\n" && options.serialize_as.is_some()));
+    }
+
+    #[cfg(feature = "ron")]
+    #[test]
+    fn ron() {
+        let schema = FSchema::from_ron_str(r#"{
+            "root": {
+                "piped": ["echo hello", {"ftype": Piped, "defer": 1}],
+                "dir": {
+                    "bits": ["00000001", {"ftype": Bits}],
+                },
+            },
+            "prebuild": ["true"],
+        }"#).unwrap();
+        assert_eq!(schema.root_ord, vec!["piped".to_string(), "dir".to_string()]);
+        assert!(matches!(schema.root["piped"], Node::File { options: FileOptions { ftype: FileType::Piped, defer: 1, .. }, .. }));
+    }
+}
@@ -0,0 +1,128 @@
+/// A hunk of a unified diff
+#[derive(Debug)]
+struct Hunk<'a> {
+    /// Line the hunk starts at in the original file, counting from 1, or 0 if it's empty
+    old_start: usize,
+    /// Line the hunk starts at in the patched file
+    new_start: usize,
+    /// Lines of the original file, context and removed lines
+    old: Vec<&'a str>,
+    /// Lines of the patched file, context and added lines
+    new: Vec<&'a str>,
+}
+
+/// Parse the start line and length of one side of a hunk header, e.g. "12,3" or "12"
+fn parse_range(range: &str) -> Option<(usize, usize)> {
+    match range.split_once(',') {
+        Some((start, length)) => Some((start.parse().ok()?, length.parse().ok()?)),
+        None => Some((range.parse().ok()?, 1)),
+    }
+}
+
+/// Parse the hunks of a unified diff, like diff -u or git diff writes. File headers and other lines outside hunks are ignored
+fn parse_hunks(patch: &str) -> Result<Vec<Hunk<'_>>, String> {
+    let mut hunks = vec![];
+    let mut lines = patch.lines();
+    while let Some(line) = lines.next() {
+        let header = match line.strip_prefix("@@ -") {
+            Some(header) => header,
+            None => continue,
+        };
+        let invalid = || format!("Invalid hunk header in patch, {}", line);
+        let (old_range, new_range) = header
+            .split_once(" @@")
+            .and_then(|(ranges, _)| ranges.split_once(" +"))
+            .ok_or_else(invalid)?;
+        let (old_start, old_length) = parse_range(old_range).ok_or_else(invalid)?;
+        let (new_start, new_length) = parse_range(new_range).ok_or_else(invalid)?;
+
+        let mut hunk = Hunk { old_start, new_start, old: vec![], new: vec![] };
+        while hunk.old.len() < old_length || hunk.new.len() < new_length {
+            let line = lines.next().ok_or_else(|| format!("Patch ends part way through a hunk, {}", header))?;
+            match line.split_at_checked(1).unwrap_or((" ", "")) {
+                (" ", line) => {
+                    hunk.old.push(line);
+                    hunk.new.push(line);
+                },
+                ("-", line) => hunk.old.push(line),
+                ("+", line) => hunk.new.push(line),
+                ("\\", _) => (),
+                _ => return Err(format!("Unexpected line in hunk of patch, {}", line)),
+            }
+        }
+        hunks.push(hunk);
+    }
+
+    if hunks.is_empty() {
+        return Err("Expected a unified diff with at least one hunk".to_string());
+    }
+    Ok(hunks)
+}
+
+/// Apply hunks to lines, or apply them in reverse to undo them. Each hunk is applied where its lines are found nearest to where
+/// it says it starts, allowing for lines added or removed elsewhere. Returns the number of the first hunk that doesn't apply
+fn apply_hunks<'a>(lines: &[&'a str], hunks: &[Hunk<'a>], reverse: bool) -> Result<Vec<&'a str>, usize> {
+    let mut patched = vec![];
+    let mut cursor = 0;
+    let mut shift = 0isize;
+    for (number, hunk) in hunks.iter().enumerate() {
+        let (start, old, new) = match reverse {
+            false => (hunk.old_start, &hunk.old, &hunk.new),
+            true => (hunk.new_start, &hunk.new, &hunk.old),
+        };
+        let stated = start.saturating_sub(1) as isize;
+        let expected = stated + shift;
+        let found = (cursor..=lines.len().saturating_sub(old.len()))
+            .filter(|position| lines[*position..].starts_with(old))
+            .min_by_key(|position| (*position as isize - expected).abs())
+            .ok_or(number + 1)?;
+
+        patched.extend(&lines[cursor..found]);
+        patched.extend(new);
+        cursor = found + old.len();
+        shift = found as isize - stated;
+    }
+    patched.extend(&lines[cursor..]);
+    Ok(patched)
+}
+
+/// Check a unified diff can be parsed
+pub(crate) fn parse_patch(patch: &str) -> Result<(), String> {
+    parse_hunks(patch).map(|_| ())
+}
+
+/// Apply a unified diff to text. Text the diff has already been applied to is left as it is, and a diff that conflicts with the text fails
+pub(crate) fn apply_patch(text: &str, patch: &str) -> Result<String, String> {
+    let hunks = parse_hunks(patch)?;
+    let lines = text.lines().collect::<Vec<_>>();
+    let patched = match apply_hunks(&lines, &hunks, false) {
+        Ok(patched) => patched,
+        Err(_) if apply_hunks(&lines, &hunks, true).is_ok() => return Ok(text.to_string()),
+        Err(hunk) => return Err(format!("Hunk {} of the patch doesn't apply", hunk)),
+    };
+
+    if patched.is_empty() {
+        return Ok(String::new());
+    }
+    Ok(patched.join("\n") + if text.is_empty() || text.ends_with('\n') { "\n" } else { "" })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::apply_patch;
+
+    #[test]
+    fn test() {
+        let original = "listen 80;\nroot /var/www;\nindex index.html;\ngzip off;\n";
+        let patch = "--- a/site.conf\n+++ b/site.conf\n@@ -1,3 +1,3 @@\n-listen 80;\n+listen 8080;\n root /var/www;\n index index.html;\n@@ -4 +4,2 @@\n-gzip off;\n+gzip on;\n+gzip_types text/css;\n";
+        let patched = apply_patch(original, patch).unwrap();
+        assert_eq!(patched, "listen 8080;\nroot /var/www;\nindex index.html;\ngzip on;\ngzip_types text/css;\n");
+        assert_eq!(apply_patch(&patched, patch).unwrap(), patched);
+        assert_eq!(apply_patch(&format!("# moved\n{}", original), patch).unwrap(), format!("# moved\n{}", patched));
+
+        let conflict = apply_patch("listen 443;\nroot /srv;\nindex index.html;\ngzip off;\n", patch);
+        assert_eq!(conflict, Err("Hunk 1 of the patch doesn't apply".to_string()));
+        assert!(apply_patch(original, "not a patch").is_err());
+        assert_eq!(apply_patch("", "@@ -0,0 +1 @@\n+new\n").unwrap(), "new\n");
+    }
+}
@@ -0,0 +1,1717 @@
+use std::{
+    collections::{BTreeMap, HashMap, VecDeque},
+    fmt::Display,
+    fs::{self, Metadata},
+    io,
+    ops::Range,
+    path::{Path, PathBuf},
+    panic,
+    rc::Rc,
+    sync::atomic::{AtomicBool, AtomicUsize, Ordering},
+    thread,
+};
+
+use glob::Pattern;
+use itertools::Itertools;
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroizing;
+
+use crate::{
+    diff::extraneous,
+    archive::{extract, extracted, source},
+    checksums::checksums,
+    policy::check_policy,
+    rollback::Backups,
+    run_as::{create_as, install_commands},
+    command::{check_commands, Step, PATH_VARIABLE, ROOT_VARIABLE},
+    copy::{copied_glob, copy_file, copy_glob, glob_files, preserve},
+    download::{cached, check_offline, download, is_cached, is_url},
+    edit::{edited, is_edit},
+    git::{clone, is_clone},
+    functions::Functions,
+    condition::{holds, on_host, on_platform},
+    interpolate::{expand, expand_home, substitute, substitute_root},
+    manifest::{hash_bytes, hash_file, verify_sha256, Checkpoint, Progress},
+    attributes::{clear_attrs, set_acl, set_attrs, set_capabilities, set_xattrs},
+    compress::{decode_gzip_base64, decode_zstd_base64},
+    mode::{effective_mode, mode_of, parse_mode, set_mode},
+    secret::{decrypt, reveal, write_secret, SECRET_MODE},
+    special::{make_device, make_fifo, hash_random, make_random, make_sized, parse_device, parse_size, special, symlink, Special},
+    resolve_data_path, times::set_times, Cache, Command, CreateOptions, CreationOrder, DirectoryOptions, Error, FSchema, FileOptions, FileType, Manifest, Node, OverwritePolicy,
+};
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+/// An ordered list of operations that creates a file system structure at a root. 
+/// Can be inspected, filtered, reordered or serialized before being executed
+pub struct Plan {
+    root: PathBuf,
+    operations: Vec<Operation>,
+}
+
+impl Plan {
+    /// Create a plan from a list of operations creating a file system structure at root
+    pub fn new(root: PathBuf, operations: Vec<Operation>) -> Plan {
+        Plan { root, operations }
+    }
+
+    /// Location of the root of the file system structure
+    pub fn root(&self) -> &PathBuf {
+        &self.root
+    }
+
+    /// Operations in the order they will be executed
+    pub fn operations(&self) -> &[Operation] {
+        &self.operations
+    }
+
+    /// Mutable access to the operations, for filtering or reordering
+    pub fn operations_mut(&mut self) -> &mut Vec<Operation> {
+        &mut self.operations
+    }
+
+    /// Keep only the operations matching the predicate
+    pub fn retain<F>(&mut self, f: F) 
+    where
+        F: FnMut(&Operation) -> bool
+    {
+        self.operations.retain(f)
+    }
+
+    /// Execute each operation in order, stopping at the first error
+    pub fn execute(&self) -> Result<(), Error> {
+        self.execute_with(&CreateOptions::default())
+    }
+
+    /// Set the access and modification times, then the file attribute flags, of everything created, deepest first, once everything has been created. 
+    /// Creating entries inside a directory, or reading a file, would otherwise change the times, and immutable entries couldn't be changed
+    pub(crate) fn finish(&self, options: &CreateOptions) -> Result<(), Error> {
+        for operation in self.operations.iter().rev() {
+            match operation {
+                Operation::Directory { path, options: directory } => {
+                    clear_attrs(path, &directory.attrs)?;
+                    set_times(path, directory.accessed.or(options.timestamp), directory.modified.or(options.timestamp))?;
+                    set_attrs(path, &directory.attrs)?;
+                },
+                Operation::File { path, options: file, .. } if file.run_as.is_none() && fs::symlink_metadata(path).is_ok() => {
+                    clear_attrs(path, &file.attrs)?;
+                    set_times(path, file.accessed.or(options.timestamp), file.modified.or(options.timestamp))?;
+                    set_attrs(path, &file.attrs)?;
+                },
+                _ => (),
+            }
+        }
+        Ok(())
+    }
+
+    /// Execute each operation in order with the given options, stopping at the first error
+    pub fn execute_with(&self, options: &CreateOptions) -> Result<(), Error> {
+        if options.offline {
+            check_offline(&self.operations)?;
+        }
+        if !options.allow_commands {
+            check_commands(&self.operations)?;
+        }
+        if let Some(policy) = &options.policy {
+            check_policy(policy, &self.operations)?;
+        }
+
+        let fingerprint = serde_json::to_vec(&self.operations)
+            .map(|plan| hash_bytes(&plan))
+            .map_err(|e| Error::IO(e.into(), "plan".to_string()))?;
+        let resumed = match &options.checkpoint {
+            Some(path) if options.resume && path.exists() => Some(Checkpoint::load(path)?)
+                .filter(|checkpoint| checkpoint.fingerprint == fingerprint),
+            _ => None,
+        };
+
+        let mut cache = match &options.cache {
+            Some(path) => Some(Cache::load(path)?),
+            None => None,
+        };
+
+        let mut manifest = match &options.manifest {
+            Some(path) if path.exists() => Manifest::load(path)?,
+            _ => Manifest::new(),
+        };
+        let mut created = Manifest::new();
+        let mut backups = Backups::new();
+        let mut progress = match &options.checkpoint {
+            Some(path) => Some(Progress::start(path, &fingerprint)?),
+            None => None,
+        };
+        for batch in batches(&self.operations) {
+            let pending = batch
+                .clone()
+                .map(|index| {
+                    let operation = &self.operations[index];
+                    let complete = resumed
+                        .as_ref()
+                        .map(|checkpoint| checkpoint.is_complete(&self.root, index, operation))
+                        .unwrap_or(false)
+                        || cache
+                            .as_ref()
+                            .map(|cache| cache.is_fresh(&self.root, operation))
+                            .unwrap_or(false);
+                    let existed = operation.path().map(|path| fs::symlink_metadata(path).is_ok()).unwrap_or(true);
+                    (index, operation, complete, existed)
+                })
+                .collect::<Vec<_>>();
+            if options.rollback_on_error {
+                for (_, operation, ..) in pending.iter().filter(|(.., complete, existed)| !complete && *existed) {
+                    if let Some(path) = operation.path() {
+                        backups.save(path)?;
+                    }
+                }
+            }
+            let mut results = execute_concurrently(
+                &pending.iter().filter(|(.., complete, _)| !complete).map(|(_, operation, ..)| *operation).collect::<Vec<_>>(),
+                options,
+            )
+            .into_iter();
+            let mut outcomes = pending
+                .into_iter()
+                .map(|(index, operation, complete, existed)| (index, operation, existed, if complete { None } else { results.next().flatten() }));
+
+            while let Some((index, operation, existed, result)) = outcomes.next() {
+                if let Some(Err(e)) = result {
+                    if let (Some(cache), Some(path)) = (&cache, &options.cache) {
+                        cache.save(path)?;
+                    }
+                    if !options.rollback_on_error {
+                        return Err(e);
+                    }
+                    for (_, operation, existed, _) in [(index, operation, existed, None)].into_iter().chain(outcomes) {
+                        if !existed {
+                            created.record(&self.root, operation).ok();
+                        }
+                    }
+                    return match created.remove(&self.root).and_then(|_| backups.restore()) {
+                        Ok(_) => Err(e),
+                        Err(rollback) => Err(Error::Rollback(Box::new(e), Box::new(rollback))),
+                    };
+                }
+
+                if let Some(cache) = &mut cache {
+                    cache.record(&self.root, operation);
+                }
+                if options.manifest.is_some() && owned(&manifest, &self.root, operation, existed) {
+                    manifest.record(&self.root, operation)?;
+                }
+                if options.rollback_on_error && !existed {
+                    created.record(&self.root, operation)?;
+                }
+                if let Some(progress) = &mut progress {
+                    progress.record(index, operation)?;
+                }
+            }
+        }
+
+        if let Some(path) = &options.manifest {
+            manifest.save(path)?;
+        }
+        if let (Some(cache), Some(path)) = (&cache, &options.cache) {
+            cache.save(path)?;
+        }
+        if let Some(progress) = progress {
+            progress.finish()?;
+        }
+        self.finish(options)
+    }
+}
+
+/// Was what an operation created made by fschema, so it belongs in the manifest: it didn't exist before the operation,
+/// or an earlier build recorded it. Edits of files are never recorded, as removing them would remove the file they edit
+fn owned(manifest: &Manifest, root: &Path, operation: &Operation, existed: bool) -> bool {
+    match operation {
+        Operation::File { options, .. } if is_edit(options.ftype) => false,
+        operation => !existed || operation
+            .path()
+            .and_then(|path| path.strip_prefix(root).ok())
+            .is_some_and(|relative| manifest.get(relative).is_some()),
+    }
+}
+
+/// Split operations into batches that can be executed concurrently: runs of consecutive files with the same defer level. 
+/// Every other operation is a batch of its own
+pub(crate) fn batches(operations: &[Operation]) -> Vec<Range<usize>> {
+    let mut batches: Vec<Range<usize>> = vec![];
+    for (index, operation) in operations.iter().enumerate() {
+        match (batches.last_mut(), operation) {
+            (Some(batch), Operation::File { options, .. }) if matches!(
+                &operations[batch.start], 
+                Operation::File { options: first, .. } if first.defer == options.defer
+            ) => batch.end = index + 1,
+            _ => batches.push(index..index + 1),
+        }
+    }
+    batches
+}
+
+/// Execute operations using up to `options.parallelism` threads, returning their results in order. 
+/// No further operations are started once one fails, so operations after it may not have a result
+fn execute_concurrently(operations: &[&Operation], options: &CreateOptions) -> Vec<Option<Result<(), Error>>> {
+    let next = AtomicUsize::new(0);
+    let failed = AtomicBool::new(false);
+    let execute = || {
+        let mut results = vec![];
+        while !failed.load(Ordering::Relaxed) {
+            let index = next.fetch_add(1, Ordering::Relaxed);
+            let result = match operations.get(index) {
+                Some(operation) => operation.execute_with(options),
+                None => break,
+            };
+            if result.is_err() {
+                failed.store(true, Ordering::Relaxed);
+            }
+            results.push((index, result));
+        }
+        results
+    };
+
+    let jobs = options.parallelism.min(operations.len());
+    let finished = if jobs <= 1 {
+        execute()
+    } else {
+        thread::scope(|scope| {
+            (0..jobs)
+                .map(|_| scope.spawn(execute))
+                .collect::<Vec<_>>()
+                .into_iter()
+                .flat_map(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+                .collect()
+        })
+    };
+
+    let mut results = operations.iter().map(|_| None).collect::<Vec<_>>();
+    for (index, result) in finished {
+        results[index] = Some(result);
+    }
+    results
+}
+
+impl Display for Plan {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for operation in &self.operations {
+            writeln!(f, "{}", operation)?;
+        }
+        Ok(())
+    }
+}
+
+impl IntoIterator for Plan {
+    type Item = Operation;
+    type IntoIter = std::vec::IntoIter<Operation>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.operations.into_iter()
+    }
+}
+
+impl<'a> IntoIterator for &'a Plan {
+    type Item = &'a Operation;
+    type IntoIter = std::slice::Iter<'a, Operation>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.operations.iter()
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+/// A single step taken whilst creating a file system structure. Paths are resolved against the root the plan was made for
+#[allow(clippy::large_enum_variant)]
+pub enum Operation {
+    /// Run a command
+    Run(Command),
+    /// Run commands in parallel, failing once they've all finished if any of them failed
+    Parallel(Vec<Command>),
+    /// Create a directory
+    Directory{path: PathBuf, options: DirectoryOptions},
+    /// Create a file. Paths stored in the data of path based file types are already resolved
+    File{path: PathBuf, data: String, options: FileOptions},
+    /// Remove an entry that isn't part of the schema, and everything inside it
+    Remove(PathBuf),
+}
+
+impl Display for Operation {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Operation::Run(command) => {
+                f.write_fmt(format_args!("run '{}'", command))?;
+                match &command.cwd {
+                    Some(cwd) => f.write_fmt(format_args!(" in {:?}", cwd)),
+                    None => Ok(()),
+                }
+            },
+            Operation::Parallel(commands) => f.write_fmt(format_args!("run '{}' in parallel", commands.iter().join("', '"))),
+            Operation::Directory { path, options } => {
+                f.write_fmt(format_args!("mkdir {:?}", path))?;
+                if let Some(mode) = options.mode {
+                    f.write_fmt(format_args!(" mode {:o}", mode))?;
+                }
+                f.write_fmt(format_args!("{}", options.ownership))?;
+                write_hooks(f, &options.on_create, &options.on_error)
+            },
+            Operation::Remove(path) => f.write_fmt(format_args!("remove {:?}", path)),
+            Operation::File { path, data, options } => {
+                match options.ftype {
+                    FileType::Text => f.write_fmt(format_args!("write {:?} ({} bytes)", path, data.len()))?,
+                    FileType::Copy => f.write_fmt(format_args!("copy {:?} -> {:?}", data, path))?,
+                    FileType::Link => f.write_fmt(format_args!("link {:?} -> {:?}", path, data))?,
+                    FileType::Piped => f.write_fmt(format_args!("pipe '{}' > {:?}", data, path))?,
+                    FileType::Secret => f.write_fmt(format_args!("write {:?} (secret)", path))?,
+                    FileType::Encrypted => f.write_fmt(format_args!("write {:?} (decrypted)", path))?,
+                    FileType::Hex => f.write_fmt(format_args!("write {:?} ({} bytes)", path, data.len() / 2))?,
+                    FileType::Bits => f.write_fmt(format_args!("write {:?} ({} bytes)", path, data.len() / 8))?,
+                    FileType::Fifo => f.write_fmt(format_args!("mkfifo {:?}", path))?,
+                    FileType::Touch => f.write_fmt(format_args!("touch {:?}", path))?,
+                    FileType::Checksums => f.write_fmt(format_args!("checksum {:?} ({})", path, data.lines().map(str::trim).filter(|line| !line.is_empty()).collect::<Vec<_>>().join(", ")))?,
+                    FileType::Device => f.write_fmt(format_args!("mknod {:?} {}", path, data))?,
+                    FileType::Size => f.write_fmt(format_args!("{} {:?} ({})", if options.preallocate { "fallocate" } else { "truncate" }, path, data))?,
+                    FileType::Random => f.write_fmt(format_args!("write {:?} ({} random)", path, data))?,
+                    FileType::GzipBase64 | FileType::ZstdBase64 => f.write_fmt(format_args!("write {:?} ({} bytes compressed)", path, data.len()))?,
+                    FileType::Append => f.write_fmt(format_args!("append {:?} ({} bytes)", path, data.len()))?,
+                    FileType::LineInFile => f.write_fmt(format_args!("edit {:?} (line '{}')", path, data))?,
+                    FileType::BlockInFile => f.write_fmt(format_args!("edit {:?} (block of {} lines)", path, data.lines().count()))?,
+                    FileType::Patch => f.write_fmt(format_args!("patch {:?}", path))?,
+                    FileType::Merge => f.write_fmt(format_args!("merge {:?} ({} bytes)", path, data.len()))?,
+                    FileType::Archive => f.write_fmt(format_args!("extract {} -> {:?}", data, path))?,
+                    FileType::CopyGlob => f.write_fmt(format_args!("cp {} {:?}", data, path))?,
+                    FileType::Git => f.write_fmt(format_args!("git clone {} {:?}", data, path))?,
+                    FileType::Download => f.write_fmt(format_args!("download {} -> {:?}{}", data, path, if is_cached(data, &options.sha256) { " (cached)" } else { "" }))?,
+                }
+                if let Some(mode) = options.mode {
+                    f.write_fmt(format_args!(" mode {:o}", mode))?;
+                }
+                f.write_fmt(format_args!("{}", options.ownership))?;
+                write_hooks(f, &options.on_create, &options.on_error)
+            },
+        }
+    }
+}
+
+/// Write the commands run once a file or directory is created, and if creating it fails
+fn write_hooks(f: &mut std::fmt::Formatter<'_>, on_create: &[Command], on_error: &[Command]) -> std::fmt::Result {
+    if !on_create.is_empty() {
+        f.write_fmt(format_args!(", then run '{}'", on_create.iter().join("', '")))?;
+    }
+    if !on_error.is_empty() {
+        f.write_fmt(format_args!(", on error run '{}'", on_error.iter().join("', '")))?;
+    }
+    Ok(())
+}
+
+impl Operation {
+    /// Path of the file or directory the operation creates
+    pub fn path(&self) -> Option<&PathBuf> {
+        match self {
+            Operation::Run(_) | Operation::Parallel(_) | Operation::Remove(_) => None,
+            Operation::Directory { path, .. } | Operation::File { path, .. } => Some(path),
+        }
+    }
+
+    /// Perform the operation
+    pub fn execute(&self) -> Result<(), Error> {
+        self.execute_with(&CreateOptions::default())
+    }
+
+    /// Perform the operation with the given options. The onCreate commands of the file or directory it creates are run once
+    /// it's created, or its onError commands if it fails
+    pub fn execute_with(&self, create_options: &CreateOptions) -> Result<(), Error> {
+        let result = self.create(create_options);
+        let (on_create, on_error) = self.hooks();
+        match result {
+            Ok(()) => on_create.iter().try_for_each(|command| command.run(create_options.quiet)),
+            Err(e) => {
+                for command in on_error {
+                    if let Err(hook) = command.run(create_options.quiet) {
+                        eprintln!("Warning: {}, whilst handling {}", hook, e);
+                    }
+                }
+                Err(e)
+            },
+        }
+    }
+
+    /// Commands run once the file or directory the operation creates is created, and if creating it fails
+    pub(crate) fn hooks(&self) -> (&[Command], &[Command]) {
+        match self {
+            Operation::Directory { options, .. } => (&options.on_create, &options.on_error),
+            Operation::File { options, .. } => (&options.on_create, &options.on_error),
+            Operation::Run(_) | Operation::Parallel(_) | Operation::Remove(_) => (&[], &[]),
+        }
+    }
+
+    /// Commands the operation runs, its command, a Piped or Secret file's command, the commands installing a file created as
+    /// another user, and the hooks of the file or directory it creates
+    pub(crate) fn commands(&self) -> Vec<Command> {
+        let (on_create, on_error) = self.hooks();
+        let mut commands = match self {
+            Operation::Run(command) => vec![command.clone()],
+            Operation::Parallel(commands) => commands.clone(),
+            Operation::File { data, options, .. } if matches!(options.ftype, FileType::Piped | FileType::Secret) => vec![options.command(data)],
+            _ => vec![],
+        };
+        if let Operation::File { path, options: options @ FileOptions { run_as: Some(user), .. }, .. } = self {
+            commands.extend(install_commands(user, path, options, false));
+        }
+        commands.into_iter().chain(on_create.iter().cloned()).chain(on_error.iter().cloned()).collect()
+    }
+
+    /// Create the file or directory, run the command or remove the entry the operation is for
+    pub(crate) fn create(&self, create_options: &CreateOptions) -> Result<(), Error> {
+        match self {
+            Operation::Run(command) => command.run(create_options.quiet),
+            Operation::Parallel(commands) => thread::scope(|scope| {
+                commands
+                    .iter()
+                    .map(|command| scope.spawn(|| command.run(create_options.quiet)))
+                    .collect::<Vec<_>>()
+                    .into_iter()
+                    .try_for_each(|handle| handle.join().unwrap_or_else(|e| panic::resume_unwind(e)))
+            }),
+            Operation::Directory { path, options } => {
+                fs::create_dir_all(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+                clear_attrs(path, &options.attrs)?;
+                options.ownership.apply(path)?;
+                set_xattrs(path, &options.xattrs)?;
+                if let Some(mode) = options.mode {
+                    set_mode(path, mode).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+                }
+                set_acl(path, &options.acl)
+            },
+            Operation::Remove(path) => match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).map_err(|e| Error::IO(e, format!("{:?}", path))),
+                Ok(_) => fs::remove_file(path).map_err(|e| Error::IO(e, format!("{:?}", path))),
+                Err(_) => Ok(()),
+            },
+            Operation::File { path, data, options } => match &options.run_as {
+                Some(user) => create_as(user, path, data, options, create_options),
+                None => create_file(path, data, options, create_options),
+            },
+        }
+    }
+}
+
+/// Create a file with the given options
+pub(crate) fn create_file(path: &Path, data: &str, options: &FileOptions, create_options: &CreateOptions) -> Result<(), Error> {
+    create_file_from(path, data, options, create_options, None)
+}
+
+/// Create a file with the given options. A Piped file's command isn't run if its output has already been written to output
+pub(crate) fn create_file_from(path: &Path, data: &str, options: &FileOptions, create_options: &CreateOptions, output: Option<Partial>) -> Result<(), Error> {
+    let preserved;
+    let options = match options.ftype {
+        FileType::Copy if !options.preserve.is_empty() => {
+            preserved = preserve(Path::new(data), options)?;
+            &preserved
+        },
+        _ => options,
+    };
+    let context = || format!("{:?}: [{}, {:?}]", path, data, options.ftype);
+
+    let policy = create_options.overwrite_policy(options);
+    let existing = fs::symlink_metadata(path).ok();
+    if existing.is_some() && policy == OverwritePolicy::Skip && !is_edit(options.ftype) {
+        return Ok(());
+    }
+    if existing.is_some() {
+        clear_attrs(path, &options.attrs)?;
+    }
+
+    let ran = output.is_some();
+    let partial = output.unwrap_or_else(|| Partial(partial(path)));
+    let contents = match options.ftype {
+        FileType::Piped => {
+            if !ran {
+                options.command(data).output_to(&partial.0, options.max_size, create_options.quiet)?;
+            }
+            None
+        },
+        FileType::Checksums => Some(checksums(path, data)?),
+        FileType::Secret => Some(reveal(&options.command(data))?),
+        FileType::Encrypted => Some(decrypt(data, create_options.decryption_key.as_deref())?),
+        ftype if is_edit(ftype) => Some(edited(path, data, options)?),
+        FileType::Download => {
+            download(data, &options.sha256, create_options.offline)?;
+            None
+        },
+        FileType::Archive => {
+            if is_url(data) {
+                download(data, &options.sha256, create_options.offline)?;
+            } else {
+                verify_sha256(Path::new(data), &options.sha256)?;
+            }
+            None
+        },
+        ftype => inline_contents(data, ftype).map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidData, e), context()))?,
+    };
+    let contents = contents.map(Zeroizing::new);
+
+    if let Some(existing) = existing {
+        if unchanged(path, &existing, data, options, contents.as_ref().map(|contents| contents.as_slice()))? {
+            verify_sha256(path, &options.sha256)?;
+            options.ownership.apply(path)?;
+            set_xattrs(path, &options.xattrs)?;
+            if let Some(mode) = options.mode.filter(|mode| mode_of(&existing) != effective_mode(*mode)) {
+                set_mode(path, mode).map_err(|e| Error::IO(e, context()))?;
+            }
+            set_acl(path, &options.acl)?;
+            set_capabilities(path, &options.capabilities)?;
+            return set_times(path, options.accessed.or(create_options.timestamp), options.modified.or(create_options.timestamp));
+        }
+
+        let edit = is_edit(options.ftype) && existing.is_file();
+        match policy {
+            OverwritePolicy::Error | OverwritePolicy::Skip if edit => (),
+            OverwritePolicy::Error => return Err(Error::Exists(path.to_path_buf())),
+            OverwritePolicy::Skip => return Ok(()),
+            OverwritePolicy::Overwrite => if existing.is_dir() && matches!(options.ftype, FileType::Git) {
+                fs::remove_dir_all(path).map_err(|e| Error::IO(e, context()))?;
+            } else if existing.file_type().is_symlink() 
+                || matches!(options.ftype, FileType::Link | FileType::Fifo | FileType::Device | FileType::Git) 
+                || matches!(options.ftype, FileType::Archive | FileType::CopyGlob) && !existing.is_dir() {
+                fs::remove_file(path).map_err(|e| Error::IO(e, context()))?;
+            },
+            OverwritePolicy::Backup => {
+                let mut backup = path.as_os_str().to_os_string();
+                backup.push("~");
+                if edit {
+                    fs::copy(path, &backup).map_err(|e| Error::IO(e, context()))?;
+                } else {
+                    fs::rename(path, &backup).map_err(|e| Error::IO(e, context()))?;
+                }
+            },
+        }
+    }
+
+    match options.ftype {
+        FileType::Piped => fs::rename(&partial.0, path).map_err(|e| Error::IO(e, context()))?,
+        FileType::Text | FileType::Hex | FileType::Bits | FileType::GzipBase64 | FileType::ZstdBase64
+        | FileType::Append | FileType::LineInFile | FileType::BlockInFile | FileType::Patch | FileType::Merge | FileType::Touch | FileType::Checksums => fs::write(path, contents.unwrap_or_default())
+            .map_err(|e| Error::IO(e, context()))?,
+        FileType::Secret | FileType::Encrypted => write_secret(path, &contents.unwrap_or_default())?,
+        FileType::Copy => copy_file(Path::new(data), path)
+            .map_err(|e| Error::IO(e, context()))?,
+        FileType::Download => copy_file(&cached(data, &options.sha256), path)
+            .map_err(|e| Error::IO(e, context()))?,
+        FileType::Link => symlink(data, path)
+                .map_err(|e| Error::IO(e, context()))?,
+        FileType::Fifo => make_fifo(path).map_err(|e| Error::IO(e, context()))?,
+        FileType::Git => clone(path, data, &options.git_ref, options.shallow)?,
+        FileType::Archive => extract(&source(data, &options.sha256), path, &options.selection())?,
+        FileType::CopyGlob => copy_glob(data, path)?,
+        FileType::Device => make_device(path, data).map_err(|e| Error::IO(e, context()))?,
+        FileType::Size => make_sized(path, data, options.preallocate).map_err(|e| Error::IO(e, context()))?,
+        FileType::Random => make_random(path, data, options.seed).map_err(|e| Error::IO(e, context()))?,
+    }
+
+    if let Err(e) = verify_sha256(path, &options.sha256) {
+        fs::remove_file(path).ok();
+        return Err(e);
+    }
+    options.ownership.apply(path)?;
+
+    set_xattrs(path, &options.xattrs)?;
+    if let Some(mode) = options.mode {
+        set_mode(path, mode).map_err(|e| Error::IO(e, context()))?;
+    }
+    set_acl(path, &options.acl)?;
+    set_capabilities(path, &options.capabilities)?;
+    set_times(path, options.accessed.or(create_options.timestamp), options.modified.or(create_options.timestamp))
+}
+
+/// Check whether an existing file already holds what the operation would create. Contents are compared by hash
+pub(crate) fn unchanged(path: &Path, existing: &Metadata, data: &str, options: &FileOptions, contents: Option<&[u8]>) -> Result<bool, Error> {
+    let ftype = options.ftype;
+    if let FileType::Link = ftype {
+        return Ok(existing.file_type().is_symlink() 
+            && fs::read_link(path).map(|target| target.as_os_str() == data).unwrap_or(false));
+    }
+    if let FileType::Fifo = ftype {
+        return Ok(special(existing) == Some(Special::Fifo));
+    }
+    if let FileType::Touch = ftype {
+        return Ok(existing.is_file());
+    }
+    if let FileType::Git = ftype {
+        return Ok(existing.is_dir() && is_clone(path, data));
+    }
+    if let FileType::Archive = ftype {
+        return Ok(existing.is_dir() && extracted(&source(data, &options.sha256), path, &options.selection())?);
+    }
+    if let FileType::CopyGlob = ftype {
+        return Ok(existing.is_dir() && copied_glob(data, path)?);
+    }
+    if let FileType::Device = ftype {
+        return Ok(parse_device(data).map(|device| Some(device) == special(existing)).unwrap_or(false));
+    }
+    if !existing.is_file() {
+        return Ok(false);
+    }
+
+    match (ftype, contents) {
+        (FileType::Size, _) => Ok(parse_size(data).map(|size| size == existing.len()).unwrap_or(false)),
+        (FileType::Random, _) => match (parse_size(data), options.seed) {
+            (Ok(size), Some(seed)) => Ok(size == existing.len() && hash_random(size, seed) == hash_file(path)?),
+            (Ok(size), None) => Ok(size == existing.len()),
+            _ => Ok(false),
+        },
+        (FileType::Copy, _) => {
+            let source = fs::metadata(data).map_err(|e| Error::IO(e, data.to_string()))?;
+            Ok(source.len() == existing.len() && hash_file(Path::new(data))? == hash_file(path)?)
+        },
+        (FileType::Piped, _) => {
+            let output = partial(path);
+            Ok(fs::metadata(&output).map(|output| output.len() == existing.len()).unwrap_or(false) 
+                && hash_file(&output)? == hash_file(path)?)
+        },
+        (FileType::Download, _) => {
+            let source = cached(data, &options.sha256);
+            Ok(fs::metadata(&source).map(|source| source.len() == existing.len()).unwrap_or(false) 
+                && hash_file(&source)? == hash_file(path)?)
+        },
+        (_, Some(contents)) => Ok(contents.len() as u64 == existing.len() && hash_bytes(contents) == hash_file(path)?),
+        _ => Ok(false),
+    }
+}
+
+/// Where a Piped file's command writes its output before it's moved into place, a hidden sibling of the file
+pub(crate) fn partial(path: &Path) -> PathBuf {
+    let name = path.file_name().unwrap_or_default().to_string_lossy();
+    path.with_file_name(format!(".{}.fschema-part", name))
+}
+
+/// Output a Piped file's command is writing, removed once it's dropped unless it's been moved into place
+pub(crate) struct Partial(pub(crate) PathBuf);
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        fs::remove_file(&self.0).ok();
+    }
+}
+
+/// Decode the contents of a file type whose contents are stored in the schema. 
+/// Returns None for file types whose contents come from elsewhere, or why the data can't be decoded
+pub(crate) fn inline_contents(data: &str, ftype: FileType) -> Result<Option<Vec<u8>>, String> {
+    match ftype {
+        FileType::Text => Ok(Some(data.as_bytes().to_vec())),
+        FileType::Hex if !data.len().is_multiple_of(2) || !data.chars().all(|c| c.is_ascii_hexdigit()) => 
+            Err("Expected data of hex file to be a hexadecimal number of even length".to_string()),
+        FileType::Hex => Ok(Some(data.chars()
+            .chunks(2)
+            .into_iter()
+            .map(|byte| u8::from_str_radix(&byte.collect::<String>(), 16).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<u8>, String>>()?)),
+        FileType::Bits if !data.len().is_multiple_of(8) || !data.chars().all(|c| c == '0' || c == '1') => 
+            Err("Expected data of bit file to be a string of bits, a multiple of 8 long".to_string()),
+        FileType::Bits => Ok(Some(data.chars()
+            .chunks(8)
+            .into_iter()
+            .map(|byte| u8::from_str_radix(&byte.collect::<String>(), 2).map_err(|e| e.to_string()))
+            .collect::<Result<Vec<u8>, String>>()?)),
+        FileType::GzipBase64 => decode_gzip_base64(data).map(Some),
+        FileType::ZstdBase64 => decode_zstd_base64(data).map(Some),
+        FileType::Copy | FileType::Link | FileType::Piped | FileType::Fifo | FileType::Device | FileType::Size | FileType::Random | FileType::Download | FileType::Git | FileType::Archive 
+        | FileType::Append | FileType::LineInFile | FileType::BlockInFile | FileType::Patch | FileType::Merge | FileType::Touch | FileType::CopyGlob | FileType::Secret | FileType::Encrypted
+        | FileType::Checksums => Ok(None),
+    }
+}
+
+impl FSchema {
+    /// Plan the operations, in order, that creating the file system structure at root would perform. Nothing is touched on disk
+    pub fn plan(&self, root: PathBuf) -> Result<Plan, Error> {
+        self.plan_with(root, &CreateOptions::default())
+    }
+
+    /// Plan the operations, in order, that creating the file system structure at root with the given options would perform. 
+    /// When syncing, entries on disk inside the schema's directories that aren't part of the schema are removed before anything is created, 
+    /// unless their path relative to root matches one of the exclude patterns, or they are the manifest, checkpoint or cache. 
+    /// Entries directly inside root are only removed if sync_root is set
+    pub fn plan_with(&self, root: PathBuf, create_options: &CreateOptions) -> Result<Plan, Error> {
+        let mut plan = self.plan_nodes(root.clone(), create_options.ordering)?;
+        if !create_options.sync {
+            return Ok(plan);
+        }
+
+        let exclude = create_options.exclude
+            .iter()
+            .map(|pattern| Pattern::new(pattern).map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), pattern.to_string())))
+            .collect::<Result<Vec<Pattern>, Error>>()?;
+        let removals = extraneous(plan.operations())?
+            .into_iter()
+            .filter(|path| {
+                let relative = path.strip_prefix(&root).unwrap_or(path);
+                (create_options.sync_root || path.parent() != Some(root.as_path()))
+                    && !exclude.iter().any(|pattern| pattern.matches_path(relative))
+                    && relative != Path::new(Manifest::DEFAULT_NAME)
+                    && relative != Path::new(Manifest::CHECKPOINT_NAME)
+                    && relative != Path::new(Cache::DEFAULT_NAME)
+                    && Some(path) != create_options.manifest.as_ref()
+                    && Some(path) != create_options.checkpoint.as_ref()
+                    && Some(path) != create_options.cache.as_ref()
+            })
+            .map(Operation::Remove);
+
+        let operations = plan.operations_mut();
+        let first_directory = operations
+            .iter()
+            .position(|operation| matches!(operation, Operation::Directory { .. }))
+            .map(|index| index + 1)
+            .unwrap_or(operations.len());
+        operations.splice(first_directory..first_directory, removals);
+        Ok(plan)
+    }
+
+    /// Files outside of root that the schema copies from when creating the file system structure at root
+    pub fn sources(&self, root: PathBuf) -> Result<Vec<PathBuf>, Error> {
+        let plan = self.plan_nodes(root.clone(), CreationOrder::default())?;
+        Ok(plan
+            .into_iter()
+            .filter_map(|operation| match operation {
+                Operation::File { data, options: FileOptions { ftype: FileType::Copy, .. }, .. } => Some(vec![PathBuf::from(data)]),
+                Operation::File { data, options: FileOptions { ftype: FileType::Archive, .. }, .. } if !is_url(&data) => Some(vec![PathBuf::from(data)]),
+                Operation::File { data, options: FileOptions { ftype: FileType::CopyGlob, .. }, .. } => Some(
+                    glob_files(&data).map(|files| files.into_iter().map(|(source, _)| source).collect()).unwrap_or_default()
+                ),
+                _ => None,
+            })
+            .flatten()
+            .filter(|source| !source.starts_with(&root))
+            .collect())
+    }
+
+    /// Plan the operations creating the schema's nodes, and running its commands
+    fn plan_nodes(&self, root: PathBuf, ordering: CreationOrder) -> Result<Plan, Error> {
+        let levels = self.levels()?;
+        let variables = self.variables();
+        let mut functions = Functions::new(self.seed)?;
+        let mut operations = self.prebuild
+            .iter()
+            .map(|step| self.planned_step(step, &root))
+            .collect::<Vec<Operation>>();
+
+        let inherited = Rc::new(Inherited { umask: self.umask, ..Default::default() });
+        let mut stack = ordered(&self.root_ord, ordering)
+            .into_iter()
+            .map(|name| (name.to_string(), &self.root[name], inherited.clone()))
+            .collect::<VecDeque<(String, &Node, Rc<Inherited>)>>();
+        let mut backstack = VecDeque::new();
+        let mut defered = VecDeque::new();
+
+        let hook_levels = self.before_level.keys().chain(self.after_level.keys());
+        let first = levels.values().chain(hook_levels.clone()).copied().min().unwrap_or(0).min(0);
+        let last = levels.values().chain(hook_levels).copied().max().unwrap_or(0).max(0);
+
+        operations.push(Operation::Directory { path: root.clone(), options: DirectoryOptions::default() });
+
+        for deferal_level in first..=last {
+            operations.extend(self.before_level
+                .get(&deferal_level)
+                .into_iter()
+                .flatten()
+                .map(|command| Operation::Run(self.planned(command, &root))));
+
+            while !stack.is_empty() {
+                while let Some((inner_path, node, inherited)) = stack.pop_front() {
+                    let (when, platform, hosts) = match node {
+                        Node::File { options, .. } => (options.when.as_deref(), options.platform.as_slice(), options.hosts.as_slice()),
+                        Node::Directory { options, .. } => (options.when.as_deref(), options.platform.as_slice(), options.hosts.as_slice()),
+                        Node::Comment(_) | Node::Delete | Node::Ref { .. } | Node::Foreach { .. } => (None, [].as_slice(), [].as_slice()),
+                    };
+                    if !on_platform(platform) || !on_host(hosts, &variables) {
+                        continue;
+                    }
+                    if let Some(when) = when {
+                        if !holds(when, &variables)? {
+                            continue;
+                        }
+                    }
+                    let path = root.join(expand(&substitute(&inner_path, &variables))?);
+
+                    match node {
+                        Node::File { data, options } => {
+                            let level = levels[&inner_path];
+                            if level > deferal_level{
+                                defered.push_back((inner_path, node, inherited));
+                                continue;
+                            }
+
+                            let options = &inherited.file(options);
+                            let data = match (options.raw, options.ftype) {
+                                (true, _) => data.to_string(),
+                                (false, FileType::Copy | FileType::Link | FileType::CopyGlob | FileType::Archive) => expand_home(&substitute(data, &variables))?,
+                                (false, FileType::Piped | FileType::Secret) => substitute_root(&functions.call_all(&substitute(data, &variables))?, &root),
+                                (false, _) if options.expand => functions.call_all(&substitute(&expand(data)?, &variables))?,
+                                (false, _) => functions.call_all(&substitute(data, &variables))?,
+                            };
+                            let (data, options) = match options.ftype {
+                                ftype if matches!(ftype, FileType::Copy | FileType::Link | FileType::CopyGlob) || matches!(ftype, FileType::Archive) && !is_url(&data) => (
+                                    resolve_data_path(&data, options.internal, &root)?.to_string_lossy().to_string(),
+                                    FileOptions { internal: false, defer: level, ..options.clone() },
+                                ),
+                                _ => (data, FileOptions { 
+                                    defer: level, 
+                                    shell: options.shell.clone().or_else(|| self.shell.clone()), 
+                                    timeout: options.timeout.or(self.timeout),
+                                    ..options.clone() 
+                                }),
+                            };
+                            let options = FileOptions {
+                                env: match options.ftype {
+                                    FileType::Piped => piped_env(&self.exported(&options.env), &variables, &root, &path),
+                                    FileType::Secret => self.exported(&options.env),
+                                    _ => options.env.clone(),
+                                },
+                                stdin: options.stdin.as_ref().map(|stdin| root.join(stdin).to_string_lossy().to_string()),
+                                on_create: self.hooks(&options.on_create, &root, &path),
+                                on_error: self.hooks(&options.on_error, &root, &path),
+                                ..options
+                            };
+                            operations.push(Operation::File { path, data, options });
+                        }
+                        Node::Directory{contents, ord, options} => {
+                            if levels[&inner_path] > deferal_level {
+                                defered.push_back((inner_path, node, inherited));
+                                continue;
+                            }
+
+                            let created = DirectoryOptions {
+                                on_create: self.hooks(&options.on_create, &root, &path),
+                                on_error: self.hooks(&options.on_error, &root, &path),
+                                ..inherited.directory(options)
+                            };
+                            operations.push(Operation::Directory { path, options: created });
+
+                            let inherited = Rc::new(inherited.within(options));
+                            backstack.extend(
+                                ordered(ord, ordering)
+                                    .into_iter()
+                                    .map(|name| (inner_path.to_string() + "/" + name, &contents[name], inherited.clone())),
+                            );
+                        }
+                        Node::Comment(_) | Node::Delete | Node::Ref { .. } | Node::Foreach { .. } => (),
+                    }
+                }
+
+                (stack, backstack) = (backstack, stack);
+            }
+            (stack, defered) = (defered, stack);
+
+            operations.extend(self.after_level
+                .get(&deferal_level)
+                .into_iter()
+                .flatten()
+                .map(|command| Operation::Run(self.planned(command, &root))));
+        }
+
+        operations.extend(self.postbuild
+            .iter()
+            .map(|step| self.planned_step(step, &root)));
+
+        Ok(Plan::new(root, operations))
+    }
+
+    /// Plan a prebuild or postbuild step, running a command, or commands in parallel
+    fn planned_step(&self, step: &Step, root: &Path) -> Operation {
+        match step {
+            Step::Command(command) => Operation::Run(self.planned(command, root)),
+            Step::Parallel(commands) => Operation::Parallel(commands.iter().map(|command| self.planned(command, root)).collect()),
+        }
+    }
+
+    /// Plan a command, giving it the schema's variables, environment, shell and timeout
+    fn planned(&self, command: &Command, root: &Path) -> Command {
+        let planned = command.planned(&self.variables(), root, self.shell.as_ref());
+        Command { env: self.exported(&planned.env), timeout: planned.timeout.or(self.timeout), ..planned }
+    }
+
+    /// Environment of a command, that loaded from .env files, then the schema's environment, with the command's own on top
+    fn exported(&self, env: &BTreeMap<String, String>) -> BTreeMap<String, String> {
+        self.loaded_env.iter().chain(&self.env).chain(env).map(|(name, value)| (name.clone(), value.clone())).collect()
+    }
+
+    /// Plan the commands run once a file or directory is created, or if creating it fails. They're given its path as FSCHEMA_PATH
+    fn hooks(&self, commands: &[Command], root: &Path, path: &Path) -> Vec<Command> {
+        commands
+            .iter()
+            .map(|command| {
+                let mut planned = self.planned(command, root);
+                planned.env.entry(PATH_VARIABLE.to_string()).or_insert_with(|| path.to_string_lossy().to_string());
+                planned
+            })
+            .collect()
+    }
+
+    /// Find the level each file and directory is created at: its defer level, its parent's level, 
+    /// or one level after the last of its dependencies, whichever is later. 
+    /// Depending on a directory depends on everything inside it
+    fn levels(&self) -> Result<HashMap<String, i64>, Error> {
+        let mut entries = HashMap::new();
+        let mut directories = HashMap::new();
+        collect_entries(&self.root, &self.root_ord, None, &mut entries, &mut directories);
+
+        let mut levels = HashMap::new();
+        for path in entries.keys() {
+            level(path, &entries, &directories, &mut levels, &mut vec![])?;
+        }
+        Ok(levels)
+    }
+}
+
+#[derive(Default)]
+/// Defaults that files and directories inherit from the directories they're inside
+struct Inherited {
+    /// Mode of everything that doesn't set its own
+    mode: Option<String>,
+    /// Options of files that don't set their own
+    file: FileOptions,
+    /// Umask applied to the permissions of everything without a mode
+    umask: Option<u32>,
+}
+
+impl Inherited {
+    /// Defaults inside a directory, the directory's own defaults taking precedence
+    fn within(&self, directory: &DirectoryOptions) -> Inherited {
+        Inherited {
+            mode: directory.default_mode.clone().or_else(|| self.mode.clone()),
+            file: match &directory.default_file_options {
+                Some(defaults) => inherit(defaults, &self.file),
+                None => self.file.clone(),
+            },
+            umask: directory.umask.or(self.umask),
+        }
+    }
+
+    /// Options of a file with the defaults filled in
+    fn file(&self, options: &FileOptions) -> FileOptions {
+        let mut options = inherit(options, &self.file);
+        if let (None, FileType::Secret | FileType::Encrypted) = (options.mode, options.ftype) {
+            options.mode = Some(SECRET_MODE);
+        }
+        if options.mode.is_none() {
+            options.mode = self.mode.as_ref().and_then(|mode| parse_mode(mode, false).ok());
+        }
+        if let (None, FileType::Text | FileType::Hex | FileType::Bits | FileType::GzipBase64 | FileType::ZstdBase64 | FileType::Piped | FileType::Fifo | FileType::Device | FileType::Size | FileType::Random | FileType::Download | FileType::Touch | FileType::Checksums) = (options.mode, options.ftype) {
+            options.mode = self.umask.map(|umask| 0o666 & !umask);
+        }
+        options
+    }
+
+    /// Options of a directory with the default mode filled in. The defaults it passes on aren't needed to create it
+    fn directory(&self, options: &DirectoryOptions) -> DirectoryOptions {
+        DirectoryOptions {
+            mode: options.mode
+                .or_else(|| self.mode.as_ref().and_then(|mode| parse_mode(mode, true).ok()))
+                .or_else(|| self.umask.map(|umask| 0o777 & !umask)),
+            default_mode: None,
+            default_file_options: None,
+            umask: None,
+            ..options.clone()
+        }
+    }
+}
+
+/// Fill in the options of a file that aren't set from defaults. 
+/// The mode, ownership, times, overwrite policy, extended attributes, ACL, capabilities, attribute flags, user and whether
+/// environment variables are expanded are inherited
+fn inherit(options: &FileOptions, defaults: &FileOptions) -> FileOptions {
+    let mut xattrs = defaults.xattrs.clone();
+    xattrs.extend(options.xattrs.clone());
+    FileOptions {
+        mode: options.mode.or(defaults.mode),
+        overwrite: options.overwrite.or(defaults.overwrite),
+        ownership: options.ownership.inherit(&defaults.ownership),
+        accessed: options.accessed.or(defaults.accessed),
+        modified: options.modified.or(defaults.modified),
+        xattrs,
+        acl: if options.acl.is_empty() { defaults.acl.clone() } else { options.acl.clone() },
+        capabilities: options.capabilities.clone().or_else(|| defaults.capabilities.clone()),
+        attrs: options.attrs.clone().or_else(|| defaults.attrs.clone()),
+        run_as: options.run_as.clone().or_else(|| defaults.run_as.clone()),
+        expand: options.expand || defaults.expand,
+        ..options.clone()
+    }
+}
+
+/// Environment of a Piped file's command, its own with variables and root substituted into them, and the root and the file's path
+fn piped_env(env: &BTreeMap<String, String>, variables: &BTreeMap<String, String>, root: &Path, path: &Path) -> BTreeMap<String, String> {
+    let mut env = env
+        .iter()
+        .map(|(name, value)| (name.clone(), substitute_root(&substitute(value, variables), root)))
+        .collect::<BTreeMap<_, _>>();
+    env.entry(ROOT_VARIABLE.to_string()).or_insert_with(|| root.to_string_lossy().to_string());
+    env.entry(PATH_VARIABLE.to_string()).or_insert_with(|| path.to_string_lossy().to_string());
+    env
+}
+
+/// Names of a directory's entries in the order they should be created
+fn ordered(ord: &[String], ordering: CreationOrder) -> Vec<&String> {
+    let mut names = ord.iter().collect::<Vec<_>>();
+    if let CreationOrder::Alphabetical = ordering {
+        names.sort();
+    }
+    names
+}
+
+/// A file or directory's defer level and dependencies
+struct Entry<'a> {
+    defer: i64,
+    depends_on: &'a [String],
+    /// File of the schema the entry's command reads its input from, created before it
+    stdin: Option<&'a str>,
+    parent: Option<String>,
+    /// Whether the entry is a Checksums file, created after everything else in its directory
+    checksums: bool,
+}
+
+/// Collect every file and directory under a directory by path, and the entries inside every directory. 
+/// Returns the lowest defer level found, or 0 if none are lower
+fn collect_entries<'a>(
+    contents: &'a HashMap<String, Node>, 
+    ord: &[String], 
+    parent: Option<&str>, 
+    entries: &mut HashMap<String, Entry<'a>>, 
+    directories: &mut HashMap<String, Vec<String>>,
+) -> i64 {
+    let mut lowest = 0;
+    for name in ord {
+        let path = match parent {
+            Some(parent) => parent.to_string() + "/" + name,
+            None => name.to_string(),
+        };
+        let entry = match &contents[name] {
+            Node::File { options, .. } => Entry {
+                defer: options.defer,
+                depends_on: &options.depends_on,
+                stdin: options.stdin.as_deref().filter(|stdin| Path::new(stdin).is_relative()),
+                parent: parent.map(str::to_string),
+                checksums: matches!(options.ftype, FileType::Checksums),
+            },
+            Node::Directory { contents, ord, options } => {
+                directories.entry(path.clone()).or_default();
+                let inside = collect_entries(contents, ord, Some(&path), entries, directories);
+                Entry { defer: options.defer.unwrap_or(inside), depends_on: &options.depends_on, stdin: None, parent: parent.map(str::to_string), checksums: false }
+            },
+            Node::Comment(_) | Node::Delete | Node::Ref { .. } | Node::Foreach { .. } => continue,
+        };
+        lowest = lowest.min(entry.defer);
+
+        let mut ancestor = parent;
+        while let Some(directory) = ancestor {
+            directories.entry(directory.to_string()).or_default().push(path.clone());
+            ancestor = directory.rsplit_once('/').map(|(directory, _)| directory);
+        }
+        entries.insert(path, entry);
+    }
+    lowest
+}
+
+/// Find the level a file or directory is created at, finding the levels of its parent and dependencies first
+fn level(
+    path: &str, 
+    entries: &HashMap<String, Entry>, 
+    directories: &HashMap<String, Vec<String>>, 
+    levels: &mut HashMap<String, i64>, 
+    visiting: &mut Vec<String>,
+) -> Result<i64, Error> {
+    if let Some(level) = levels.get(path) {
+        return Ok(*level);
+    }
+    if let Some(start) = visiting.iter().position(|visited| visited == path) {
+        let mut cycle = visiting[start..].to_vec();
+        cycle.push(path.to_string());
+        return Err(Error::DependencyCycle(cycle));
+    }
+
+    visiting.push(path.to_string());
+    let entry = &entries[path];
+    let mut entry_level = entry.defer;
+    if let Some(parent) = &entry.parent {
+        entry_level = entry_level.max(level(parent, entries, directories, levels, visiting)?);
+    }
+    if entry.checksums {
+        let siblings = match &entry.parent {
+            Some(parent) => directories[parent].clone(),
+            None => entries.keys().cloned().collect(),
+        };
+        for sibling in siblings.iter().filter(|sibling| !entries[sibling.as_str()].checksums) {
+            entry_level = entry_level.max(level(sibling, entries, directories, levels, visiting)? + 1);
+        }
+    }
+    for dependency in entry.depends_on.iter().map(String::as_str).chain(entry.stdin) {
+        let dependency = dependency.trim_start_matches("./").trim_matches('/');
+        if !entries.contains_key(dependency) {
+            return Err(Error::UnknownDependency(path.to_string(), dependency.to_string()));
+        }
+        let inside = directories.get(dependency).map(|contents| contents.as_slice()).unwrap_or_default();
+        for dependency in [dependency.to_string()].iter().chain(inside) {
+            entry_level = entry_level.max(level(dependency, entries, directories, levels, visiting)? + 1);
+        }
+    }
+    visiting.pop();
+
+    levels.insert(path.to_string(), entry_level);
+    Ok(entry_level)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{mode::{effective_mode, mode_of}, CreateOptions, CreationOrder, Error, FSchema, OverwritePolicy};
+
+    use super::{batches, partial, Operation, Plan};
+
+    #[test]
+    fn test() {
+        let schema = FSchema::from_str(r#"{
+            "prebuild": ["echo pre"],
+            "root": {
+                "late": ["echo late", {"ftype": "Piped", "defer": 1}],
+                "dir": { ".": {"mode": "700", "uid": 0}, "file": ["data"] },
+                "link": ["dir/file", {"ftype": "Link", "internal": true}]
+            }
+        }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/out")).unwrap();
+        let paths = plan
+            .operations()
+            .iter()
+            .map(|operation| match operation {
+                Operation::Run(command) => command.to_string(),
+                Operation::Parallel(commands) => commands.iter().map(ToString::to_string).collect::<Vec<_>>().join(" & "),
+                Operation::Directory { path, .. } | Operation::Remove(path) => path.to_string_lossy().to_string(),
+                Operation::File { path, .. } => path.to_string_lossy().to_string(),
+            })
+            .collect::<Vec<String>>();
+        assert_eq!(paths, vec!["echo pre", "/out", "/out/dir", "/out/link", "/out/dir/file", "/out/late"]);
+        assert!(matches!(&plan.operations()[3], Operation::File { data, .. } if data == "/out/dir/file"));
+        assert_eq!(plan.operations()[2].to_string(), r#"mkdir "/out/dir" mode 700 uid 0"#);
+
+        let json = serde_json::to_string(&plan).unwrap();
+        assert_eq!(serde_json::from_str::<Plan>(&json).unwrap().operations().len(), plan.operations().len());
+
+        let options = CreateOptions { ordering: CreationOrder::Alphabetical, ..Default::default() };
+        let plan = schema.plan_with(PathBuf::from("/out"), &options).unwrap();
+        assert!(matches!(&plan.operations()[2], Operation::Directory { path, .. } if path.ends_with("dir")));
+
+        let root = std::env::temp_dir().join(format!("fschema-hooks-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{
+            "root": {
+                "file": ["data", {"onCreate": ["echo $FSCHEMA_PATH > \"$ROOT/created\""]}],
+                "missing": ["missing", {"ftype": "Copy", "onError": ["touch \"$ROOT/failed\""]}]
+            }
+        }"#).unwrap();
+        let plan = schema.plan(root.clone()).unwrap();
+        assert!(plan.operations()[1].to_string().contains(", then run 'echo"));
+        plan.operations()[0].execute().unwrap();
+        plan.operations()[1].execute().unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("created")).unwrap().trim(), root.join("file").to_string_lossy());
+        assert!(plan.operations()[2].execute().is_err());
+        assert!(root.join("failed").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn batch() {
+        let schema = FSchema::from_str(r#"{
+            "root": {
+                "a": ["a"],
+                "b": ["b"],
+                "dir": { "c": ["c"] },
+                "d": ["d", {"defer": 1}],
+                "e": ["e", {"defer": 1}]
+            }
+        }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/out")).unwrap();
+        assert_eq!(batches(plan.operations()), vec![0..1, 1..3, 3..4, 4..5, 5..7]);
+    }
+
+    #[test]
+    fn inheritance() {
+        let schema = FSchema::from_str(r#"{
+            "root": {
+                "srv": {
+                    ".": {"defaultMode": "u=rwX,g=rX,o=", "defaultFileOptions": {"owner": "root", "xattrs": {"user.a": "a"}}},
+                    "file": ["data", {"xattrs": {"user.b": "b"}}],
+                    "sub": {
+                        ".": {"defaultFileOptions": {"mode": "600", "uid": 0}},
+                        "secret": ["data"],
+                        "open": ["data", {"mode": "644"}]
+                    }
+                }
+            }
+        }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/out")).unwrap();
+        let modes = plan
+            .operations()
+            .iter()
+            .filter_map(|operation| match operation {
+                Operation::Directory { path, options } => Some((path.to_string_lossy().to_string(), options.mode)),
+                Operation::File { path, options, .. } => Some((path.to_string_lossy().to_string(), options.mode)),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(modes, vec![
+            ("/out".to_string(), None),
+            ("/out/srv".to_string(), None),
+            ("/out/srv/file".to_string(), Some(0o640)),
+            ("/out/srv/sub".to_string(), Some(0o750)),
+            ("/out/srv/sub/secret".to_string(), Some(0o600)),
+            ("/out/srv/sub/open".to_string(), Some(0o644)),
+        ]);
+
+        let file = plan.operations().iter().find_map(|operation| match operation {
+            Operation::File { path, options, .. } if path.ends_with("file") => Some(options),
+            _ => None,
+        }).unwrap();
+        assert_eq!(file.ownership.owner.as_deref(), Some("root"));
+        assert_eq!(file.xattrs.len(), 2);
+        let secret = plan.operations().iter().find_map(|operation| match operation {
+            Operation::File { path, options, .. } if path.ends_with("secret") => Some(options),
+            _ => None,
+        }).unwrap();
+        assert_eq!((secret.ownership.uid, secret.ownership.owner.as_deref()), (Some(0), None));
+
+        let schema = FSchema::from_str(r#"{
+            "umask": "077",
+            "root": {
+                "private": ["data"],
+                "public": { ".": {"umask": "022"}, "file": ["data"], "link": ["file", {"ftype": "Link"}] }
+            }
+        }"#).unwrap();
+        let modes = schema
+            .plan(PathBuf::from("/out"))
+            .unwrap()
+            .into_iter()
+            .filter_map(|operation| match operation {
+                Operation::Directory { options, .. } => Some(options.mode),
+                Operation::File { options, .. } => Some(options.mode),
+                _ => None,
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(modes, vec![None, Some(0o600), Some(0o700), Some(0o644), None]);
+    }
+
+    #[test]
+    fn dependencies() {
+        let schema = FSchema::from_str(r#"{
+            "root": {
+                "c": ["cat b", {"ftype": "Piped", "dependsOn": ["b"]}],
+                "b": ["cat dir/a", {"ftype": "Piped", "dependsOn": ["dir"]}],
+                "dir": { "a": ["a"] }
+            }
+        }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/out")).unwrap();
+        let paths = plan
+            .operations()
+            .iter()
+            .filter_map(|operation| operation.path())
+            .map(|path| path.to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(paths, vec!["/out", "/out/dir", "/out/dir/a", "/out/b", "/out/c"]);
+
+        let schema = FSchema::from_str(r#"{
+            "root": {
+                "late": { ".": {"dependsOn": ["setup"]}, "file": ["data"] },
+                "setup": ["echo setup", {"ftype": "Piped", "defer": 1}],
+                "early": ["data"]
+            }
+        }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/out")).unwrap();
+        let paths = plan
+            .operations()
+            .iter()
+            .filter_map(|operation| operation.path())
+            .map(|path| path.to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(paths, vec!["/out", "/out/early", "/out/setup", "/out/late", "/out/late/file"]);
+        let json = schema.to_string_format(crate::Format::Json).unwrap();
+        assert_eq!(FSchema::from_str(&json).unwrap().plan(PathBuf::from("/out")).unwrap().operations().len(), plan.operations().len());
+
+        let schema = FSchema::from_str(r#"{
+            "root": {
+                "a": ["cat dir/seed", {"ftype": "Piped"}],
+                "dir": { "seed": ["seed", {"defer": -1}], "other": ["other"] }
+            }
+        }"#).unwrap();
+        let paths = schema.plan(PathBuf::from("/out"))
+            .unwrap()
+            .operations()
+            .iter()
+            .filter_map(|operation| operation.path())
+            .map(|path| path.to_string_lossy().to_string())
+            .collect::<Vec<String>>();
+        assert_eq!(paths, vec!["/out", "/out/dir", "/out/dir/seed", "/out/a", "/out/dir/other"]);
+
+        let schema = FSchema::from_str(r#"{ "root": { "a": ["a", {"dependsOn": ["b"]}], "b": ["b", {"dependsOn": ["a"]}] } }"#).unwrap();
+        assert!(matches!(schema.plan(PathBuf::from("/out")), Err(Error::DependencyCycle(_))));
+
+        let schema = FSchema::from_str(r#"{ "root": { "a": ["a", {"dependsOn": ["missing"]}] } }"#).unwrap();
+        assert!(matches!(schema.plan(PathBuf::from("/out")), Err(Error::UnknownDependency(..))));
+    }
+
+    #[test]
+    fn hooks() {
+        let schema = FSchema::from_str(r#"{
+            "root": { "early": ["data"], "late": ["data", {"defer": 1}] },
+            "hooks": { "after_level_0": ["make generate"], "before_level_2": ["make check"] }
+        }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/out")).unwrap();
+        assert!(matches!(&plan.operations()[2], Operation::Run(command) if command.cmd == "make generate"));
+        assert!(matches!(&plan.operations()[3], Operation::File { path, .. } if path.ends_with("late")));
+        assert!(matches!(&plan.operations()[4], Operation::Run(command) if command.cmd == "make check"));
+
+        let json = schema.to_string_format(crate::Format::Json).unwrap();
+        assert_eq!(FSchema::from_str(&json).unwrap().plan(PathBuf::from("/out")).unwrap().operations().len(), 5);
+    }
+
+    #[test]
+    fn append() {
+        let root = std::env::temp_dir().join(format!("fschema-append-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("hosts"), "127.0.0.1 localhost\n").unwrap();
+        let schema = FSchema::from_str(r#"{ "root": { "hosts": ["10.0.0.1 db\n", {"ftype": "Append"}] } }"#).unwrap();
+        let options = CreateOptions { overwrite: crate::OverwritePolicy::Error, ..Default::default() };
+
+        schema.create_with(root.clone(), &options).unwrap();
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("hosts")).unwrap(), "127.0.0.1 localhost\n10.0.0.1 db\n");
+
+        std::fs::write(root.join("hosts"), "127.0.0.1 localhost\n").unwrap();
+        schema.create_with(root.clone(), &CreateOptions { overwrite: crate::OverwritePolicy::Skip, ..Default::default() }).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("hosts")).unwrap(), "127.0.0.1 localhost\n10.0.0.1 db\n");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn touch() {
+        let root = std::env::temp_dir().join(format!("fschema-touch-{}", std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("kept.log"), "history\n").unwrap();
+        let schema = FSchema::from_str(r#"{ "root": { "kept.log": ["", {"ftype": "Touch"}], "new.log": ["", {"ftype": "Touch"}] } }"#).unwrap();
+        let options = CreateOptions { overwrite: crate::OverwritePolicy::Overwrite, ..Default::default() };
+
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("kept.log")).unwrap(), "history\n");
+        assert_eq!(std::fs::read_to_string(root.join("new.log")).unwrap(), "");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn checksums() {
+        let root = std::env::temp_dir().join(format!("fschema-checksums-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": { "release": {
+            "SHA256SUMS": ["*.tar\nbin/*", {"ftype": "Checksums"}],
+            "app.tar": ["tar"],
+            "notes.txt": ["notes"],
+            "bin": { "app": ["echo -n app", {"ftype": "Piped", "defer": 2}] }
+        } } }"#).unwrap();
+        let plan = schema.plan(root.clone()).unwrap();
+        assert!(matches!(plan.operations().last(), Some(Operation::File { path, .. }) if path.ends_with("SHA256SUMS")));
+
+        schema.create(root.clone()).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("release/SHA256SUMS")).unwrap(), concat!(
+            "90aebae315675cbf04612de4f7d5874850f48e0b8dd82becbeaa47ca93f5ebfb  app.tar\n",
+            "a172cedcae47474b615c54d510a5d84a8dea3032e958587430b413538be3f333  bin/app\n",
+        ));
+        assert!(FSchema::from_str(r#"{ "root": { "SUMS": ["[", {"ftype": "Checksums"}] } }"#).is_err());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sha256() {
+        let root = std::env::temp_dir().join(format!("fschema-sha256-{}", std::process::id()));
+        let hash = "3a6eb0790f39ac87c94f3856b2dd2c5d110e6811602261a9a923d3bb23adc8b7";
+        let wrong = "5e1b2c8f79fb58bd6d5dbcb6ef5b22a1d4fbb4df4e99c47af1bdbd96b0bbd1d8";
+        let options = CreateOptions { quiet: true, ..Default::default() };
+        let schema = FSchema::from_str(&format!(r#"{{ "root": {{ "good": ["data", {{"sha256": "{}"}}] }} }}"#, hash)).unwrap();
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("good")).unwrap(), "data");
+
+        for (name, data, ftype) in [("text", "data".to_string(), "Text"), ("piped", "printf data".to_string(), "Piped"), ("copy", root.join("good").display().to_string(), "Copy")] {
+            let schema = FSchema::from_str(&format!(r#"{{ "root": {{ "{}": ["{}", {{"ftype": "{}", "sha256": "{}"}}] }} }}"#, name, data, ftype, wrong)).unwrap();
+            let result = schema.create_with(root.clone(), &options);
+            assert!(matches!(result, Err(Error::Checksum(path, expected, actual)) if path == root.join(name) && expected == wrong && actual == hash), "{}", name);
+            assert!(!root.join(name).exists() && !partial(&root.join(name)).exists(), "{}", name);
+        }
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn when() {
+        let schema = FSchema::from_str(r#"{
+            "variables": { "feature_x": "true" },
+            "root": {
+                "linux.conf": ["", {"when": "os == 'linux' && feature_x"}],
+                "never.conf": ["", {"when": "!feature_x"}],
+                "skipped": { ".": {"when": "os == 'plan9'"}, "inner": [""] },
+                "windows.ini": ["", {"platform": ["windows"]}]
+            }
+        }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/tmp/when")).unwrap();
+        let paths = plan.operations().iter().filter_map(|operation| operation.path().cloned()).collect::<Vec<_>>();
+        assert_eq!(paths.contains(&PathBuf::from("/tmp/when/linux.conf")), cfg!(target_os = "linux"));
+        assert!(!paths.iter().any(|path| path.starts_with("/tmp/when/never.conf") || path.starts_with("/tmp/when/skipped")));
+        assert_eq!(paths.contains(&PathBuf::from("/tmp/when/windows.ini")), cfg!(windows));
+        assert!(FSchema::from_str(r#"{ "root": { "a": ["", {"when": "os =="}] } }"#).is_err());
+    }
+
+    #[test]
+    fn secret() {
+        let root = std::env::temp_dir().join(format!("fschema-secret-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": { "token": ["echo s3cret", {"ftype": "Secret"}] } }"#).unwrap();
+        assert!(!schema.plan(root.clone()).unwrap().to_string().contains("s3cret"));
+
+        schema.create(root.clone()).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("token")).unwrap(), "s3cret\n");
+        assert_eq!(mode_of(&std::fs::metadata(root.join("token")).unwrap()), effective_mode(0o600));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn sync() {
+        let root = std::env::temp_dir().join(format!("fschema-sync-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("src/old")).unwrap();
+        std::fs::write(root.join("schema.json"), "{}").unwrap();
+        std::fs::write(root.join("src/old/mod.rs"), "").unwrap();
+        std::fs::write(root.join("src/lib.rs"), "").unwrap();
+        std::fs::write(root.join("src/local.rs"), "").unwrap();
+        std::fs::write(root.join(crate::Manifest::DEFAULT_NAME), "{}").unwrap();
+        let schema = FSchema::from_str(r#"{ "root": { "src": { "main.rs": ["fn main() {}"] } } }"#).unwrap();
+        let options = CreateOptions { sync: true, exclude: vec!["src/local.*".to_string()], quiet: true, ..Default::default() };
+
+        let plan = schema.plan_with(root.clone(), &options).unwrap();
+        let removed = plan.operations().iter().filter_map(|operation| match operation {
+            crate::Operation::Remove(path) => Some(path.clone()),
+            _ => None,
+        }).collect::<Vec<_>>();
+        assert_eq!(removed, [root.join("src/lib.rs"), root.join("src/old")]);
+        assert!(root.join("src/old/mod.rs").exists());
+
+        schema.create_with(root.clone(), &options).unwrap();
+        assert!(!root.join("src/lib.rs").exists() && !root.join("src/old").exists());
+        assert!(root.join("src/main.rs").is_file() && root.join("src/local.rs").is_file());
+        assert!(root.join("schema.json").is_file());
+
+        schema.create_with(root.clone(), &CreateOptions { sync_root: true, ..options }).unwrap();
+        assert!(!root.join("schema.json").exists());
+        assert!(root.join(crate::Manifest::DEFAULT_NAME).is_file() && root.join("src/main.rs").is_file());
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn prune() {
+        let dir = std::env::temp_dir().join(format!("fschema-prune-{}", std::process::id()));
+        let root = dir.join("out");
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::create_dir_all(dir.join("outside")).unwrap();
+        std::fs::write(dir.join("outside/data"), "kept").unwrap();
+        std::fs::write(dir.join("sibling"), "kept").unwrap();
+        std::fs::write(root.join("src/stale.rs"), "").unwrap();
+        std::fs::write(root.join("src/local.rs"), "").unwrap();
+        crate::special::symlink(dir.join("outside"), root.join("src/linked")).unwrap();
+        crate::special::symlink(dir.join("sibling"), root.join("src/file_link")).unwrap();
+        let schema = FSchema::from_str(r#"{ "root": { "src": { "main.rs": ["fn main() {}"] } } }"#).unwrap();
+        let options = CreateOptions { sync: true, sync_root: true, exclude: vec!["src/local.rs".to_string()], quiet: true, ..Default::default() };
+
+        schema.create_with(root.clone(), &options).unwrap();
+        assert!(!root.join("src/stale.rs").exists());
+        assert!(root.join("src/local.rs").is_file() && root.join("src/main.rs").is_file());
+        assert!(std::fs::symlink_metadata(root.join("src/linked")).is_err() && std::fs::symlink_metadata(root.join("src/file_link")).is_err());
+        assert_eq!(std::fs::read_to_string(dir.join("outside/data")).unwrap(), "kept");
+        assert_eq!(std::fs::read_to_string(dir.join("sibling")).unwrap(), "kept");
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 3);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn rollback() {
+        let root = std::env::temp_dir().join(format!("fschema-rollback-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::write(root.join("etc/config"), "port = 80\n").unwrap();
+        crate::mode::set_mode(&root.join("etc/config"), 0o600).unwrap();
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        std::fs::File::options().write(true).open(root.join("etc/config")).unwrap().set_modified(old).unwrap();
+        crate::special::symlink(std::path::Path::new("config"), root.join("etc/current")).unwrap();
+        let schema = FSchema::from_str(r#"{ "root": {
+            "etc": {
+                "config": ["port = 8080\n", {"mode": "644"}],
+                "current": ["hosts", {"ftype": "Link"}],
+                "hosts": ["10.0.0.1 db\n"]
+            },
+            "app": { "main.conf": ["debug = false\n"] },
+            "broken": ["exit 1", {"ftype": "Piped", "defer": 1}]
+        } }"#).unwrap();
+        let options = CreateOptions { rollback_on_error: true, overwrite: OverwritePolicy::Overwrite, quiet: true, ..Default::default() };
+
+        assert!(matches!(schema.create_with(root.clone(), &options), Err(Error::Command(..))));
+        assert!(!root.join("app").exists() && !root.join("etc/hosts").exists() && !root.join("broken").exists());
+        let metadata = std::fs::metadata(root.join("etc/config")).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("etc/config")).unwrap(), "port = 80\n");
+        assert_eq!(crate::mode::mode_of(&metadata) & 0o777, 0o600);
+        assert_eq!(metadata.modified().unwrap(), old);
+        assert_eq!(std::fs::read_link(root.join("etc/current")).unwrap(), std::path::Path::new("config"));
+        assert!(std::fs::read_dir(std::env::temp_dir()).unwrap().all(|entry| !entry
+            .unwrap()
+            .file_name()
+            .to_string_lossy()
+            .starts_with(&format!("fschema-rollback-{}-", std::process::id()))));
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    /// Directory for an overwrite policy test holding a config file that already exists, and a schema replacing it
+    fn existing(name: &str) -> (PathBuf, FSchema) {
+        let root = std::env::temp_dir().join(format!("fschema-overwrite-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        std::fs::write(root.join("config"), "port = 80\n").unwrap();
+        (root, FSchema::from_str(r#"{ "root": { "config": ["port = 8080\n"], "new": ["created\n"] } }"#).unwrap())
+    }
+
+    #[test]
+    fn overwrite() {
+        let (root, schema) = existing("overwrite");
+        schema.create_with(root.clone(), &CreateOptions { overwrite: OverwritePolicy::Overwrite, ..Default::default() }).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("config")).unwrap(), "port = 8080\n");
+        assert!(!root.join("config~").exists());
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn skip_existing() {
+        let (root, schema) = existing("skip");
+        schema.create_with(root.clone(), &CreateOptions { overwrite: OverwritePolicy::Skip, ..Default::default() }).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("config")).unwrap(), "port = 80\n");
+        assert_eq!(std::fs::read_to_string(root.join("new")).unwrap(), "created\n");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn no_clobber() {
+        let (root, schema) = existing("no-clobber");
+        let result = schema.create_with(root.clone(), &CreateOptions { overwrite: OverwritePolicy::Error, ..Default::default() });
+        assert!(matches!(result, Err(Error::Exists(path)) if path == root.join("config")));
+        assert_eq!(std::fs::read_to_string(root.join("config")).unwrap(), "port = 80\n");
+
+        std::fs::write(root.join("config"), "port = 8080\n").unwrap();
+        schema.create_with(root.clone(), &CreateOptions { overwrite: OverwritePolicy::Error, ..Default::default() }).unwrap();
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn backup() {
+        let (root, schema) = existing("backup");
+        let options = CreateOptions { overwrite: OverwritePolicy::Backup, ..Default::default() };
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("config")).unwrap(), "port = 8080\n");
+        assert_eq!(std::fs::read_to_string(root.join("config~")).unwrap(), "port = 80\n");
+
+        std::fs::write(root.join("config"), "port = 90\n").unwrap();
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("config")).unwrap(), "port = 8080\n");
+        assert_eq!(std::fs::read_to_string(root.join("config~")).unwrap(), "port = 90\n");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn file_policy() {
+        let (root, _) = existing("file-policy");
+        let schema = |config: &str| FSchema::from_str(&format!(r#"{{ "root": {{ "etc": {{
+            ".": {{ "defaultFileOptions": {{ "overwrite": "Skip" }} }},
+            "config": {}
+        }} }} }}"#, config)).unwrap();
+        std::fs::create_dir_all(root.join("etc")).unwrap();
+        std::fs::rename(root.join("config"), root.join("etc/config")).unwrap();
+        let options = CreateOptions { overwrite: OverwritePolicy::Overwrite, ..Default::default() };
+
+        assert!(matches!(schema(r#"["port = 8080\n", {"overwrite": "Error"}]"#).create_with(root.clone(), &options), Err(Error::Exists(_))));
+        schema(r#"["port = 8080\n"]"#).create_with(root.clone(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("etc/config")).unwrap(), "port = 80\n");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn force() {
+        let (root, _) = existing("force");
+        let schema = FSchema::from_str(r#"{ "root": { "config": ["port = 8080\n", {"overwrite": "Error"}] } }"#).unwrap();
+        let options = CreateOptions { overwrite: OverwritePolicy::Skip, force: true, ..Default::default() };
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("config")).unwrap(), "port = 8080\n");
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn unchanged() {
+        let root = std::env::temp_dir().join(format!("fschema-unchanged-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": {
+            "config": ["port = 8080\n", {"mode": "600"}],
+            "link": ["config", {"ftype": "Link"}],
+            "hex": ["00ff", {"ftype": "Hex"}],
+            "copy": ["config", {"ftype": "Copy", "internal": true, "defer": 1}]
+        } }"#).unwrap();
+        schema.create(root.clone()).unwrap();
+
+        let old = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        let age = |name: &str| std::fs::File::options().write(true).open(root.join(name)).unwrap().set_modified(old).unwrap();
+        for name in ["config", "hex", "copy"] {
+            age(name);
+        }
+        crate::mode::set_mode(&root.join("config"), 0o644).unwrap();
+        schema.create_with(root.clone(), &CreateOptions { overwrite: OverwritePolicy::Error, ..Default::default() }).unwrap();
+        let metadata = std::fs::metadata(root.join("config")).unwrap();
+        assert_eq!(metadata.modified().unwrap(), old);
+        assert_eq!(mode_of(&metadata), effective_mode(0o600));
+        assert_eq!(std::fs::metadata(root.join("hex")).unwrap().modified().unwrap(), old);
+        assert_eq!(std::fs::metadata(root.join("copy")).unwrap().modified().unwrap(), old);
+        assert_eq!(std::fs::read_link(root.join("link")).unwrap(), PathBuf::from("config"));
+
+        std::fs::write(root.join("config"), "port = 9090\n").unwrap();
+        age("config");
+        schema.create(root.clone()).unwrap();
+        assert_eq!(std::fs::read_to_string(root.join("config")).unwrap(), "port = 8080\n");
+        assert_ne!(std::fs::metadata(root.join("config")).unwrap().modified().unwrap(), old);
+        assert_eq!(std::fs::metadata(root.join("copy")).unwrap().modified().unwrap(), old);
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+
+    #[test]
+    fn dry_run() {
+        let root = std::env::temp_dir().join(format!("fschema-dry-run-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{
+            "prebuild": ["touch \"$ROOT/ran\""],
+            "root": { "src": { "main.rs": ["fn main() {}"] }, "tool": ["touch \"$ROOT/piped\"", {"ftype": "Piped"}] }
+        }"#).unwrap();
+        let options = CreateOptions {
+            sync: true,
+            manifest: Some(root.join(crate::Manifest::DEFAULT_NAME)),
+            checkpoint: Some(root.join(crate::Manifest::CHECKPOINT_NAME)),
+            cache: Some(root.join(crate::Cache::DEFAULT_NAME)),
+            ..Default::default()
+        };
+
+        let plan = schema.plan_with(root.clone(), &options).unwrap();
+        assert!(plan.to_string().contains("main.rs"));
+        assert!(!root.exists());
+
+        std::fs::create_dir_all(root.join("src")).unwrap();
+        std::fs::write(root.join("src/main.rs"), "old").unwrap();
+        std::fs::write(root.join("src/stale.rs"), "").unwrap();
+        let plan = schema.plan_with(root.clone(), &options).unwrap();
+        assert!(plan.operations().iter().any(|operation| matches!(operation, Operation::Remove(path) if path.ends_with("stale.rs"))));
+        let mut entries = std::fs::read_dir(root.join("src")).unwrap().map(|entry| entry.unwrap().file_name()).collect::<Vec<_>>();
+        entries.sort();
+        assert_eq!(entries, ["main.rs", "stale.rs"]);
+        assert_eq!(std::fs::read_dir(&root).unwrap().count(), 1);
+        assert_eq!(std::fs::read_to_string(root.join("src/main.rs")).unwrap(), "old");
+
+        std::fs::remove_dir_all(&root).unwrap();
+    }
+}
@@ -0,0 +1,195 @@
+use std::{fs, path::Path};
+
+use glob::Pattern;
+use serde::{Deserialize, Serialize};
+
+use crate::{Command, Error, Operation};
+
+/// Shell builtins commands can always run, as they can't run other programs
+const BUILTINS: &[&str] = &[":", "true", "false", "echo", "printf", "cd", "test", "[", "export", "set", "unset", "exit", "read", "shift", "local", "return"];
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq)]
+#[serde(rename_all = "camelCase")]
+/// Programs a schema's commands may run, so schemas from elsewhere can be built without trusting them,
+/// e.g. {"allowedPrograms": ["git", "make", "/usr/local/bin/*"]}
+pub struct Policy {
+    /// Glob patterns of the programs commands may run. Patterns with a path separator match a program's path as it's
+    /// written in the command, others match its name. Shell builtins, e.g. echo and cd, are always allowed
+    pub allowed_programs: Vec<String>,
+}
+
+impl Policy {
+    /// Load a policy from a JSON file
+    pub fn load(path: &Path) -> Result<Policy, Error> {
+        let policy = fs::read_to_string(path).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+        serde_json::from_str(&policy).map_err(|e| Error::IO(e.into(), format!("{:?}", path)))
+    }
+
+    /// Check a command only runs programs the policy allows. Command lines are split into the simple commands they run,
+    /// e.g. "make all | tee log" runs make and tee. Command lines whose programs can't be known without running them,
+    /// e.g. with command substitutions or programs given by variables, are never allowed. Commands run as another user need sudo
+    pub fn check(&self, command: &Command) -> Result<(), Error> {
+        if let (Some(user), false) = (&command.run_as, self.allows("sudo")) {
+            return Err(Error::Disallowed(command.to_string(), format!("runs as {} with sudo", user)));
+        }
+        let programs = match command.argv.first() {
+            Some(program) => vec![program.clone()],
+            None => programs(&command.cmd).map_err(|e| Error::Disallowed(command.to_string(), e))?,
+        };
+        match programs.into_iter().find(|program| !self.allows(program)) {
+            Some(program) => Err(Error::Disallowed(command.to_string(), format!("runs '{}'", program))),
+            None => Ok(()),
+        }
+    }
+
+    /// Check whether the policy allows a program, given by its name or path
+    fn allows(&self, program: &str) -> bool {
+        let name = program.rsplit(['/', '\\']).next().unwrap_or(program);
+        BUILTINS.contains(&program) || self.allowed_programs.iter().any(|allowed| {
+            let program = match allowed.contains(['/', '\\']) {
+                true => program,
+                false => name,
+            };
+            Pattern::new(allowed).map_or(allowed == program, |pattern| pattern.matches(program))
+        })
+    }
+}
+
+/// Check every command of a plan is allowed by a policy, so a build breaking it fails before any command runs
+pub(crate) fn check_policy(policy: &Policy, operations: &[Operation]) -> Result<(), Error> {
+    operations.iter().flat_map(Operation::commands).try_for_each(|command| policy.check(&command))
+}
+
+/// Programs a command line runs, the first word of each of its simple commands, e.g. ["make", "tee"] for
+/// "CC=clang make all 2>&1 | tee log". Fails with what stops them being known, e.g. a command substitution
+fn programs(line: &str) -> Result<Vec<String>, String> {
+    let mut programs = vec![];
+    for segment in segments(line)? {
+        let mut words = words(&segment).into_iter();
+        while let Some(word) = words.next() {
+            let name = word.split('=').next().unwrap_or_default();
+            let assignment = word.contains('=') && !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_');
+            let redirect = word.trim_start_matches(|c: char| c.is_ascii_digit()).starts_with(['<', '>', '&']);
+            if assignment {
+                continue;
+            } else if redirect {
+                if word.trim_start_matches(|c: char| c.is_ascii_digit() || matches!(c, '<' | '>' | '&' | '|')).is_empty() {
+                    words.next();
+                }
+                continue;
+            }
+            match word.as_str() {
+                "if" | "then" | "else" | "elif" | "do" | "while" | "until" | "!" | "{" | "time" => continue,
+                "fi" | "done" | "esac" | "}" | "for" | "case" | "select" | "in" => (),
+                program if program.contains('$') => return Err(format!("runs a program given by a variable, '{}'", program)),
+                program => programs.push(program.to_string()),
+            }
+            break;
+        }
+    }
+    Ok(programs)
+}
+
+/// Split a command line into its simple commands at ;, |, &, newlines and parentheses outside quotes
+fn segments(line: &str) -> Result<Vec<String>, String> {
+    let chars = line.chars().collect::<Vec<_>>();
+    let mut segments = vec![String::new()];
+    let mut quote = None;
+    let mut i = 0;
+    while i < chars.len() {
+        let (previous, c, next) = (i.checked_sub(1).map(|i| chars[i]), chars[i], chars.get(i + 1).copied());
+        let segment = segments.last_mut().expect("there's always a segment");
+        if quote != Some('\'') && c == '\\' {
+            segment.extend(&chars[i..(i + 2).min(chars.len())]);
+            i += 2;
+            continue;
+        }
+        if quote != Some('\'') && c == '$' && next == Some('(') && chars.get(i + 2) == Some(&'(') {
+            let length = chars[i..].windows(2).position(|pair| pair == [')', ')']).map_or(chars.len() - i, |end| end + 2);
+            let arithmetic = chars[i + 3..i + length].iter().collect::<String>();
+            if arithmetic.contains("$(") || arithmetic.contains('`') {
+                return Err("uses a command substitution".to_string());
+            }
+            segment.extend(&chars[i..i + length]);
+            i += length;
+            continue;
+        }
+
+        let separator = match (quote, c) {
+            (Some('\''), '\'') | (Some('"'), '"') => {
+                quote = None;
+                false
+            },
+            (Some('\''), _) => false,
+            (_, '`') => return Err("uses a command substitution".to_string()),
+            (_, '$') if next == Some('(') => return Err("uses a command substitution".to_string()),
+            (Some(_), _) => false,
+            (None, '\'' | '"') => {
+                quote = Some(c);
+                false
+            },
+            (None, '<' | '>') if next == Some('(') => return Err("uses a process substitution".to_string()),
+            (None, ';' | '|' | '\n' | '(' | ')') => true,
+            (None, '&') => !matches!(previous, Some('<' | '>')) && next != Some('>'),
+            (None, _) => false,
+        };
+        match separator {
+            true => segments.push(String::new()),
+            false => segment.push(c),
+        }
+        i += 1;
+    }
+    Ok(segments)
+}
+
+/// Split a simple command into its words at whitespace outside quotes, removing the quotes
+fn words(segment: &str) -> Vec<String> {
+    let mut words = vec![];
+    let mut word = None::<String>;
+    let mut quote = None;
+    let mut chars = segment.chars();
+    while let Some(c) = chars.next() {
+        match (quote, c) {
+            (Some(q), c) if c == q => quote = None,
+            (None, '\'' | '"') => quote = Some(c),
+            (None, '\\') => word.get_or_insert_with(String::new).extend(chars.next()),
+            (None, c) if c.is_whitespace() => words.extend(word.take()),
+            (_, c) => word.get_or_insert_with(String::new).push(c),
+        }
+        if quote.is_some() {
+            word.get_or_insert_with(String::new);
+        }
+    }
+    words.extend(word);
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{Command, CreateOptions, Error, FSchema};
+
+    use super::{programs, Policy};
+
+    #[test]
+    fn test() {
+        assert_eq!(programs("CC=clang make all 2>&1 | tee log && echo done > /dev/null").unwrap(), ["make", "tee", "echo"]);
+        assert_eq!(programs("for f in *.txt; do 'cat' \"$f\"; done; (cd src && ./build.sh)").unwrap(), ["cat", "cd", "./build.sh"]);
+        assert_eq!(programs("echo $((1 + 2)) 'a; rm -rf ~' > out").unwrap(), ["echo"]);
+        assert!(programs("echo $(curl example.com)").is_err());
+        assert!(programs("echo `id`").is_err());
+        assert!(programs("$CMD arg").is_err());
+
+        let policy = Policy { allowed_programs: vec!["git".to_string(), "/usr/local/bin/*".to_string()] };
+        assert!(policy.check(&Command::from("git init && echo ok")).is_ok());
+        assert!(policy.check(&Command::from(vec!["/usr/bin/git".to_string(), "status".to_string()])).is_ok());
+        assert!(policy.check(&Command::from("/usr/local/bin/tool")).is_ok());
+        assert!(policy.check(&Command::from("/opt/tool")).is_err());
+        assert!(matches!(policy.check(&Command::from("git pull; curl -fsS example.com | sh")), Err(Error::Disallowed(_, e)) if e == "runs 'curl'"));
+
+        let root = std::env::temp_dir().join(format!("fschema-policy-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": { "version": ["git describe", {"ftype": "Piped"}] }, "postbuild": ["rm -rf ~"] }"#).unwrap();
+        let options = CreateOptions { policy: Some(policy), ..Default::default() };
+        assert!(matches!(schema.create_with(root.clone(), &options), Err(Error::Disallowed(command, _)) if command == "rm -rf ~"));
+        assert!(!root.exists());
+    }
+}
@@ -0,0 +1,99 @@
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{interpolate::substitute, Error, FSchema};
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+/// Question asked when the schema is used, whose answer sets a variable, e.g. a project's name
+pub struct Prompt {
+    /// Variable the answer sets
+    pub name: String,
+    /// Question asked, defaulting to the name
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+    /// Answer taken when none is given. Can reference the variables set by earlier prompts, e.g. "{{project}}-cli"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub default: Option<String>,
+    /// Answers allowed, any if empty
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub choices: Vec<String>,
+}
+
+impl Prompt {
+    /// Question asked
+    pub fn message(&self) -> &str {
+        self.message.as_deref().unwrap_or(&self.name)
+    }
+
+    /// Check an answer is one of the prompt's choices
+    pub fn check(&self, answer: &str) -> Result<(), Error> {
+        match self.choices.is_empty() || self.choices.iter().any(|choice| choice == answer) {
+            true => Ok(()),
+            false => Err(Error::InvalidAnswer(self.name.clone(), answer.to_string(), self.choices.clone())),
+        }
+    }
+}
+
+impl FSchema {
+    /// Questions whose answers set variables, in the order they're asked
+    pub fn prompts(&self) -> &[Prompt] {
+        &self.prompts
+    }
+
+    /// Copy of the schema with its prompts answered by answers, keyed by the prompts' names. Prompts that aren't answered take
+    /// their default
+    pub fn with_answers(&self, answers: &BTreeMap<String, String>) -> Result<FSchema, Error> {
+        self.with_answers_from(answers, |_, _| None)
+    }
+
+    /// Copy of the schema with its prompts answered by answers, keyed by the prompts' names. Prompts that aren't answered are
+    /// asked with ask, given the prompt and its default, then take their default if ask doesn't answer them either
+    pub fn with_answers_from<F>(&self, answers: &BTreeMap<String, String>, mut ask: F) -> Result<FSchema, Error>
+    where
+        F: FnMut(&Prompt, Option<&str>) -> Option<String>
+    {
+        let mut schema = self.clone();
+        for prompt in &self.prompts {
+            let default = prompt.default.as_ref().map(|default| substitute(default, &schema.variables()));
+            let answer = answers.get(&prompt.name).cloned()
+                .or_else(|| ask(prompt, default.as_deref()))
+                .or(default)
+                .ok_or_else(|| Error::Unanswered(prompt.name.clone()))?;
+            prompt.check(&answer)?;
+            schema.overrides.insert(prompt.name.clone(), answer);
+        }
+        Ok(schema)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::FSchema;
+
+    #[test]
+    fn test() {
+        let schema = FSchema::from_str(r#"{
+            "prompts": [
+                { "name": "project", "message": "Project name?" },
+                { "name": "binary", "default": "{{project}}-cli" },
+                { "name": "license", "choices": ["MIT", "Apache-2.0"], "default": "MIT" }
+            ],
+            "root": { "{{project}}": { "LICENSE": ["{{license}}"] } }
+        }"#).unwrap();
+        assert_eq!(schema.prompts()[0].message(), "Project name?");
+        assert_eq!(schema.prompts()[1].message(), "binary");
+
+        let answers = BTreeMap::from([("project".to_string(), "app".to_string())]);
+        let answered = schema.with_answers(&answers).unwrap();
+        assert_eq!(answered.variables()["binary"], "app-cli");
+        assert_eq!(answered.variables()["license"], "MIT");
+
+        let answered = schema.with_answers_from(&answers, |prompt, _| (prompt.name == "license").then(|| "Apache-2.0".to_string())).unwrap();
+        assert_eq!(answered.variables()["license"], "Apache-2.0");
+        assert!(schema.with_answers_from(&answers, |_, _| Some("GPL".to_string())).is_err());
+        assert!(schema.with_answers(&BTreeMap::new()).is_err());
+    }
+}
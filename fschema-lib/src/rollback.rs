@@ -0,0 +1,165 @@
+use std::{
+    ffi::CString,
+    fs, io,
+    path::{Path, PathBuf},
+    process,
+    sync::atomic::{AtomicUsize, Ordering},
+    time::SystemTime,
+};
+#[cfg(unix)]
+use std::os::unix::prelude::MetadataExt;
+
+use crate::{
+    attributes::{get_flags, get_xattrs, put_flags, put_xattrs},
+    copy::copy_file,
+    mode::{mode_of, set_mode},
+    owner::Ownership,
+    special::symlink,
+    times::set_times,
+    Error,
+};
+
+/// Number of backups taken by this process, so each build rolling back on error has its own directory
+static BACKUPS: AtomicUsize = AtomicUsize::new(0);
+
+/// What an entry was before a build changed it
+enum Saved {
+    /// A file's contents, copied into the backup directory
+    File(PathBuf),
+    /// A link's target
+    Link(PathBuf),
+    /// A directory, or a special file, whose metadata is all that's kept
+    Other,
+}
+
+/// An entry that existed before a build with rollback changed it, kept so it can be put back if the build fails
+struct Backup {
+    path: PathBuf,
+    saved: Saved,
+    mode: u32,
+    ownership: Ownership,
+    accessed: Option<SystemTime>,
+    modified: Option<SystemTime>,
+    xattrs: Vec<(CString, Vec<u8>)>,
+    flags: Option<libc::c_int>,
+}
+
+/// Entries a build changed, backed up before it changed them. The backups are removed when they're dropped
+pub(crate) struct Backups {
+    dir: PathBuf,
+    backups: Vec<Backup>,
+}
+
+impl Backups {
+    /// Start backing up the entries a build changes
+    pub fn new() -> Backups {
+        let dir = std::env::temp_dir().join(format!("fschema-rollback-{}-{}", process::id(), BACKUPS.fetch_add(1, Ordering::Relaxed)));
+        Backups { dir, backups: vec![] }
+    }
+
+    /// Back up the entry at path before it's changed, its contents, permissions, owner, times and attributes. Entries already
+    /// backed up keep their first backup
+    pub fn save(&mut self, path: &Path) -> Result<(), Error> {
+        if self.backups.iter().any(|backup| backup.path == path) {
+            return Ok(());
+        }
+        let context = |e| Error::IO(e, format!("{:?}", path));
+        let metadata = fs::symlink_metadata(path).map_err(context)?;
+        let saved = if metadata.file_type().is_symlink() {
+            Saved::Link(fs::read_link(path).map_err(context)?)
+        } else if metadata.is_file() {
+            fs::create_dir_all(&self.dir).map_err(|e| Error::IO(e, format!("{:?}", self.dir)))?;
+            let copy = self.dir.join(self.backups.len().to_string());
+            copy_file(path, &copy).map_err(context)?;
+            Saved::File(copy)
+        } else {
+            Saved::Other
+        };
+        let is_link = matches!(saved, Saved::Link(_));
+
+        self.backups.push(Backup {
+            path: path.to_path_buf(),
+            saved,
+            mode: mode_of(&metadata),
+            ownership: ownership(&metadata),
+            accessed: metadata.accessed().ok(),
+            modified: metadata.modified().ok(),
+            xattrs: get_xattrs(path).map_err(context)?,
+            flags: if is_link { None } else { get_flags(path).ok() },
+        });
+        Ok(())
+    }
+
+    /// Put back every entry that was backed up, newest first
+    pub fn restore(&self) -> Result<(), Error> {
+        for backup in self.backups.iter().rev() {
+            backup.restore()?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Backups {
+    fn drop(&mut self) {
+        fs::remove_dir_all(&self.dir).ok();
+    }
+}
+
+impl Backup {
+    /// Put the entry back as it was when it was backed up
+    fn restore(&self) -> Result<(), Error> {
+        let context = |e| Error::IO(e, format!("{:?}", self.path));
+        if let Ok(flags) = get_flags(&self.path) {
+            if flags != 0 {
+                put_flags(&self.path, 0).map_err(context)?;
+            }
+        }
+
+        match &self.saved {
+            Saved::File(copy) => {
+                remove(&self.path).map_err(context)?;
+                copy_file(copy, &self.path).map_err(context)?;
+                put_xattrs(&self.path, &self.xattrs).map_err(context)?;
+            },
+            Saved::Link(target) => {
+                remove(&self.path).map_err(context)?;
+                symlink(target, &self.path).map_err(context)?;
+                put_xattrs(&self.path, &self.xattrs).map_err(context)?;
+            },
+            Saved::Other if fs::symlink_metadata(&self.path).is_err() => return Ok(()),
+            Saved::Other => (),
+        }
+
+        self.ownership.apply(&self.path)?;
+        if !matches!(self.saved, Saved::Link(_)) {
+            set_mode(&self.path, self.mode).map_err(context)?;
+        }
+        set_times(&self.path, self.accessed, self.modified)?;
+        match self.flags {
+            Some(flags) if get_flags(&self.path).ok() != Some(flags) => put_flags(&self.path, flags).map_err(context),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Remove whatever is at path, if anything
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+        Ok(_) => fs::remove_file(path),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e),
+    }
+}
+
+#[cfg(unix)]
+/// Owner and group of an entry
+fn ownership(metadata: &fs::Metadata) -> Ownership {
+    Ownership { uid: Some(metadata.uid()), gid: Some(metadata.gid()), ..Default::default() }
+}
+
+#[cfg(windows)]
+/// Owner and group of an entry, which windows files don't have
+fn ownership(_metadata: &fs::Metadata) -> Ownership {
+    Ownership::default()
+}
@@ -0,0 +1,132 @@
+use std::{env, fs, path::{Path, PathBuf}, process};
+
+use crate::{
+    checksums::checksums,
+    edit::is_edit,
+    manifest::hash_bytes,
+    mode::set_mode,
+    plan::create_file,
+    Command, CreateOptions, Error, FileOptions, FileType, OverwritePolicy,
+};
+
+/// Private directory a file created as another user is built in before it's installed, unique to the build and the file
+fn staging(path: &Path) -> PathBuf {
+    let hash = hash_bytes(path.to_string_lossy().as_bytes());
+    env::temp_dir().join(format!("fschema-{}-{}", process::id(), &hash[..16]))
+}
+
+/// Where a file created as another user is built before it's installed
+fn staged(path: &Path) -> PathBuf {
+    staging(path).join(path.file_name().unwrap_or(path.as_os_str()))
+}
+
+/// Commands installing a file built by the invoking user into its path as user, with sudo, then giving it the ownership,
+/// capabilities and attribute flags the invoking user couldn't. Files that exist are replaced, or backed up if backup is set
+pub(crate) fn install_commands(user: &str, path: &Path, options: &FileOptions, backup: bool) -> Vec<Command> {
+    let as_user = |argv: Vec<String>| Command { argv, run_as: Some(user.to_string()), ..Default::default() };
+    let path = path.to_string_lossy().to_string();
+    let replace = match backup {
+        true => "--backup=simple",
+        false => "--remove-destination",
+    };
+    let mut commands = vec![as_user([
+        "cp", "-R", "--no-dereference", "--preserve=mode,timestamps,links,xattr", "--no-target-directory", replace,
+    ].map(String::from).into_iter().chain([staged(Path::new(&path)).to_string_lossy().to_string(), path.clone()]).collect())];
+
+    let ownership = &options.ownership;
+    let owner = ownership.uid.map(|uid| uid.to_string()).or_else(|| ownership.owner.clone());
+    let group = ownership.gid.map(|gid| gid.to_string()).or_else(|| ownership.group.clone());
+    let owners = match (owner, group) {
+        (Some(owner), Some(group)) => Some(format!("{}:{}", owner, group)),
+        (Some(owner), None) => Some(owner),
+        (None, Some(group)) => Some(format!(":{}", group)),
+        (None, None) => None,
+    };
+    if let Some(owners) = owners {
+        commands.push(as_user(vec!["chown".to_string(), "--no-dereference".to_string(), owners, path.clone()]));
+    }
+    if let Some(capabilities) = &options.capabilities {
+        commands.push(as_user(vec!["setcap".to_string(), capabilities.clone(), path.clone()]));
+    }
+    if let Some(attrs) = &options.attrs {
+        commands.push(as_user(vec!["chattr".to_string(), format!("+{}", attrs), path]));
+    }
+    commands
+}
+
+/// Create a file as another user. The file is built by the invoking user in a private directory, then installed into its
+/// path as user with sudo, so only installing it needs privileges
+pub(crate) fn create_as(user: &str, path: &Path, data: &str, options: &FileOptions, create_options: &CreateOptions) -> Result<(), Error> {
+    let policy = create_options.overwrite_policy(options);
+    let existing = fs::symlink_metadata(path).ok();
+    match (&existing, policy) {
+        (Some(_), OverwritePolicy::Skip) => return Ok(()),
+        (Some(_), OverwritePolicy::Error) if !is_edit(options.ftype) => return Err(Error::Exists(path.to_path_buf())),
+        _ => (),
+    }
+
+    let staging = staging(path);
+    let context = || format!("{:?}", staging);
+    fs::remove_dir_all(&staging).ok();
+    fs::create_dir_all(&staging).map_err(|e| Error::IO(e, context()))?;
+    set_mode(&staging, 0o700).map_err(|e| Error::IO(e, context()))?;
+
+    let result = build_and_install(user, path, data, options, create_options, existing.is_some(), policy);
+    fs::remove_dir_all(&staging).ok();
+    result
+}
+
+/// Build a file in its staging directory, then install it into its path as user. Its Piped or Secret command runs as user
+fn build_and_install(user: &str, path: &Path, data: &str, options: &FileOptions, create_options: &CreateOptions, existing: bool, policy: OverwritePolicy) -> Result<(), Error> {
+    let staged = staged(path);
+    if is_edit(options.ftype) && path.is_file() {
+        fs::copy(path, &staged).map_err(|e| Error::IO(e, format!("{:?}", path)))?;
+    }
+    let (data, ftype) = match options.ftype {
+        FileType::Checksums => (String::from_utf8_lossy(&checksums(path, data)?).to_string(), FileType::Text),
+        ftype => (data.to_string(), ftype),
+    };
+    let unprivileged = FileOptions {
+        ftype,
+        overwrite: Some(OverwritePolicy::Overwrite),
+        ownership: Default::default(),
+        capabilities: None,
+        attrs: None,
+        ..options.clone()
+    };
+    create_file(&staged, &data, &unprivileged, create_options)?;
+
+    if let (Some(attrs), true) = (&options.attrs, existing) {
+        let clear = vec!["chattr".to_string(), format!("-{}", attrs), path.to_string_lossy().to_string()];
+        Command { argv: clear, run_as: Some(user.to_string()), ..Default::default() }.run(create_options.quiet)?;
+    }
+    install_commands(user, path, options, existing && policy == OverwritePolicy::Backup).iter().try_for_each(|command| command.run(create_options.quiet))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::{Command, CreateOptions, Error, FSchema, FileOptions, Operation};
+
+    use super::install_commands;
+
+    #[test]
+    fn test() {
+        let options = serde_json::from_str::<FileOptions>(r#"{"runAs": "root", "uid": 0, "group": "adm", "attrs": "i"}"#).unwrap();
+        let commands = install_commands("root", Path::new("/etc/app.conf"), &options, false).iter().map(ToString::to_string).collect::<Vec<_>>();
+        assert!(commands[0].starts_with("cp -R") && commands[0].ends_with("/app.conf /etc/app.conf"));
+        assert_eq!(&commands[1..], ["chown --no-dereference 0:adm /etc/app.conf", "chattr +i /etc/app.conf"]);
+
+        let command = serde_json::from_str::<Command>(r#"{"cmd": ["id", "-un"], "env": {"A": "b"}, "runAs": "root"}"#).unwrap();
+        let process = command.process();
+        assert_eq!(process.get_program(), "sudo");
+        assert_eq!(process.get_args().collect::<Vec<_>>(), ["-u", "root", "--", "env", "A=b", "id", "-un"]);
+
+        let schema = FSchema::from_str(r#"{ "root": { "etc": { ".": {"defaultFileOptions": {"runAs": "root"}}, "app.conf": ["port=80"] } } }"#).unwrap();
+        let plan = schema.plan(PathBuf::from("/out")).unwrap();
+        assert!(matches!(plan.operations().last(), Some(Operation::File { options, .. }) if options.run_as.as_deref() == Some("root")));
+        let options = CreateOptions { allow_commands: false, ..Default::default() };
+        assert!(matches!(plan.execute_with(&options), Err(Error::CommandsDisabled(_))));
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    fs::OpenOptions,
+    io::{self, Write},
+    path::Path,
+    process::{Command, Stdio},
+    thread,
+};
+
+#[cfg(unix)]
+use std::os::unix::{fs::OpenOptionsExt, prelude::PermissionsExt};
+
+use zeroize::Zeroize;
+
+use crate::Error;
+
+/// Mode Secret files are created with unless they're given one
+pub(crate) const SECRET_MODE: u32 = 0o600;
+
+/// Run the command of a Secret file, e.g. "pass show db/password", for the file's contents. The command can prompt for a
+/// passphrase on the terminal. Its output is zeroed if it fails, callers should zero the contents once they're written
+pub(crate) fn reveal(command: &crate::Command) -> Result<Vec<u8>, Error> {
+    let output = command.process()
+        .stdin(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .output()
+        .map_err(|e| Error::IO(e, command.to_string()))?;
+    let mut contents = output.stdout;
+    if !output.status.success() {
+        contents.zeroize();
+        return Err(Error::Command(output.status.code().unwrap_or(1), command.to_string(), String::new()));
+    }
+    Ok(contents)
+}
+
+/// Armor header of age encrypted data
+const AGE_HEADER: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+/// Armor header of GPG encrypted data
+const GPG_HEADER: &str = "-----BEGIN PGP MESSAGE-----";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Ciphers Encrypted files' data can be encrypted with
+enum Cipher {
+    Age,
+    Gpg,
+}
+
+/// Cipher of Encrypted data, detected from its armor header
+fn cipher(data: &str) -> Result<Cipher, String> {
+    match data.trim_start() {
+        data if data.starts_with(AGE_HEADER) => Ok(Cipher::Age),
+        data if data.starts_with(GPG_HEADER) => Ok(Cipher::Gpg),
+        _ => Err(format!("Expected encrypted data to be age or GPG ciphertext in ASCII armor, starting with {} or {}", AGE_HEADER, GPG_HEADER)),
+    }
+}
+
+/// Check the data of an Encrypted file is age or GPG ciphertext
+pub(crate) fn parse_encrypted(data: &str) -> Result<(), String> {
+    cipher(data).map(|_| ())
+}
+
+/// Decrypt the data of an Encrypted file with age or gpg. Age needs the key, an identity file. GPG uses the key as a file
+/// holding the passphrase of symmetrically encrypted data if it's given, otherwise the keyring. The decrypted contents are
+/// zeroed if decrypting fails, callers should zero them once they're written
+pub(crate) fn decrypt(data: &str, key: Option<&Path>) -> Result<Vec<u8>, Error> {
+    let mut command = match (cipher(data), key) {
+        (Ok(Cipher::Age), Some(key)) => {
+            let mut command = Command::new("age");
+            command.arg("--decrypt").arg("--identity").arg(key);
+            command
+        },
+        (Ok(Cipher::Age), None) => return Err(Error::IO(io::Error::other("age encrypted data needs a decryption key"), "age --decrypt".to_string())),
+        (Ok(Cipher::Gpg), key) => {
+            let mut command = Command::new("gpg");
+            command.args(["--batch", "--quiet", "--decrypt"]);
+            if let Some(key) = key {
+                command.args(["--pinentry-mode", "loopback", "--passphrase-file"]).arg(key);
+            }
+            command
+        },
+        (Err(e), _) => return Err(Error::IO(io::Error::new(io::ErrorKind::InvalidData, e), "decrypt".to_string())),
+    };
+    let context = format!("{:?}", command);
+
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .map_err(|e| Error::IO(e, context.clone()))?;
+    let mut stdin = child.stdin.take().expect("stdin is piped");
+    let ciphertext = data.to_string();
+    let writer = thread::spawn(move || stdin.write_all(ciphertext.as_bytes()));
+    let output = child.wait_with_output().map_err(|e| Error::IO(e, context.clone()))?;
+    let written = writer.join().unwrap_or_else(|_| Err(io::Error::other("writing the encrypted data panicked")));
+
+    let mut contents = output.stdout;
+    if !output.status.success() {
+        contents.zeroize();
+        return Err(Error::Command(output.status.code().unwrap_or(1), context, String::new()));
+    }
+    if let Err(e) = written {
+        contents.zeroize();
+        return Err(Error::IO(e, context));
+    }
+    Ok(contents)
+}
+
+/// Write a Secret or Encrypted file's contents. The file is only readable by its owner before anything is written to it,
+/// so the secret is never exposed by a wider mode. On windows the file has the access its directory gives
+pub(crate) fn write_secret(path: &Path, contents: &[u8]) -> Result<(), Error> {
+    let context = |e| Error::IO(e, format!("{:?}", path));
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(SECRET_MODE);
+    let mut file = options.open(path).map_err(context)?;
+    #[cfg(unix)]
+    file.set_permissions(PermissionsExt::from_mode(SECRET_MODE)).map_err(context)?;
+    file.write_all(contents).map_err(context)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process::Command};
+
+    use super::{decrypt, parse_encrypted};
+
+    #[test]
+    fn test() {
+        assert!(parse_encrypted("-----BEGIN AGE ENCRYPTED FILE-----\nYWdl\n-----END AGE ENCRYPTED FILE-----\n").is_ok());
+        assert!(parse_encrypted("plain text").is_err());
+        assert!(decrypt("-----BEGIN AGE ENCRYPTED FILE-----\n", None).is_err());
+
+        let dir = env::temp_dir().join(format!("fschema-decrypt-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        fs::write(dir.join("passphrase"), "correct horse").unwrap();
+        let encrypted = Command::new("gpg")
+            .args(["--batch", "--quiet", "--armor", "--symmetric", "--pinentry-mode", "loopback", "--passphrase-file"])
+            .arg(dir.join("passphrase"))
+            .arg("--homedir")
+            .arg(&dir)
+            .arg("--output")
+            .arg(dir.join("token.asc"))
+            .arg(dir.join("passphrase"))
+            .status();
+        if encrypted.map(|status| status.success()).unwrap_or(false) {
+            let data = fs::read_to_string(dir.join("token.asc")).unwrap();
+            assert_eq!(decrypt(&data, Some(&dir.join("passphrase"))).unwrap(), b"correct horse");
+        }
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
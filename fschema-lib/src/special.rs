@@ -0,0 +1,329 @@
+use std::{
+    fs::{self, Metadata},
+    io::{self, Write},
+    path::Path,
+};
+#[cfg(unix)]
+use std::{ffi::CString, os::unix::prelude::{FileTypeExt, MetadataExt, OsStrExt}};
+#[cfg(target_os = "linux")]
+use std::os::unix::prelude::AsRawFd;
+#[cfg(windows)]
+use std::process::{self, Stdio};
+
+use sha2::{Digest, Sha256};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// A named pipe (FIFO), or a character or block device and its major and minor numbers
+pub(crate) enum Special {
+    Fifo,
+    Character(u32, u32),
+    Block(u32, u32),
+}
+
+#[cfg(unix)]
+/// Convert a path into a C string
+fn c_path(path: &Path) -> io::Result<CString> {
+    CString::new(path.as_os_str().as_bytes()).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))
+}
+
+/// Create a named pipe (FIFO). Its permissions are set afterwards, like any other file's
+pub(crate) fn make_fifo(path: &Path) -> io::Result<()> {
+    make_special(path, Special::Fifo)
+}
+
+/// Parse a device written like mknod's arguments, e.g. "c 1 3" for a character device or "b 8 0" for a block device
+pub(crate) fn parse_device(device: &str) -> Result<Special, String> {
+    let invalid = || format!("Expected a device like \"c 1 3\" or \"b 8 0\", found {}", device);
+    let parts = device.split_whitespace().collect::<Vec<_>>();
+    let (kind, major, minor) = match parts.as_slice() {
+        [kind, major, minor] => (kind, major.parse::<u32>().map_err(|_| invalid())?, minor.parse::<u32>().map_err(|_| invalid())?),
+        _ => return Err(invalid()),
+    };
+    match *kind {
+        "c" | "u" => Ok(Special::Character(major, minor)),
+        "b" => Ok(Special::Block(major, minor)),
+        _ => Err(invalid()),
+    }
+}
+
+/// Create a character or block device. Only root can create devices
+pub(crate) fn make_device(path: &Path, device: &str) -> io::Result<()> {
+    let device = parse_device(device).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    make_special(path, device)
+}
+
+#[cfg(unix)]
+/// The kind of special file a file is, or None if it isn't one
+pub(crate) fn special(metadata: &Metadata) -> Option<Special> {
+    let file_type = metadata.file_type();
+    let (major, minor) = (libc::major(metadata.rdev() as _) as u32, libc::minor(metadata.rdev() as _) as u32);
+    if file_type.is_fifo() {
+        Some(Special::Fifo)
+    } else if file_type.is_char_device() {
+        Some(Special::Character(major, minor))
+    } else if file_type.is_block_device() {
+        Some(Special::Block(major, minor))
+    } else {
+        None
+    }
+}
+
+#[cfg(windows)]
+/// The kind of special file a file is, windows has none
+pub(crate) fn special(_metadata: &Metadata) -> Option<Special> {
+    None
+}
+
+#[cfg(unix)]
+/// Create a named pipe or device. Only root can create devices
+pub(crate) fn make_special(path: &Path, special: Special) -> io::Result<()> {
+    let (kind, major, minor) = match special {
+        Special::Fifo => return match unsafe { libc::mkfifo(c_path(path)?.as_ptr(), 0o666) } {
+            0 => Ok(()),
+            _ => Err(io::Error::last_os_error()),
+        },
+        Special::Character(major, minor) => (libc::S_IFCHR, major, minor),
+        Special::Block(major, minor) => (libc::S_IFBLK, major, minor),
+    };
+    if unsafe { libc::geteuid() } != 0 {
+        return Err(io::Error::new(io::ErrorKind::PermissionDenied, "device nodes can only be created by root"));
+    }
+    if unsafe { libc::mknod(c_path(path)?.as_ptr(), kind | 0o666, libc::makedev(major as _, minor as _)) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+/// Create a named pipe or device, unsupported on windows
+pub(crate) fn make_special(_path: &Path, _special: Special) -> io::Result<()> {
+    Err(io::Error::new(io::ErrorKind::Unsupported, "named pipes and devices can't be created on windows"))
+}
+
+#[cfg(unix)]
+/// Create a symbolic link
+pub(crate) fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(target: P, link: Q) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+#[cfg(windows)]
+/// Create a symbolic link. Windows links to files and directories differently, and links to directories fall back on
+/// junctions when the user isn't allowed to create symbolic links, e.g. without developer mode
+pub(crate) fn symlink<P: AsRef<Path>, Q: AsRef<Path>>(target: P, link: Q) -> io::Result<()> {
+    use std::os::windows::fs::{symlink_dir, symlink_file};
+    const ERROR_PRIVILEGE_NOT_HELD: i32 = 1314;
+
+    let (target, link) = (target.as_ref(), link.as_ref());
+    let resolved = link.parent().unwrap_or(Path::new("")).join(target);
+    if !resolved.is_dir() {
+        return symlink_file(target, link);
+    }
+    match symlink_dir(target, link) {
+        Err(e) if e.raw_os_error() == Some(ERROR_PRIVILEGE_NOT_HELD) => junction(&std::path::absolute(&resolved)?, link),
+        result => result,
+    }
+}
+
+#[cfg(windows)]
+/// Create a junction, a link to a directory any user can create. Junctions need an absolute target
+fn junction(target: &Path, link: &Path) -> io::Result<()> {
+    let status = process::Command::new("cmd")
+        .args(["/C", "mklink", "/J"])
+        .arg(link)
+        .arg(target)
+        .stdout(Stdio::null())
+        .status()?;
+    match status.success() {
+        true => Ok(()),
+        false => Err(io::Error::other(format!("mklink /J {:?} {:?} failed, {}", link, target, status))),
+    }
+}
+
+/// Parse a size in bytes, optionally followed by a binary unit, e.g. "4096", "512K", "10G" or "1.5MiB"
+pub(crate) fn parse_size(size: &str) -> Result<u64, String> {
+    let size = size.trim();
+    let number_end = size.find(|c: char| !c.is_ascii_digit() && c != '.').unwrap_or(size.len());
+    let (number, unit) = size.split_at(number_end);
+    let number = number.parse::<f64>().map_err(|_| format!("Expected a size like 4096 or 10G, found {}", size))?;
+    let multiplier = match unit.trim().to_ascii_uppercase().trim_end_matches("IB").trim_end_matches('B') {
+        "" => 1u64,
+        "K" => 1 << 10,
+        "M" => 1 << 20,
+        "G" => 1 << 30,
+        "T" => 1 << 40,
+        "P" => 1 << 50,
+        _ => return Err(format!("Expected a size unit of K, M, G, T or P, found {}", unit)),
+    };
+    Ok((number * multiplier as f64) as u64)
+}
+
+/// Create a file of a size. The file is sparse, taking no space on disk, unless it's preallocated
+pub(crate) fn make_sized(path: &Path, size: &str, preallocate: bool) -> io::Result<()> {
+    let size = parse_size(size).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let file = fs::File::create(path)?;
+    if preallocate && size > 0 {
+        allocate(&file, size)?;
+    }
+    file.set_len(size)
+}
+
+#[cfg(target_os = "linux")]
+/// Allocate the space of a file on disk
+fn allocate(file: &fs::File, size: u64) -> io::Result<()> {
+    if unsafe { libc::fallocate(file.as_raw_fd(), 0, 0, size as libc::off_t) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Allocate the space of a file on disk. Files other than on linux are only sparse if they're made sparse, so setting their
+/// length allocates them
+fn allocate(_file: &fs::File, _size: u64) -> io::Result<()> {
+    Ok(())
+}
+
+/// Pseudo-random numbers from a seed (splitmix64). Fast and reproducible, but not suitable for secrets
+pub(crate) struct SplitMix(pub(crate) u64);
+
+impl SplitMix {
+    pub(crate) fn next(&mut self) -> u64 {
+        self.0 = self.0.wrapping_add(0x9E3779B97F4A7C15);
+        let mut z = self.0;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58476D1CE4E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D049BB133111EB);
+        z ^ (z >> 31)
+    }
+}
+
+/// Generate size pseudo-random bytes from a seed, a chunk at a time
+fn random_chunks<F>(size: u64, seed: u64, mut f: F) -> io::Result<()>
+where
+    F: FnMut(&[u8]) -> io::Result<()>
+{
+    let mut random = SplitMix(seed);
+    let mut buffer = vec![0; 64 * 1024];
+    let mut remaining = size;
+    while remaining > 0 {
+        let len = remaining.min(buffer.len() as u64) as usize;
+        for word in buffer[..len].chunks_mut(8) {
+            word.copy_from_slice(&random.next().to_le_bytes()[..word.len()]);
+        }
+        f(&buffer[..len])?;
+        remaining -= len as u64;
+    }
+    Ok(())
+}
+
+#[cfg(target_os = "linux")]
+/// A random seed from the operating system
+pub(crate) fn random_seed() -> io::Result<u64> {
+    let mut seed = [0u8; 8];
+    if unsafe { libc::getrandom(seed.as_mut_ptr() as *mut libc::c_void, seed.len(), 0) } != seed.len() as isize {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(u64::from_le_bytes(seed))
+}
+
+#[cfg(not(target_os = "linux"))]
+/// A random seed, from the random keys the standard library gets from the operating system for hash maps
+pub(crate) fn random_seed() -> io::Result<u64> {
+    use std::{collections::hash_map::RandomState, hash::BuildHasher, time::SystemTime};
+    Ok(RandomState::new().hash_one(SystemTime::now()))
+}
+
+/// Create a file of a size filled with random data. The data is reproducible if a seed is given
+pub(crate) fn make_random(path: &Path, size: &str, seed: Option<u64>) -> io::Result<()> {
+    let size = parse_size(size).map_err(|e| io::Error::new(io::ErrorKind::InvalidInput, e))?;
+    let seed = match seed {
+        Some(seed) => seed,
+        None => random_seed()?,
+    };
+    let mut file = io::BufWriter::new(fs::File::create(path)?);
+    random_chunks(size, seed, |chunk| file.write_all(chunk))?;
+    file.flush()
+}
+
+/// Sha256 hash, as a hex string, of the random data a seed generates
+pub(crate) fn hash_random(size: u64, seed: u64) -> String {
+    let mut hasher = Sha256::new();
+    random_chunks(size, seed, |chunk| {
+        hasher.update(chunk);
+        Ok(())
+    })
+    .ok();
+    hasher.finalize().iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    #[cfg(unix)]
+    use std::fs;
+
+    #[cfg(unix)]
+    use crate::{CreateOptions, Error, FSchema, OverwritePolicy};
+
+    #[cfg(unix)]
+    use super::special;
+    use super::{hash_random, parse_device, parse_size, Special};
+
+    #[test]
+    fn device() {
+        assert_eq!(parse_device("c 1 3"), Ok(Special::Character(1, 3)));
+        assert_eq!(parse_device("b 8 0"), Ok(Special::Block(8, 0)));
+        assert!(parse_device("p 1 3").is_err());
+        assert!(parse_device("c 1").is_err());
+    }
+
+    #[test]
+    fn size() {
+        assert_eq!(parse_size("4096"), Ok(4096));
+        assert_eq!(parse_size("10G"), Ok(10 << 30));
+        assert_eq!(parse_size("1.5MiB"), Ok(3 << 19));
+        assert_eq!(parse_size("2 kb"), Ok(2048));
+        assert!(parse_size("10X").is_err());
+        assert!(parse_size("G").is_err());
+    }
+
+    #[test]
+    fn random() {
+        assert_eq!(hash_random(100_000, 42), hash_random(100_000, 42));
+        assert_ne!(hash_random(100_000, 42), hash_random(100_000, 43));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rerun() {
+        let root = std::env::temp_dir().join(format!("fschema-special-{}", std::process::id()));
+        let schema = FSchema::from_str(r#"{ "root": {
+            "pipe": ["", {"ftype": "Fifo"}],
+            "zeros": ["4K", {"ftype": "Size"}],
+            "noise": ["1K", {"ftype": "Random", "seed": 7}]
+        } }"#).unwrap();
+        let strict = CreateOptions { overwrite: OverwritePolicy::Error, ..Default::default() };
+        let kind = |name: &str| special(&fs::symlink_metadata(root.join(name)).unwrap());
+
+        schema.create(root.clone()).unwrap();
+        let noise = fs::read(root.join("noise")).unwrap();
+        schema.create_with(root.clone(), &strict).unwrap();
+        assert_eq!(kind("pipe"), Some(Special::Fifo));
+        assert_eq!(fs::read(root.join("noise")).unwrap(), noise);
+        assert_eq!(fs::metadata(root.join("zeros")).unwrap().len(), 4096);
+
+        fs::remove_file(root.join("pipe")).unwrap();
+        fs::write(root.join("pipe"), "not a pipe").unwrap();
+        assert!(matches!(schema.create_with(root.clone(), &strict), Err(Error::Exists(path)) if path.ends_with("pipe")));
+        assert_eq!(kind("pipe"), None);
+        schema.create(root.clone()).unwrap();
+        assert_eq!(kind("pipe"), Some(Special::Fifo));
+
+        // creating devices needs privileges tests may not have
+        let device = FSchema::from_str(r#"{ "root": { "null": ["c 1 3", {"ftype": "Device"}] } }"#).unwrap();
+        if device.create(root.clone()).is_ok() {
+            device.create_with(root.clone(), &strict).unwrap();
+            assert_eq!(kind("null"), Some(Special::Character(1, 3)));
+        }
+
+        fs::remove_dir_all(&root).unwrap();
+    }
+}
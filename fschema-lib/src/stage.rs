@@ -0,0 +1,187 @@
+use std::{
+    env,
+    fs,
+    io,
+    path::{Path, PathBuf},
+    process,
+};
+#[cfg(target_os = "linux")]
+use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+use crate::{CreateOptions, Error, FSchema, FileType, Operation};
+
+impl FSchema {
+    /// Build the file system structure in a sibling of root, then move it into place in a single step. 
+    /// Root must be empty or not exist yet, as carrying existing contents over to the sibling would lose their ownership,
+    /// extended attributes and hard links
+    pub(crate) fn create_staged(&self, root: PathBuf, options: &CreateOptions) -> Result<(), Error> {
+        let root = absolute_root(&root)?;
+        if root.exists() {
+            let empty = fs::read_dir(&root)
+                .map_err(|e| Error::IO(e, format!("{:?}", root)))?
+                .next()
+                .is_none();
+            if !empty {
+                let e = io::Error::new(io::ErrorKind::DirectoryNotEmpty, "a staged build needs root to be empty or not exist yet");
+                return Err(Error::IO(e, format!("{:?}", root)));
+            }
+        }
+        let staging = sibling(&root, "staging");
+        if staging.exists() {
+            fs::remove_dir_all(&staging).map_err(|e| Error::IO(e, format!("{:?}", staging)))?;
+        }
+
+        if let Err(e) = self.build_staging(&root, &staging, options) {
+            fs::remove_dir_all(&staging).ok();
+            return Err(e);
+        }
+
+        swap_into_place(&staging, &root)
+    }
+
+    /// Build the file system structure in the staging directory as if it were built in root
+    fn build_staging(&self, root: &Path, staging: &Path, options: &CreateOptions) -> Result<(), Error> {
+        fs::create_dir(staging).map_err(|e| Error::IO(e, format!("{:?}", staging)))?;
+        if let Ok(metadata) = fs::metadata(root) {
+            fs::set_permissions(staging, metadata.permissions()).map_err(|e| Error::IO(e, format!("{:?}", staging)))?;
+        }
+
+        let mut plan = self.plan_with(staging.to_path_buf(), options)?;
+        for operation in plan.operations_mut() {
+            if let Operation::File { data, options, .. } = operation {
+                if let FileType::Link = options.ftype {
+                    if let Ok(inner) = Path::new(data).strip_prefix(staging) {
+                        *data = root.join(inner).to_string_lossy().to_string();
+                    }
+                }
+            }
+        }
+
+        let current_dir = env::current_dir().map_err(|e| Error::IO(e, "current directory".to_string()))?;
+        let options = CreateOptions {
+            manifest: options.manifest.as_ref().map(|manifest| {
+                let manifest = current_dir.join(manifest);
+                match manifest.strip_prefix(root) {
+                    Ok(inner) => staging.join(inner),
+                    Err(_) => manifest,
+                }
+            }),
+            staged: false,
+            cache: options.cache.as_ref().map(|cache| {
+                let cache = current_dir.join(cache);
+                match cache.strip_prefix(root) {
+                    Ok(inner) => staging.join(inner),
+                    Err(_) => cache,
+                }
+            }),
+            ..options.clone()
+        };
+        plan.execute_with(&options)
+    }
+}
+
+/// Absolute path of root, which may not exist yet. Missing parents of root are created
+fn absolute_root(root: &Path) -> Result<PathBuf, Error> {
+    if root.exists() {
+        return fs::canonicalize(root).map_err(|e| Error::IO(e, format!("{:?}", root)));
+    }
+
+    let parent = match root.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    let name = root.file_name().ok_or_else(|| Error::IO(io::ErrorKind::InvalidInput.into(), format!("{:?}", root)))?;
+    fs::create_dir_all(parent).map_err(|e| Error::IO(e, format!("{:?}", parent)))?;
+    fs::canonicalize(parent)
+        .map(|parent| parent.join(name))
+        .map_err(|e| Error::IO(e, format!("{:?}", parent)))
+}
+
+/// Hidden sibling of root, used to stage or back up the file system structure
+fn sibling(root: &Path, purpose: &str) -> PathBuf {
+    let name = root.file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+    root.with_file_name(format!(".{}.fschema-{}-{}", name, purpose, process::id()))
+}
+
+/// Move the staging directory to root. An existing root is atomically exchanged with the staging directory where supported, 
+/// otherwise it is moved aside first
+fn swap_into_place(staging: &Path, root: &Path) -> Result<(), Error> {
+    let context = || format!("{:?} -> {:?}", staging, root);
+
+    if !root.exists() {
+        return fs::rename(staging, root).map_err(|e| Error::IO(e, context()));
+    }
+
+    match exchange(staging, root) {
+        Ok(()) => fs::remove_dir_all(staging).map_err(|e| Error::IO(e, format!("{:?}", staging))),
+        Err(e) if matches!(e.raw_os_error(), Some(libc::EINVAL) | Some(libc::ENOSYS)) || e.kind() == io::ErrorKind::Unsupported => {
+            let backup = sibling(root, "backup");
+            fs::rename(root, &backup).map_err(|e| Error::IO(e, format!("{:?} -> {:?}", root, backup)))?;
+            if let Err(e) = fs::rename(staging, root) {
+                fs::rename(&backup, root).ok();
+                return Err(Error::IO(e, context()));
+            }
+            fs::remove_dir_all(&backup).map_err(|e| Error::IO(e, format!("{:?}", backup)))
+        },
+        Err(e) => Err(Error::IO(e, context())),
+    }
+}
+
+#[cfg(target_os = "linux")]
+/// Atomically exchange two paths
+fn exchange(a: &Path, b: &Path) -> io::Result<()> {
+    let a = CString::new(a.as_os_str().as_bytes())?;
+    let b = CString::new(b.as_os_str().as_bytes())?;
+    // SAFETY: both paths are valid nul terminated strings that outlive the call
+    let result = unsafe { libc::renameat2(libc::AT_FDCWD, a.as_ptr(), libc::AT_FDCWD, b.as_ptr(), libc::RENAME_EXCHANGE) };
+    if result == 0 {
+        Ok(())
+    } else {
+        Err(io::Error::last_os_error())
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+/// Atomically exchange two paths
+fn exchange(_a: &Path, _b: &Path) -> io::Result<()> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, io};
+
+    use crate::{CreateOptions, Error, FSchema};
+
+    #[test]
+    fn test() {
+        let dir = std::env::temp_dir().join(format!("fschema-stage-{}", std::process::id()));
+        let root = dir.join("out");
+        let options = CreateOptions { staged: true, quiet: true, ..Default::default() };
+        let entries = || fs::read_dir(&dir).unwrap().map(|entry| entry.unwrap().file_name()).collect::<Vec<_>>();
+        let schema = FSchema::from_str(r#"{ "root": { "src": { "main.rs": ["fn main() {}"] }, "current": ["src/main.rs", {"ftype": "Link"}] } }"#).unwrap();
+
+        let failing = FSchema::from_str(r#"{ "root": { "a": ["a"], "b": ["exit 1", {"ftype": "Piped"}] } }"#).unwrap();
+        assert!(failing.create_with(root.clone(), &options).is_err());
+        assert!(!root.exists() && entries().is_empty());
+
+        schema.create_with(root.clone(), &options).unwrap();
+        assert_eq!(fs::read_to_string(root.join("src/main.rs")).unwrap(), "fn main() {}");
+        assert_eq!(fs::read_to_string(root.join("current")).unwrap(), "fn main() {}");
+        assert_eq!(entries(), ["out"]);
+
+        fs::write(root.join("keep"), "mine").unwrap();
+        let result = schema.create_with(root.clone(), &options);
+        assert!(matches!(result, Err(Error::IO(e, _)) if e.kind() == io::ErrorKind::DirectoryNotEmpty));
+        assert_eq!(fs::read_to_string(root.join("keep")).unwrap(), "mine");
+        assert_eq!(entries(), ["out"]);
+
+        fs::remove_dir_all(&root).unwrap();
+        fs::create_dir(&root).unwrap();
+        schema.create_with(root.clone(), &options).unwrap();
+        assert!(root.join("src/main.rs").is_file());
+        assert_eq!(entries(), ["out"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,101 @@
+use std::{
+    path::Path,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+#[cfg(unix)]
+use std::{ffi::CString, io, os::unix::prelude::OsStrExt};
+#[cfg(windows)]
+use std::{fs::{FileTimes, OpenOptions}, os::windows::fs::OpenOptionsExt};
+
+use crate::Error;
+
+/// Parse a timestamp given as an RFC3339 date, e.g. "2024-01-01T00:00:00Z", or as seconds since the unix epoch
+pub(crate) fn parse_time(time: &str) -> Option<SystemTime> {
+    match time.trim().parse::<f64>() {
+        Ok(seconds) => from_seconds(seconds),
+        Err(_) => humantime::parse_rfc3339_weak(time).ok(),
+    }
+}
+
+/// Convert seconds since the unix epoch into a time
+pub(crate) fn from_seconds(seconds: f64) -> Option<SystemTime> {
+    if seconds.is_finite() && seconds >= 0.0 {
+        UNIX_EPOCH.checked_add(Duration::from_secs_f64(seconds))
+    } else {
+        None
+    }
+}
+
+/// Format a time as an RFC3339 date
+pub(crate) fn format_time(time: SystemTime) -> String {
+    match time.duration_since(UNIX_EPOCH).map(|since| since.subsec_nanos()) {
+        Ok(0) => humantime::format_rfc3339(time).to_string(),
+        _ => humantime::format_rfc3339_nanos(time).to_string(),
+    }
+}
+
+#[cfg(unix)]
+/// Set the access and modification times of a file, directory or link, without following links. 
+/// Times that aren't given are left unchanged
+pub(crate) fn set_times(path: &Path, accessed: Option<SystemTime>, modified: Option<SystemTime>) -> Result<(), Error> {
+    if accessed.is_none() && modified.is_none() {
+        return Ok(());
+    }
+
+    let timespec = |time: Option<SystemTime>| match time.map(|time| time.duration_since(UNIX_EPOCH).unwrap_or_default()) {
+        Some(since) => libc::timespec { tv_sec: since.as_secs() as libc::time_t, tv_nsec: since.subsec_nanos() as _ },
+        None => libc::timespec { tv_sec: 0, tv_nsec: libc::UTIME_OMIT },
+    };
+    let times = [timespec(accessed), timespec(modified)];
+    let cpath = CString::new(path.as_os_str().as_bytes())
+        .map_err(|e| Error::IO(io::Error::new(io::ErrorKind::InvalidInput, e), format!("{:?}", path)))?;
+
+    if unsafe { libc::utimensat(libc::AT_FDCWD, cpath.as_ptr(), times.as_ptr(), libc::AT_SYMLINK_NOFOLLOW) } != 0 {
+        return Err(Error::IO(io::Error::last_os_error(), format!("{:?}", path)));
+    }
+    Ok(())
+}
+
+#[cfg(windows)]
+/// Set the access and modification times of a file, directory or link, without following links. 
+/// Times that aren't given are left unchanged
+pub(crate) fn set_times(path: &Path, accessed: Option<SystemTime>, modified: Option<SystemTime>) -> Result<(), Error> {
+    const FILE_WRITE_ATTRIBUTES: u32 = 0x100;
+    const FILE_FLAG_BACKUP_SEMANTICS: u32 = 0x02000000;
+    const FILE_FLAG_OPEN_REPARSE_POINT: u32 = 0x00200000;
+
+    let mut times = FileTimes::new();
+    if let Some(accessed) = accessed {
+        times = times.set_accessed(accessed);
+    }
+    if let Some(modified) = modified {
+        times = times.set_modified(modified);
+    }
+    if accessed.is_none() && modified.is_none() {
+        return Ok(());
+    }
+
+    // directories can only be opened with backup semantics, and links themselves are opened as reparse points
+    OpenOptions::new()
+        .access_mode(FILE_WRITE_ATTRIBUTES)
+        .custom_flags(FILE_FLAG_BACKUP_SEMANTICS | FILE_FLAG_OPEN_REPARSE_POINT)
+        .open(path)
+        .and_then(|file| file.set_times(times))
+        .map_err(|e| Error::IO(e, format!("{:?}", path)))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, UNIX_EPOCH};
+
+    use super::{format_time, parse_time};
+
+    #[test]
+    fn test() {
+        let time = UNIX_EPOCH + Duration::from_secs(1577836800);
+        assert_eq!(parse_time("2020-01-01T00:00:00Z"), Some(time));
+        assert_eq!(parse_time("1577836800"), Some(time));
+        assert_eq!(parse_time(&format_time(time)), Some(time));
+        assert_eq!(parse_time("yesterday"), None);
+    }
+}
@@ -0,0 +1,167 @@
+use std::{collections::HashMap, path::Path};
+
+use crate::{FSchema, Node};
+
+/// Visits the nodes of a schema, depth first in the order they're declared, without changing them
+pub trait NodeVisitor {
+    /// Called with a node, and its path relative to the root, before everything inside it
+    fn enter(&mut self, _path: &Path, _node: &Node) {}
+
+    /// Called with a node, and its path relative to the root, after everything inside it
+    fn leave(&mut self, _path: &Path, _node: &Node) {}
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Default)]
+/// What a transformer does with a node once it has been given it
+pub enum Transform {
+    /// Keep the node, as it has been rewritten
+    #[default]
+    Keep,
+    /// Keep the node, without visiting anything inside it. The same as Keep when leaving a node
+    Skip,
+    /// Remove the node, and everything inside it
+    Remove,
+    /// Keep the node under another name, replacing the entry with that name if there is one
+    Rename(String),
+}
+
+/// Rewrites the nodes of a schema in place, depth first in the order they're declared, e.g. to strip comments or change modes
+pub trait NodeTransformer {
+    /// Called with a node, and its path relative to the root, before everything inside it. Entries added to a directory here
+    /// are transformed too
+    fn enter(&mut self, _path: &Path, _node: &mut Node) -> Transform {
+        Transform::Keep
+    }
+
+    /// Called with a node, and its path relative to the root, after everything inside it
+    fn leave(&mut self, _path: &Path, _node: &mut Node) -> Transform {
+        Transform::Keep
+    }
+}
+
+impl FSchema {
+    /// Give every node of the schema to a visitor
+    pub fn visit<V: NodeVisitor>(&self, visitor: &mut V) {
+        visit_contents(Path::new(""), &self.root, &self.root_ord, visitor);
+    }
+
+    /// Give every node of the schema to a transformer, which can rewrite, rename or remove it
+    pub fn transform<T: NodeTransformer>(&mut self, transformer: &mut T) {
+        transform_contents(Path::new(""), &mut self.root, &mut self.root_ord, transformer);
+    }
+}
+
+/// Give the entries of a directory, and everything inside them, to a visitor
+fn visit_contents<V: NodeVisitor>(parent: &Path, contents: &HashMap<String, Node>, ord: &[String], visitor: &mut V) {
+    for name in ord {
+        let path = parent.join(name);
+        if let Some(node) = contents.get(name) {
+            visitor.enter(&path, node);
+            if let Node::Directory { contents, ord, .. } = node {
+                visit_contents(&path, contents, ord, visitor);
+            }
+            visitor.leave(&path, node);
+        }
+    }
+}
+
+/// Give the entries of a directory, and everything inside them, to a transformer, then rebuild the directory from the nodes
+/// it keeps, under the names it gives them
+fn transform_contents<T: NodeTransformer>(parent: &Path, contents: &mut HashMap<String, Node>, ord: &mut Vec<String>, transformer: &mut T) {
+    let mut entries = HashMap::new();
+    let mut names = vec![];
+    for name in ord.drain(..) {
+        let mut node = match contents.remove(&name) {
+            Some(node) => node,
+            None => continue,
+        };
+        let (mut name, skip) = match transformer.enter(&parent.join(&name), &mut node) {
+            Transform::Remove => continue,
+            Transform::Rename(renamed) => (renamed, false),
+            Transform::Skip => (name, true),
+            Transform::Keep => (name, false),
+        };
+        let path = parent.join(&name);
+        if let (Node::Directory { contents, ord, .. }, false) = (&mut node, skip) {
+            transform_contents(&path, contents, ord, transformer);
+        }
+        match transformer.leave(&path, &mut node) {
+            Transform::Remove => continue,
+            Transform::Rename(renamed) => name = renamed,
+            Transform::Keep | Transform::Skip => (),
+        }
+        if entries.insert(name.clone(), node).is_none() {
+            names.push(name);
+        }
+    }
+    *contents = entries;
+    *ord = names;
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use crate::{FSchema, Node};
+
+    use super::{NodeTransformer, NodeVisitor, Transform};
+
+    /// Records the order nodes are entered and left in
+    struct Trace(Vec<String>);
+
+    impl NodeVisitor for Trace {
+        fn enter(&mut self, path: &Path, _node: &Node) {
+            self.0.push(format!("enter {}", path.display()));
+        }
+
+        fn leave(&mut self, path: &Path, _node: &Node) {
+            self.0.push(format!("leave {}", path.display()));
+        }
+    }
+
+    /// Strips comments, prefixes the entries of the root, makes files private and leaves vendored directories as they are
+    struct Cleanup;
+
+    impl NodeTransformer for Cleanup {
+        fn enter(&mut self, path: &Path, node: &mut Node) -> Transform {
+            match node {
+                Node::Comment(_) => Transform::Remove,
+                Node::Directory { .. } if path.ends_with("vendor") => Transform::Skip,
+                Node::File { options, .. } => {
+                    *options = options.clone().mode(0o600);
+                    Transform::Keep
+                },
+                _ => Transform::Keep,
+            }
+        }
+
+        fn leave(&mut self, path: &Path, _node: &mut Node) -> Transform {
+            match path.parent() {
+                Some(parent) if parent == Path::new("") => Transform::Rename(format!("app-{}", path.display())),
+                _ => Transform::Keep,
+            }
+        }
+    }
+
+    #[test]
+    fn test() {
+        let mut schema = FSchema::from_str(r#"{ "root": {
+            "src": { "main.rs": ["fn main() {}"], "note": "generated" },
+            "vendor": { "lib.rs": [""] },
+            "readme": ["hi", { "mode": "644" }]
+        } }"#).unwrap();
+        let mut trace = Trace(vec![]);
+        schema.visit(&mut trace);
+        assert_eq!(trace.0, [
+            "enter src", "enter src/main.rs", "leave src/main.rs", "enter src/note", "leave src/note", "leave src",
+            "enter vendor", "enter vendor/lib.rs", "leave vendor/lib.rs", "leave vendor", "enter readme", "leave readme",
+        ]);
+
+        schema.transform(&mut Cleanup);
+        assert_eq!(schema.walk().map(|(path, _)| path.to_string_lossy().replace('\\', "/")).collect::<Vec<_>>(), [
+            "app-src", "app-src/main.rs", "app-vendor", "app-vendor/lib.rs", "app-readme",
+        ]);
+        let mode = |path: &str| schema.get(path).and_then(Node::file_options).and_then(|options| options.get_mode());
+        assert_eq!((mode("app-src/main.rs"), mode("app-vendor/lib.rs"), mode("app-readme")), (Some(0o600), None, Some(0o600)));
+    }
+}
@@ -0,0 +1,95 @@
+use std::{collections::HashMap, path::{Path, PathBuf}};
+
+use crate::{FSchema, Node};
+
+/// Iterator over the nodes of a schema, depth first in the order they're declared, with their paths relative to the root
+pub struct Walk<'a> {
+    /// Entries left to visit, with the path of the directory they're in, the last visited first
+    stack: Vec<(PathBuf, &'a str, &'a Node)>,
+}
+
+impl<'a> Walk<'a> {
+    /// Queue the entries of a directory, so the first is visited next
+    fn push(&mut self, parent: &Path, contents: &'a HashMap<String, Node>, ord: &'a [String]) {
+        for name in ord.iter().rev() {
+            if let Some(node) = contents.get(name) {
+                self.stack.push((parent.to_path_buf(), name, node));
+            }
+        }
+    }
+}
+
+impl<'a> Iterator for Walk<'a> {
+    type Item = (PathBuf, &'a Node);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (parent, name, node) = self.stack.pop()?;
+        let path = parent.join(name);
+        if let Node::Directory { contents, ord, .. } = node {
+            self.push(&path, contents, ord);
+        }
+        Some((path, node))
+    }
+}
+
+impl FSchema {
+    /// Every node of the schema, depth first in the order they're declared, with its path relative to the root.
+    /// A directory comes before everything inside it
+    pub fn walk(&self) -> Walk<'_> {
+        let mut walk = Walk { stack: vec![] };
+        walk.push(Path::new(""), &self.root, &self.root_ord);
+        walk
+    }
+
+    /// Call f with every node of the schema, and its path relative to the root, depth first in the order they're declared,
+    /// so nodes can be changed in place. A directory is given to f before everything inside it, so the entries f adds to a
+    /// directory are walked too
+    pub fn walk_mut<F>(&mut self, mut f: F)
+    where
+        F: FnMut(&Path, &mut Node)
+    {
+        walk_contents_mut(Path::new(""), &mut self.root, &self.root_ord, &mut f);
+    }
+}
+
+/// Call f with the entries of a directory, and everything inside them
+fn walk_contents_mut<F>(parent: &Path, contents: &mut HashMap<String, Node>, ord: &[String], f: &mut F)
+where
+    F: FnMut(&Path, &mut Node)
+{
+    for name in ord {
+        let path = parent.join(name);
+        if let Some(node) = contents.get_mut(name) {
+            f(&path, node);
+            if let Node::Directory { contents, ord, .. } = node {
+                walk_contents_mut(&path, contents, ord, f);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::{FSchema, Node};
+
+    #[test]
+    fn test() {
+        let mut schema = FSchema::from_str(r#"{ "root": {
+            "src": { "main.rs": ["fn main() {}"], "bin": { "tool.rs": [""] } },
+            "readme": ["hi"],
+            "docs": {}
+        } }"#).unwrap();
+        let paths = schema.walk().map(|(path, _)| path).collect::<Vec<_>>();
+        assert_eq!(paths, ["src", "src/main.rs", "src/bin", "src/bin/tool.rs", "readme", "docs"].map(PathBuf::from));
+        assert_eq!(schema.walk().filter(|(_, node)| node.is_file()).count(), 3);
+
+        schema.walk_mut(|path, node| {
+            if let Node::File { data, .. } = node {
+                *data = format!("// {}\n{}", path.display(), data);
+            }
+        });
+        assert_eq!(schema.get("src/bin/tool.rs").and_then(Node::data), Some("// src/bin/tool.rs\n"));
+    }
+}
@@ -1,36 +1,695 @@
-use std::{path::PathBuf, str::FromStr, process::exit, env, fs::{self, File}};
+use std::{collections::BTreeMap, io::{self, IsTerminal, Write}, path::{Path, PathBuf}, sync::mpsc, thread, time::{Duration, SystemTime, UNIX_EPOCH}, str::FromStr, process::exit, env, fs};
 
-use clap::Parser;
-use fschema_lib::FSchema;
+use clap::{Parser, Subcommand, Args};
+use fschema_lib::{expand_home, Cache, CreateOptions, CreationOrder, DiffOptions, Drift, Error, FSchema, Format, ImportOptions, Manifest, OverwritePolicy, Policy, Prompt};
+use notify::{RecursiveMode, Watcher};
+use similar::TextDiff;
 
 #[derive(Parser)]
 #[command(author, version, about, long_about = None)]
-struct Args {
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Commands>,
+
+    #[command(flatten)]
+    build: BuildArgs,
+}
+
+#[derive(Subcommand)]
+#[allow(clippy::large_enum_variant)]
+enum Commands {
+    /// Create a file system structure from a schema (default)
+    Build(BuildArgs),
+    /// Create a schema from an existing directory
+    Import(ImportArgs),
+    /// Compare a schema against an existing directory
+    Diff(DiffArgs),
+    /// Remove the files and directories a schema would create
+    Clean(CleanArgs),
+    /// Check the files and directories recorded in a manifest haven't changed
+    Verify(VerifyArgs),
+    /// Rebuild whenever a schema, a file it includes or a file it copies changes. Takes the same options as build
+    Watch(BuildArgs),
+    /// List every command a schema, or any of its profiles, can run, with what runs it, without building it
+    Audit(AuditArgs),
+}
+
+#[derive(Args)]
+struct BuildArgs {
     /// Schema
-    schema: String,
+    #[arg(required = true)]
+    schema: Option<String>,
 
     /// Output Directory
-    output: Option<String>
+    output: Option<String>,
+
+    /// Schema format (json, ron, yaml, toml). Detected from the schema's extension by default
+    #[arg(short, long)]
+    format: Option<Format>,
+
+    /// Print the files, directories, links and commands that would be created or run without touching the file system
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Write a manifest of the created files and directories. Written to the output directory by default
+    #[arg(long, value_name = "PATH", require_equals = true)]
+    manifest: Option<Option<String>>,
+
+    /// Remove everything created so far if an error occurs, and restore anything it overwrote
+    #[arg(long)]
+    rollback: bool,
+
+    /// Build in a temporary directory and move it into place once complete
+    #[arg(long)]
+    atomic: bool,
+
+    /// Save progress to the output directory as the build runs, so it can be resumed with --resume if it fails
+    #[arg(long, conflicts_with = "atomic")]
+    checkpoint: bool,
+
+    /// Resume a build that failed, skipping steps that completed and whose files are unchanged, compared by content hash. Implies --checkpoint
+    #[arg(long, conflicts_with = "atomic")]
+    resume: bool,
+
+    /// Overwrite files that already exist, even those whose "overwrite" option says otherwise. Files without one are
+    /// overwritten by default
+    #[arg(long, group = "overwrite")]
+    force: bool,
+
+    /// Fail if a file that already exists would be replaced, apart from edits such as Append
+    #[arg(long, group = "overwrite")]
+    no_clobber: bool,
+
+    /// Leave files that already exist untouched, apart from edits such as Append
+    #[arg(long, group = "overwrite")]
+    skip_existing: bool,
+
+    /// Rename files that already exist, appending '~' to their names
+    #[arg(long, group = "overwrite")]
+    backup: bool,
+
+    /// Remove entries in the schema's directories that aren't part of the schema. Check what would be removed with --dry-run first
+    #[arg(long)]
+    sync: bool,
+
+    /// Also remove entries directly inside the output directory that aren't part of the schema when syncing
+    #[arg(long, requires = "sync")]
+    sync_root: bool,
+
+    /// Glob pattern, relative to the output directory, of entries never removed by --sync
+    #[arg(long, value_name = "PATTERN", requires = "sync")]
+    exclude: Vec<String>,
+
+    /// Order the entries of a directory are created in (declared, alphabetical)
+    #[arg(long, value_name = "ORDER", default_value = "declared")]
+    order: CreationOrder,
+
+    /// Number of files to create at once. Files with the same defer level are created concurrently
+    #[arg(short, long, value_name = "N", default_value_t = 1)]
+    jobs: usize,
+
+    /// Only recreate files whose definition in the schema changed since the last incremental build
+    #[arg(long)]
+    incremental: bool,
+
+    /// Never download. Fails before anything is created if a download isn't in the download cache
+    #[arg(long)]
+    offline: bool,
+
+    /// Never run the schema's commands. Fails before anything is created if the schema has prebuild, postbuild or hook commands, or Piped or Secret files
+    #[arg(long)]
+    no_exec: bool,
+
+    /// Policy file restricting the programs the schema's commands may run, e.g. {"allowedPrograms": ["git", "make"]}. Fails before any command runs if the schema runs any others
+    #[arg(long, value_name = "PATH")]
+    policy: Option<String>,
+
+    /// Allow the schema's commands to run a program, given by a glob pattern of its name or path, and no others. Added to the programs --policy allows
+    #[arg(long = "allow-program", value_name = "PROGRAM")]
+    allowed_programs: Vec<String>,
+
+    /// Don't show the output of the schema's commands as they run. Failing commands still report the end of their stderr
+    #[arg(short, long)]
+    quiet: bool,
+
+    /// Key Encrypted files are decrypted with, an age identity file or a file holding a GPG passphrase. Read from FSCHEMA_DECRYPTION_KEY by default
+    #[arg(long, value_name = "PATH")]
+    decryption_key: Option<String>,
+
+    /// Set a variable referenced as {{NAME}} in the schema, overriding its value in the schema's variables
+    #[arg(long = "var", value_name = "NAME=VALUE")]
+    vars: Vec<String>,
+
+    /// Identify the machine to the schema's "hosts" options and conditions, instead of its hostname. Sets the "target" variable
+    #[arg(long, value_name = "ID")]
+    target: Option<String>,
+
+    /// Schema deep merged over the schema, replacing its files and removing nodes marked {"$delete": true}. Overlays are merged in the order they're given
+    #[arg(short, long = "overlay", value_name = "PATH")]
+    overlays: Vec<String>,
+
+    /// Activate a profile of the schema, overlaying its nodes and variables on the schema. Profiles are overlaid in the order they're given
+    #[arg(long = "profile", value_name = "NAME")]
+    profiles: Vec<String>,
+
+    /// JSON object answering the schema's prompts, e.g. {"project": "app"}. Prompts that aren't answered here or by --var are asked
+    /// interactively, or take their default if the input isn't a terminal
+    #[arg(long, value_name = "PATH")]
+    answers: Option<String>,
+
+    /// Seed template functions generating random values, e.g. {{uuid()}}, so they generate the same values every build
+    #[arg(long, value_name = "N")]
+    seed: Option<u64>,
+
+    /// Kill commands that run for longer than SECS seconds, failing the build, unless they give their own "timeoutSecs"
+    #[arg(long, value_name = "SECS")]
+    timeout: Option<u64>,
+
+    /// Load variables from a .env file, given to every command's environment and available as {{NAME}}. The schema's own
+    /// "variables" and "env" take precedence over them
+    #[arg(long, value_name = "PATH")]
+    env_file: Option<String>,
+}
+
+#[derive(Args)]
+struct DiffArgs {
+    /// Schema
+    schema: String,
+
+    /// Directory to compare against
+    output: Option<String>,
+
+    /// Schema format (json, ron, yaml, toml). Detected from the schema's extension by default
+    #[arg(short, long)]
+    format: Option<Format>,
+
+    /// Manifest of the directory. Read from the directory by default, if it exists
+    #[arg(long)]
+    manifest: Option<String>,
+
+    /// Download the sources of Download and Archive files that aren't cached, to compare their contents
+    #[arg(long)]
+    download: bool,
+}
+
+#[derive(Args)]
+struct CleanArgs {
+    /// Schema
+    schema: String,
+
+    /// Directory the schema was created in
+    output: Option<String>,
+
+    /// Schema format (json, ron, yaml, toml). Detected from the schema's extension by default
+    #[arg(short, long)]
+    format: Option<Format>,
+
+    /// Manifest of the directory. Only entries recorded in it are removed. Read from the directory by default, if it exists
+    #[arg(long)]
+    manifest: Option<String>,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    /// Directory the schema was created in
+    output: Option<String>,
+
+    /// Manifest of the directory. Read from the directory by default
+    #[arg(long)]
+    manifest: Option<String>,
+}
+
+#[derive(Args)]
+struct AuditArgs {
+    /// Schema
+    schema: String,
+
+    /// Schema format (json, ron, yaml, toml). Detected from the schema's extension by default
+    #[arg(short, long)]
+    format: Option<Format>,
+}
+
+#[derive(Args)]
+struct ImportArgs {
+    /// Directory to import
+    dir: String,
+
+    /// Output schema file. The schema is printed to stdout by default
+    #[arg(short, long)]
+    output: Option<String>,
+
+    /// Schema format (json, ron, yaml, toml). Detected from the output's extension by default
+    #[arg(short, long)]
+    format: Option<Format>,
+
+    /// Largest text file (in bytes) to inline into the schema. Larger or binary files are copied
+    #[arg(long, default_value_t = ImportOptions::default().inline_limit)]
+    inline_limit: u64,
+
+    /// Don't record file permissions
+    #[arg(long)]
+    no_modes: bool,
 }
 
 pub fn main() {
-    let args = Args::parse();
+    let cli = Cli::parse();
 
-    let schema_path = match PathBuf::from_str(&args.schema) {
-        Ok(path) => path,
+    match cli.command {
+        Some(Commands::Build(args)) => build(args),
+        Some(Commands::Import(args)) => import(args),
+        Some(Commands::Diff(args)) => diff(args),
+        Some(Commands::Clean(args)) => clean(args),
+        Some(Commands::Verify(args)) => verify(args),
+        Some(Commands::Watch(args)) => watch(args),
+        Some(Commands::Audit(args)) => audit(args),
+        None => build(cli.build),
+    }
+}
+
+fn build(args: BuildArgs) {
+    let creation_path = output_dir(args.output.clone());
+
+    if args.dry_run {
+        if creation_path.exists() && !creation_path.is_dir() {
+            println!("Output directory must be a directory");
+            exit(1);
+        }
+    } else if !creation_path.exists() {
+        if let Err(e) =  fs::create_dir_all(&creation_path){
+            println!("Output directory could not be created, {}", e);
+            exit(1);
+        }
+    } else if !creation_path.is_dir() {
+        println!("Output directory must be a directory");
+        exit(1);
+    }
+
+    let result = load(&args)
+        .and_then(|schema| Ok((schema, create_options(&args, &creation_path)?)))
+        .and_then(|(schema, options)| apply(&schema, creation_path, &options, args.dry_run));
+    if let Err(e) = result {
+        println!("{}", e);
+        exit(1);
+    }
+}
+
+/// Load the schema a build is given, with its overlays, profiles, .env file, answers and variables applied
+fn load(args: &BuildArgs) -> Result<FSchema, String> {
+    let mut vars = parse_vars(&args.vars);
+    vars.extend(args.target.clone().map(|target| ("target".to_string(), target)));
+    let mut schema = read_schema(args.schema.as_deref().unwrap_or_default(), args.format)?;
+    for overlay in &args.overlays {
+        schema = schema.overlay(&read_schema(overlay, args.format)?);
+    }
+    for profile in &args.profiles {
+        schema = schema.with_profile(profile).map_err(|e| e.to_string())?;
+    }
+    if let Some(path) = &args.env_file {
+        schema = schema.with_env_file(Path::new(path)).map_err(|e| format!("Error loading env file, {}", e))?;
+    }
+    let mut answers = args.answers.as_deref().map(read_answers).unwrap_or_default();
+    answers.extend(vars.iter().cloned());
+    let schema = schema.with_answers_from(&answers, ask).map_err(|e| e.to_string())?.with_vars(vars);
+    let schema = match args.seed {
+        Some(seed) => schema.with_seed(seed),
+        None => schema,
+    };
+    Ok(match args.timeout {
+        Some(timeout) => schema.with_timeout(timeout),
+        None => schema,
+    })
+}
+
+/// Options a build creates the schema with in creation_path
+fn create_options(args: &BuildArgs, creation_path: &Path) -> Result<CreateOptions, String> {
+    let policy = match (&args.policy, args.allowed_programs.is_empty()) {
+        (Some(path), _) => match Policy::load(Path::new(path)) {
+            Ok(policy) => Some(Policy { allowed_programs: [policy.allowed_programs, args.allowed_programs.clone()].concat() }),
+            Err(e) => return Err(format!("Error loading policy, {}", e)),
+        },
+        (None, false) => Some(Policy { allowed_programs: args.allowed_programs.clone() }),
+        (None, true) => None,
+    };
+
+    Ok(CreateOptions {
+        manifest: args.manifest.clone().map(|manifest| match manifest {
+            Some(path) => PathBuf::from(path),
+            None => creation_path.join(Manifest::DEFAULT_NAME),
+        }),
+        rollback_on_error: args.rollback,
+        staged: args.atomic,
+        checkpoint: (args.checkpoint || args.resume).then(|| creation_path.join(Manifest::CHECKPOINT_NAME)),
+        resume: args.resume,
+        force: args.force,
+        overwrite: if args.no_clobber {
+            OverwritePolicy::Error
+        } else if args.skip_existing {
+            OverwritePolicy::Skip
+        } else if args.backup {
+            OverwritePolicy::Backup
+        } else {
+            OverwritePolicy::Overwrite
+        },
+        sync: args.sync,
+        sync_root: args.sync_root,
+        exclude: args.exclude.clone(),
+        cache: args.incremental.then(|| creation_path.join(Cache::DEFAULT_NAME)),
+        parallelism: args.jobs,
+        ordering: args.order,
+        timestamp: source_date_epoch(),
+        offline: args.offline,
+        allow_commands: !args.no_exec,
+        policy,
+        quiet: args.quiet,
+        decryption_key: args.decryption_key.clone().or_else(|| env::var("FSCHEMA_DECRYPTION_KEY").ok()).map(PathBuf::from),
+    })
+}
+
+/// Create the schema in creation_path, or print what would be created if it's a dry run
+fn apply(schema: &FSchema, creation_path: PathBuf, options: &CreateOptions, dry_run: bool) -> Result<(), String> {
+    if dry_run {
+        let plan = schema.plan_with(creation_path, options).map_err(|e| format!("Error planning directory tree from schema, {}", e))?;
+        print!("{}", plan);
+        return Ok(());
+    }
+    schema.create_with(creation_path, options).map_err(|e| format!("Error creating directory tree from schema, {}", e))
+}
+
+fn diff(args: DiffArgs) {
+    let schema = load_schema(&args.schema, args.format);
+    let root = output_dir(args.output);
+
+    let options = DiffOptions { download: args.download };
+    let drift = match find_manifest(&root, args.manifest) {
+        Some((_, manifest)) => schema.diff_with(root, Some(&manifest), &options),
+        None => schema.diff_with(root, None, &options),
+    };
+
+    let drift = match drift {
+        Ok(drift) => drift,
         Err(e) => {
-            println!("Invalid schema path, {}", e);
+            println!("Error comparing directory tree to schema, {}", e);
             exit(1);
         },
     };
 
-    if !schema_path.is_file() {
-        println!("Schema must be a file");
+    print_drift(&drift);
+
+    if !drift.is_empty() {
+        exit(1);
+    }
+}
+
+fn audit(args: AuditArgs) {
+    let schema = load_schema(&args.schema, args.format);
+    let commands = schema.commands();
+    if commands.is_empty() {
+        println!("The schema doesn't run any commands");
+    }
+    for command in commands {
+        println!("{}", command);
+    }
+}
+
+fn verify(args: VerifyArgs) {
+    let root = output_dir(args.output);
+    let manifest = match find_manifest(&root, args.manifest) {
+        Some((_, manifest)) => manifest,
+        None => {
+            println!("Couldn't find a manifest in {:?}", root);
+            exit(1);
+        },
+    };
+
+    let drift = match manifest.verify(&root) {
+        Ok(drift) => drift,
+        Err(e) => {
+            println!("Error verifying directory tree against manifest, {}", e);
+            exit(1);
+        },
+    };
+
+    print_drift(&drift);
+
+    if !drift.is_empty() {
         exit(1);
     }
+}
+
+fn watch(args: BuildArgs) {
+    let root = output_dir(args.output.clone());
+    if let Err(e) = fs::create_dir_all(&root) {
+        println!("Output directory could not be created, {}", e);
+        exit(1);
+    }
+
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = match notify::recommended_watcher(sender) {
+        Ok(watcher) => watcher,
+        Err(e) => {
+            println!("Couldn't watch for changes, {}", e);
+            exit(1);
+        },
+    };
+    let mut watched_dirs: Vec<PathBuf> = vec![];
+
+    loop {
+        let files = rebuild(&args, &root);
+        let dirs = parents(&files);
+        for dir in watched_dirs.iter().filter(|dir| !dirs.contains(dir)) {
+            watcher.unwatch(dir).ok();
+        }
+        for dir in dirs.iter().filter(|dir| !watched_dirs.contains(dir)) {
+            if let Err(e) = watcher.watch(dir, RecursiveMode::NonRecursive) {
+                println!("Couldn't watch {:?}, {}", dir, e);
+            }
+        }
+        watched_dirs = dirs;
+
+        loop {
+            match receiver.recv() {
+                Ok(Ok(event)) if !event.kind.is_access() && event.paths.iter().any(|path| files.contains(path)) => break,
+                Ok(_) => continue,
+                Err(_) => exit(1),
+            }
+        }
+        thread::sleep(Duration::from_millis(100));
+        while receiver.try_recv().is_ok() {}
+    }
+}
+
+/// Build the schema into root as build would, returning the files a change to should trigger a rebuild: the schema, its
+/// overlays, .env file, answers and policy, and if the schema could be read, the files it includes and the files it copies.
+/// Errors are printed rather than stopping the watch
+fn rebuild(args: &BuildArgs, root: &Path) -> Vec<PathBuf> {
+    let mut files = args.schema.iter()
+        .chain(&args.overlays)
+        .chain(&args.env_file)
+        .chain(&args.answers)
+        .chain(&args.policy)
+        .map(|path| absolute(Path::new(path)))
+        .collect::<Vec<_>>();
+    match load(args) {
+        Ok(schema) => {
+            files.extend(schema.loaded_files().iter().map(|path| absolute(path)));
+            match create_options(args, root).and_then(|options| apply(&schema, root.to_path_buf(), &options, args.dry_run)) {
+                Ok(()) if !args.dry_run => println!("Built {:?}", root),
+                Ok(()) => (),
+                Err(e) => println!("{}", e),
+            }
+            match schema.sources(root.to_path_buf()) {
+                Ok(sources) => files.extend(sources.iter().map(|source| absolute(source))),
+                Err(e) => println!("Couldn't find files copied by the schema, {}", e),
+            }
+        },
+        Err(e) => println!("{}", e),
+    }
+    files.sort();
+    files.dedup();
+    files
+}
+
+/// Directories containing files, which are watched rather than the files so files replaced by editors are still noticed
+fn parents(files: &[PathBuf]) -> Vec<PathBuf> {
+    let mut dirs = files.iter().filter_map(|file| file.parent().map(Path::to_path_buf)).collect::<Vec<PathBuf>>();
+    dirs.sort();
+    dirs.dedup();
+    dirs
+}
+
+/// Absolute path of a file that may not exist
+fn absolute(path: &Path) -> PathBuf {
+    let parent = match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    };
+    match (fs::canonicalize(parent), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// Print drift, showing content changes as unified diffs
+fn print_drift(drift: &[Drift]) {
+    for drift in drift {
+        match drift {
+            Drift::Content { path, expected, actual } => {
+                let path = path.to_string_lossy();
+                let expected = String::from_utf8_lossy(expected);
+                let actual = String::from_utf8_lossy(actual);
+                print!("{}", TextDiff::from_lines(&expected, &actual)
+                    .unified_diff()
+                    .header(&format!("schema/{}", path), &path));
+            },
+            drift => println!("{}", drift),
+        }
+    }
+}
+
+fn clean(args: CleanArgs) {
+    let schema = load_schema(&args.schema, args.format);
+    let root = output_dir(args.output);
+
+    let removed = match find_manifest(&root, args.manifest) {
+        Some((path, manifest)) => manifest.remove(&root).and_then(|removed| fs::remove_file(&path)
+            .map(|_| removed)
+            .map_err(|e| Error::IO(e, format!("{:?}", path)))),
+        None => schema.remove(root),
+    };
+
+    if let Err(e) = removed {
+        println!("Error removing directory tree created from schema, {}", e);
+        exit(1);
+    }
+}
+
+/// Load the manifest at the given path, or the manifest stored in root if one exists
+fn find_manifest(root: &Path, manifest: Option<String>) -> Option<(PathBuf, Manifest)> {
+    let path = match manifest {
+        Some(path) => PathBuf::from(path),
+        None => root.join(Manifest::DEFAULT_NAME),
+    };
+
+    if !path.exists() {
+        return None;
+    }
+
+    match Manifest::load(&path) {
+        Ok(manifest) => Some((path, manifest)),
+        Err(e) => {
+            println!("Couldn't load manifest, {}", e);
+            exit(1);
+        },
+    }
+}
+
+/// Time given by SOURCE_DATE_EPOCH, for reproducible builds
+fn source_date_epoch() -> Option<SystemTime> {
+    let epoch = env::var("SOURCE_DATE_EPOCH").ok()?;
+    match epoch.trim().parse::<u64>() {
+        Ok(seconds) => Some(UNIX_EPOCH + Duration::from_secs(seconds)),
+        Err(e) => {
+            println!("SOURCE_DATE_EPOCH must be a number of seconds, {}", e);
+            exit(1);
+        },
+    }
+}
+
+/// Load a schema, detecting its format from its extension if one isn't given
+fn load_schema(path: &str, format: Option<Format>) -> FSchema {
+    match read_schema(path, format) {
+        Ok(schema) => schema,
+        Err(e) => {
+            println!("{}", e);
+            exit(1);
+        },
+    }
+}
+
+/// Parse variables given as NAME=VALUE
+fn parse_vars(vars: &[String]) -> Vec<(String, String)> {
+    vars.iter()
+        .map(|var| match var.split_once('=') {
+            Some((name, value)) if !name.is_empty() => (name.to_string(), value.to_string()),
+            _ => {
+                println!("Invalid variable {}, expected NAME=VALUE", var);
+                exit(1);
+            },
+        })
+        .collect()
+}
+
+/// Read answers to a schema's prompts from a JSON object of strings, numbers and booleans
+fn read_answers(path: &str) -> BTreeMap<String, String> {
+    let answers = fs::read_to_string(path)
+        .map_err(|e| e.to_string())
+        .and_then(|text| serde_json::from_str::<BTreeMap<String, serde_json::Value>>(&text).map_err(|e| e.to_string()));
+    let invalid = |e: String| -> ! {
+        println!("Couldn't read answers from {}, {}", path, e);
+        exit(1);
+    };
+    match answers {
+        Ok(answers) => answers.into_iter()
+            .map(|(name, value)| match value {
+                serde_json::Value::String(value) => (name, value),
+                value @ (serde_json::Value::Number(_) | serde_json::Value::Bool(_)) => (name, value.to_string()),
+                _ => invalid(format!("expected answer to {} to be a string, number or boolean", name)),
+            })
+            .collect(),
+        Err(e) => invalid(e),
+    }
+}
 
-    let creation_path = match args.output {
-        Some(path) => match PathBuf::from_str(&path) {
+/// Ask a prompt on the terminal until it's given a valid answer. An empty answer takes the default. Prompts aren't asked
+/// when the input isn't a terminal
+fn ask(prompt: &Prompt, default: Option<&str>) -> Option<String> {
+    let stdin = io::stdin();
+    if !stdin.is_terminal() {
+        return None;
+    }
+    loop {
+        print!("{}", prompt.message());
+        if !prompt.choices.is_empty() {
+            print!(" ({})", prompt.choices.join("/"));
+        }
+        if let Some(default) = default {
+            print!(" [{}]", default);
+        }
+        print!(": ");
+        let mut answer = String::new();
+        if io::stdout().flush().and_then(|_| stdin.read_line(&mut answer)).is_err() {
+            return None;
+        }
+        let answer = answer.trim();
+        if answer.is_empty() {
+            if default.is_some() {
+                return None;
+            }
+            continue;
+        }
+        match prompt.check(answer) {
+            Ok(_) => return Some(answer.to_string()),
+            Err(e) => println!("{}", e),
+        }
+    }
+}
+
+/// Read a schema, detecting its format from its extension if one isn't given
+fn read_schema(path: &str, format: Option<Format>) -> Result<FSchema, String> {
+    let schema_path = PathBuf::from_str(path).map_err(|e| format!("Invalid schema path, {}", e))?;
+
+    if !schema_path.is_file() {
+        return Err("Schema must be a file".to_string());
+    }
+
+    FSchema::from_path(&schema_path, format).map_err(|e| format!("Couldn't parse schema, {}", e))
+}
+
+/// Get the output directory, defaulting to the current directory. "~", "$HOME" and XDG base directories are expanded
+fn output_dir(output: Option<String>) -> PathBuf {
+    match output {
+        Some(path) => match expand_home(&path).map_err(|e| e.to_string()).and_then(|path| PathBuf::from_str(&path).map_err(|e| e.to_string())) {
             Ok(path) => path,
             Err(e) => {
                 println!("Invalid output path, {}", e);
@@ -44,37 +703,132 @@ pub fn main() {
                 exit(1);
             },
         },
-    };
+    }
+}
 
-    if !creation_path.exists() {
-        if let Err(e) =  fs::create_dir_all(&creation_path){
-            println!("Output directory could not be created, {}", e);
+fn import(args: ImportArgs) {
+    let dir_path = match PathBuf::from_str(&args.dir) {
+        Ok(path) => path,
+        Err(e) => {
+            println!("Invalid directory path, {}", e);
             exit(1);
-        }
-    } else if !creation_path.is_dir() {
-        println!("Output directory must be a directory");
+        },
+    };
+
+    if !dir_path.is_dir() {
+        println!("Imported path must be a directory");
         exit(1);
     }
 
-    let mut reader = match File::open(&schema_path) {
+    let options = ImportOptions {
+        inline_limit: args.inline_limit,
+        modes: !args.no_modes,
+    };
+
+    let schema = match FSchema::from_dir(&dir_path, &options) {
         Ok(schema) => schema,
         Err(e) => {
-            println!("Couldn't open schema, {}", e);
+            println!("Error importing directory, {}", e);
             exit(1);
         },
     };
 
-    let schema = match FSchema::from_reader(&mut reader) {
-        Ok(schema) => schema,
+    let format = args.format
+        .or_else(|| args.output.as_ref().and_then(|path| Format::from_path(path.as_ref())))
+        .unwrap_or_default();
+
+    let serialized = match schema.to_string_format(format) {
+        Ok(serialized) => serialized,
         Err(e) => {
-            println!("Couldn't parse schema, {}", e);
+            println!("Couldn't serialize schema, {}", e);
             exit(1);
         },
     };
 
-    if let Err(e) =  schema.create(creation_path) {
-        println!("Error creating directory tree from schema, {}", e);
-        exit(1);
+    match args.output {
+        Some(path) => if let Err(e) = fs::write(&path, serialized) {
+            println!("Couldn't write schema, {}", e);
+            exit(1);
+        },
+        None => println!("{}", serialized),
     }
-    
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs, process};
+
+    use clap::Parser;
+
+    use super::{parents, rebuild, BuildArgs, Cli, Commands};
+
+    /// Arguments of `fschema watch` given args
+    fn watch_args(args: &[&str]) -> BuildArgs {
+        match Cli::parse_from(["fschema", "watch"].iter().chain(args)).command {
+            Some(Commands::Watch(args)) => args,
+            _ => unreachable!("watch was given"),
+        }
+    }
+
+    #[test]
+    fn watch() {
+        let dir = fs::canonicalize(env::temp_dir()).unwrap().join(format!("fschema-watch-{}", process::id()));
+        fs::create_dir_all(dir.join("assets")).unwrap();
+        fs::write(dir.join("assets/logo"), "v1").unwrap();
+        let schema = dir.join("schema.json");
+        fs::write(&schema, format!(r#"{{ "root": {{ "logo": ["{}", {{"ftype": "Copy"}}], "readme": ["hi"] }} }}"#, dir.join("assets/logo").display())).unwrap();
+        let root = dir.join("out");
+        let args = watch_args(&[&schema.to_string_lossy(), &root.to_string_lossy(), "--quiet"]);
+
+        let files = rebuild(&args, &root);
+        assert_eq!(files, [dir.join("assets/logo"), schema.clone()]);
+        assert_eq!(parents(&files), [dir.clone(), dir.join("assets")]);
+        assert_eq!(fs::read_to_string(root.join("logo")).unwrap(), "v1");
+
+        fs::write(dir.join("assets/logo"), "v2").unwrap();
+        rebuild(&args, &root);
+        assert_eq!(fs::read_to_string(root.join("logo")).unwrap(), "v2");
+
+        fs::write(&schema, "{ \"root\": ").unwrap();
+        assert_eq!(rebuild(&args, &root), [schema]);
+        assert_eq!(fs::read_to_string(root.join("logo")).unwrap(), "v2");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn watch_loaded() {
+        let dir = fs::canonicalize(env::temp_dir()).unwrap().join(format!("fschema-watch-loaded-{}", process::id()));
+        fs::create_dir_all(dir.join("parts")).unwrap();
+        fs::write(dir.join("parts/motd"), "hello {{name}}").unwrap();
+        fs::write(dir.join("parts/etc.json"), r#"{ "root": { "hosts": ["{{host}}"] } }"#).unwrap();
+        fs::write(dir.join(".env"), "host=db\n").unwrap();
+        fs::write(dir.join("local.env"), "port=80\n").unwrap();
+        fs::write(dir.join("overlay.json"), r#"{ "root": { "port": ["{{port}}"] } }"#).unwrap();
+        let schema = dir.join("schema.json");
+        fs::write(&schema, r#"{
+            "envFile": ".env",
+            "variables": { "name": "schema" },
+            "root": { "$include": "parts/etc.json", "motd": { "$include": "parts/motd" } }
+        }"#).unwrap();
+        let root = dir.join("out");
+        let args = watch_args(&[
+            &schema.to_string_lossy(), &root.to_string_lossy(), "--quiet", "--var", "name=var",
+            "--overlay", &dir.join("overlay.json").to_string_lossy(), "--env-file", &dir.join("local.env").to_string_lossy(),
+        ]);
+
+        let files = rebuild(&args, &root);
+        assert_eq!(files, [dir.join(".env"), dir.join("local.env"), dir.join("overlay.json"), dir.join("parts/etc.json"), dir.join("parts/motd"), schema.clone()]);
+        assert_eq!(fs::read_to_string(root.join("motd")).unwrap(), "hello var");
+        assert_eq!(fs::read_to_string(root.join("hosts")).unwrap(), "db");
+        assert_eq!(fs::read_to_string(root.join("port")).unwrap(), "80");
+
+        fs::write(dir.join("parts/motd"), "bye {{name}}").unwrap();
+        fs::write(dir.join(".env"), "host=cache\n").unwrap();
+        rebuild(&args, &root);
+        assert_eq!(fs::read_to_string(root.join("motd")).unwrap(), "bye var");
+        assert_eq!(fs::read_to_string(root.join("hosts")).unwrap(), "cache");
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}